@@ -2,18 +2,54 @@ import { render as prender } from "preact-render-to-string";
 import { h } from "preact";
 import { Helmet } from "react-helmet";
 
+// when the page's client-side JS is loaded, from `export const hydrate`
+// in the page module. "none" pages don't get a hydration script at all.
+const HYDRATE_MODES = ["load", "idle", "visible", "none"];
+
+const hydrateCall = (mode) => {
+  switch (mode) {
+    case "idle":
+      return `if ("requestIdleCallback" in window) {
+  requestIdleCallback(renderPage);
+} else {
+  setTimeout(renderPage, 200);
+}`;
+    case "visible":
+      return `const section = document.getElementById("toast-page-section");
+if ("IntersectionObserver" in window) {
+  const observer = new IntersectionObserver((entries) => {
+    if (entries.some((entry) => entry.isIntersecting)) {
+      observer.disconnect();
+      renderPage();
+    }
+  });
+  observer.observe(section);
+} else {
+  renderPage();
+}`;
+    default:
+      return "renderPage();";
+  }
+};
+
+const hydrationScript = ({ hydrate, componentPath, pageWrapperPath, dataPath }) =>
+  hydrate === "none"
+    ? ""
+    : `<script>
+window.componentPath = "${componentPath}";
+window.wrapperComponentPath = ${pageWrapperPath && `"${pageWrapperPath}"`};
+window.dataPath = ${dataPath && `"${dataPath}"`};
+</script>`;
+
 const htmlTemplate = ({
   componentPath,
   pageWrapperPath,
   dataPath,
   appHtml,
   helmet,
+  hydrate,
 }) => `<!DOCTYPE html>
-<script>
-window.componentPath = "${componentPath}";
-window.wrapperComponentPath = ${pageWrapperPath && `"${pageWrapperPath}"`};
-window.dataPath = ${dataPath && `"${dataPath}"`};
-</script>
+${hydrationScript({ hydrate, componentPath, pageWrapperPath, dataPath })}
 <html ${helmet.htmlAttributes.toString()}>
   <head>
   ${helmet.title.toString()}
@@ -24,7 +60,7 @@ window.dataPath = ${dataPath && `"${dataPath}"`};
   </head>
   <body ${helmet.bodyAttributes.toString()}>
     <div id="toast-page-section">${appHtml}</div>
-    <script type="module">
+    ${hydrate === "none" ? "" : `<script type="module">
     /* @jsx jsx */
 
 async function renderPage() {
@@ -59,9 +95,9 @@ async function renderPage() {
   );
 }
 
-renderPage();
+${hydrateCall(hydrate)}
 
-</script>
+</script>`}
   </body>
 </html>
 `;
@@ -75,7 +111,16 @@ export const render = async ({
   browserComponentPath,
   browserPageWrapperPath,
   browserDataPath,
+  hydrate = "load",
 }) => {
+  if (!HYDRATE_MODES.includes(hydrate)) {
+    console.warn(
+      `unknown hydrate mode "${hydrate}" for ${browserComponentPath}, expected one of ${HYDRATE_MODES.join(
+        ", "
+      )}. Hydrating on load instead.`
+    );
+    hydrate = "load";
+  }
   browserPageWrapperPath = pageWrapper ? browserPageWrapperPath : undefined;
   pageWrapper = pageWrapper
     ? pageWrapper
@@ -96,5 +141,6 @@ export const render = async ({
         : undefined,
     appHtml: output,
     helmet,
+    hydrate,
   });
 };
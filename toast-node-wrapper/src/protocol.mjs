@@ -0,0 +1,21 @@
+// The version of the contract between these scripts and the toast
+// binary: the arguments, stdin, and stdout each script uses. Bump it with
+// `PROTOCOL_VERSION` in `toast/src/protocol.rs`, and `toastProtocol` in
+// `package.json`, whenever either side changes in a way the other has to
+// know about.
+export const PROTOCOL_VERSION = 1;
+
+// The binary sets `TOAST_PROTOCOL` for every node process it starts, so a
+// script run by a binary it wasn't written for stops before it reads or
+// writes anything the binary would misread.
+const expected = process.env.TOAST_PROTOCOL;
+if (expected !== undefined && Number(expected) !== PROTOCOL_VERSION) {
+  const upgrade =
+    Number(expected) > PROTOCOL_VERSION
+      ? "upgrade the toast npm package to match the toast binary"
+      : "upgrade the toast binary to match the toast npm package";
+  console.error(
+    `toast's node scripts speak protocol ${PROTOCOL_VERSION}, but the toast binary speaks protocol ${expected}: ${upgrade}`
+  );
+  process.exit(1);
+}
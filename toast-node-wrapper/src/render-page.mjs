@@ -0,0 +1,151 @@
+import path from "path";
+import { fileURLToPath } from "url";
+import { promises as fs, existsSync } from "fs";
+import { performance } from "perf_hooks";
+import "./module-aliases.mjs";
+import { render } from "./page-renderer-pre.mjs";
+
+// Imports are relative to this file because they can't begin with a drive
+// letter on Windows.
+const importPath = (filepath, version) => {
+  const relative =
+    "./" +
+    path.posix.join(
+      ...path
+        .relative(path.dirname(fileURLToPath(import.meta.url)), filepath)
+        .split(path.sep)
+    );
+  // a new version makes node import the module again instead of using
+  // the copy it already has
+  return version === undefined ? relative : `${relative}?v=${version}`;
+};
+
+const DEFAULT_PAGE_ROOTS = [{ dir: "src/pages", prefix: "" }];
+
+// where a page module goes in the output directory, inside of the url
+// prefix of the deepest page root it's in. Pages created by toast.js aren't
+// in a root, and are already relative to the output directory. Must match
+// `pages::page_path` in the toast binary.
+const pagePath = (file, pageRoots) => {
+  const [root] = pageRoots
+    .filter(({ dir }) => file.startsWith(`${dir}/`))
+    .sort((a, b) => b.dir.length - a.dir.length);
+  if (!root) {
+    return file;
+  }
+  const rest = file.slice(root.dir.length + 1);
+  return root.prefix ? `${root.prefix}/${rest}` : rest;
+};
+
+// the page wrapper is optional, so this resolves to undefined if the
+// project doesn't have one
+export async function importPageWrapper(srcDir, version) {
+  const pageWrapperPath = path.join(srcDir, "src", "page-wrapper.js");
+  if (!existsSync(pageWrapperPath)) {
+    return undefined;
+  }
+  try {
+    const wrapper = await import(importPath(pageWrapperPath, version));
+    return wrapper.default;
+  } catch (e) {
+    console.error("Error while importing page-wrapper", e);
+  }
+}
+
+// a page that exports `collections` gets those collections from the
+// content index as a `collections` prop. `"*"` is every page on the site.
+async function loadCollections(outputDir, names) {
+  const collections = {};
+  for (const name of names) {
+    const file =
+      name === "*"
+        ? path.resolve(outputDir, "toast-data", "pages.json")
+        : path.resolve(outputDir, "toast-data", "collections", `${name}.json`);
+    try {
+      collections[name] = JSON.parse(await fs.readFile(file));
+    } catch (e) {
+      // a collection with no pages in it doesn't have a file
+      collections[name] = [];
+    }
+  }
+  return collections;
+}
+
+// the toast binary adds head tags from a page's `meta` export, which is
+// an object or a function of the page's props. See `toast/src/head.rs`.
+async function writeMeta(outputDir, outputPath, meta, data) {
+  const metaPath = path.resolve(
+    outputDir,
+    "toast-data",
+    "meta",
+    outputPath.replace(".js", ".json")
+  );
+  if (meta === undefined) {
+    // the page stopped exporting `meta` since it was last rendered
+    await fs.unlink(metaPath).catch(() => {});
+    return;
+  }
+  const value = typeof meta === "function" ? await meta(data || {}) : meta;
+  await fs.mkdir(path.dirname(metaPath), { recursive: true });
+  await fs.writeFile(metaPath, JSON.stringify(value));
+}
+
+// render one page module to html in the output directory and report how
+// long rendering and writing it took
+export async function renderPage({
+  srcDir,
+  outputDir,
+  file,
+  pageWrapper,
+  version,
+  pageRoots = DEFAULT_PAGE_ROOTS,
+}) {
+  const renderStart = performance.now();
+  const nodeComponent = await import(
+    importPath(path.resolve(srcDir, file), version)
+  );
+  const outputPath = pagePath(file, pageRoots);
+  const dataPath = `${path.resolve(outputDir, outputPath)}on`;
+  let data;
+  try {
+    data = await fs.readFile(dataPath);
+    data = JSON.parse(data);
+  } catch (e) {
+    // TODO: figure out what errors are important here
+  }
+  if (Array.isArray(nodeComponent.collections)) {
+    data = {
+      ...data,
+      collections: await loadCollections(outputDir, nodeComponent.collections),
+    };
+    // the browser hydrates from the same props, so they're written back
+    await fs.mkdir(path.dirname(dataPath), { recursive: true });
+    await fs.writeFile(dataPath, JSON.stringify(data));
+  }
+  const html = await render({
+    component: nodeComponent.default,
+    pageWrapper,
+    data,
+    browserPageWrapperPath: "/src/page-wrapper.js",
+    browserComponentPath: path.resolve("/", file),
+    // .js(on)
+    browserDataPath: path.resolve("/", `${outputPath}on`),
+    hydrate: nodeComponent.hydrate,
+  });
+
+  const writeStart = performance.now();
+  // write HTML file out for page
+  const htmlFilePath = path.resolve(
+    outputDir,
+    outputPath.replace(".js", ".html")
+  );
+  // pages in a prefixed root can be the first thing in their directory
+  await fs.mkdir(path.dirname(htmlFilePath), { recursive: true });
+  await fs.writeFile(htmlFilePath, html);
+  await writeMeta(outputDir, outputPath, nodeComponent.meta, data);
+  return {
+    page: file,
+    render_ms: writeStart - renderStart,
+    write_ms: performance.now() - writeStart,
+  };
+}
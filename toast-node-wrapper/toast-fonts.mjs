@@ -0,0 +1,27 @@
+import "./src/protocol.mjs";
+import subsetFont from "subset-font";
+import { promises as fs } from "fs";
+
+main();
+
+// reads a json array of `{ input, output, text, format }` font jobs from
+// stdin and writes each font, subset to the characters in `text`, to
+// `output`. See `toast/src/fonts.rs`.
+async function main() {
+  const jobs = JSON.parse(await readStdin());
+  await Promise.all(jobs.map(subset));
+}
+
+async function subset({ input, output, text, format }) {
+  const font = await fs.readFile(input);
+  const subset = await subsetFont(font, text, { targetFormat: format });
+  await fs.writeFile(output, subset);
+}
+
+async function readStdin() {
+  const chunks = [];
+  for await (const chunk of process.stdin) {
+    chunks.push(chunk);
+  }
+  return Buffer.concat(chunks).toString("utf-8");
+}
@@ -0,0 +1,54 @@
+import "./src/protocol.mjs";
+import sharp from "sharp";
+import path from "path";
+import { promises as fs } from "fs";
+
+main();
+
+// reads a json array of image jobs from stdin and writes a json array of
+// `{ width, height, variants }` to stdout, in the same order, for the
+// toast binary to read. See `toast/src/images.rs`.
+async function main() {
+  const jobs = JSON.parse(await readStdin());
+  const results = await Promise.all(jobs.map(processImage));
+  process.stdout.write(JSON.stringify(results));
+}
+
+async function processImage({
+  input,
+  output_prefix,
+  src_prefix,
+  widths,
+  formats,
+}) {
+  const { width, height } = await sharp(input).metadata();
+  // never enlarge images. If the original is smaller than every width,
+  // it's still worth re-encoding it at its own size.
+  let targetWidths = widths.filter((w) => w <= width);
+  if (targetWidths.length === 0) {
+    targetWidths = [width];
+  }
+  await fs.mkdir(path.dirname(output_prefix), { recursive: true });
+
+  const variants = [];
+  for (const targetWidth of targetWidths) {
+    for (const format of formats) {
+      const extension = format === "jpeg" ? "jpg" : format;
+      const suffix = `-${targetWidth}.${extension}`;
+      await sharp(input)
+        .resize({ width: targetWidth })
+        .toFormat(format)
+        .toFile(output_prefix + suffix);
+      variants.push({ src: src_prefix + suffix, width: targetWidth, format });
+    }
+  }
+  return { width, height, variants };
+}
+
+async function readStdin() {
+  const chunks = [];
+  for await (const chunk of process.stdin) {
+    chunks.push(chunk);
+  }
+  return Buffer.concat(chunks).toString("utf-8");
+}
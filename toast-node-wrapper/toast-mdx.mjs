@@ -0,0 +1,194 @@
+import "./src/protocol.mjs";
+import mdx from "@mdx-js/mdx";
+import shiki from "shiki";
+
+main();
+
+// reads a json object of `{ [filepath]: mdx }` from stdin, compiles each
+// file to a jsx module and writes a json object of
+// `{ [filepath]: { jsx, headings } }` to stdout for the toast binary to
+// read. Frontmatter has already been removed by the toast binary. Files
+// that fail to compile are `{ error: { message, line, column, length } }`
+// instead, so the toast binary can show where.
+// `--highlight-theme=<theme>` highlights code blocks with shiki, and
+// `--heading-anchors` links every heading to itself.
+async function main() {
+  const files = JSON.parse(await readStdin());
+  const themeFlag = process.argv.find((arg) =>
+    arg.startsWith("--highlight-theme=")
+  );
+  const anchors = process.argv.includes("--heading-anchors");
+  const rehypePlugins = [];
+  if (themeFlag) {
+    const highlighter = await shiki.getHighlighter({
+      theme: themeFlag.slice("--highlight-theme=".length),
+    });
+    rehypePlugins.push(highlightCodeBlocks(highlighter));
+  }
+  const compiled = {};
+  await Promise.all(
+    Object.entries(files).map(async ([file, contents]) => {
+      const headings = [];
+      let jsx;
+      try {
+        jsx = await mdx(contents, {
+          filepath: file,
+          rehypePlugins: [...rehypePlugins, headingIds(anchors, headings)],
+        });
+      } catch (e) {
+        compiled[file] = { error: compileError(e) };
+        return;
+      }
+      compiled[file] = {
+        jsx: `import { h } from "preact";
+import { mdx } from "@mdx-js/preact";
+${jsx}`,
+        headings,
+      };
+    })
+  );
+  process.stdout.write(JSON.stringify(compiled));
+}
+
+// where an error from the mdx compiler is. vfile messages have a 1-based
+// line and column and the span they're about, and babel's syntax errors
+// have a 0-based column in `loc`
+function compileError(e) {
+  const error = { message: e.reason || e.message };
+  if (e.line) {
+    error.line = e.line;
+    error.column = e.column || 1;
+    const end = e.location && e.location.end;
+    if (end && end.line === e.line && end.column > error.column) {
+      error.length = end.column - error.column;
+    }
+  } else if (e.loc) {
+    error.line = e.loc.line;
+    error.column = e.loc.column + 1;
+  }
+  return error;
+}
+
+// replaces every `<pre><code class="language-*">` with one that has a
+// span with an inline color for each token
+function highlightCodeBlocks(highlighter) {
+  const languages = new Set(highlighter.getLoadedLanguages());
+  const highlight = (node) => {
+    const code = node.children.find((child) => child.tagName === "code");
+    const language = ((code && code.properties.className) || [])
+      .map(String)
+      .find((name) => name.startsWith("language-"));
+    const lang = language && language.slice("language-".length);
+    if (!lang || !languages.has(lang)) {
+      return node;
+    }
+    const lines = highlighter.codeToThemedTokens(
+      textContent(code).replace(/\n$/, ""),
+      lang
+    );
+    const children = [];
+    lines.forEach((tokens, index) => {
+      if (index > 0) {
+        children.push({ type: "text", value: "\n" });
+      }
+      children.push(
+        element(
+          "span",
+          { className: ["line"] },
+          tokens.map((token) =>
+            element(
+              "span",
+              { style: tokenStyle(token) },
+              [{ type: "text", value: token.content }]
+            )
+          )
+        )
+      );
+    });
+    return element(
+      "pre",
+      {
+        className: ["shiki"],
+        style: `background-color: ${highlighter.getBackgroundColor()}; color: ${highlighter.getForegroundColor()}`,
+      },
+      [element("code", { className: [language] }, children)]
+    );
+  };
+  const visit = (node) => {
+    if (node.children) {
+      node.children = node.children.map((child) =>
+        child.tagName === "pre" ? highlight(child) : visit(child)
+      );
+    }
+    return node;
+  };
+  return () => visit;
+}
+
+// gives every heading an id from its text, the same way the toast binary
+// does for `.md` pages (see `taxonomy::slugify` and `toc::Slugger`), and
+// pushes `{ depth, text, id }` onto `headings` in document order
+function headingIds(anchors, headings) {
+  const seen = new Map();
+  const slug = (text) => {
+    const base =
+      text
+        .toLowerCase()
+        .split(/[^\p{L}\p{N}]/u)
+        .filter(Boolean)
+        .join("-") || "section";
+    const count = seen.get(base) || 0;
+    seen.set(base, count + 1);
+    return count === 0 ? base : `${base}-${count}`;
+  };
+  const visit = (node) => {
+    const depth = /^h([1-6])$/.exec(node.tagName || "");
+    if (depth) {
+      const text = textContent(node);
+      const id = slug(text);
+      node.properties = { ...node.properties, id };
+      if (anchors) {
+        node.children.push(
+          element(
+            "a",
+            { className: ["heading-anchor"], href: `#${id}`, ariaHidden: "true" },
+            [{ type: "text", value: "#" }]
+          )
+        );
+      }
+      headings.push({ depth: Number(depth[1]), text, id });
+    } else if (node.children) {
+      node.children.forEach(visit);
+    }
+    return node;
+  };
+  return () => visit;
+}
+
+// shiki's font styles are bit flags
+function tokenStyle(token) {
+  const styles = [`color: ${token.color}`];
+  if (token.fontStyle & 1) styles.push("font-style: italic");
+  if (token.fontStyle & 2) styles.push("font-weight: bold");
+  if (token.fontStyle & 4) styles.push("text-decoration: underline");
+  return styles.join("; ");
+}
+
+function element(tagName, properties, children) {
+  return { type: "element", tagName, properties, children };
+}
+
+function textContent(node) {
+  if (node.type === "text") {
+    return node.value;
+  }
+  return (node.children || []).map(textContent).join("");
+}
+
+async function readStdin() {
+  const chunks = [];
+  for await (const chunk of process.stdin) {
+    chunks.push(chunk);
+  }
+  return Buffer.concat(chunks).toString("utf-8");
+}
@@ -0,0 +1,65 @@
+import "./src/protocol.mjs";
+import { pathToFileURL } from "url";
+
+main();
+
+// reads `{ hook, plugins, inputs }` from stdin, calls `hook` in every
+// plugin module that exports it for each input, and writes a json array
+// of results to stdout, in the same order, for the toast binary to read.
+// See `toast/src/plugins.rs`.
+async function main() {
+  const { hook, plugins, inputs } = JSON.parse(await readStdin());
+  const modules = await Promise.all(
+    plugins.map((plugin) => import(pathToFileURL(plugin).href))
+  );
+  const hooks = modules
+    .map((module) => module[hook])
+    .filter((fn) => typeof fn === "function");
+  const results = [];
+  for (const input of inputs) {
+    results.push(await runHook(hook, hooks, input));
+  }
+  process.stdout.write(JSON.stringify(results));
+}
+
+// each plugin gets what the one before it returned
+async function runHook(hook, hooks, input) {
+  switch (hook) {
+    case "onPageDiscovered": {
+      for (const fn of hooks) {
+        if ((await fn(input)) === false) {
+          return false;
+        }
+      }
+      return true;
+    }
+    case "transformSource": {
+      let source = input.source;
+      for (const fn of hooks) {
+        source = (await fn({ ...input, source })) ?? source;
+      }
+      return source;
+    }
+    case "onHtmlRendered": {
+      let html = input.html;
+      for (const fn of hooks) {
+        html = (await fn({ ...input, html })) ?? html;
+      }
+      return html;
+    }
+    default: {
+      for (const fn of hooks) {
+        await fn(input);
+      }
+      return null;
+    }
+  }
+}
+
+async function readStdin() {
+  const chunks = [];
+  for await (const chunk of process.stdin) {
+    chunks.push(chunk);
+  }
+  return Buffer.concat(chunks).toString("utf-8");
+}
@@ -0,0 +1,47 @@
+import "./src/protocol.mjs";
+import readline from "readline";
+import { importPageWrapper, renderPage } from "./src/render-page.mjs";
+
+// A long-lived renderer for `renderer::NodePool` in the toast binary.
+//
+// Requests are newline-delimited json on stdin:
+//   { "id": 1, "srcDir": "...", "outputDir": "...", "page": "src/pages/index.js", "pageRoots": [{ "dir": "src/pages", "prefix": "" }], "version": 0 }
+// and each one gets a line of json on stdout, in whatever order pages
+// finish:
+//   { "id": 1, "page": "src/pages/index.js", "render_ms": 1.2, "write_ms": 0.3 }
+//   { "id": 1, "page": "src/pages/index.js", "error": "..." }
+//
+// stdout is only for responses, so anything pages log goes to stderr.
+const respond = (response) =>
+  process.stdout.write(JSON.stringify(response) + "\n");
+console.log = console.error;
+console.info = console.error;
+
+// the page wrapper is imported once per version of the site
+const pageWrappers = new Map();
+const getPageWrapper = (srcDir, version) => {
+  const key = `${srcDir}@${version}`;
+  if (!pageWrappers.has(key)) {
+    pageWrappers.set(key, importPageWrapper(srcDir, version));
+  }
+  return pageWrappers.get(key);
+};
+
+const lines = readline.createInterface({ input: process.stdin });
+lines.on("line", async (line) => {
+  const { id, srcDir, outputDir, page, pageRoots, version } = JSON.parse(line);
+  try {
+    const pageWrapper = await getPageWrapper(srcDir, version);
+    const timing = await renderPage({
+      srcDir,
+      outputDir,
+      file: page,
+      pageWrapper,
+      version,
+      pageRoots,
+    });
+    respond({ id, ...timing });
+  } catch (e) {
+    respond({ id, page, error: (e && e.stack) || String(e) });
+  }
+});
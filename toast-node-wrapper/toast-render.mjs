@@ -1,76 +1,44 @@
-import path from "path";
-import { fileURLToPath } from "url";
-import { promises as fs, existsSync } from "fs";
-import "./src/module-aliases.mjs";
-import { render } from "./src/page-renderer-pre.mjs";
+import "./src/protocol.mjs";
+import { importPageWrapper, renderPage } from "./src/render-page.mjs";
 
 // loader doesn't show up in argv
-const [_node, _binStr, srcDir, outputDir, ...args] = process.argv;
+const [_node, _binStr, srcDir, outputDir, ...rest] = process.argv;
+// `--page-roots=<json>` can come before the list of pages
+const PAGE_ROOTS_FLAG = "--page-roots=";
+const pageRoots =
+  rest[0] && rest[0].startsWith(PAGE_ROOTS_FLAG)
+    ? JSON.parse(rest[0].slice(PAGE_ROOTS_FLAG.length))
+    : undefined;
+const args = pageRoots === undefined ? rest : rest.slice(1);
+
+// every line of stdout starting with this is a page timing for the toast
+// binary. Must match `TIMING_PREFIX` in `toast/src/node.rs`.
+const TIMING_PREFIX = "toast-timing:";
+// and this is a page that threw, which doesn't stop the other pages.
+// Must match `FAILURE_PREFIX` in `toast/src/node.rs`.
+const FAILURE_PREFIX = "toast-render-failure:";
 
 main();
 
 async function main() {
-  // require pageWrapper
-  let pageWrapper;
-  const pageWrapperExists = existsSync(
-    path.join(srcDir, "src", "page-wrapper.js")
-  );
-
-  // Only try to import the page wrapper if it exists, otherwise ignore
-  if (pageWrapperExists) {
-    // Imports are expected to be in posix. We receive a full path here through
-    // the import.meta.url, use the srcDir and convert it to posix.
-    // It also can't import if it begins with a drive letter on Windows, so
-    // we find the relative path from this file to the srcDir.
-    const pageWrapperPath =
-      "./" +
-      path.posix.join(
-        ...path
-          .relative(path.dirname(fileURLToPath(import.meta.url)), srcDir)
-          .split(path.sep),
-        "src",
-        "page-wrapper.js"
-      );
-    try {
-      const wrapper = await import(pageWrapperPath);
-      pageWrapper = wrapper.default;
-    } catch (e) {
-      console.error("Error while importing page-wrapper", e);
-    }
-  }
+  const pageWrapper = await importPageWrapper(srcDir);
 
   // render html
   return Promise.all(
     args.map(async (file) => {
-      const nodeComponent = await import(path.resolve(srcDir, file));
-      let data;
       try {
-        data = await fs.readFile(
-          `${path.resolve(outputDir, file.replace("src/pages/", ""))}on`
-        );
-        data = JSON.parse(data);
+        const timing = await renderPage({
+          srcDir,
+          outputDir,
+          file,
+          pageWrapper,
+          pageRoots,
+        });
+        console.log(TIMING_PREFIX + JSON.stringify(timing));
       } catch (e) {
-        // TODO: figure out what errors are important here
+        const error = (e && e.stack) || String(e);
+        console.log(FAILURE_PREFIX + JSON.stringify({ page: file, error }));
       }
-      return render({
-        component: nodeComponent.default,
-        pageWrapper,
-        data,
-        browserPageWrapperPath: "/src/page-wrapper.js",
-        browserComponentPath: path.resolve("/", file),
-        // .js(on)
-        browserDataPath: path.resolve(
-          "/",
-          `${file.replace("src/pages/", "")}on`
-        ),
-      }).then((html) => {
-        // write HTML file out for page
-        const htmlFilePath = path.resolve(
-          outputDir,
-          file.replace("src/pages/", "").replace(".js", ".html")
-        );
-        return fs.writeFile(htmlFilePath, html);
-      });
     })
   );
 }
@@ -0,0 +1,26 @@
+import "./src/protocol.mjs";
+import sharp from "sharp";
+import path from "path";
+import { promises as fs } from "fs";
+
+main();
+
+// reads a json array of `{ svg, output }` jobs from stdin and writes each
+// svg to `output` as a png. See `toast/src/social_cards.rs`.
+async function main() {
+  const jobs = JSON.parse(await readStdin());
+  await Promise.all(jobs.map(drawCard));
+}
+
+async function drawCard({ svg, output }) {
+  await fs.mkdir(path.dirname(output), { recursive: true });
+  await sharp(Buffer.from(svg)).png().toFile(output);
+}
+
+async function readStdin() {
+  const chunks = [];
+  for await (const chunk of process.stdin) {
+    chunks.push(chunk);
+  }
+  return Buffer.concat(chunks).toString("utf-8");
+}
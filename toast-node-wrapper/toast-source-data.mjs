@@ -1,18 +1,59 @@
-import got from "got";
+import "./src/protocol.mjs";
+import { promises as fs } from "fs";
 
 // --loader doesn't show up in argv
-const [_node, _binPath, socketPath, toastFilePath, ...args] = process.argv;
+const [_node, _binPath, toastFilePath, ...args] = process.argv;
+
+// every line of stdout starting with this is a page record for the toast
+// binary. Must match `RECORD_PREFIX` in `toast/src/source_data.rs`.
+const RECORD_PREFIX = "toast-record:";
+// the remote sources the data file declares, when run with
+// `--remote-sources`. Must match `SOURCES_PREFIX` in
+// `toast/src/remote_data.rs`.
+const SOURCES_PREFIX = "toast-remote-sources:";
 
 main();
 
 async function main() {
   let toast = await import(toastFilePath);
-  const res = await got(`http://unix:${socketPath}:/`);
-  if (res.body === "ready" && toast.sourceData) {
-    await toast.sourceData({ setDataForSlug });
-  } else if (res.body !== "ready") {
-    throw new Error("Unable to get ready to run toast.sourceData");
+  if (args.includes("--remote-sources")) {
+    const sources = Object.entries(toast.remoteData || {}).map(
+      ([name, { url, headers = {} }]) => {
+        if (typeof url !== "string") {
+          throw new Error(
+            `\`remoteData.${name}\` needs a \`url\` to fetch, like \`{ url: "https://example.com/posts.json" }\``
+          );
+        }
+        return { name, url, headers };
+      }
+    );
+    process.stdout.write(`${SOURCES_PREFIX}${JSON.stringify(sources)}\n`);
+    return;
+  }
+  if (toast.sourceData) {
+    await toast.sourceData({
+      setDataForSlug,
+      remoteData: await loadRemoteData(toast.remoteData || {}),
+    });
+  }
+}
+
+// responses toast already fetched, passed through each source's
+// `transform` if it has one
+async function loadRemoteData(declarations) {
+  const flag = args.find((arg) => arg.startsWith("--remote-data="));
+  if (!flag) {
+    return {};
+  }
+  const responses = JSON.parse(
+    await fs.readFile(flag.slice("--remote-data=".length), "utf-8")
+  );
+  const remoteData = {};
+  for (const [name, response] of Object.entries(responses)) {
+    const transform = declarations[name] && declarations[name].transform;
+    remoteData[name] = transform ? await transform(response) : response;
   }
+  return remoteData;
 }
 
 // pageArgs is `{module: JSModuleAsString, slug: String, data: {}}`
@@ -113,21 +154,8 @@ const page = {
       mode: "no-module",
     };
   }
-  try {
-    await got.post(`http://unix:${socketPath}:/set-data-for-slug`, {
-      json: { slug, ...pageArgs },
-    });
-  } catch (error) {
-    if (error.response.statusCode === 422) {
-      // unprocessable entity, something is wrong with the payload
-      throw new Error(
-        `for slug \`${slug}\`, payload keys [${Object.keys(
-          pageArgs
-        )}] were malformatted`
-      );
-    } else {
-      console.error(error);
-    }
-  }
+  process.stdout.write(
+    `${RECORD_PREFIX}${JSON.stringify({ slug, ...pageArgs })}\n`
+  );
   return { ok: true };
 };
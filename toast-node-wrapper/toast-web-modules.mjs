@@ -0,0 +1,97 @@
+import "./src/protocol.mjs";
+import esbuild from "esbuild";
+import path from "path";
+import { promises as fs } from "fs";
+
+main();
+
+// reads `{ projectRoot, outDir, packages }` as json from stdin, bundles the
+// ESM entry point of every package (and every subpath it exports) into
+// `outDir`, and writes a json object of specifier to output file, relative
+// to `outDir`, to stdout. See `toast/src/web_modules.rs`.
+async function main() {
+  const { projectRoot, outDir, packages } = JSON.parse(await readStdin());
+  const entryPoints = {};
+  for (const name of packages) {
+    const packageDir = path.join(projectRoot, "node_modules", name);
+    const manifest = JSON.parse(
+      await fs.readFile(path.join(packageDir, "package.json"), "utf-8")
+    );
+    for (const [specifier, entry] of entries(name, manifest)) {
+      entryPoints[specifier] = path.join(packageDir, entry);
+    }
+  }
+  await esbuild.build({
+    entryPoints,
+    outdir: outDir,
+    bundle: true,
+    // dependencies that are shared between packages, like preact between
+    // preact/hooks and preact, are only loaded once
+    splitting: true,
+    format: "esm",
+    chunkNames: "common/[name]-[hash]",
+    mainFields: ["browser", "module", "main"],
+    define: { "process.env.NODE_ENV": '"production"' },
+    minify: true,
+    logLevel: "error",
+  });
+  const imports = {};
+  for (const specifier of Object.keys(entryPoints)) {
+    imports[specifier] = `./${specifier}.js`;
+  }
+  process.stdout.write(JSON.stringify(imports));
+}
+
+// the file for a conditional export, preferring ESM for the browser
+const exportTarget = (target) => {
+  if (typeof target === "string") {
+    return target;
+  }
+  if (Array.isArray(target)) {
+    return target.map(exportTarget).find(Boolean);
+  }
+  if (target && typeof target === "object") {
+    for (const condition of ["browser", "import", "module", "default"]) {
+      const file = exportTarget(target[condition]);
+      if (file) {
+        return file;
+      }
+    }
+  }
+  return undefined;
+};
+
+// every specifier a package can be imported with, and the file it points
+// at. Subpath patterns (`./*`) can't be listed, so they're skipped.
+function entries(name, manifest) {
+  const { exports } = manifest;
+  const isSubpathMap =
+    exports &&
+    typeof exports === "object" &&
+    !Array.isArray(exports) &&
+    Object.keys(exports).some((key) => key.startsWith("."));
+  if (isSubpathMap) {
+    return Object.entries(exports)
+      .filter(
+        ([key]) =>
+          key.startsWith(".") && !key.includes("*") && !key.endsWith("/")
+      )
+      .filter(([key]) => key !== "./package.json")
+      .map(([key, target]) => [
+        key === "." ? name : `${name}/${key.slice(2)}`,
+        exportTarget(target),
+      ])
+      .filter(([, file]) => file);
+  }
+  const main =
+    exportTarget(exports) || manifest.module || manifest.main || "index.js";
+  return [[name, main]];
+}
+
+async function readStdin() {
+  const chunks = [];
+  for await (const chunk of process.stdin) {
+    chunks.push(chunk);
+  }
+  return Buffer.concat(chunks).toString("utf-8");
+}
@@ -0,0 +1,188 @@
+//! Building a site that's served from a subdirectory, like GitHub Pages
+//! project sites at `https://user.github.io/my-project/`, with
+//! `--base-path /my-project/` or `base_path` in `toast.config.toml`.
+//!
+//! Root-relative urls toast generates are prefixed with the base path:
+//!
+//! - imports in browser modules, which resolve through the import map
+//!   (see `ImportMap::with_base_path`), so `/web_modules/preact.js`
+//!   becomes `/my-project/web_modules/preact.js`
+//! - urls in quotes in page html, which covers links, images,
+//!   stylesheets, and each page's hydration script
+//! - `url()`s and `@import`s in stylesheet bundles
+//!
+//! Absolute urls built from `base_url`, like canonical links and feeds,
+//! are already correct as long as `base_url` includes the base path.
+//! Links between pages are written as if the site were at the root, so
+//! the same pages work with or without a base path.
+use color_eyre::eyre::{Result, WrapErr};
+use std::{fs, path::Path};
+use tracing::instrument;
+use walkdir::WalkDir;
+
+use crate::{
+    css::CSS_DIR,
+    fingerprint::{css_specifiers, html_specifiers, Specifier},
+    fonts::FONTS_DIR,
+};
+
+/// `/my-project/` and `my-project` are both `/my-project`. An empty base
+/// path or `/` is the root of the site, which doesn't need a prefix.
+pub fn normalize_base_path(base_path: &str) -> Option<String> {
+    let trimmed = base_path.trim().trim_matches('/');
+    if trimmed.is_empty() {
+        None
+    } else {
+        Some(format!("/{}", trimmed))
+    }
+}
+
+/// Whether `url` is root-relative and doesn't already have the prefix
+fn needs_prefix(base_path: &str, url: &str) -> bool {
+    let already_prefixed = url
+        .strip_prefix(base_path)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with(&['/', '?', '#'][..]));
+    url.starts_with('/') && !url.starts_with("//") && !already_prefixed
+}
+
+/// `url` under `base_path`, if it's root-relative
+pub fn prefix_url(base_path: &str, url: &str) -> String {
+    if needs_prefix(base_path, url) {
+        format!("{}{}", base_path, url)
+    } else {
+        url.to_string()
+    }
+}
+
+/// `url` without `base_path`, the way it's laid out in the output
+/// directory
+pub fn strip_base_path<'a>(base_path: Option<&str>, url: &'a str) -> &'a str {
+    match base_path.and_then(|base_path| url.strip_prefix(base_path)) {
+        Some("") => "/",
+        Some(rest) if rest.starts_with('/') => rest,
+        _ => url,
+    }
+}
+
+fn prefix_specifiers(source: &str, specifiers: &[Specifier], base_path: &str) -> String {
+    let mut output = String::with_capacity(source.len());
+    let mut last = 0;
+    for specifier in specifiers {
+        if needs_prefix(base_path, &specifier.url) {
+            output.push_str(&source[last..specifier.start]);
+            output.push_str(base_path);
+            output.push_str(&specifier.url);
+            last = specifier.end;
+        }
+    }
+    output.push_str(&source[last..]);
+    output
+}
+
+/// The url in `<meta http-equiv="refresh" content="0; url=/new">`, which
+/// redirect pages use
+fn refresh_specifiers(html: &str) -> Vec<Specifier> {
+    html.match_indices("url=/")
+        .filter_map(|(index, _)| {
+            let start = index + "url=".len();
+            let len = html[start..].find(&['"', '\''][..])?;
+            Some(Specifier {
+                start,
+                end: start + len,
+                url: html[start..start + len].to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Prefix the root-relative urls in quotes in `html`, and the url of a
+/// meta refresh. Urls that already have the prefix are left alone, so
+/// running this twice is safe.
+pub fn prefix_html(html: &str, base_path: &str) -> String {
+    let mut specifiers = html_specifiers(html);
+    specifiers.extend(refresh_specifiers(html));
+    specifiers.sort_by_key(|specifier| specifier.start);
+    prefix_specifiers(html, &specifiers, base_path)
+}
+
+pub fn prefix_css(css: &str, base_path: &str) -> String {
+    prefix_specifiers(css, &css_specifiers(css), base_path)
+}
+
+/// Prefix urls in every html file and stylesheet bundle in `output_dir`.
+/// Pages that weren't rerendered in a rebuild already have the prefix,
+/// and are left as they are.
+#[instrument]
+pub fn apply_base_path(output_dir: &Path, base_path: &str) -> Result<()> {
+    for entry in WalkDir::new(output_dir)
+        .into_iter()
+        .filter_map(|result| result.ok())
+        .filter(|entry| entry.file_type().is_file())
+    {
+        let path = entry.path();
+        let is_bundle = path
+            .strip_prefix(output_dir)
+            .is_ok_and(|relative| relative.starts_with(CSS_DIR) || relative.starts_with(FONTS_DIR));
+        let prefix: fn(&str, &str) -> String = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("html") => prefix_html,
+            Some("css") if is_bundle => prefix_css,
+            _ => continue,
+        };
+        let contents = fs::read_to_string(path)
+            .wrap_err_with(|| format!("Failed to read `{}`", path.display()))?;
+        let prefixed = prefix(&contents, base_path);
+        if prefixed != contents {
+            fs::write(path, prefixed)
+                .wrap_err_with(|| format!("Failed to write `{}`", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_base_path() {
+        assert_eq!(
+            normalize_base_path("/my-project/"),
+            Some(String::from("/my-project"))
+        );
+        assert_eq!(
+            normalize_base_path("docs/v2"),
+            Some(String::from("/docs/v2"))
+        );
+        assert_eq!(normalize_base_path("/"), None);
+    }
+
+    #[test]
+    fn test_prefix_html() {
+        let html = r#"<script>
+window.componentPath = "/src/pages/index.js";
+</script><a href="/about">a</a><a href="https://toast.dev/">b</a><img src='//cdn.example.com/x.png'><a href="/my-project/">c</a>"#;
+        let prefixed = prefix_html(html, "/my-project");
+        assert_eq!(
+            prefixed,
+            r#"<script>
+window.componentPath = "/my-project/src/pages/index.js";
+</script><a href="/my-project/about">a</a><a href="https://toast.dev/">b</a><img src='//cdn.example.com/x.png'><a href="/my-project/">c</a>"#
+        );
+        assert_eq!(prefix_html(&prefixed, "/my-project"), prefixed);
+        assert_eq!(
+            prefix_html(
+                r#"<meta http-equiv="refresh" content="0; url=/new">"#,
+                "/my-project"
+            ),
+            r#"<meta http-equiv="refresh" content="0; url=/my-project/new">"#
+        );
+    }
+
+    #[test]
+    fn test_strip_base_path() {
+        assert_eq!(strip_base_path(Some("/proj"), "/proj/about"), "/about");
+        assert_eq!(strip_base_path(Some("/proj"), "/proj"), "/");
+        assert_eq!(strip_base_path(Some("/proj"), "/project"), "/project");
+        assert_eq!(strip_base_path(None, "/about"), "/about");
+    }
+}
@@ -0,0 +1,491 @@
+//! `toast bench`, for catching regressions in the compiler and renderer
+//! before a release. A project with `--pages` pages is generated, built
+//! `--runs` times, and the percentiles of how long each build and each of
+//! its phases took are printed:
+//!
+//! ```text
+//! toast bench --shape jsx --pages 500 --runs 10
+//! ```
+//!
+//! Shapes are `md` for markdown pages, `jsx` for JSX pages that import a
+//! few components, and `images` for markdown pages that each have their
+//! own image in `static/images`. Every build is cold, without the cache
+//! of the one before it, unless `--warm` is passed, which times rebuilds
+//! where nothing changed instead.
+//!
+//! The project is generated in `.toast/bench` in the current directory,
+//! so toast and preact are installed from the project it's run in, like
+//! toast's own example sites. `--report json` prints the percentiles as
+//! JSON, which can be passed back as a `--baseline` to fail if the median
+//! build got more than `--max-regression` percent slower.
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use flate2::{write::ZlibEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use std::{
+    env, fmt, fs,
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use tracing::instrument;
+
+use crate::{
+    build::{build_site, load_settings, Build},
+    cache::CACHE_DIR,
+    cli_args::{BenchArgs, BuildArgs},
+    new_project::template_files,
+    npm_bin::find_npm_bin_dir,
+    report::ReportFormat,
+};
+
+/// Marks a directory as a generated project, so it can be replaced
+const MARKER: &str = ".toast-bench";
+
+const IMAGE_WIDTH: u32 = 1200;
+const IMAGE_HEIGHT: u32 = 800;
+
+/// What the pages of a generated project are
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BenchShape {
+    Md,
+    Jsx,
+    Images,
+}
+
+impl FromStr for BenchShape {
+    type Err = color_eyre::Report;
+    fn from_str(shape: &str) -> Result<Self> {
+        match shape {
+            "md" => Ok(BenchShape::Md),
+            "jsx" => Ok(BenchShape::Jsx),
+            "images" => Ok(BenchShape::Images),
+            _ => Err(eyre!(
+                "Unknown bench shape `{}`, the shapes are `md`, `jsx`, and `images`",
+                shape
+            )),
+        }
+    }
+}
+
+impl fmt::Display for BenchShape {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let shape = match self {
+            BenchShape::Md => "md",
+            BenchShape::Jsx => "jsx",
+            BenchShape::Images => "images",
+        };
+        write!(f, "{}", shape)
+    }
+}
+
+/// The percentiles of one phase across every run, in milliseconds
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PhaseStats {
+    pub name: String,
+    pub min_ms: f64,
+    pub p50_ms: f64,
+    pub p90_ms: f64,
+    pub p99_ms: f64,
+    pub max_ms: f64,
+}
+
+/// What `--report json` prints, and what `--baseline` reads
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct BenchSummary {
+    pub shape: BenchShape,
+    pub pages: usize,
+    pub runs: usize,
+    pub warm: bool,
+    /// `total` first, then each phase of the build
+    pub phases: Vec<PhaseStats>,
+}
+
+impl BenchSummary {
+    fn total(&self) -> Option<&PhaseStats> {
+        self.phases.iter().find(|phase| phase.name == "total")
+    }
+
+    pub fn table(&self) -> String {
+        let mut table = format!(
+            "{} {} pages, {} {} builds\n{:<10} {:>10} {:>10} {:>10} {:>10} {:>10}\n",
+            self.pages,
+            self.shape,
+            self.runs,
+            if self.warm { "warm" } else { "cold" },
+            "phase",
+            "min",
+            "p50",
+            "p90",
+            "p99",
+            "max"
+        );
+        for phase in &self.phases {
+            table.push_str(&format!(
+                "{:<10} {:>8.1}ms {:>8.1}ms {:>8.1}ms {:>8.1}ms {:>8.1}ms\n",
+                phase.name, phase.min_ms, phase.p50_ms, phase.p90_ms, phase.p99_ms, phase.max_ms
+            ));
+        }
+        table
+    }
+}
+
+/// The `p`th percentile of `sorted` by nearest rank, so it's always one of
+/// the timings
+pub fn percentile(sorted: &[f64], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn phase_stats(name: &str, mut timings: Vec<f64>) -> PhaseStats {
+    timings.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    PhaseStats {
+        name: name.to_string(),
+        min_ms: percentile(&timings, 0.0),
+        p50_ms: percentile(&timings, 50.0),
+        p90_ms: percentile(&timings, 90.0),
+        p99_ms: percentile(&timings, 99.0),
+        max_ms: percentile(&timings, 100.0),
+    }
+}
+
+/// An error if the median build of `summary` is more than `max_regression`
+/// percent slower than the one of `baseline`
+pub fn check_regression(
+    summary: &BenchSummary,
+    baseline: &BenchSummary,
+    max_regression: f64,
+) -> Result<()> {
+    let (current, base) = match (summary.total(), baseline.total()) {
+        (Some(current), Some(base)) if base.p50_ms > 0.0 => (current, base),
+        _ => return Ok(()),
+    };
+    let change = (current.p50_ms - base.p50_ms) / base.p50_ms * 100.0;
+    if change > max_regression {
+        return Err(eyre!(
+            "The median build took {:.1}ms, {:.1}% slower than the baseline's {:.1}ms, which is more than the allowed {}%",
+            current.p50_ms,
+            change,
+            base.p50_ms,
+            max_regression
+        ));
+    }
+    Ok(())
+}
+
+fn markdown_page(i: usize, image: bool) -> String {
+    let mut page = format!(
+        "---\ntitle: Page {i}\ndate: 2020-01-{day:02}\ntags: [bench, tag-{tag}]\n---\n\n# Page {i}\n\nThe **first** paragraph of page {i}, with a [link](/p{next}) to the next one and some `inline code`.\n\n",
+        i = i,
+        day = i % 28 + 1,
+        tag = i % 10,
+        next = i + 1
+    );
+    if image {
+        page.push_str(&format!("![Image {i}](/images/image-{i}.png)\n\n", i = i));
+    }
+    for section in 1..=3 {
+        page.push_str(&format!(
+            "## Section {section}\n\n- one item\n- another item with _emphasis_\n- a third item\n\n```js\nconst page = {i};\nconsole.log(page * {section});\n```\n\nA closing paragraph for section {section} that goes on for a while so there's some text to render.\n\n",
+            section = section,
+            i = i
+        ));
+    }
+    page
+}
+
+fn jsx_page(i: usize) -> String {
+    format!(
+        r#"import {{ h }} from "preact";
+import Layout from "../components/layout.js";
+import Card from "../components/card.js";
+
+const cards = Array.from({{ length: 20 }}, (_, n) => ({{
+  title: `Card ${{n}} of page {i}`,
+  body: "Some text for the card, long enough to wrap a line or two.",
+}}));
+
+export default () => (
+  <Layout title="Page {i}">
+    {{cards.map((card) => (
+      <Card title={{card.title}}>{{card.body}}</Card>
+    ))}}
+  </Layout>
+);
+"#,
+        i = i
+    )
+}
+
+const LAYOUT_COMPONENT: &str = r#"import { h } from "preact";
+
+export default ({ title, children }) => (
+  <main>
+    <h1>{title}</h1>
+    <nav>
+      <a href="/">Home</a>
+    </nav>
+    <section>{children}</section>
+  </main>
+);
+"#;
+
+const CARD_COMPONENT: &str = r#"import { h } from "preact";
+
+export default ({ title, children }) => (
+  <article class="card">
+    <h2>{title}</h2>
+    <p>{children}</p>
+  </article>
+);
+"#;
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn png_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// An RGB gradient, different for every `seed` so no two images have the
+/// same hash
+fn png_image(width: u32, height: u32, seed: usize) -> Result<Vec<u8>> {
+    let mut pixels = Vec::with_capacity(((width * 3 + 1) * height) as usize);
+    for y in 0..height {
+        // no filter
+        pixels.push(0);
+        for x in 0..width {
+            pixels.extend_from_slice(&[
+                (x * 255 / width) as u8,
+                (y * 255 / height) as u8,
+                (seed * 37 % 256) as u8,
+            ]);
+        }
+    }
+    let mut encoder = ZlibEncoder::new(vec![], Compression::fast());
+    encoder.write_all(&pixels)?;
+    let data = encoder.finish()?;
+
+    let mut header = vec![];
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // 8 bit RGB, deflate, no filters, no interlacing
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+    let mut png = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+    png_chunk(&mut png, b"IHDR", &header);
+    png_chunk(&mut png, b"IDAT", &data);
+    png_chunk(&mut png, b"IEND", &[]);
+    Ok(png)
+}
+
+fn write_file(dir: &Path, path: &str, contents: impl AsRef<[u8]>) -> Result<()> {
+    let file = dir.join(path);
+    if let Some(parent) = file.parent() {
+        fs::create_dir_all(parent)
+            .wrap_err_with(|| format!("Failed to create directories for `{}`", file.display()))?;
+    }
+    fs::write(&file, contents).wrap_err_with(|| format!("Failed to write `{}`", file.display()))
+}
+
+/// Generate a project of `pages` pages shaped like `shape` in `dir`,
+/// replacing one that was generated before
+#[instrument]
+pub fn generate_project(dir: &Path, shape: BenchShape, pages: usize) -> Result<()> {
+    if dir.join(MARKER).exists() {
+        fs::remove_dir_all(dir)
+            .wrap_err_with(|| format!("Failed to remove `{}`", dir.display()))?;
+    } else if fs::read_dir(dir).is_ok_and(|mut entries| entries.next().is_some()) {
+        return Err(eyre!(
+            "Can't generate a bench project in `{}` because it isn't empty",
+            dir.display()
+        ));
+    }
+    write_file(dir, MARKER, "")?;
+    for (path, contents) in template_files("toast-bench") {
+        if path.ends_with("import-map.json") {
+            write_file(dir, path, contents)?;
+        }
+    }
+    match shape {
+        BenchShape::Md => {
+            for i in 0..pages {
+                write_file(
+                    dir,
+                    &format!("src/pages/p{}.md", i),
+                    markdown_page(i, false),
+                )?;
+            }
+        }
+        BenchShape::Jsx => {
+            write_file(dir, "src/components/layout.js", LAYOUT_COMPONENT)?;
+            write_file(dir, "src/components/card.js", CARD_COMPONENT)?;
+            for i in 0..pages {
+                write_file(dir, &format!("src/pages/p{}.js", i), jsx_page(i))?;
+            }
+        }
+        BenchShape::Images => {
+            for i in 0..pages {
+                write_file(dir, &format!("src/pages/p{}.md", i), markdown_page(i, true))?;
+                write_file(
+                    dir,
+                    &format!("static/images/image-{}.png", i),
+                    png_image(IMAGE_WIDTH, IMAGE_HEIGHT, i)?,
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Remove everything a build keeps for the next one
+fn clear_cache(dir: &Path) -> Result<()> {
+    for cache in &[dir.join(CACHE_DIR), dir.join(".tmp")] {
+        if cache.exists() {
+            fs::remove_dir_all(cache)
+                .wrap_err_with(|| format!("Failed to remove `{}`", cache.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Build `dir` once, returning how long the build and each of its phases
+/// took
+fn timed_build(dir: &Path, npm_bin_dir: &Path) -> Result<Vec<(String, f64)>> {
+    let args = BuildArgs {
+        input_dir: Some(dir.to_path_buf()),
+        ..BuildArgs::default()
+    };
+    let settings = load_settings(&args)?;
+    let Build { opts, report, .. } =
+        build_site(&args, &settings, npm_bin_dir.to_path_buf(), vec![])?;
+    opts.diagnostics.finish()?;
+    Ok(std::iter::once((String::from("total"), report.duration_ms))
+        .chain(
+            report
+                .phases
+                .into_iter()
+                .map(|phase| (phase.name, phase.duration_ms)),
+        )
+        .collect())
+}
+
+/// Generate a project, build it over and over, and print the percentiles
+/// of how long the builds took
+#[instrument]
+pub fn bench(args: &BenchArgs) -> Result<()> {
+    if args.runs == 0 {
+        return Err(eyre!("`--runs` has to be at least 1"));
+    }
+    let dir = match &args.dir {
+        Some(dir) => dir.clone(),
+        None => env::current_dir()
+            .wrap_err("Failed to get the current directory")?
+            .join(CACHE_DIR)
+            .join("bench"),
+    };
+    generate_project(&dir, args.shape, args.pages)?;
+    let dir: PathBuf = dunce::canonicalize(&dir)
+        .wrap_err_with(|| format!("Could not find bench directory `{}`", dir.display()))?;
+    let npm_bin_dir = find_npm_bin_dir(&dir)?;
+
+    if args.warm {
+        // fill the cache, this one isn't timed
+        timed_build(&dir, &npm_bin_dir)?;
+    }
+    // timings by phase, in the order phases first finished in
+    let mut timings: Vec<(String, Vec<f64>)> = vec![];
+    for run in 1..=args.runs {
+        if !args.warm {
+            clear_cache(&dir)?;
+        }
+        let phases = timed_build(&dir, &npm_bin_dir)?;
+        eprintln!("run {}/{}: {:.1}ms", run, args.runs, phases[0].1);
+        for (name, duration) in phases {
+            match timings.iter_mut().find(|(phase, _)| *phase == name) {
+                Some((_, durations)) => durations.push(duration),
+                None => timings.push((name, vec![duration])),
+            }
+        }
+    }
+    let summary = BenchSummary {
+        shape: args.shape,
+        pages: args.pages,
+        runs: args.runs,
+        warm: args.warm,
+        phases: timings
+            .into_iter()
+            .map(|(name, durations)| phase_stats(&name, durations))
+            .collect(),
+    };
+    match args.report {
+        Some(ReportFormat::Json) => println!("{}", serde_json::to_string_pretty(&summary)?),
+        None => eprint!("{}", summary.table()),
+    }
+    if let Some(baseline_path) = &args.baseline {
+        let baseline = fs::read_to_string(baseline_path)
+            .wrap_err_with(|| format!("Failed to read baseline `{}`", baseline_path.display()))?;
+        let baseline: BenchSummary = serde_json::from_str(&baseline).wrap_err_with(|| {
+            format!(
+                "`{}` isn't the output of `toast bench --report json`",
+                baseline_path.display()
+            )
+        })?;
+        check_regression(&summary, &baseline, args.max_regression)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentiles() {
+        let timings = vec![10.0, 20.0, 30.0, 40.0, 50.0, 60.0, 70.0, 80.0, 90.0, 100.0];
+        assert_eq!(percentile(&timings, 0.0), 10.0);
+        assert_eq!(percentile(&timings, 50.0), 50.0);
+        assert_eq!(percentile(&timings, 90.0), 90.0);
+        assert_eq!(percentile(&timings, 99.0), 100.0);
+
+        let summary = |p50_ms| BenchSummary {
+            shape: BenchShape::Md,
+            pages: 100,
+            runs: 5,
+            warm: false,
+            phases: vec![PhaseStats {
+                p50_ms,
+                ..phase_stats("total", vec![p50_ms])
+            }],
+        };
+        assert!(check_regression(&summary(105.0), &summary(100.0), 10.0).is_ok());
+        assert!(check_regression(&summary(120.0), &summary(100.0), 10.0).is_err());
+    }
+
+    #[test]
+    fn test_png_image() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        let png = png_image(4, 2, 1).unwrap();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&png[12..16], b"IHDR");
+    }
+}
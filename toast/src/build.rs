@@ -0,0 +1,325 @@
+//! Building a site from Rust, for embedding toast in another program.
+//! The `toast` binary is a CLI over this module:
+//!
+//! ```no_run
+//! use toast::build::{build, BuildOptions};
+//!
+//! let report = build(BuildOptions::new("./my-site")).unwrap();
+//! println!("built {} pages", report.pages.len());
+//! ```
+//!
+//! `BuildOptions` takes the same flags as `toast incremental`, which
+//! override `toast.config.toml` in the input directory like they do on the
+//! command line. `build_site` is the lower level version the CLI uses,
+//! which also returns what watch mode needs to keep rebuilding, and
+//! leaves what the build's `diagnostics` found for the caller to report.
+use async_std::task;
+use color_eyre::eyre::{Result, WrapErr};
+use std::{env, path::PathBuf, sync::Arc};
+use tracing::instrument;
+
+use crate::{
+    cache::{init, Cache},
+    cli_args::BuildArgs,
+    config::{self, BuildSettings},
+    diagnostics::{Diagnostic, Diagnostics, FailurePolicy, Severity},
+    diff::record_build,
+    dry_run::BuildPlan,
+    esinstall::{load_import_map, ImportMap},
+    exit_code::Failure,
+    filesystem::{Filesystem, RealFs},
+    incremental::{build_with_cache, plan_build, IncrementalOpts},
+    links::report_broken_links,
+    node::check_node_version,
+    npm_bin::{disable_registry, find_npm_bin_dir},
+    plugins::{load_plugins, Plugin},
+    protocol::handshake,
+    renderer::{renderer_for, RendererKind},
+    report::BuildReport,
+    s3::S3Fs,
+    staging::build_staged,
+    supervisor::render_timeout,
+};
+
+/// What to build, see the module docs
+#[derive(Debug, Default)]
+pub struct BuildOptions {
+    pub args: BuildArgs,
+    /// run after the `plugins` in the config file, see `plugins`
+    pub plugins: Vec<Arc<dyn Plugin>>,
+}
+
+impl BuildOptions {
+    /// Build the site in `input_dir` with its config and no other flags
+    pub fn new(input_dir: impl Into<PathBuf>) -> BuildOptions {
+        BuildOptions {
+            args: BuildArgs {
+                input_dir: Some(input_dir.into()),
+                ..BuildArgs::default()
+            },
+            plugins: vec![],
+        }
+    }
+}
+
+/// A finished build, and the options and cache it used
+pub struct Build<'a> {
+    pub opts: IncrementalOpts<'a>,
+    pub cache: Cache,
+    /// page modules rendered to html
+    pub pages: Vec<String>,
+    pub report: BuildReport,
+}
+
+/// Build a site, returning a summary of the build. Warnings are printed,
+/// and errors fail it, once it's done, see `diagnostics`.
+#[instrument]
+pub fn build(options: BuildOptions) -> Result<BuildReport> {
+    let args =
+        BuildArgs {
+            input_dir: match options.args.input_dir {
+                Some(dir) => Some(dunce::canonicalize(&dir).wrap_err_with(|| {
+                    format!("Could not find input directory `{}`", dir.display())
+                })?),
+                None => None,
+            },
+            ..options.args
+        };
+    let settings = load_settings(&args)?;
+    let npm_bin_dir = find_npm_bin_dir(&settings.input_dir)?;
+    let Build { opts, report, .. } = build_site(&args, &settings, npm_bin_dir, options.plugins)?;
+    opts.diagnostics.finish()?;
+    Ok(report)
+}
+
+/// Load the project config file and merge the CLI flags over it
+#[instrument]
+pub fn load_settings(args: &BuildArgs) -> Result<BuildSettings> {
+    let config_dir = match &args.input_dir {
+        Some(dir) => dir.clone(),
+        None => env::current_dir().wrap_err("Failed to get the current directory")?,
+    };
+    let settings = config::load(&config_dir)
+        .wrap_err(Failure::Config)?
+        .merge(&config_dir, args);
+    if settings.offline {
+        disable_registry();
+    }
+    Ok(settings)
+}
+
+/// What building the site described by `settings` would do, without
+/// writing anything, see `dry_run`
+#[instrument]
+pub fn dry_run(
+    args: &BuildArgs,
+    settings: &BuildSettings,
+    npm_bin_dir: PathBuf,
+) -> Result<BuildPlan> {
+    handshake(&npm_bin_dir)?;
+    // `--create-import-map` would start with an empty one
+    let import_map = if args.create_import_map && !settings.import_map.exists() {
+        ImportMap::default()
+    } else {
+        load_import_map(&settings.import_map, &settings.input_dir, false)?
+    };
+    let import_map = match &settings.base_path {
+        Some(base_path) => import_map.with_base_path(base_path),
+        None => import_map,
+    };
+    // the cache records canonical output paths, but the output directory
+    // might not exist yet
+    let output_dir =
+        dunce::canonicalize(&settings.output_dir).unwrap_or_else(|_| settings.output_dir.clone());
+    let opts = incremental_opts(
+        args,
+        settings,
+        npm_bin_dir,
+        output_dir,
+        import_map,
+        Diagnostics::new(settings.failure_policy),
+        vec![],
+    );
+    let mut cache = init(opts.npm_bin_dir.clone(), opts.project_root_dir);
+    plan_build(opts, &mut cache)
+}
+
+/// Build the site described by `settings`, returning the options and cache
+/// used so that watch mode can rebuild from them.
+#[instrument]
+pub fn build_site<'a>(
+    args: &BuildArgs,
+    settings: &'a BuildSettings,
+    npm_bin_dir: PathBuf,
+    plugins: Vec<Arc<dyn Plugin>>,
+) -> Result<Build<'a>> {
+    handshake(&npm_bin_dir)?;
+    let diagnostics = Diagnostics::new(settings.failure_policy);
+    // other renderers don't need node to render pages
+    if settings.renderer == RendererKind::Node {
+        match check_node_version(&settings.min_node_version) {
+            Err(err) if settings.skip_node_check => {
+                diagnostics.report(Diagnostic::warning("node", format!("{:#}", err)))?
+            }
+            result => result?,
+        }
+    }
+    let import_map = load_import_map(
+        &settings.import_map,
+        &settings.input_dir,
+        args.create_import_map,
+    )?;
+    let import_map = match &settings.base_path {
+        Some(base_path) => import_map.with_base_path(base_path),
+        None => import_map,
+    };
+
+    std::fs::create_dir_all(&settings.output_dir).wrap_err_with(|| {
+        format!(
+            "Failed create directories for path `{}`",
+            &settings.output_dir.display()
+        )
+    })?;
+    let output_dir = dunce::canonicalize(&settings.output_dir)
+        .wrap_err_with(|| "Failed canonicalize the output directory path")?;
+
+    let opts = incremental_opts(
+        args,
+        settings,
+        npm_bin_dir,
+        output_dir,
+        import_map,
+        diagnostics,
+        plugins,
+    );
+    let mut cache = init(opts.npm_bin_dir.clone(), opts.project_root_dir);
+    let mut report = BuildReport::default();
+    // links are checked before the build is swapped in, so a broken link
+    // that fails the build doesn't replace the last good site
+    let pages = build_staged(&opts, &mut cache, |staged_opts, cache| {
+        let pages = task::block_on(build_with_cache(staged_opts.clone(), cache, &mut report))?;
+        let check_links = args.check_links || settings.link_check.external;
+        if check_links || settings.failure_policy == FailurePolicy::Strict {
+            task::block_on(report_broken_links(
+                &staged_opts.output_dir,
+                staged_opts.project_root_dir,
+                settings.base_path.as_deref(),
+                &settings.link_check,
+                if check_links {
+                    Severity::Error
+                } else {
+                    Severity::Warning
+                },
+                &staged_opts.diagnostics,
+            ))?;
+        }
+        Ok(pages)
+    })?;
+    // `toast diff` compares the last two of these
+    if settings.record_output {
+        record_build(&settings.input_dir, &opts.output_dir)?;
+    }
+    Ok(Build {
+        opts,
+        cache,
+        pages,
+        report,
+    })
+}
+
+/// The options a build of `settings` runs with, writing to `output_dir`
+pub(crate) fn incremental_opts<'a>(
+    args: &BuildArgs,
+    settings: &'a BuildSettings,
+    npm_bin_dir: PathBuf,
+    output_dir: PathBuf,
+    import_map: ImportMap,
+    diagnostics: Diagnostics,
+    plugins: Vec<Arc<dyn Plugin>>,
+) -> IncrementalOpts<'a> {
+    let renderer = renderer_for(
+        settings.renderer,
+        &npm_bin_dir,
+        args.render_workers.unwrap_or_else(num_cpus::get),
+        render_timeout(args.render_timeout),
+    );
+    let plugins = load_plugins(
+        &settings.input_dir,
+        &npm_bin_dir,
+        &settings.plugins,
+        plugins,
+    );
+    let disk = RealFs {
+        follow_symlinks: settings.follow_symlinks,
+    };
+    let fs: Arc<dyn Filesystem> = match &settings.output_bucket {
+        Some(bucket) => Arc::new(S3Fs {
+            disk,
+            bucket: bucket.clone(),
+            root: output_dir.clone(),
+            project_root_dir: settings.input_dir.clone(),
+        }),
+        None => Arc::new(disk),
+    };
+    IncrementalOpts {
+        debug: args.debug,
+        project_root_dir: &settings.input_dir,
+        output_dir,
+        page_roots: settings.page_roots.clone(),
+        i18n: settings.i18n.clone(),
+        drafts: settings.drafts,
+        npm_bin_dir,
+        import_map,
+        jobs: args.jobs.unwrap_or_else(num_cpus::get),
+        render_timeout: render_timeout(args.render_timeout),
+        base_url: settings.base_url.clone(),
+        base_path: settings.base_path.clone(),
+        feeds: settings.feeds.clone(),
+        fonts: settings.fonts.clone(),
+        images: settings.images.clone(),
+        paginate: settings.paginate.clone(),
+        taxonomies: settings.taxonomies.clone(),
+        search: settings.search.clone(),
+        related: settings.related.clone(),
+        redirects: settings.redirects.clone(),
+        headers: settings.headers.clone(),
+        robots: settings.robots.clone(),
+        required_frontmatter: settings.required_frontmatter.clone(),
+        fingerprint: settings.fingerprint,
+        integrity: settings.integrity,
+        css: settings.css.clone(),
+        highlight: settings.highlight.clone(),
+        markdown: settings.markdown.clone(),
+        toc: settings.toc.clone(),
+        excerpt: settings.excerpt.clone(),
+        preload: settings.preload.clone(),
+        minify_html: settings.minify_html,
+        precompress: settings.precompress,
+        source_maps: settings.source_maps,
+        minify_js: settings.minify_js,
+        social_cards: settings.social_cards.clone(),
+        url_style: settings.url_style,
+        reproducible: settings.reproducible,
+        offline: settings.offline,
+        prune: settings.prune,
+        filter: settings.filter.clone(),
+        shard: settings.shard,
+        plugins,
+        renderer,
+        fs,
+        diagnostics,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_options_default_like_cli() {
+        let options = BuildOptions::new("./my-site");
+        assert!(options.args.prune);
+        assert!(!options.args.drafts);
+        assert_eq!(options.args.input_dir, Some(PathBuf::from("./my-site")));
+    }
+}
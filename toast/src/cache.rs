@@ -1,39 +1,212 @@
-use std::path::PathBuf;
+use color_eyre::eyre::{Result, WrapErr};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tracing::instrument;
 
+mod inspect;
+mod manifest;
 mod salsa_db;
 
-use crate::{esinstall::ImportMap, sources::Source};
+use crate::{
+    diagnostics::CompileError,
+    esinstall::ImportMap,
+    filesystem::Filesystem,
+    hydration::HydrateMode,
+    module_graph::ModuleGraph,
+    public_env::{public_env, referenced_env},
+    sources::Source,
+    swc_ops::{BrowserJsOptions, CompiledJs},
+};
+pub use inspect::{
+    cache_entries, cache_stats, collect_garbage, format_size, parse_size, CacheStats, EntryInfo,
+    GcSummary,
+};
+pub use manifest::{content_hash, HitCount};
+use manifest::{hash_inputs, Manifest};
+use salsa::ParallelDatabase;
 use salsa_db::{Files, SalsaToastDatabaseStruct};
 
+/// Directory in the project root that persistent cache files live in
+pub const CACHE_DIR: &str = ".toast";
+
+/// The manifest in `CACHE_DIR`
+pub const MANIFEST_FILE: &str = "cache.json";
+
+/// The manifest as it was before a staged build, to go back to if the
+/// build fails, see `staging`
+pub struct ManifestCheckpoint(Manifest);
+
 pub struct Cache {
     db: SalsaToastDatabaseStruct,
     npm_bin_dir: PathBuf,
+    manifest: Manifest,
+    manifest_path: PathBuf,
+    graph: ModuleGraph,
 }
 
 impl Cache {
     pub fn set_source(&mut self, key: &str, source: Source) {
+        self.graph.set_imports(key, &source.source);
         let db: &mut dyn Files = &mut self.db;
         db.set_source(key.to_string(), Arc::new(source));
     }
+    /// Every source that imports `key`, directly or through other sources,
+    /// see `module_graph`
+    pub fn dependents(&self, key: &str) -> BTreeSet<String> {
+        self.graph.dependents(key)
+    }
+    /// Every source `key` imports, directly or through other sources
+    pub fn imports(&self, key: &str) -> BTreeSet<String> {
+        self.graph.imports(key)
+    }
     pub fn read(&mut self, key: PathBuf) -> String {
         let db: &mut dyn Files = &mut self.db;
         db.read(key)
     }
-    pub fn get_js_for_browser(&mut self, key: &str, import_map: ImportMap) -> String {
+    pub fn get_js_for_browser(
+        &mut self,
+        key: &str,
+        import_map: ImportMap,
+        options: BrowserJsOptions,
+    ) -> Result<CompiledJs, CompileError> {
+        let db: &mut dyn Files = &mut self.db;
+        db.js_for_browser(
+            key.to_string(),
+            self.npm_bin_dir.clone(),
+            import_map,
+            options,
+        )
+    }
+    pub fn get_js_for_server(&mut self, key: &str) -> Result<String, CompileError> {
+        let db: &mut dyn Files = &mut self.db;
+        db.js_for_server(key.to_string(), self.npm_bin_dir.clone())
+    }
+    pub fn get_hydrate_mode(&mut self, key: &str) -> HydrateMode {
         let db: &mut dyn Files = &mut self.db;
-        db.js_for_browser(key.to_string(), self.npm_bin_dir.clone(), import_map)
+        db.hydrate(key.to_string())
     }
-    pub fn get_js_for_server(&mut self, key: &str) -> String {
+    /// Hash of everything that goes into compiling the source set for `key`
+    pub fn input_hash(
+        &mut self,
+        key: &str,
+        import_map: &ImportMap,
+        options: BrowserJsOptions,
+    ) -> String {
         let db: &mut dyn Files = &mut self.db;
+        let source = db.source(key.to_string());
+        let import_map = serde_json::to_string(import_map).unwrap_or_default();
+        let options = serde_json::to_string(&options).unwrap_or_default();
+        // public env vars are inlined into compiled output
+        let env = referenced_env(&source.source, &public_env());
+        hash_inputs(&[&source.source, &import_map, &env, &options])
+    }
+    /// Whether `key` was already compiled from inputs with this hash by a
+    /// previous build, and its outputs haven't been deleted since.
+    pub fn is_fresh(&mut self, key: &str, hash: &str) -> bool {
+        self.manifest.is_fresh(key, hash)
+    }
+    pub fn record_outputs(&mut self, key: &str, hash: String, outputs: Vec<PathBuf>) {
+        self.manifest.record(key, hash, outputs);
+    }
+    pub fn snapshot(&self) -> CacheSnapshot {
+        CacheSnapshot {
+            db: self.db.snapshot(),
+            npm_bin_dir: self.npm_bin_dir.clone(),
+        }
+    }
+    /// Delete the outputs of every entry that wasn't checked or recorded
+    /// since the cache was loaded, because its source is gone. Only makes
+    /// sense after a full build.
+    pub fn prune(&mut self, fs: &dyn Filesystem) -> Result<Vec<PathBuf>> {
+        let orphaned = self.manifest.prune();
+        for path in &orphaned {
+            if fs.exists(path) {
+                fs.remove_file(path)
+                    .wrap_err_with(|| format!("Failed to remove `{}`", path.display()))?;
+            }
+        }
+        Ok(orphaned)
+    }
+    /// The outputs `prune` would delete if `sources` were all the sources
+    /// there are, for `--dry-run`
+    pub fn orphaned_source_outputs(&self, sources: &BTreeSet<String>) -> Vec<PathBuf> {
+        self.manifest.orphaned_source_outputs(sources)
+    }
+    /// Point outputs recorded under `from` at `to`, see `staging`
+    pub fn relocate_outputs(&mut self, from: &Path, to: &Path) {
+        self.manifest.relocate(from, to);
+    }
+    pub fn checkpoint(&self) -> ManifestCheckpoint {
+        ManifestCheckpoint(self.manifest.clone())
+    }
+    /// Forget the outputs recorded since `checkpoint`, because they were
+    /// thrown away
+    pub fn rollback(&mut self, checkpoint: ManifestCheckpoint) {
+        self.manifest = checkpoint.0;
+    }
+    /// Write the persistent cache manifest to disk
+    pub fn save(&self) -> Result<()> {
+        self.manifest.save(&self.manifest_path)
+    }
+}
+
+/// Combine the manifests written by each shard of a build into one at
+/// `output`, returning how many entries it has
+#[instrument]
+pub fn merge_manifests(manifests: &[PathBuf], output: &Path) -> Result<usize> {
+    let mut merged = Manifest::default();
+    for path in manifests {
+        merged.merge(Manifest::read(path)?)?;
+    }
+    merged.save(output)?;
+    Ok(merged.entries.len())
+}
+
+/// A read-only handle to the cache, fixed at the revision it was taken
+/// at, that can be sent to another thread. Setting sources on the
+/// `Cache` blocks until every snapshot has been dropped.
+pub struct CacheSnapshot {
+    db: salsa::Snapshot<SalsaToastDatabaseStruct>,
+    npm_bin_dir: PathBuf,
+}
+
+impl CacheSnapshot {
+    pub fn get_js_for_browser(
+        &self,
+        key: &str,
+        import_map: ImportMap,
+        options: BrowserJsOptions,
+    ) -> Result<CompiledJs, CompileError> {
+        let db: &dyn Files = &*self.db;
+        db.js_for_browser(
+            key.to_string(),
+            self.npm_bin_dir.clone(),
+            import_map,
+            options,
+        )
+    }
+    pub fn get_js_for_server(&self, key: &str) -> Result<String, CompileError> {
+        let db: &dyn Files = &*self.db;
         db.js_for_server(key.to_string(), self.npm_bin_dir.clone())
     }
+    pub fn get_hydrate_mode(&self, key: &str) -> HydrateMode {
+        let db: &dyn Files = &*self.db;
+        db.hydrate(key.to_string())
+    }
 }
 
 #[instrument]
-pub fn init(npm_bin_dir: PathBuf) -> Cache {
+pub fn init(npm_bin_dir: PathBuf, project_root_dir: &Path) -> Cache {
     let db = SalsaToastDatabaseStruct::default();
+    let manifest_path = project_root_dir.join(CACHE_DIR).join(MANIFEST_FILE);
+    let manifest = Manifest::load(&manifest_path);
 
-    Cache { db, npm_bin_dir }
+    Cache {
+        db,
+        npm_bin_dir,
+        manifest,
+        manifest_path,
+        graph: ModuleGraph::default(),
+    }
 }
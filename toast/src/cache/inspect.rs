@@ -0,0 +1,468 @@
+//! `toast cache`, for seeing what's in `CACHE_DIR` and keeping it from
+//! growing forever:
+//!
+//! - `toast cache stats` shows how big the cache is, how many manifest
+//!   entries there are of each kind, and how often the last run of toast
+//!   hit them
+//! - `toast cache ls` lists every manifest entry with its outputs
+//! - `toast cache gc` removes entries whose sources are gone, with the
+//!   outputs only they produced, and then the least recently written
+//!   cached files, like remote data responses, until the cache is under
+//!   `--max-size`
+//!
+//! Full builds already prune entries for sources that are gone, so `gc`
+//! is for projects that mostly build with `--filter`, `--shard`, or
+//! `--no-prune`, and for responses and other files that are never pruned.
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt, fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use tracing::instrument;
+use walkdir::WalkDir;
+
+use super::{
+    manifest::{entry_kind, HitCount, Manifest, ManifestEntry},
+    CACHE_DIR, MANIFEST_FILE,
+};
+use crate::{exit_code::Failure, remote_data::REMOTE_CACHE_DIR};
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// A size like `500MB` or `2GB`, in bytes. Sizes are decimal, so `1KB` is
+/// 1000 bytes, and a number without a unit is bytes.
+pub fn parse_size(size: &str) -> Result<u64> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let multiplier = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1.0,
+        "K" | "KB" => 1e3,
+        "M" | "MB" => 1e6,
+        "G" | "GB" => 1e9,
+        _ => {
+            return Err(eyre!(
+                "Unknown unit in `{}`, sizes are like `500MB` or `2GB`",
+                size
+            ))
+        }
+    };
+    let number: f64 = number
+        .parse()
+        .wrap_err_with(|| format!("`{}` isn't a size like `500MB` or `2GB`", size))?;
+    Ok((number * multiplier) as u64)
+}
+
+pub fn format_size(bytes: u64) -> String {
+    match bytes {
+        0..=999 => format!("{}B", bytes),
+        1_000..=999_999 => format!("{:.1}KB", bytes as f64 / 1e3),
+        1_000_000..=999_999_999 => format!("{:.1}MB", bytes as f64 / 1e6),
+        _ => format!("{:.1}GB", bytes as f64 / 1e9),
+    }
+}
+
+/// The size of a file, or of everything in a directory, and when it was
+/// last written to
+fn disk_usage(path: &Path) -> (u64, SystemTime) {
+    WalkDir::new(path)
+        .into_iter()
+        .filter_map(|result| result.ok())
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .fold((0, SystemTime::UNIX_EPOCH), |(bytes, newest), metadata| {
+            let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            (bytes + metadata.len(), newest.max(modified))
+        })
+}
+
+fn outputs_size(entry: &ManifestEntry) -> u64 {
+    entry
+        .outputs
+        .iter()
+        .filter_map(|output| fs::metadata(output).ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+/// The manifest in `project_root_dir` whatever version of toast wrote it,
+/// or an empty one if there isn't one yet
+fn read_manifest(project_root_dir: &Path) -> Result<Manifest> {
+    let path = project_root_dir.join(CACHE_DIR).join(MANIFEST_FILE);
+    if !path.exists() {
+        return Ok(Manifest::default());
+    }
+    let contents = fs::read_to_string(&path)
+        .wrap_err_with(|| format!("Failed to read cache manifest `{}`", path.display()))?;
+    serde_json::from_str(&contents)
+        .wrap_err_with(|| format!("Failed to parse cache manifest `{}`", path.display()))
+        .wrap_err(Failure::Cache)
+}
+
+/// Whether the source of an entry is gone. Pages, social cards, and
+/// other entries that aren't keyed by a file can only be told apart by
+/// their outputs being gone.
+fn is_stale(project_root_dir: &Path, key: &str, entry: &ManifestEntry) -> bool {
+    let rest = key.split_once(':').map(|(_, rest)| rest).unwrap_or(key);
+    match entry_kind(key) {
+        "source" | "font" => !project_root_dir.join(rest).exists(),
+        "image" => !project_root_dir
+            .join("static")
+            .join(rest.trim_start_matches('/'))
+            .exists(),
+        "precompress" => !Path::new(rest).exists(),
+        _ => !entry.outputs.is_empty() && entry.outputs.iter().all(|output| !output.exists()),
+    }
+}
+
+/// One entry of the cache manifest, for `toast cache ls`
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntryInfo {
+    pub key: String,
+    pub hash: String,
+    pub outputs: Vec<PathBuf>,
+    /// how much of the outputs are still on disk
+    pub bytes: u64,
+    /// whether `toast cache gc` would remove it
+    pub stale: bool,
+}
+
+impl fmt::Display for EntryInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{}{}",
+            self.key,
+            if self.stale { " (stale)" } else { "" }
+        )?;
+        for output in &self.outputs {
+            writeln!(f, "    {}", output.display())?;
+        }
+        write!(
+            f,
+            "    {} output(s), {}, hash {}",
+            self.outputs.len(),
+            format_size(self.bytes),
+            self.hash
+        )
+    }
+}
+
+/// Every entry of the cache manifest in `project_root_dir`, by key
+#[instrument]
+pub fn cache_entries(project_root_dir: &Path) -> Result<Vec<EntryInfo>> {
+    let manifest = read_manifest(project_root_dir)?;
+    Ok(manifest
+        .entries
+        .iter()
+        .map(|(key, entry)| EntryInfo {
+            key: key.clone(),
+            hash: entry.hash.clone(),
+            outputs: entry.outputs.clone(),
+            bytes: outputs_size(entry),
+            stale: is_stale(project_root_dir, key, entry),
+        })
+        .collect())
+}
+
+/// What `toast cache stats` shows
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct CacheStats {
+    pub dir: PathBuf,
+    pub bytes: u64,
+    /// everything in the cache directory, and how big it is
+    pub contents: Vec<(String, u64)>,
+    /// how many entries there are of each kind, and how big their outputs
+    /// are
+    pub kinds: BTreeMap<String, (usize, u64)>,
+    pub stale: usize,
+    pub last_run: BTreeMap<String, HitCount>,
+    /// the version of toast that wrote the manifest, if it isn't this one
+    pub other_version: Option<String>,
+}
+
+fn hit_rate(count: &HitCount) -> f64 {
+    let checks = count.hits + count.misses;
+    if checks == 0 {
+        0.0
+    } else {
+        count.hits as f64 / checks as f64 * 100.0
+    }
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "`{}` is {}", self.dir.display(), format_size(self.bytes))?;
+        for (name, bytes) in &self.contents {
+            writeln!(f, "    {:<24} {:>9}", name, format_size(*bytes))?;
+        }
+        if let Some(version) = &self.other_version {
+            writeln!(
+                f,
+                "The manifest was written by toast {}, so the next build starts over",
+                version
+            )?;
+        }
+        let entries: usize = self.kinds.values().map(|(entries, _)| entries).sum();
+        writeln!(f, "{} entries, {} of them stale", entries, self.stale)?;
+        for (kind, (entries, bytes)) in &self.kinds {
+            writeln!(
+                f,
+                "    {:<24} {:>6} entries, {} of outputs",
+                kind,
+                entries,
+                format_size(*bytes)
+            )?;
+        }
+        let total = self
+            .last_run
+            .values()
+            .fold(HitCount::default(), |total, count| HitCount {
+                hits: total.hits + count.hits,
+                misses: total.misses + count.misses,
+            });
+        write!(
+            f,
+            "The last run had {} hits and {} misses, a {:.1}% hit rate",
+            total.hits,
+            total.misses,
+            hit_rate(&total)
+        )?;
+        for (kind, count) in &self.last_run {
+            write!(
+                f,
+                "\n    {:<24} {:>6} hits, {:>6} misses, {:.1}%",
+                kind,
+                count.hits,
+                count.misses,
+                hit_rate(count)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The files and directories in the cache directory, and how big they are
+fn cache_contents(dir: &Path) -> Vec<(PathBuf, u64, SystemTime)> {
+    let mut contents: Vec<(PathBuf, u64, SystemTime)> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| {
+            let (bytes, modified) = disk_usage(&entry.path());
+            (entry.path(), bytes, modified)
+        })
+        .collect();
+    contents.sort();
+    contents
+}
+
+#[instrument]
+pub fn cache_stats(project_root_dir: &Path) -> Result<CacheStats> {
+    let dir = project_root_dir.join(CACHE_DIR);
+    let manifest = read_manifest(project_root_dir)?;
+    let contents = cache_contents(&dir);
+    let mut kinds: BTreeMap<String, (usize, u64)> = BTreeMap::new();
+    let mut stale = 0;
+    for (key, entry) in &manifest.entries {
+        let kind = kinds.entry(entry_kind(key).to_string()).or_default();
+        kind.0 += 1;
+        kind.1 += outputs_size(entry);
+        if is_stale(project_root_dir, key, entry) {
+            stale += 1;
+        }
+    }
+    Ok(CacheStats {
+        bytes: contents.iter().map(|(_, bytes, _)| bytes).sum(),
+        contents: contents
+            .iter()
+            .map(|(path, bytes, _)| {
+                let name = path.file_name().unwrap_or_default();
+                (name.to_string_lossy().to_string(), *bytes)
+            })
+            .collect(),
+        dir,
+        kinds,
+        stale,
+        last_run: manifest.last_run,
+        other_version: Some(manifest.version).filter(|version| version != VERSION),
+    })
+}
+
+/// What `toast cache gc` removed, or would remove with `--dry-run`
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct GcSummary {
+    pub dry_run: bool,
+    /// stale manifest entries
+    pub entries: Vec<String>,
+    /// outputs that only stale entries produced
+    pub outputs: Vec<PathBuf>,
+    /// cached files removed to get under `--max-size`, and their sizes
+    pub evicted: Vec<(PathBuf, u64)>,
+}
+
+impl fmt::Display for GcSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let verb = if self.dry_run {
+            "Would remove"
+        } else {
+            "Removed"
+        };
+        for key in &self.entries {
+            writeln!(f, "{} entry {}", verb, key)?;
+        }
+        for output in &self.outputs {
+            writeln!(f, "{} output {}", verb, output.display())?;
+        }
+        for (path, bytes) in &self.evicted {
+            writeln!(f, "{} {} ({})", verb, path.display(), format_size(*bytes))?;
+        }
+        write!(
+            f,
+            "{} {} stale entries, {} outputs, and {} cached files ({})",
+            verb,
+            self.entries.len(),
+            self.outputs.len(),
+            self.evicted.len(),
+            format_size(self.evicted.iter().map(|(_, bytes)| bytes).sum())
+        )
+    }
+}
+
+/// The oldest of `candidates` to remove to get `total` down to
+/// `max_size`, oldest first
+fn evictions(
+    mut candidates: Vec<(PathBuf, u64, SystemTime)>,
+    total: u64,
+    max_size: u64,
+) -> Vec<(PathBuf, u64)> {
+    candidates.sort_by_key(|(_, _, modified)| *modified);
+    let mut total = total;
+    candidates
+        .into_iter()
+        .take_while(|(_, bytes, _)| {
+            let over = total > max_size;
+            total = total.saturating_sub(*bytes);
+            over
+        })
+        .map(|(path, bytes, _)| (path, bytes))
+        .collect()
+}
+
+fn remove(path: &Path) -> Result<()> {
+    let removed = if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    };
+    removed.wrap_err_with(|| format!("Failed to remove `{}`", path.display()))
+}
+
+/// Remove stale entries and their outputs, then the least recently
+/// written cached files until the cache is no bigger than `max_size`
+#[instrument]
+pub fn collect_garbage(
+    project_root_dir: &Path,
+    max_size: Option<u64>,
+    dry_run: bool,
+) -> Result<GcSummary> {
+    let dir = project_root_dir.join(CACHE_DIR);
+    let mut manifest = read_manifest(project_root_dir)?;
+    let mut summary = GcSummary {
+        dry_run,
+        ..GcSummary::default()
+    };
+
+    // entries from another version are thrown away by the next build
+    // anyway, but their outputs may still be live
+    let stale: BTreeSet<String> = if manifest.version == VERSION {
+        manifest
+            .entries
+            .iter()
+            .filter(|(key, entry)| is_stale(project_root_dir, key, entry))
+            .map(|(key, _)| key.clone())
+            .collect()
+    } else {
+        manifest.entries.keys().cloned().collect()
+    };
+    if !stale.is_empty() {
+        manifest.used = manifest
+            .entries
+            .keys()
+            .filter(|key| !stale.contains(*key))
+            .cloned()
+            .collect();
+        let orphaned = manifest.prune();
+        summary.entries = stale.into_iter().collect();
+        if manifest.version == VERSION {
+            summary.outputs = orphaned.into_iter().filter(|path| path.exists()).collect();
+        }
+        if !dry_run {
+            for output in &summary.outputs {
+                remove(output)?;
+            }
+            manifest.version = VERSION.to_string();
+            manifest.save(&dir.join(MANIFEST_FILE))?;
+        }
+    }
+
+    if let Some(max_size) = max_size {
+        let contents = cache_contents(&dir);
+        let total = contents.iter().map(|(_, bytes, _)| bytes).sum();
+        // responses are evicted one by one, everything else as a whole,
+        // and the manifest is always kept
+        let candidates = contents
+            .into_iter()
+            .filter(|(path, _, _)| {
+                path.file_name()
+                    .is_some_and(|name| name != MANIFEST_FILE && name != REMOTE_CACHE_DIR)
+            })
+            .chain(cache_contents(&dir.join(REMOTE_CACHE_DIR)))
+            .collect();
+        summary.evicted = evictions(candidates, total, max_size);
+        if !dry_run {
+            for (path, _) in &summary.evicted {
+                remove(path)?;
+            }
+        }
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_sizes() {
+        assert_eq!(parse_size("500MB").unwrap(), 500_000_000);
+        assert_eq!(parse_size("1.5 gb").unwrap(), 1_500_000_000);
+        assert_eq!(parse_size("2048").unwrap(), 2048);
+        assert!(parse_size("10 parsecs").is_err());
+        assert_eq!(format_size(12_345_678), "12.3MB");
+    }
+
+    #[test]
+    fn test_evictions() {
+        let at = |seconds| SystemTime::UNIX_EPOCH + Duration::from_secs(seconds);
+        let candidates = vec![
+            (PathBuf::from("new.json"), 40, at(3)),
+            (PathBuf::from("old.json"), 30, at(1)),
+            (PathBuf::from("middle.json"), 20, at(2)),
+        ];
+        assert_eq!(
+            evictions(candidates.clone(), 100, 60),
+            vec![
+                (PathBuf::from("old.json"), 30),
+                (PathBuf::from("middle.json"), 20)
+            ]
+        );
+        assert!(evictions(candidates, 100, 100).is_empty());
+    }
+}
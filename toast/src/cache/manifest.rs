@@ -0,0 +1,367 @@
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Path, PathBuf},
+};
+use tracing::instrument;
+
+use crate::exit_code::Failure;
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Inputs at least this big are hashed on every core
+const PARALLEL_HASH_MIN: usize = 128 * 1024;
+
+/// On-disk record of which source hashes produced which output files,
+/// so that unchanged files can be skipped across separate runs of toast.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    /// manifests written by other versions of toast are thrown away
+    /// because the compiled output may be different
+    pub version: String,
+    pub entries: BTreeMap<String, ManifestEntry>,
+    /// how often entries were fresh during the run of toast that saved
+    /// this manifest, by the kind of entry, see `entry_kind`
+    #[serde(default)]
+    pub last_run: BTreeMap<String, HitCount>,
+    /// keys checked or recorded during this run, so entries for sources
+    /// that are gone can be pruned
+    #[serde(skip)]
+    pub used: BTreeSet<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HitCount {
+    pub hits: usize,
+    pub misses: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub hash: String,
+    pub outputs: Vec<PathBuf>,
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Manifest {
+            version: VERSION.to_string(),
+            entries: BTreeMap::new(),
+            last_run: BTreeMap::new(),
+            used: BTreeSet::new(),
+        }
+    }
+}
+
+impl Manifest {
+    /// Load a manifest, starting from scratch if it doesn't exist, is
+    /// corrupt, or was written by a different version of toast.
+    #[instrument]
+    pub fn load(path: &Path) -> Manifest {
+        let manifest = fs::read_to_string(path)
+            .ok()
+            .and_then(|contents| serde_json::from_str::<Manifest>(&contents).ok());
+        match manifest {
+            // hit counts start over with each run
+            Some(manifest) if manifest.version == VERSION => Manifest {
+                last_run: BTreeMap::new(),
+                ..manifest
+            },
+            _ => Manifest::default(),
+        }
+    }
+
+    /// Load a manifest that has to be there and match this version of
+    /// toast, like one from another shard of a build
+    #[instrument]
+    pub fn read(path: &Path) -> Result<Manifest> {
+        let contents = fs::read_to_string(path)
+            .wrap_err_with(|| format!("Failed to read cache manifest `{}`", path.display()))?;
+        let manifest: Manifest = serde_json::from_str(&contents)
+            .wrap_err_with(|| format!("Failed to parse cache manifest `{}`", path.display()))
+            .wrap_err(Failure::Cache)?;
+        if manifest.version != VERSION {
+            return Err(eyre!(
+                "`{}` was written by toast {}, not {}",
+                path.display(),
+                manifest.version,
+                VERSION
+            ));
+        }
+        Ok(manifest)
+    }
+
+    /// Add the entries of `other`, a manifest from another build of the
+    /// same sources. Sources both built, like components every shard
+    /// compiles, have to have been built from the same hash.
+    pub fn merge(&mut self, other: Manifest) -> Result<()> {
+        for (kind, count) in other.last_run {
+            let total = self.last_run.entry(kind).or_default();
+            total.hits += count.hits;
+            total.misses += count.misses;
+        }
+        for (key, entry) in other.entries {
+            match self.entries.get_mut(&key) {
+                Some(existing) if existing.hash != entry.hash => {
+                    return Err(eyre!(
+                        "`{}` was built from different sources in the manifests being merged",
+                        key
+                    ))
+                }
+                Some(existing) => {
+                    for output in entry.outputs {
+                        if !existing.outputs.contains(&output) {
+                            existing.outputs.push(output);
+                        }
+                    }
+                }
+                None => {
+                    self.entries.insert(key, entry);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).wrap_err_with(|| {
+                format!("Failed to create cache directory `{}`", dir.display())
+            })?;
+        }
+        let contents = serde_json::to_string_pretty(self)?;
+        // written next to the manifest and renamed over it, so a build
+        // that's killed partway through a save can't leave half of one
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        let partial = PathBuf::from(partial);
+        fs::write(&partial, contents)
+            .wrap_err_with(|| format!("Failed to write cache manifest `{}`", partial.display()))?;
+        fs::rename(&partial, path)
+            .wrap_err_with(|| format!("Failed to write cache manifest `{}`", path.display()))
+    }
+
+    /// An entry is fresh if it was built from the same hash and all of
+    /// its outputs are still on disk.
+    pub fn is_fresh(&mut self, key: &str, hash: &str) -> bool {
+        self.used.insert(key.to_string());
+        let fresh = match self.entries.get(key) {
+            Some(entry) => entry.hash == hash && entry.outputs.iter().all(|path| path.exists()),
+            None => false,
+        };
+        let count = self
+            .last_run
+            .entry(entry_kind(key).to_string())
+            .or_default();
+        if fresh {
+            count.hits += 1;
+        } else {
+            count.misses += 1;
+        }
+        fresh
+    }
+
+    pub fn record(&mut self, key: &str, hash: String, outputs: Vec<PathBuf>) {
+        self.used.insert(key.to_string());
+        self.entries
+            .insert(key.to_string(), ManifestEntry { hash, outputs });
+    }
+
+    /// Point outputs under `from` at the same path under `to`, after the
+    /// directory they're in has moved
+    pub fn relocate(&mut self, from: &Path, to: &Path) {
+        for entry in self.entries.values_mut() {
+            for output in entry.outputs.iter_mut() {
+                if let Ok(rest) = output.strip_prefix(from) {
+                    *output = to.join(rest);
+                }
+            }
+        }
+    }
+
+    /// Forget every entry that wasn't used this run, returning the outputs
+    /// that only those entries produced
+    pub fn prune(&mut self) -> Vec<PathBuf> {
+        let (used, unused): (BTreeMap<_, _>, BTreeMap<_, _>) = std::mem::take(&mut self.entries)
+            .into_iter()
+            .partition(|(key, _)| self.used.contains(key));
+        self.entries = used;
+        // a renamed source, like `post.js` to `post.mdx`, can have the same
+        // outputs as the source it replaced
+        let live: BTreeSet<&PathBuf> = self
+            .entries
+            .values()
+            .flat_map(|entry| entry.outputs.iter())
+            .collect();
+        unused
+            .into_values()
+            .flat_map(|entry| entry.outputs)
+            .filter(|output| !live.contains(output))
+            .collect()
+    }
+
+    /// The outputs `prune` would remove after a full build that found
+    /// only `sources`, without forgetting anything. Entries that aren't
+    /// sources are left out, since only a build knows which are used.
+    pub fn orphaned_source_outputs(&self, sources: &BTreeSet<String>) -> Vec<PathBuf> {
+        let (live, orphaned): (Vec<_>, Vec<_>) = self
+            .entries
+            .iter()
+            .filter(|(key, _)| entry_kind(key) == "source")
+            .partition(|(key, _)| sources.contains(key.as_str()));
+        let live: BTreeSet<&PathBuf> = live
+            .into_iter()
+            .flat_map(|(_, entry)| entry.outputs.iter())
+            .collect();
+        orphaned
+            .into_iter()
+            .flat_map(|(_, entry)| entry.outputs.iter())
+            .filter(|output| !live.contains(output))
+            .cloned()
+            .collect()
+    }
+}
+
+/// What made an entry, like `image` for `image:/images/hero.jpg`, or
+/// `source` for compiled sources, which are keyed by their path
+pub fn entry_kind(key: &str) -> &str {
+    match key.split_once(':') {
+        Some((kind, _)) => kind,
+        None => "source",
+    }
+}
+
+/// BLAKE3, which unlike `DefaultHasher` is the same on every machine
+/// and version of Rust, so a manifest stays fresh wherever it's used, and
+/// is fast enough to hash every input on every build instead of trusting
+/// mtimes, which network filesystems and CI caches don't keep
+fn hash_parts<'a>(inputs: impl Iterator<Item = &'a [u8]>) -> String {
+    let mut hasher = blake3::Hasher::new();
+    for input in inputs {
+        // the length keeps `["ab", "c"]` and `["a", "bc"]` apart
+        hasher.update(&(input.len() as u64).to_le_bytes());
+        if input.len() >= PARALLEL_HASH_MIN {
+            hasher.update_with_join::<blake3::join::RayonJoin>(input);
+        } else {
+            hasher.update(input);
+        }
+    }
+    // 64 bits is plenty to tell two versions of a file apart, and keeps
+    // fingerprinted file names short
+    hasher.finalize().to_hex()[..16].to_string()
+}
+
+/// Hash everything that can change the compiled output of a file
+pub fn hash_inputs(inputs: &[&str]) -> String {
+    hash_parts(inputs.iter().map(|input| input.as_bytes()))
+}
+
+/// Hash the contents of binary files, like images
+pub fn content_hash(inputs: &[&[u8]]) -> String {
+    hash_parts(inputs.iter().copied())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prune_unused_entries() {
+        let mut previous = Manifest::default();
+        previous.record(
+            "src/pages/old.js",
+            String::from("a"),
+            vec![PathBuf::from("public/old.js")],
+        );
+        previous.record(
+            "src/pages/post.js",
+            String::from("b"),
+            vec![PathBuf::from("public/post.js")],
+        );
+        previous.used.clear();
+
+        previous.record(
+            "src/pages/post.mdx",
+            String::from("c"),
+            vec![PathBuf::from("public/post.js")],
+        );
+        assert_eq!(previous.prune(), vec![PathBuf::from("public/old.js")]);
+        assert_eq!(
+            previous.entries.keys().collect::<Vec<_>>(),
+            vec!["src/pages/post.mdx"]
+        );
+    }
+
+    #[test]
+    fn test_orphaned_source_outputs() {
+        let mut manifest = Manifest::default();
+        manifest.record(
+            "src/pages/old.js",
+            String::from("a"),
+            vec![PathBuf::from("public/old.js")],
+        );
+        manifest.record(
+            "src/pages/post.mdx",
+            String::from("b"),
+            vec![PathBuf::from("public/post.js")],
+        );
+        manifest.record(
+            "image:/images/hero.jpg",
+            String::from("c"),
+            vec![PathBuf::from("public/images/hero.webp")],
+        );
+        let sources = BTreeSet::from([String::from("src/pages/post.mdx")]);
+        assert_eq!(
+            manifest.orphaned_source_outputs(&sources),
+            vec![PathBuf::from("public/old.js")]
+        );
+        assert_eq!(manifest.entries.len(), 3);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut first = Manifest::default();
+        first.record(
+            "src/nav.js",
+            String::from("a"),
+            vec![PathBuf::from("public/src/nav.js")],
+        );
+        first.record(
+            "src/pages/a.js",
+            String::from("b"),
+            vec![PathBuf::from("public/a.html")],
+        );
+        let mut second = Manifest::default();
+        second.record(
+            "src/nav.js",
+            String::from("a"),
+            vec![PathBuf::from("public/src/nav.js")],
+        );
+        second.record(
+            "src/pages/b.js",
+            String::from("c"),
+            vec![PathBuf::from("public/b.html")],
+        );
+        first.merge(second).unwrap();
+        assert_eq!(
+            first.entries.keys().collect::<Vec<_>>(),
+            vec!["src/nav.js", "src/pages/a.js", "src/pages/b.js"]
+        );
+        assert_eq!(first.entries["src/nav.js"].outputs.len(), 1);
+
+        let mut changed = Manifest::default();
+        changed.record("src/nav.js", String::from("d"), vec![]);
+        assert!(first.merge(changed).is_err());
+    }
+
+    #[test]
+    fn test_hashes() {
+        assert_eq!(content_hash(&[b"toast"]), hash_inputs(&["toast"]));
+        assert_ne!(hash_inputs(&["ab", "c"]), hash_inputs(&["a", "bc"]));
+        let large = vec![7; PARALLEL_HASH_MIN * 2];
+        assert_eq!(content_hash(&[&large]).len(), 16);
+    }
+}
@@ -1,7 +1,9 @@
 use crate::{
+    diagnostics::CompileError,
     esinstall::ImportMap,
+    hydration::{hydrate_mode, HydrateMode},
     sources::Source,
-    swc_ops::{compile_js_for_browser, compile_js_for_server},
+    swc_ops::{compile_js_for_browser, compile_js_for_server, BrowserJsOptions, CompiledJs},
 };
 use std::path::PathBuf;
 use std::sync::Arc;
@@ -13,8 +15,15 @@ pub trait Files: salsa::Database {
     fn source(&self, key: String) -> Arc<Source>;
 
     // compile js for targets
-    fn js_for_browser(&self, key: String, npm_bin_dir: PathBuf, import_map: ImportMap) -> String;
-    fn js_for_server(&self, key: String, npm_bin_dir: PathBuf) -> String;
+    fn js_for_browser(
+        &self,
+        key: String,
+        npm_bin_dir: PathBuf,
+        import_map: ImportMap,
+        options: BrowserJsOptions,
+    ) -> Result<CompiledJs, CompileError>;
+    fn js_for_server(&self, key: String, npm_bin_dir: PathBuf) -> Result<String, CompileError>;
+    fn hydrate(&self, key: String) -> HydrateMode;
 
     // not meant to be used by users
     fn read(&self, path: PathBuf) -> String;
@@ -43,17 +52,32 @@ fn js_for_browser(
     key: String,
     npm_bin_dir: PathBuf,
     import_map: ImportMap,
-) -> String {
+    options: BrowserJsOptions,
+) -> Result<CompiledJs, CompileError> {
     let source_file = db.source(key.to_string());
-    compile_js_for_browser(source_file.source.clone(), key, npm_bin_dir, import_map)
+    compile_js_for_browser(
+        source_file.source.clone(),
+        key,
+        npm_bin_dir,
+        import_map,
+        options,
+    )
 }
 
 #[instrument(skip(db))]
-fn js_for_server(db: &dyn Files, key: String, npm_bin_dir: PathBuf) -> String {
+fn js_for_server(
+    db: &dyn Files,
+    key: String,
+    npm_bin_dir: PathBuf,
+) -> Result<String, CompileError> {
     let source_file = db.source(key.to_string());
     compile_js_for_server(source_file.source.clone(), key, npm_bin_dir)
 }
 
+fn hydrate(db: &dyn Files, key: String) -> HydrateMode {
+    hydrate_mode(&db.source(key).source)
+}
+
 #[salsa::database(FilesStorage)]
 #[derive(Default)]
 pub struct SalsaToastDatabaseStruct {
@@ -61,3 +85,11 @@ pub struct SalsaToastDatabaseStruct {
 }
 
 impl salsa::Database for SalsaToastDatabaseStruct {}
+
+impl salsa::ParallelDatabase for SalsaToastDatabaseStruct {
+    fn snapshot(&self) -> salsa::Snapshot<Self> {
+        salsa::Snapshot::new(SalsaToastDatabaseStruct {
+            storage: self.storage.snapshot(),
+        })
+    }
+}
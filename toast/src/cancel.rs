@@ -0,0 +1,135 @@
+//! Stopping a build cleanly on Ctrl+C or `SIGTERM`.
+//!
+//! While a build is running, the first signal kills the node processes
+//! toast started and marks the build as cancelled. The build stops at the
+//! next page batch or compile job it would have started, and
+//! `build_staged` rolls the cache back and saves it, and throws the
+//! staging directory away, so the last good site and a consistent cache
+//! manifest are left behind. A second signal exits right away. Outside of
+//! a build, like while `toast serve` is waiting for requests, there's
+//! nothing to clean up and a signal exits immediately.
+//!
+//! Either way toast exits with `128 + signal`, 130 for Ctrl+C, so scripts
+//! can tell a cancelled build from a failed one.
+use color_eyre::eyre::{Result, WrapErr};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, AtomicUsize, Ordering};
+use thiserror::Error;
+
+/// The signal that cancelled the build, 0 until there is one
+static SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+/// How many builds are running, signals only wait for builds
+static BUILDING: AtomicUsize = AtomicUsize::new(0);
+
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+/// Child processes to kill when a build is cancelled. They're kept in a
+/// fixed set of slots so the signal handler can read them without taking
+/// a lock or allocating.
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_CHILD: AtomicU32 = AtomicU32::new(0);
+static CHILDREN: [AtomicU32; 64] = [NO_CHILD; 64];
+
+#[derive(Error, Debug)]
+#[error("The build was cancelled")]
+pub struct Cancelled;
+
+/// Handle `SIGINT` and `SIGTERM` for the rest of the process
+pub fn install() -> Result<()> {
+    for &signal in &[SIGINT, SIGTERM] {
+        // Safety: the handler only touches atomics and calls `kill` and
+        // `_exit`, which are all async-signal-safe
+        unsafe { signal_hook::low_level::register(signal, move || handle(signal)) }
+            .wrap_err_with(|| format!("Failed to handle signal {}", signal))?;
+    }
+    Ok(())
+}
+
+fn handle(signal: i32) {
+    let exit_code = 128 + signal;
+    if BUILDING.load(Ordering::SeqCst) == 0 || CANCELLED.swap(true, Ordering::SeqCst) {
+        kill_children();
+        signal_hook::low_level::exit(exit_code);
+    }
+    SIGNAL.store(signal, Ordering::SeqCst);
+    kill_children();
+}
+
+fn kill_children() {
+    for slot in CHILDREN.iter() {
+        let pid = slot.load(Ordering::SeqCst);
+        if pid != 0 {
+            kill(pid);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn kill(pid: u32) {
+    // Safety: `kill` is a plain syscall, at worst the process is gone
+    unsafe {
+        libc::kill(pid as libc::pid_t, libc::SIGTERM);
+    }
+}
+
+/// Ctrl+C reaches every process attached to the console on Windows, so
+/// the node processes get it themselves
+#[cfg(not(unix))]
+fn kill(_pid: u32) {}
+
+/// Remember a child process to kill if the build is cancelled
+pub fn track_child(pid: u32) {
+    for slot in CHILDREN.iter() {
+        if slot
+            .compare_exchange(0, pid, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
+            return;
+        }
+    }
+}
+
+/// Forget a child process that has exited, before its pid is reused
+pub fn untrack_child(pid: u32) {
+    for slot in CHILDREN.iter() {
+        let _ = slot.compare_exchange(pid, 0, Ordering::SeqCst, Ordering::SeqCst);
+    }
+}
+
+/// Marks a build as running until it's dropped, so that a signal lets it
+/// clean up instead of exiting
+pub struct Building(());
+
+pub fn building() -> Building {
+    BUILDING.fetch_add(1, Ordering::SeqCst);
+    Building(())
+}
+
+impl Drop for Building {
+    fn drop(&mut self) {
+        BUILDING.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub fn is_cancelled() -> bool {
+    CANCELLED.load(Ordering::SeqCst)
+}
+
+/// Fail with `Cancelled` if the build has been cancelled, called before
+/// starting more work
+pub fn check() -> Result<()> {
+    if is_cancelled() {
+        Err(Cancelled.into())
+    } else {
+        Ok(())
+    }
+}
+
+/// The code to exit with if the build was cancelled
+pub fn exit_code() -> Option<i32> {
+    match SIGNAL.load(Ordering::SeqCst) {
+        0 => None,
+        signal => Some(128 + signal),
+    }
+}
@@ -1,9 +1,14 @@
 use color_eyre::{eyre::eyre, Result};
 use std::env;
 use std::path::PathBuf;
-use structopt::StructOpt;
+use structopt::{clap::Shell, StructOpt};
 use tracing::instrument;
 
+use crate::{
+    bench::BenchShape, cache::parse_size, logging::LogFormat, pages::Shard, report::ReportFormat,
+    telemetry::TelemetryKind,
+};
+
 #[instrument]
 fn abspath(input_dir: &str) -> Result<PathBuf> {
     match dunce::canonicalize(input_dir) {
@@ -16,22 +21,378 @@ fn abspath(input_dir: &str) -> Result<PathBuf> {
     }
 }
 
+/// Flags shared by every subcommand that builds a site
+#[derive(Debug, StructOpt)]
+pub struct BuildArgs {
+    /// Activate debug mode
+    #[structopt(short, long)]
+    pub debug: bool,
+
+    /// The directory of your Toast site, the current directory if not present
+    #[structopt(parse(try_from_str = abspath))]
+    pub input_dir: Option<PathBuf>,
+
+    /// Output directory, `output_dir` from the config file or "./public" if not present
+    #[structopt(parse(from_os_str))]
+    pub output_dir: Option<PathBuf>,
+
+    /// Where page modules are, relative to the input directory. Defaults to "src/pages"
+    #[structopt(long)]
+    pub pages_dir: Option<String>,
+
+    /// Build pages with `draft: true` or a `date` in the future, which `serve` always does
+    #[structopt(long)]
+    pub drafts: bool,
+
+    /// Keep running and rebuild when files in the input directory change
+    #[structopt(short, long)]
+    pub watch: bool,
+
+    /// Print what `toast incremental` would compile, render, copy, and
+    /// delete, or what `toast deploy` would upload, without writing
+    /// anything. `toast serve` and `toast listen` refuse it.
+    #[structopt(long)]
+    pub dry_run: bool,
+
+    /// How many files to compile in parallel, defaults to the number of cpus
+    #[structopt(short, long)]
+    pub jobs: Option<usize>,
+
+    /// How many node processes to render pages with, defaults to the number of cpus
+    #[structopt(long)]
+    pub render_workers: Option<usize>,
+
+    /// Stop a render that goes this many seconds without finishing a page, or a `sourceData` without output, 60 by default and 0 to wait forever
+    #[structopt(long)]
+    pub render_timeout: Option<u64>,
+
+    /// Write an empty import map if there isn't one yet instead of failing
+    #[structopt(long)]
+    pub create_import_map: bool,
+
+    /// Print a machine-readable summary of the build. The only format is `json`
+    #[structopt(long)]
+    pub report: Option<ReportFormat>,
+
+    /// Print how long each page took to compile, render, and write
+    #[structopt(long)]
+    pub profile: bool,
+
+    /// Write the `--report` to this file instead of stdout
+    #[structopt(long, parse(from_os_str))]
+    pub report_file: Option<PathBuf>,
+
+    /// Inline each page's stylesheet into a `<style>` tag and defer loading other stylesheets
+    #[structopt(long)]
+    pub inline_css: bool,
+
+    /// Collapse whitespace and remove comments in rendered html
+    #[structopt(long)]
+    pub minify_html: bool,
+
+    /// Write `.br` and `.gz` copies of html, JS, and CSS outputs
+    #[structopt(long)]
+    pub precompress: bool,
+
+    /// Write a source map next to every browser module, always on for `toast serve`
+    #[structopt(long)]
+    pub source_maps: bool,
+
+    /// Minify browser modules, the default unless `--debug` is passed
+    #[structopt(long)]
+    pub minify: bool,
+
+    /// Don't minify browser modules
+    #[structopt(long, conflicts_with = "minify")]
+    pub no_minify: bool,
+
+    /// Fail the build if any link or asset in the rendered html points at a file that doesn't exist
+    #[structopt(long)]
+    pub check_links: bool,
+
+    /// Also request `http` and `https` links, implies `--check-links`
+    #[structopt(long)]
+    pub check_external_links: bool,
+
+    /// Write a `robots.txt` that disallows every crawler, for preview and staging builds
+    #[structopt(long)]
+    pub disallow_all: bool,
+
+    /// Fail on the first warning, like a page missing `required_frontmatter` or a broken internal link
+    #[structopt(long)]
+    pub strict: bool,
+
+    /// Build everything that can be built and write the site even if some of it failed, then exit with an error
+    #[structopt(long, conflicts_with = "strict")]
+    pub keep_going: bool,
+
+    /// Sort traversal and fix timestamps so two builds of the same commit are byte-identical
+    #[structopt(long)]
+    pub reproducible: bool,
+
+    /// Never use the network: use cached remote data only, don't check external links, keep npm off the registry, and only write the local copy of an `s3://` output directory
+    #[structopt(long, conflicts_with_all = &["check-external-links", "telemetry"])]
+    pub offline: bool,
+
+    /// The oldest node version to build with, like `16` or `16.14.0`, instead of 14
+    #[structopt(long, env = "TOAST_MIN_NODE_VERSION")]
+    pub min_node_version: Option<String>,
+
+    /// Warn instead of failing when node is missing or older than the minimum version
+    #[structopt(long)]
+    pub skip_node_check: bool,
+
+    /// Find pages and modules through symlinked files and directories instead of skipping them
+    #[structopt(long)]
+    pub follow_symlinks: bool,
+
+    /// Keep outputs in the output directory whose sources have been deleted or renamed
+    #[structopt(long = "no-prune", parse(from_flag = std::ops::Not::not))]
+    pub prune: bool,
+
+    /// Url path the site is served under, like `/my-project/` for GitHub Pages project sites
+    #[structopt(long)]
+    pub base_path: Option<String>,
+
+    /// Only render and write pages whose url path or source matches this glob, like `blog/**`
+    #[structopt(long)]
+    pub filter: Option<glob::Pattern>,
+
+    /// Only render and write one part of the pages, like `2/4` for the second of four, for builds split across machines
+    #[structopt(long)]
+    pub shard: Option<Shard>,
+
+    /// Send the build's timings as an OpenTelemetry trace, to `otlp` or `honeycomb`, configured by environment variables
+    #[structopt(long)]
+    pub telemetry: Option<TelemetryKind>,
+}
+
+/// The flags `toast incremental` runs with when none are passed, so
+/// `--no-prune` and the like default the same way for the embedding API
+impl Default for BuildArgs {
+    fn default() -> Self {
+        BuildArgs::from_iter(&["toast"])
+    }
+}
+
+/// What `toast cache` does, see `cache::inspect`
+#[derive(Debug, StructOpt)]
+pub enum CacheCommand {
+    /// Show how big the cache is, and how often the last run of toast hit it
+    #[structopt(name = "stats")]
+    Stats {
+        /// The directory of your Toast site, the current directory if not present
+        #[structopt(parse(try_from_str = abspath))]
+        input_dir: Option<PathBuf>,
+    },
+    /// List every entry in the cache manifest and its outputs
+    #[structopt(name = "ls")]
+    Ls {
+        /// The directory of your Toast site, the current directory if not present
+        #[structopt(parse(try_from_str = abspath))]
+        input_dir: Option<PathBuf>,
+    },
+    /// Remove entries whose sources are gone, and the oldest cached files when the cache is over `--max-size`
+    #[structopt(name = "gc")]
+    Gc {
+        /// The directory of your Toast site, the current directory if not present
+        #[structopt(parse(try_from_str = abspath))]
+        input_dir: Option<PathBuf>,
+
+        /// How big the cache can be, like `500MB` or `2GB`
+        #[structopt(long, parse(try_from_str = parse_size))]
+        max_size: Option<u64>,
+
+        /// Print what would be removed without removing it
+        #[structopt(long)]
+        dry_run: bool,
+    },
+}
+
+/// Flags for `toast bench`, see `bench`
+#[derive(Debug, StructOpt)]
+pub struct BenchArgs {
+    /// How many pages the generated project has
+    #[structopt(long, default_value = "100")]
+    pub pages: usize,
+
+    /// What the pages are, `md`, `jsx`, or `images`
+    #[structopt(long, default_value = "md")]
+    pub shape: BenchShape,
+
+    /// How many builds to time
+    #[structopt(long, default_value = "5")]
+    pub runs: usize,
+
+    /// Keep the cache between builds, to time rebuilds where nothing changed
+    #[structopt(long)]
+    pub warm: bool,
+
+    /// Where to generate the project, defaults to `.toast/bench` in the current directory
+    #[structopt(long, parse(from_os_str))]
+    pub dir: Option<PathBuf>,
+
+    /// Print the timings in a machine-readable format. The only format is `json`
+    #[structopt(long)]
+    pub report: Option<ReportFormat>,
+
+    /// A `--report json` from an earlier run to compare the median build against
+    #[structopt(long, parse(from_os_str))]
+    pub baseline: Option<PathBuf>,
+
+    /// Fail if the median build is this many percent slower than the `--baseline`
+    #[structopt(long, default_value = "10")]
+    pub max_regression: f64,
+}
+
 #[derive(Debug, StructOpt)]
 #[structopt(name = "toast", about = "The best place to stack your JAM")]
+pub struct Cli {
+    #[structopt(flatten)]
+    pub log: LogArgs,
+
+    /// The node binary, or the directory it's in, to run instead of the `node` on your PATH
+    #[structopt(long, env = "TOAST_NODE", global = true)]
+    pub node_path: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    pub command: Toast,
+}
+
+/// Flags for log output, which every subcommand takes, see `logging`
+#[derive(Debug, StructOpt)]
+pub struct LogArgs {
+    /// Log more, once per level, `-vvv` logs every span with its timing
+    #[structopt(short, long, parse(from_occurrences), global = true)]
+    pub verbose: u8,
+
+    /// Only log errors
+    #[structopt(short, long, global = true)]
+    pub quiet: bool,
+
+    /// How to write log lines, `text` or `json`
+    #[structopt(long, default_value = "text", global = true)]
+    pub log_format: LogFormat,
+}
+
+#[derive(Debug, StructOpt)]
 pub enum Toast {
     /// Incrementally build your input directory
     #[structopt(name = "incremental")]
     Incremental {
-        /// Activate debug mode
-        #[structopt(short, long)]
-        debug: bool,
+        #[structopt(flatten)]
+        build: BuildArgs,
+    },
+    /// Build your input directory, then serve the output directory over http
+    #[structopt(name = "serve")]
+    Serve {
+        #[structopt(flatten)]
+        build: BuildArgs,
+
+        /// Port to serve the site on
+        #[structopt(short, long, default_value = "3000")]
+        port: u16,
+    },
+    /// Build your input directory, then rebuild it whenever a webhook POSTs to this port
+    #[structopt(name = "listen")]
+    Listen {
+        #[structopt(flatten)]
+        build: BuildArgs,
+
+        /// Port to listen for webhooks on
+        #[structopt(short, long, default_value = "3000")]
+        port: u16,
+
+        /// Secret webhooks have to send, defaults to `TOAST_WEBHOOK_SECRET`
+        #[structopt(long)]
+        secret: Option<String>,
+    },
+    /// Check your environment and project for problems that would stop a build
+    #[structopt(name = "doctor")]
+    Doctor {
+        #[structopt(flatten)]
+        build: BuildArgs,
+    },
+    /// Print the resolved settings, pages, import map, cache, and toolchain versions of your project
+    #[structopt(name = "info")]
+    Info {
+        #[structopt(flatten)]
+        build: BuildArgs,
+
+        /// Print it as JSON, for build scripts
+        #[structopt(long)]
+        json: bool,
+    },
+    /// Bundle the dependencies in package.json into web_modules and write an import map for them
+    #[structopt(name = "import-map")]
+    ImportMap {
+        #[structopt(flatten)]
+        build: BuildArgs,
+    },
+    /// Upload what changed in the output directory since the last deploy to the `[deploy]` target in toast.config.toml
+    #[structopt(name = "deploy")]
+    Deploy {
+        #[structopt(flatten)]
+        build: BuildArgs,
 
-        /// The directory of your Toast site
+        /// Upload every file, not just the ones that changed since the last deploy
+        #[structopt(long)]
+        all: bool,
+    },
+    /// List the files that were added, removed, or changed between two builds, with their size changes
+    #[structopt(name = "diff")]
+    Diff {
+        /// The directory of your Toast site, the current directory if not present
         #[structopt(parse(try_from_str = abspath))]
-        input_dir: PathBuf,
+        input_dir: Option<PathBuf>,
 
-        /// Output directory, "./public" if not present
+        /// The build to compare from, by number or the path of an output manifest, defaults to the one before `--to`
+        #[structopt(long)]
+        from: Option<String>,
+
+        /// The build to compare to, by number or the path of an output manifest, defaults to the last one
+        #[structopt(long)]
+        to: Option<String>,
+
+        /// Print it as JSON, for build scripts
+        #[structopt(long)]
+        json: bool,
+    },
+    /// Combine the cache manifests written by each `--shard` of a build into one
+    #[structopt(name = "merge-manifests")]
+    MergeManifests {
+        /// The `.toast/cache.json` of each shard
+        #[structopt(parse(from_os_str), required = true)]
+        manifests: Vec<PathBuf>,
+
+        /// Where to write the combined manifest
+        #[structopt(short, long, parse(from_os_str), default_value = ".toast/cache.json")]
+        output: PathBuf,
+    },
+    /// Inspect the cache in `.toast`, or remove what's no longer needed from it
+    #[structopt(name = "cache")]
+    Cache {
+        #[structopt(subcommand)]
+        command: CacheCommand,
+    },
+    /// Time repeated builds of a generated project
+    #[structopt(name = "bench")]
+    Bench {
+        #[structopt(flatten)]
+        bench: BenchArgs,
+    },
+    /// Create a new Toast project
+    #[structopt(name = "new")]
+    New {
+        /// Directory to create the project in
         #[structopt(parse(from_os_str))]
-        output_dir: Option<PathBuf>,
+        dir: PathBuf,
+    },
+    /// Print a completion script for your shell, like `toast completions bash > /etc/bash_completion.d/toast`
+    #[structopt(name = "completions")]
+    Completions {
+        /// `bash`, `zsh`, `fish`, `powershell`, or `elvish`
+        #[structopt(possible_values = &Shell::variants(), case_insensitive = true)]
+        shell: Shell,
     },
 }
@@ -0,0 +1,640 @@
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+use tracing::instrument;
+
+use crate::{
+    base_path::normalize_base_path,
+    cli_args::BuildArgs,
+    css::CssConfig,
+    deploy::DeployConfig,
+    diagnostics::FailurePolicy,
+    excerpt::ExcerptConfig,
+    feeds::FeedConfig,
+    fonts::FontConfig,
+    headers::HeadersConfig,
+    i18n::I18nConfig,
+    images::ImageConfig,
+    links::LinkCheckConfig,
+    markdown::MarkdownConfig,
+    mdx::HighlightConfig,
+    node::{node_bin, DEFAULT_MIN_NODE_VERSION},
+    pages::{PageRoot, Shard, DEFAULT_PAGES_DIR},
+    pagination::PaginateConfig,
+    preload::PreloadConfig,
+    related::RelatedConfig,
+    renderer::RendererKind,
+    robots::RobotsConfig,
+    s3::{bucket_dir, Bucket, S3StoreConfig},
+    search::SearchConfig,
+    social_cards::SocialCardConfig,
+    supervisor::{batch_timeout, run_node},
+    taxonomy::TaxonomyConfig,
+    toc::TocConfig,
+    url_style::UrlStyle,
+};
+
+pub const TOML_CONFIG_FILE: &str = "toast.config.toml";
+pub const JS_CONFIG_FILE: &str = "toast.config.js";
+
+/// Project-level configuration read from `toast.config.toml` or the
+/// default export of `toast.config.js`. Relative paths are relative to
+/// the directory the config file is in.
+#[derive(Debug, Default, Clone, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ToastConfig {
+    pub input_dir: Option<PathBuf>,
+    pub output_dir: Option<PathBuf>,
+    pub import_map: Option<PathBuf>,
+    /// where page modules are, relative to the input directory rather
+    /// than the config file. Defaults to `src/pages`.
+    pub pages_dir: Option<String>,
+    /// more directories of pages, each served under a url prefix
+    pub content: Vec<PageRoot>,
+    /// a directory of pages for each locale, see `i18n`
+    pub i18n: I18nConfig,
+    /// modules with build hooks, relative to the input directory, see
+    /// `plugins`
+    pub plugins: Vec<String>,
+    /// find sources through symlinks instead of skipping them, see `pages`
+    pub follow_symlinks: bool,
+    /// the oldest node to build with, like `16`. Defaults to 14.
+    pub min_node_version: Option<String>,
+    pub base_url: Option<String>,
+    /// url path the site is served under, see `base_path`
+    pub base_path: Option<String>,
+    pub feeds: Vec<FeedConfig>,
+    /// webfonts to copy or subset, see `fonts`
+    pub fonts: Vec<FontConfig>,
+    pub images: ImageConfig,
+    pub paginate: Vec<PaginateConfig>,
+    pub taxonomies: Vec<TaxonomyConfig>,
+    pub search: Vec<SearchConfig>,
+    pub related: Vec<RelatedConfig>,
+    /// old url to new url
+    pub redirects: BTreeMap<String, String>,
+    pub headers: HeadersConfig,
+    pub robots: RobotsConfig,
+    /// frontmatter keys every content page should have, see `diagnostics`
+    pub required_frontmatter: Vec<String>,
+    /// content-hash JS and CSS file names for long-lived caching
+    pub fingerprint: bool,
+    /// `integrity` attributes on stylesheets and scripts, see `integrity`
+    pub integrity: bool,
+    pub css: CssConfig,
+    pub highlight: HighlightConfig,
+    pub markdown: MarkdownConfig,
+    pub toc: TocConfig,
+    pub excerpt: ExcerptConfig,
+    pub preload: PreloadConfig,
+    /// collapse whitespace and remove comments in rendered html
+    pub minify_html: bool,
+    /// write `.br` and `.gz` copies of html, JS, and CSS outputs
+    pub precompress: bool,
+    /// write a source map for every browser module, see `source_maps`
+    pub source_maps: bool,
+    /// minify browser modules. Unset means the CLI decides: on, unless
+    /// `--debug` is passed.
+    pub minify_js: Option<bool>,
+    pub link_check: LinkCheckConfig,
+    /// og:image cards for pages with a title
+    pub social_cards: SocialCardConfig,
+    /// `bare`, `directory`, or `file`, see `url_style`
+    pub url_style: UrlStyle,
+    pub renderer: RendererKind,
+    /// where `toast deploy` uploads to, see `deploy`
+    pub deploy: Option<DeployConfig>,
+    /// the store an `s3://` output directory is in, see `s3`
+    pub s3: S3StoreConfig,
+}
+
+/// The settings a build actually runs with, after CLI flags have been
+/// merged over the config file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BuildSettings {
+    pub input_dir: PathBuf,
+    pub output_dir: PathBuf,
+    pub import_map: PathBuf,
+    /// the pages directory, served from the root of the site, followed by
+    /// the `content` roots and the locale roots
+    pub page_roots: Vec<PageRoot>,
+    pub i18n: I18nConfig,
+    /// build pages with `draft: true` or a future `date`
+    pub drafts: bool,
+    /// from `--strict` and `--keep-going`, see `diagnostics`
+    pub failure_policy: FailurePolicy,
+    pub reproducible: bool,
+    /// find sources through symlinks, see `pages`
+    pub follow_symlinks: bool,
+    /// the oldest node to build with, see `node::check_node_version`
+    pub min_node_version: String,
+    /// never use the network, see `remote_data`
+    pub offline: bool,
+    /// warn instead of failing when node is too old or missing
+    pub skip_node_check: bool,
+    /// delete outputs whose sources are gone
+    pub prune: bool,
+    /// only render pages that match, see `pages::matches_filter`
+    pub filter: Option<glob::Pattern>,
+    /// only render this part of the pages, see `pages::Shard`
+    pub shard: Option<Shard>,
+    pub plugins: Vec<String>,
+    pub base_url: Option<String>,
+    /// normalized to a leading slash and no trailing slash, like
+    /// `/my-project`. `None` when the site is at the root.
+    pub base_path: Option<String>,
+    pub feeds: Vec<FeedConfig>,
+    /// webfonts to copy or subset, see `fonts`
+    pub fonts: Vec<FontConfig>,
+    pub images: ImageConfig,
+    pub paginate: Vec<PaginateConfig>,
+    pub taxonomies: Vec<TaxonomyConfig>,
+    pub search: Vec<SearchConfig>,
+    pub related: Vec<RelatedConfig>,
+    /// old url to new url
+    pub redirects: BTreeMap<String, String>,
+    pub headers: HeadersConfig,
+    pub robots: RobotsConfig,
+    /// frontmatter keys every content page should have, see `diagnostics`
+    pub required_frontmatter: Vec<String>,
+    /// content-hash JS and CSS file names for long-lived caching
+    pub fingerprint: bool,
+    /// `integrity` attributes on stylesheets and scripts, see `integrity`
+    pub integrity: bool,
+    pub css: CssConfig,
+    pub highlight: HighlightConfig,
+    pub markdown: MarkdownConfig,
+    pub toc: TocConfig,
+    pub excerpt: ExcerptConfig,
+    pub preload: PreloadConfig,
+    /// collapse whitespace and remove comments in rendered html
+    pub minify_html: bool,
+    /// write `.br` and `.gz` copies of html, JS, and CSS outputs
+    pub precompress: bool,
+    /// write a source map for every browser module, see `source_maps`
+    pub source_maps: bool,
+    /// minify browser modules
+    pub minify_js: bool,
+    pub link_check: LinkCheckConfig,
+    /// og:image cards for pages with a title
+    pub social_cards: SocialCardConfig,
+    /// `bare`, `directory`, or `file`, see `url_style`
+    pub url_style: UrlStyle,
+    pub renderer: RendererKind,
+    /// where `toast deploy` uploads to, see `deploy`
+    pub deploy: Option<DeployConfig>,
+    /// from an `s3://` output directory, which `output_dir` is a copy of,
+    /// see `s3`
+    pub output_bucket: Option<Bucket>,
+    /// keep the output manifest for `toast diff`, off for `toast serve`'s
+    /// development builds so they're never compared with real ones
+    pub record_output: bool,
+}
+
+impl ToastConfig {
+    /// Merge CLI flags over this config. Flags always win, then config
+    /// values, then the defaults (`./public` for output and the
+    /// `web_modules` import map inside of it).
+    pub fn merge(self, config_dir: &Path, args: &BuildArgs) -> BuildSettings {
+        let input_dir = match (&args.input_dir, &self.input_dir) {
+            (Some(dir), _) => dir.clone(),
+            (None, Some(dir)) => config_dir.join(dir),
+            (None, None) => config_dir.to_path_buf(),
+        };
+        // a url would be joined into nonsense like `/site/s3:/bucket`
+        let output_bucket = args
+            .output_dir
+            .as_ref()
+            .or(self.output_dir.as_ref())
+            .and_then(|dir| dir.to_str())
+            .and_then(|url| Bucket::from_url(url, &self.s3));
+        let output_dir = match (&output_bucket, &args.output_dir, &self.output_dir) {
+            (Some(bucket), _, _) => bucket_dir(&input_dir, bucket),
+            (None, Some(dir), _) => dir.clone(),
+            (None, None, Some(dir)) => config_dir.join(dir),
+            (None, None, None) => input_dir.join("public"),
+        };
+        let import_map = match &self.import_map {
+            Some(path) => config_dir.join(path),
+            None => output_dir.join("web_modules").join("import-map.json"),
+        };
+        let pages_dir = args
+            .pages_dir
+            .as_deref()
+            .or(self.pages_dir.as_deref())
+            .unwrap_or(DEFAULT_PAGES_DIR);
+        let page_roots = std::iter::once(PageRoot::new(pages_dir, ""))
+            .chain(
+                self.content
+                    .iter()
+                    .map(|root| PageRoot::new(&root.dir, &root.prefix)),
+            )
+            .chain(self.i18n.page_roots())
+            .collect();
+        BuildSettings {
+            input_dir,
+            output_dir,
+            import_map,
+            page_roots,
+            i18n: self.i18n,
+            drafts: args.drafts,
+            failure_policy: if args.strict {
+                FailurePolicy::Strict
+            } else if args.keep_going || args.watch {
+                FailurePolicy::KeepGoing
+            } else {
+                FailurePolicy::Default
+            },
+            reproducible: args.reproducible,
+            follow_symlinks: self.follow_symlinks || args.follow_symlinks,
+            min_node_version: args
+                .min_node_version
+                .clone()
+                .or(self.min_node_version)
+                .unwrap_or_else(|| DEFAULT_MIN_NODE_VERSION.to_string()),
+            skip_node_check: args.skip_node_check,
+            offline: args.offline,
+            prune: args.prune,
+            filter: args.filter.clone(),
+            shard: args.shard,
+            plugins: self.plugins,
+            base_url: self.base_url,
+            base_path: args
+                .base_path
+                .as_deref()
+                .or(self.base_path.as_deref())
+                .and_then(normalize_base_path),
+            feeds: self.feeds,
+            fonts: self.fonts,
+            images: self.images,
+            paginate: self.paginate,
+            taxonomies: self.taxonomies,
+            search: self.search,
+            related: self.related,
+            redirects: self.redirects,
+            headers: self.headers,
+            robots: RobotsConfig {
+                disallow_all: self.robots.disallow_all || args.disallow_all,
+                ..self.robots
+            },
+            required_frontmatter: self.required_frontmatter,
+            fingerprint: self.fingerprint,
+            integrity: self.integrity,
+            css: CssConfig {
+                inline: self.css.inline || args.inline_css,
+                ..self.css
+            },
+            highlight: self.highlight,
+            markdown: self.markdown,
+            toc: self.toc,
+            excerpt: self.excerpt,
+            preload: self.preload,
+            minify_html: self.minify_html || args.minify_html,
+            precompress: self.precompress || args.precompress,
+            source_maps: self.source_maps || args.source_maps,
+            minify_js: if args.minify {
+                true
+            } else if args.no_minify {
+                false
+            } else {
+                self.minify_js.unwrap_or(!args.debug)
+            },
+            link_check: LinkCheckConfig {
+                external: (self.link_check.external || args.check_external_links) && !args.offline,
+                ..self.link_check
+            },
+            social_cards: self.social_cards,
+            url_style: self.url_style,
+            renderer: self.renderer,
+            deploy: self.deploy,
+            // `--offline` builds only write the copy, the next build that
+            // isn't offline sends what changed
+            output_bucket: output_bucket.filter(|_| !args.offline),
+            record_output: true,
+        }
+    }
+}
+
+/// Load the config file from `dir`, if there is one. `toast.config.toml`
+/// takes precedence over `toast.config.js`.
+#[instrument]
+pub fn load(dir: &Path) -> Result<ToastConfig> {
+    let toml_path = dir.join(TOML_CONFIG_FILE);
+    let js_path = dir.join(JS_CONFIG_FILE);
+    if toml_path.exists() {
+        let contents = fs::read_to_string(&toml_path)
+            .wrap_err_with(|| format!("Failed to read `{}`", toml_path.display()))?;
+        parse_toml(&contents)
+            .wrap_err_with(|| format!("Failed to parse config from `{}`", toml_path.display()))
+    } else if js_path.exists() {
+        load_js(&js_path)
+    } else {
+        Ok(ToastConfig::default())
+    }
+}
+
+pub fn parse_toml(contents: &str) -> Result<ToastConfig> {
+    Ok(toml::from_str(contents)?)
+}
+
+/// Marks the line of node's output that is the exported config, so
+/// anything the config file logs while it's imported passes through
+const CONFIG_PREFIX: &str = "toast-config:";
+
+/// `toast.config.js` is an ES module, so we have node import it and
+/// print the default export back to us as JSON. This avoids top-level
+/// `await`, which node only has in `-e` from 14.8, below the minimum.
+#[instrument]
+fn load_js(js_path: &Path) -> Result<ToastConfig> {
+    let url = url::Url::from_file_path(js_path)
+        .map_err(|_| eyre!("Failed to make a file url from `{}`", js_path.display()))?;
+    let script = format!(
+        "import(process.argv[1]).then(config => console.log({:?} + JSON.stringify(config.default || {{}})));",
+        CONFIG_PREFIX
+    );
+    let stdout = run_node(
+        JS_CONFIG_FILE,
+        duct::cmd!(
+            node_bin(),
+            "--input-type=module",
+            "-e",
+            script,
+            url.as_str()
+        ),
+        batch_timeout(1),
+    )
+    .wrap_err_with(|| format!("Failed to evaluate `{}` with node", js_path.display()))?;
+    let config = stdout
+        .lines()
+        .find_map(|line| line.strip_prefix(CONFIG_PREFIX))
+        .ok_or_else(|| eyre!("`{}` didn't print its config", js_path.display()))?;
+    serde_json::from_str(config).wrap_err_with(|| {
+        format!(
+            "Failed to parse config exported from `{}`",
+            js_path.display()
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(input_dir: Option<&str>, output_dir: Option<&str>) -> BuildArgs {
+        BuildArgs {
+            debug: false,
+            input_dir: input_dir.map(PathBuf::from),
+            output_dir: output_dir.map(PathBuf::from),
+            pages_dir: None,
+            drafts: false,
+            watch: false,
+            dry_run: false,
+            jobs: None,
+            render_workers: None,
+            render_timeout: None,
+            telemetry: None,
+            create_import_map: false,
+            report: None,
+            profile: false,
+            report_file: None,
+            inline_css: false,
+            minify_html: false,
+            precompress: false,
+            source_maps: false,
+            minify: false,
+            no_minify: false,
+            check_links: false,
+            check_external_links: false,
+            disallow_all: false,
+            strict: false,
+            keep_going: false,
+            reproducible: false,
+            offline: false,
+            min_node_version: None,
+            skip_node_check: false,
+            follow_symlinks: false,
+            prune: true,
+            base_path: None,
+            filter: None,
+            shard: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_toml() -> Result<()> {
+        let config = parse_toml(
+            r#"
+            output_dir = "dist"
+            plugins = ["feeds"]
+            base_url = "https://toast.dev"
+            "#,
+        )?;
+        assert_eq!(
+            config,
+            ToastConfig {
+                input_dir: None,
+                output_dir: Some(PathBuf::from("dist")),
+                import_map: None,
+                pages_dir: None,
+                content: vec![],
+                i18n: I18nConfig::default(),
+                plugins: vec![String::from("feeds")],
+                follow_symlinks: false,
+                min_node_version: None,
+                base_url: Some(String::from("https://toast.dev")),
+                base_path: None,
+                feeds: vec![],
+                fonts: vec![],
+                images: ImageConfig::default(),
+                paginate: vec![],
+                taxonomies: vec![],
+                search: vec![],
+                related: vec![],
+                redirects: BTreeMap::new(),
+                headers: HeadersConfig::default(),
+                robots: RobotsConfig::default(),
+                required_frontmatter: vec![],
+                fingerprint: false,
+                integrity: false,
+                css: CssConfig::default(),
+                highlight: HighlightConfig::default(),
+                markdown: MarkdownConfig::default(),
+                toc: TocConfig::default(),
+                excerpt: ExcerptConfig::default(),
+                preload: PreloadConfig::default(),
+                minify_html: false,
+                precompress: false,
+                source_maps: false,
+                minify_js: None,
+                link_check: LinkCheckConfig::default(),
+                social_cards: SocialCardConfig::default(),
+                url_style: UrlStyle::Bare,
+                renderer: RendererKind::Node,
+                deploy: None,
+                s3: S3StoreConfig::default(),
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_toml_rejects_unknown_keys() {
+        assert!(parse_toml("outptu_dir = \"dist\"").is_err());
+    }
+
+    #[test]
+    fn test_defaults_without_config() {
+        let settings = ToastConfig::default().merge(Path::new("/site"), &args(None, None));
+        assert_eq!(settings.input_dir, PathBuf::from("/site"));
+        assert_eq!(settings.output_dir, PathBuf::from("/site/public"));
+        assert_eq!(
+            settings.import_map,
+            PathBuf::from("/site/public/web_modules/import-map.json")
+        );
+    }
+
+    #[test]
+    fn test_flags_take_precedence_over_config() {
+        let config = ToastConfig {
+            output_dir: Some(PathBuf::from("dist")),
+            ..ToastConfig::default()
+        };
+        let settings = config
+            .clone()
+            .merge(Path::new("/site"), &args(None, Some("/elsewhere")));
+        assert_eq!(settings.output_dir, PathBuf::from("/elsewhere"));
+
+        let settings = config.merge(Path::new("/site"), &args(None, None));
+        assert_eq!(settings.output_dir, PathBuf::from("/site/dist"));
+    }
+
+    #[test]
+    fn test_s3_output_dir() {
+        let config = ToastConfig {
+            output_dir: Some(PathBuf::from("s3://my-site/docs")),
+            ..ToastConfig::default()
+        };
+        let settings = config.clone().merge(Path::new("/site"), &args(None, None));
+        assert_eq!(
+            settings.output_bucket.map(|bucket| bucket.prefix),
+            Some(String::from("docs/"))
+        );
+        assert_eq!(
+            settings.output_dir,
+            PathBuf::from("/site/.toast/s3/my-site/docs")
+        );
+
+        let offline = BuildArgs {
+            offline: true,
+            ..args(None, None)
+        };
+        let settings = config.merge(Path::new("/site"), &offline);
+        assert_eq!(settings.output_bucket, None);
+        assert_eq!(
+            settings.output_dir,
+            PathBuf::from("/site/.toast/s3/my-site/docs")
+        );
+    }
+
+    #[test]
+    fn test_base_path_flag() {
+        let config = ToastConfig {
+            base_path: Some(String::from("/docs/")),
+            ..ToastConfig::default()
+        };
+        let settings = config.clone().merge(Path::new("/site"), &args(None, None));
+        assert_eq!(settings.base_path.as_deref(), Some("/docs"));
+        let flags = BuildArgs {
+            base_path: Some(String::from("/")),
+            ..args(None, None)
+        };
+        assert_eq!(config.merge(Path::new("/site"), &flags).base_path, None);
+    }
+
+    #[test]
+    fn test_minify_js_defaults_to_not_debug() {
+        let merge = |config: ToastConfig, args: BuildArgs| config.merge(Path::new("/site"), &args);
+        assert!(merge(ToastConfig::default(), args(None, None)).minify_js);
+        let debug = || BuildArgs {
+            debug: true,
+            ..args(None, None)
+        };
+        assert!(!merge(ToastConfig::default(), debug()).minify_js);
+        let config = ToastConfig {
+            minify_js: Some(true),
+            ..ToastConfig::default()
+        };
+        assert!(merge(config.clone(), debug()).minify_js);
+        let flags = BuildArgs {
+            no_minify: true,
+            ..args(None, None)
+        };
+        assert!(!merge(config, flags).minify_js);
+    }
+
+    #[test]
+    fn test_page_roots() -> Result<()> {
+        let settings = ToastConfig::default().merge(Path::new("/site"), &args(None, None));
+        assert_eq!(settings.page_roots, vec![PageRoot::new("src/pages", "")]);
+        let config = parse_toml(
+            r#"
+            pages_dir = "./app/pages/"
+
+            [[content]]
+            dir = "docs"
+            prefix = "/docs"
+            "#,
+        )?;
+        let settings = config.merge(Path::new("/site"), &args(None, None));
+        assert_eq!(
+            settings.page_roots,
+            vec![
+                PageRoot::new("app/pages", ""),
+                PageRoot::new("docs", "docs")
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_offline_skips_external_links() -> Result<()> {
+        let config = parse_toml("[link_check]\nexternal = true")?;
+        let offline = BuildArgs {
+            offline: true,
+            ..args(None, None)
+        };
+        assert!(
+            config
+                .clone()
+                .merge(Path::new("/site"), &args(None, None))
+                .link_check
+                .external
+        );
+        assert!(
+            !config
+                .merge(Path::new("/site"), &offline)
+                .link_check
+                .external
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_js_ignores_logs() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("toast-config-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let js_path = dir.join(JS_CONFIG_FILE);
+        fs::write(
+            &js_path,
+            "console.log('loading');\nconsole.warn('deprecated');\nexport default { minify_js: true };\n",
+        )?;
+        let config = load_js(&js_path);
+        fs::remove_dir_all(&dir)?;
+        assert_eq!(config?.minify_js, Some(true));
+        Ok(())
+    }
+}
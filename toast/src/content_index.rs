@@ -0,0 +1,157 @@
+//! A JSON index of every page on the site, so list pages ("all posts")
+//! don't have to crawl the filesystem themselves.
+//!
+//! Every `.mdx` and `.md` page, with its frontmatter and reading time, and
+//! every page with `data` from `setDataForSlug`, is written to
+//! `toast-data/pages.json`. Pages are also grouped into collections by
+//! the first segment of their slug, so `/blog/hello` is in
+//! `toast-data/collections/blog.json`. Entries are newest first when they
+//! have a `date`.
+//!
+//! A page module can ask for collections to be passed to it as props:
+//!
+//! ```js
+//! // `"*"` is every page on the site
+//! export const collections = ["blog"];
+//!
+//! export default ({ collections }) => (
+//!   <ul>
+//!     {collections.blog.map((post) => <li>{post.data.title}</li>)}
+//!   </ul>
+//! );
+//! ```
+use color_eyre::eyre::{Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{collections::BTreeMap, fs, path::Path};
+
+use crate::feeds::parse_date;
+
+/// Directory in the output directory that site data is written to
+pub const DATA_DIR: &str = "toast-data";
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PageEntry {
+    pub slug: String,
+    pub data: Value,
+}
+
+impl PageEntry {
+    /// The collection a page is in, `blog` for `/blog/hello`. Pages at the
+    /// top level of the site aren't in a collection.
+    pub fn collection(&self) -> Option<&str> {
+        let mut segments = self.slug.trim_matches('/').split('/');
+        let first = segments.next()?;
+        segments.next().map(|_| first)
+    }
+
+    fn date(&self) -> Option<chrono::DateTime<chrono::FixedOffset>> {
+        self.data
+            .get("date")
+            .and_then(|date| date.as_str())
+            .and_then(parse_date)
+    }
+}
+
+/// Newest first, then by slug so the order is stable
+pub fn sort_entries(entries: &mut [PageEntry]) {
+    entries.sort_by(|a, b| b.date().cmp(&a.date()).then_with(|| a.slug.cmp(&b.slug)));
+}
+
+pub fn collections(entries: &[PageEntry]) -> BTreeMap<String, Vec<PageEntry>> {
+    let mut collections: BTreeMap<String, Vec<PageEntry>> = BTreeMap::new();
+    for entry in entries {
+        if let Some(name) = entry.collection() {
+            collections
+                .entry(name.to_string())
+                .or_default()
+                .push(entry.clone());
+        }
+    }
+    collections
+}
+
+/// Write `pages.json` and one file per collection, replacing any
+/// collections that no longer have pages
+pub fn write_index(mut entries: Vec<PageEntry>, output_dir: &Path) -> Result<()> {
+    sort_entries(&mut entries);
+    let data_dir = output_dir.join(DATA_DIR);
+    let collections_dir = data_dir.join("collections");
+    if collections_dir.exists() {
+        fs::remove_dir_all(&collections_dir).wrap_err_with(|| {
+            format!(
+                "Failed to remove old collections from `{}`",
+                collections_dir.display()
+            )
+        })?;
+    }
+    fs::create_dir_all(&collections_dir)
+        .wrap_err_with(|| format!("Failed to create directory `{}`", collections_dir.display()))?;
+    for (name, pages) in collections(&entries) {
+        let file = collections_dir.join(format!("{}.json", name));
+        fs::write(&file, serde_json::to_string(&pages)?)
+            .wrap_err_with(|| format!("Failed to write collection `{}`", file.display()))?;
+    }
+    let file = data_dir.join("pages.json");
+    fs::write(&file, serde_json::to_string(&entries)?)
+        .wrap_err_with(|| format!("Failed to write page index `{}`", file.display()))
+}
+
+/// Read the index written by a previous build
+pub fn read_index(output_dir: &Path) -> Vec<PageEntry> {
+    fs::read_to_string(output_dir.join(DATA_DIR).join("pages.json"))
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+/// Replace the entry for one page, or remove it if it has no data anymore,
+/// for rebuilds in watch mode
+pub fn update_entry(output_dir: &Path, slug: &str, data: Option<Value>) -> Result<()> {
+    let mut entries: Vec<PageEntry> = read_index(output_dir)
+        .into_iter()
+        .filter(|entry| entry.slug != slug)
+        .collect();
+    if let Some(data) = data {
+        entries.push(PageEntry {
+            slug: slug.to_string(),
+            data,
+        });
+    }
+    write_index(entries, output_dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn entry(slug: &str, date: &str) -> PageEntry {
+        PageEntry {
+            slug: slug.to_string(),
+            data: json!({ "date": date }),
+        }
+    }
+
+    #[test]
+    fn test_collections_group_by_first_segment() {
+        let entries = vec![
+            entry("/blog/a", "2020-01-01"),
+            entry("/about", "2020-01-01"),
+            entry("/blog/nested/b", "2020-01-01"),
+        ];
+        let collections = collections(&entries);
+        assert_eq!(collections.keys().collect::<Vec<_>>(), vec!["blog"]);
+        assert_eq!(collections["blog"].len(), 2);
+    }
+
+    #[test]
+    fn test_sort_newest_first() {
+        let mut entries = vec![
+            entry("/blog/old", "2019-01-01"),
+            entry("/blog/new", "2020-09-01"),
+        ];
+        sort_entries(&mut entries);
+        assert_eq!(entries[0].slug, "/blog/new");
+    }
+}
@@ -0,0 +1,481 @@
+//! Stylesheets imported from page and component modules.
+//!
+//! ```js
+//! import "./post.css";
+//! ```
+//!
+//! CSS imports are removed from modules before they're compiled, because
+//! neither node nor the browser can import a stylesheet as a module.
+//! Instead, every stylesheet a page imports, directly or through the
+//! components it imports, is bundled in import order into
+//! `_css/<page>.css` and linked from the page's `<head>`. Configured with
+//! `[css]` in `toast.config.toml`:
+//!
+//! ```toml
+//! [css]
+//! # one stylesheet for the whole site instead of one per page
+//! bundle = "global"
+//! minify = true
+//! ```
+//!
+//! With `inline = true` (or `--inline-css`), each page's bundle is put in
+//! a `<style>` tag instead, and any other stylesheets the page links to
+//! are loaded without blocking the first render.
+//!
+//! `url()`s are left as they are, so they should be root-relative urls to
+//! files in `static/`.
+use color_eyre::eyre::{Result, WrapErr};
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Component, Path, PathBuf},
+};
+use tracing::instrument;
+
+use crate::{
+    fingerprint::js_specifiers,
+    pages::{page_path, PageRoot},
+    renderer::html_output_path,
+};
+
+/// Directory in the output directory that bundles are written to
+pub const CSS_DIR: &str = "_css";
+
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CssConfig {
+    pub bundle: CssBundle,
+    pub minify: bool,
+    /// inline the page's bundle instead of linking to it, and defer any
+    /// other stylesheets so they don't block rendering
+    pub inline: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CssBundle {
+    #[default]
+    Page,
+    Global,
+}
+
+/// The specifier in a side-effect import of a stylesheet, like
+/// `import "./post.css";`
+fn css_import(line: &str) -> Option<&str> {
+    let rest = line.trim().strip_prefix("import")?.trim_start();
+    let quote = rest.chars().next().filter(|c| matches!(c, '"' | '\''))?;
+    let end = rest[1..].find(quote)?;
+    let specifier = &rest[1..end + 1];
+    let path = specifier.split(&['?', '#'][..]).next()?;
+    if path.ends_with(".css") {
+        Some(specifier)
+    } else {
+        None
+    }
+}
+
+/// Remove stylesheet imports from a module's source, returning the source
+/// and what was imported. Lines are blanked rather than removed so line
+/// numbers in errors still match the file.
+pub fn split_css_imports(source: &str) -> (String, Vec<String>) {
+    let mut imports = vec![];
+    let stripped: Vec<&str> = source
+        .split('\n')
+        .map(|line| match css_import(line) {
+            Some(specifier) => {
+                imports.push(specifier.to_string());
+                ""
+            }
+            None => line,
+        })
+        .collect();
+    (stripped.join("\n"), imports)
+}
+
+/// A file that `specifier` points at from a module in `from_dir`. Root
+/// relative specifiers are relative to the project root.
+fn resolve(project_root_dir: &Path, from_dir: &Path, specifier: &str) -> Option<PathBuf> {
+    let path = specifier.split(&['?', '#'][..]).next()?;
+    let joined = if let Some(absolute) = path.strip_prefix('/') {
+        project_root_dir.join(absolute)
+    } else if path.starts_with("./") || path.starts_with("../") {
+        from_dir.join(path)
+    } else {
+        return None;
+    };
+    let mut normalized = PathBuf::new();
+    for component in joined.components() {
+        match component {
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::CurDir => {}
+            other => normalized.push(other),
+        }
+    }
+    Some(normalized)
+}
+
+/// Every stylesheet `module` imports, directly or through the modules it
+/// imports, in the order they're imported. Each one is only included the
+/// first time it's seen.
+pub fn collect_stylesheets(project_root_dir: &Path, module: &Path) -> Vec<PathBuf> {
+    let mut stylesheets = vec![];
+    let mut seen = HashSet::new();
+    visit(project_root_dir, module, &mut seen, &mut stylesheets);
+    stylesheets
+}
+
+fn visit(
+    project_root_dir: &Path,
+    module: &Path,
+    seen: &mut HashSet<PathBuf>,
+    stylesheets: &mut Vec<PathBuf>,
+) {
+    if !seen.insert(module.to_path_buf()) {
+        return;
+    }
+    let source = match fs::read_to_string(module) {
+        Ok(source) => source,
+        // imports of files that don't exist are the compiler's problem
+        Err(_) => return,
+    };
+    let from_dir = module.parent().unwrap_or(project_root_dir);
+    let (source, css_imports) = split_css_imports(&source);
+    for specifier in css_imports {
+        if let Some(stylesheet) = resolve(project_root_dir, from_dir, &specifier) {
+            if seen.insert(stylesheet.clone()) {
+                stylesheets.push(stylesheet);
+            }
+        }
+    }
+    for specifier in js_specifiers(&source) {
+        if let Some(dependency) = resolve(project_root_dir, from_dir, &specifier.url) {
+            if dependency.starts_with(project_root_dir) {
+                visit(project_root_dir, &dependency, seen, stylesheets);
+            }
+        }
+    }
+}
+
+/// Remove comments and whitespace that doesn't change what the stylesheet
+/// means. Strings are copied as they are.
+pub fn minify_css(css: &str) -> String {
+    let mut output = String::with_capacity(css.len());
+    let mut chars = css.chars().peekable();
+    let mut pending_space = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut last = '\0';
+                for c in chars.by_ref() {
+                    if last == '*' && c == '/' {
+                        break;
+                    }
+                    last = c;
+                }
+            }
+            '"' | '\'' => {
+                push_pending_space(&mut output, pending_space);
+                pending_space = false;
+                output.push(c);
+                let mut escaped = false;
+                for inner in chars.by_ref() {
+                    output.push(inner);
+                    if !escaped && inner == c {
+                        break;
+                    }
+                    escaped = !escaped && inner == '\\';
+                }
+            }
+            c if c.is_whitespace() => pending_space = !output.is_empty(),
+            '{' | '}' | ';' | ',' | '>' => {
+                if c == '}' && output.ends_with(';') {
+                    output.pop();
+                }
+                output.push(c);
+                pending_space = false;
+            }
+            c => {
+                // the space in `a :hover` matters, so the space before a
+                // colon is kept even though the one after it isn't
+                push_pending_space(&mut output, pending_space);
+                pending_space = false;
+                output.push(c);
+            }
+        }
+    }
+    output
+}
+
+fn push_pending_space(output: &mut String, pending_space: bool) {
+    if pending_space && !output.ends_with(&['{', '}', ':', ';', ',', '>'][..]) {
+        output.push(' ');
+    }
+}
+
+/// Concatenate stylesheets in order, with a comment saying where each one
+/// came from unless the bundle is minified
+pub fn bundle(project_root_dir: &Path, stylesheets: &[PathBuf], minify: bool) -> Result<String> {
+    let mut css = String::new();
+    for stylesheet in stylesheets {
+        let contents = fs::read_to_string(stylesheet)
+            .wrap_err_with(|| format!("Failed to read stylesheet `{}`", stylesheet.display()))?;
+        if minify {
+            css.push_str(&minify_css(&contents));
+        } else {
+            let name = stylesheet
+                .strip_prefix(project_root_dir)
+                .unwrap_or(stylesheet);
+            css.push_str(&format!(
+                "/* {} */\n{}\n",
+                name.display(),
+                contents.trim_end()
+            ));
+        }
+    }
+    Ok(css)
+}
+
+/// The page module's source file, `src/pages/post.mdx` for the compiled
+/// `src/pages/post.js`
+fn page_source(project_root_dir: &Path, page: &str) -> Option<PathBuf> {
+    let js = project_root_dir.join(page);
+    let stem = page.strip_suffix(".js")?;
+    [
+        js,
+        project_root_dir.join(format!("{}.mdx", stem)),
+        project_root_dir.join(format!("{}.md", stem)),
+    ]
+    .iter()
+    .find(|path| path.is_file())
+    .cloned()
+}
+
+/// Where a page's bundle is served from
+pub fn stylesheet_href(config: &CssConfig, page_roots: &[PageRoot], page: &str) -> String {
+    match config.bundle {
+        CssBundle::Global => format!("/{}/global.css", CSS_DIR),
+        CssBundle::Page => {
+            let path = page_path(page_roots, page);
+            let stem = path.strip_suffix(".js").unwrap_or(&path);
+            format!("/{}/{}.css", CSS_DIR, stem)
+        }
+    }
+}
+
+/// Bundle the stylesheets for every page in `pages` that imports any,
+/// returning the href of each page's bundle
+#[instrument(skip(pages))]
+pub fn write_stylesheets(
+    project_root_dir: &Path,
+    output_dir: &Path,
+    page_roots: &[PageRoot],
+    pages: &[String],
+    config: &CssConfig,
+) -> Result<HashMap<String, String>> {
+    let mut bundles: Vec<(String, Vec<PathBuf>)> = vec![];
+    let mut hrefs = HashMap::new();
+    for page in pages {
+        let stylesheets = match page_source(project_root_dir, page) {
+            Some(source) => collect_stylesheets(project_root_dir, &source),
+            None => continue,
+        };
+        if stylesheets.is_empty() {
+            continue;
+        }
+        let href = stylesheet_href(config, page_roots, page);
+        hrefs.insert(page.clone(), href.clone());
+        match bundles.iter_mut().find(|(existing, _)| existing == &href) {
+            Some((_, existing)) => {
+                for stylesheet in stylesheets {
+                    if !existing.contains(&stylesheet) {
+                        existing.push(stylesheet);
+                    }
+                }
+            }
+            None => bundles.push((href, stylesheets)),
+        }
+    }
+    for (href, stylesheets) in bundles {
+        let file = output_dir.join(href.trim_start_matches('/'));
+        if let Some(dir) = file.parent() {
+            fs::create_dir_all(dir)
+                .wrap_err_with(|| format!("Failed to create directory `{}`", dir.display()))?;
+        }
+        let css = bundle(project_root_dir, &stylesheets, config.minify)?;
+        fs::write(&file, css)
+            .wrap_err_with(|| format!("Failed to write stylesheet `{}`", file.display()))?;
+    }
+    Ok(hrefs)
+}
+
+/// Add a `<link>` to the stylesheet in the page's `<head>`, unless it's
+/// already there
+pub fn link_stylesheet(html: &str, href: &str) -> String {
+    let link = format!(r#"<link rel="stylesheet" href="{}">"#, href);
+    // `integrity` can have been added after the href
+    if html.contains(&link[..link.len() - 1]) {
+        return html.to_string();
+    }
+    match html.find("</head>") {
+        Some(index) => format!("{}{}\n{}", &html[..index], link, &html[index..]),
+        None => format!("{}{}", link, html),
+    }
+}
+
+/// Put a page's bundle in a `<style>` tag in its `<head>`, replacing the
+/// one from an earlier build
+pub fn inline_stylesheet(html: &str, css: &str) -> String {
+    let html = match (html.find(INLINE_STYLE_START), html.find(INLINE_STYLE_END)) {
+        (Some(start), Some(end)) if start < end => format!(
+            "{}{}",
+            &html[..start],
+            html[end + INLINE_STYLE_END.len()..].trim_start_matches('\n')
+        ),
+        _ => html.to_string(),
+    };
+    // the bundle can't close the tag it's in
+    let style = format!(
+        "{}{}{}\n",
+        INLINE_STYLE_START,
+        css.replace("</style", "<\\/style"),
+        INLINE_STYLE_END
+    );
+    match html.find("</head>") {
+        Some(index) => format!("{}{}{}", &html[..index], style, &html[index..]),
+        None => format!("{}{}", style, html),
+    }
+}
+
+const INLINE_STYLE_START: &str = "<style data-toast-css>";
+const INLINE_STYLE_END: &str = "</style>";
+
+/// Load stylesheet `<link>`s without blocking rendering by preloading
+/// them and switching them to stylesheets once they've loaded, with a
+/// `<noscript>` fallback
+pub fn defer_stylesheets(html: &str) -> String {
+    let mut output = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find("<link") {
+        let end = match rest[start..].find('>') {
+            Some(end) => start + end + 1,
+            None => break,
+        };
+        let tag = &rest[start..end];
+        output.push_str(&rest[..start]);
+        let in_noscript = output.ends_with("<noscript>");
+        // with the leading space so the `this.rel` in a deferred link's
+        // onload doesn't count
+        if !in_noscript
+            && (tag.contains(r#" rel="stylesheet""#) || tag.contains(" rel='stylesheet'"))
+        {
+            let preload = tag
+                .replacen(r#" rel="stylesheet""#, r#" rel="preload" as="style""#, 1)
+                .replacen(" rel='stylesheet'", r#" rel="preload" as="style""#, 1);
+            let preload = format!(
+                "{} onload=\"this.onload=null;this.rel='stylesheet'\">",
+                preload
+                    .trim_end_matches('>')
+                    .trim_end_matches('/')
+                    .trim_end()
+            );
+            output.push_str(&format!("{}<noscript>{}</noscript>", preload, tag));
+        } else {
+            output.push_str(tag);
+        }
+        rest = &rest[end..];
+    }
+    output.push_str(rest);
+    output
+}
+
+/// Link each rendered page in `hrefs` to its bundle, or inline the bundle
+/// if `config.inline` is set
+pub fn link_stylesheets(
+    output_dir: &Path,
+    page_roots: &[PageRoot],
+    hrefs: &HashMap<String, String>,
+    config: &CssConfig,
+) -> Result<()> {
+    for (page, href) in hrefs {
+        let html_path = html_output_path(output_dir, page_roots, page);
+        let html = match fs::read_to_string(&html_path) {
+            Ok(html) => html,
+            // pages that failed to render don't have html to link from
+            Err(_) => continue,
+        };
+        let linked = if config.inline {
+            let bundle_path = output_dir.join(href.trim_start_matches('/'));
+            let css = fs::read_to_string(&bundle_path)
+                .wrap_err_with(|| format!("Failed to read `{}`", bundle_path.display()))?;
+            defer_stylesheets(&inline_stylesheet(&html, &css))
+        } else {
+            link_stylesheet(&html, href)
+        };
+        if linked != html {
+            fs::write(&html_path, linked)
+                .wrap_err_with(|| format!("Failed to write `{}`", html_path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pages::DEFAULT_PAGES_DIR;
+
+    #[test]
+    fn test_split_css_imports() {
+        let source =
+            "import { h } from \"preact\";\nimport \"./post.css\";\nimport './theme.css?v=2';\n";
+        let (stripped, imports) = split_css_imports(source);
+        assert_eq!(stripped, "import { h } from \"preact\";\n\n\n");
+        assert_eq!(imports, vec!["./post.css", "./theme.css?v=2"]);
+    }
+
+    #[test]
+    fn test_minify_css() {
+        let css = "/* nav */\n.nav > a {\n  color: red;\n  content: \"a  b\";\n}\n\n.a, .b :hover { margin: 0 auto; }\n";
+        assert_eq!(
+            minify_css(css),
+            ".nav>a{color:red;content:\"a  b\"}.a,.b :hover{margin:0 auto}"
+        );
+    }
+
+    #[test]
+    fn test_stylesheet_href_and_link() {
+        let config = CssConfig::default();
+        assert_eq!(
+            stylesheet_href(
+                &config,
+                &[PageRoot::new(DEFAULT_PAGES_DIR, "")],
+                "src/pages/blog/post.js"
+            ),
+            "/_css/blog/post.css"
+        );
+        let html = link_stylesheet("<html><head></head></html>", "/_css/index.css");
+        assert_eq!(
+            html,
+            "<html><head><link rel=\"stylesheet\" href=\"/_css/index.css\">\n</head></html>"
+        );
+        assert_eq!(link_stylesheet(&html, "/_css/index.css"), html);
+    }
+
+    #[test]
+    fn test_inline_and_defer() {
+        let html = r#"<html><head><link rel="stylesheet" href="/fonts.css"></head></html>"#;
+        let inlined = inline_stylesheet(html, "a{color:red}");
+        // inlining again replaces the old style tag
+        assert_eq!(inline_stylesheet(&inlined, "a{color:red}"), inlined);
+        assert_eq!(
+            defer_stylesheets(&inlined),
+            "<html><head><link rel=\"preload\" as=\"style\" href=\"/fonts.css\" onload=\"this.onload=null;this.rel='stylesheet'\"><noscript><link rel=\"stylesheet\" href=\"/fonts.css\"></noscript><style data-toast-css>a{color:red}</style>\n</head></html>"
+        );
+        let deferred = defer_stylesheets(&inlined);
+        assert_eq!(defer_stylesheets(&deferred), deferred);
+    }
+}
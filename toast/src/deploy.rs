@@ -0,0 +1,369 @@
+//! `toast deploy`, which uploads the output directory to where the site
+//! is hosted, sending only the files that changed since the last deploy
+//! to the same place, so a build that only touched one page only uploads
+//! that page. It's set up in `toast.config.toml`, with one of:
+//!
+//! ```toml
+//! [deploy]
+//! target = "s3"
+//! bucket = "my-site"
+//! region = "us-east-1"
+//! # optional, invalidated for whatever changed
+//! cloudfront_distribution = "E2EXAMPLE"
+//! # optional, for stores like R2 and MinIO, see `s3`
+//! endpoint = "https://<account>.r2.cloudflarestorage.com"
+//!
+//! [deploy]
+//! target = "netlify"
+//! site_id = "my-site.netlify.app"
+//!
+//! [deploy]
+//! target = "vercel"
+//! project = "my-site"
+//!
+//! [deploy]
+//! target = "rsync"
+//! destination = "deploy@example.com:/var/www/my-site"
+//! ```
+//!
+//! Credentials come from the environment, like they do for the tools
+//! that usually deploy to each of these: `AWS_ACCESS_KEY_ID`,
+//! `AWS_SECRET_ACCESS_KEY`, and `AWS_SESSION_TOKEN` for S3,
+//! `NETLIFY_AUTH_TOKEN` for Netlify, `VERCEL_TOKEN` for Vercel, and ssh's
+//! own keys and config for rsync.
+//!
+//! What changed is worked out the same way as `toast diff`: the output
+//! directory is hashed into an `OutputManifest`, and compared with the one
+//! saved in `.toast/deploys` after the last deploy that succeeded. Netlify
+//! and Vercel keep every file they've been sent by hash, so they're told
+//! about the whole site and only sent what they ask for, which is never
+//! more than what changed. `--all` sends everything, for when the target
+//! was changed by something other than toast.
+use async_std::future::timeout;
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tracing::instrument;
+
+mod netlify;
+mod rsync;
+mod s3;
+mod vercel;
+
+use crate::{
+    cache::CACHE_DIR,
+    config::BuildSettings,
+    diff::{output_manifest, BuildDiff, ChangeKind, FileChange, OutputManifest},
+    exit_code::Failure,
+};
+pub use netlify::NetlifyConfig;
+pub use rsync::RsyncConfig;
+pub use s3::S3Config;
+pub use vercel::VercelConfig;
+
+/// Where the manifest of each target's last deploy is kept, in
+/// `CACHE_DIR`
+pub const DEPLOYS_DIR: &str = "deploys";
+
+/// How long one upload can take
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Where a site is deployed to, see the module docs
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(tag = "target", rename_all = "lowercase")]
+pub enum DeployConfig {
+    S3(S3Config),
+    Netlify(NetlifyConfig),
+    Vercel(VercelConfig),
+    Rsync(RsyncConfig),
+}
+
+impl DeployConfig {
+    fn target(&self) -> &dyn DeployTarget {
+        match self {
+            DeployConfig::S3(config) => config,
+            DeployConfig::Netlify(config) => config,
+            DeployConfig::Vercel(config) => config,
+            DeployConfig::Rsync(config) => config,
+        }
+    }
+
+    /// Where the manifest of the last deploy to this target is saved, one
+    /// per bucket, site, project, or destination, so deploying to staging
+    /// doesn't make production look up to date
+    fn state_path(&self, project_root_dir: &Path) -> PathBuf {
+        let name = match self {
+            DeployConfig::S3(config) => format!("s3-{}-{}", config.bucket, config.prefix),
+            DeployConfig::Netlify(config) => format!("netlify-{}", config.site_id),
+            DeployConfig::Vercel(config) => format!("vercel-{}", config.project),
+            DeployConfig::Rsync(config) => format!("rsync-{}", config.destination),
+        };
+        let name: String = name
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+                _ => '_',
+            })
+            .collect();
+        project_root_dir
+            .join(CACHE_DIR)
+            .join(DEPLOYS_DIR)
+            .join(format!("{}.json", name))
+    }
+}
+
+impl fmt::Display for DeployConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeployConfig::S3(config) => write!(f, "s3 bucket `{}`", config.bucket),
+            DeployConfig::Netlify(config) => write!(f, "netlify site `{}`", config.site_id),
+            DeployConfig::Vercel(config) => write!(f, "vercel project `{}`", config.project),
+            DeployConfig::Rsync(config) => write!(f, "`{}`", config.destination),
+        }
+    }
+}
+
+/// What's being deployed
+#[derive(Debug)]
+pub struct Upload<'a> {
+    pub project_root_dir: &'a Path,
+    pub output_dir: &'a Path,
+    /// every file in the output directory
+    pub manifest: &'a OutputManifest,
+    /// what changed since the last deploy, everything if there wasn't one
+    pub changes: &'a [FileChange],
+}
+
+impl Upload<'_> {
+    /// Files that are new or changed, by path in the output directory
+    pub fn uploads(&self) -> impl Iterator<Item = &str> {
+        self.changes
+            .iter()
+            .filter(|change| change.kind != ChangeKind::Removed)
+            .map(|change| change.path.as_str())
+    }
+
+    /// Files that are gone since the last deploy
+    pub fn deletes(&self) -> impl Iterator<Item = &str> {
+        self.changes
+            .iter()
+            .filter(|change| change.kind == ChangeKind::Removed)
+            .map(|change| change.path.as_str())
+    }
+
+    pub fn read(&self, path: &str) -> Result<Vec<u8>> {
+        let file = self.output_dir.join(path);
+        fs::read(&file).wrap_err_with(|| format!("Failed to read `{}`", file.display()))
+    }
+
+    /// The SHA-1 of every file, by path
+    fn sha1_digests(&self) -> Result<BTreeMap<String, String>> {
+        self.manifest
+            .files
+            .keys()
+            .map(|path| Ok((path.clone(), sha1(&self.read(path)?))))
+            .collect()
+    }
+}
+
+/// Somewhere a site can be deployed to
+pub trait DeployTarget: fmt::Debug {
+    /// Send what's new or changed in `upload`, and remove what's gone
+    fn deploy(&self, upload: &Upload) -> Result<()>;
+}
+
+/// Percent-encode everything in `path` but `/` and unreserved
+/// characters
+fn encode_path(path: &str) -> String {
+    path.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// How Netlify and Vercel tell files apart
+fn sha1(contents: &[u8]) -> String {
+    sha1::Sha1::from(contents).digest().to_string()
+}
+
+/// An environment variable a target needs
+fn credential(name: &str, target: &str) -> Result<String> {
+    std::env::var(name).map_err(|_| eyre!("Set `{}` to deploy to {}", name, target))
+}
+
+/// Send `request`, returning the status and body of the response
+async fn respond(request: surf::RequestBuilder, what: &str) -> Result<(u16, String)> {
+    let mut response = timeout(REQUEST_TIMEOUT, request)
+        .await
+        .map_err(|_| eyre!("Timed out trying to {}", what))?
+        .map_err(|error| eyre!("Failed to {}: {}", what, error))?;
+    let status = u16::from(response.status());
+    Ok((status, response.body_string().await.unwrap_or_default()))
+}
+
+/// Send `request`, failing unless it's answered with a 2xx, and return
+/// the response body
+async fn send(request: surf::RequestBuilder, what: &str) -> Result<String> {
+    let (status, body) = respond(request, what).await?;
+    if !(200..300).contains(&status) {
+        return Err(eyre!(
+            "Failed to {}, the response was {}: {}",
+            what,
+            status,
+            body
+        ));
+    }
+    Ok(body)
+}
+
+fn read_state(path: &Path) -> Result<OutputManifest> {
+    if !path.exists() {
+        return Ok(OutputManifest::default());
+    }
+    let contents = fs::read_to_string(path)
+        .wrap_err_with(|| format!("Failed to read `{}`", path.display()))?;
+    serde_json::from_str(&contents)
+        .wrap_err_with(|| format!("Failed to parse `{}`", path.display()))
+        .wrap_err(Failure::Cache)
+}
+
+fn write_state(path: &Path, manifest: &OutputManifest) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .wrap_err_with(|| format!("Failed to create `{}`", dir.display()))?;
+    }
+    fs::write(path, serde_json::to_string(manifest)?)
+        .wrap_err_with(|| format!("Failed to write `{}`", path.display()))
+}
+
+/// What `toast deploy` did, or would do with `--dry-run`
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct DeploySummary {
+    pub target: String,
+    pub dry_run: bool,
+    pub uploaded: Vec<String>,
+    pub deleted: Vec<String>,
+    pub unchanged: usize,
+}
+
+impl fmt::Display for DeploySummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (upload, delete) = if self.dry_run {
+            ("Would upload", "Would delete")
+        } else {
+            ("Uploaded", "Deleted")
+        };
+        for path in &self.uploaded {
+            writeln!(f, "{} {}", upload, path)?;
+        }
+        for path in &self.deleted {
+            writeln!(f, "{} {}", delete, path)?;
+        }
+        write!(
+            f,
+            "{} {}: {} uploaded, {} deleted, {} unchanged",
+            if self.dry_run {
+                "Would deploy to"
+            } else {
+                "Deployed to"
+            },
+            self.target,
+            self.uploaded.len(),
+            self.deleted.len(),
+            self.unchanged
+        )
+    }
+}
+
+/// Deploy the output directory of `settings` to its `[deploy]` target,
+/// see the module docs. `all` sends every file, and `dry_run` only says
+/// what would be sent.
+#[instrument]
+pub fn deploy(settings: &BuildSettings, all: bool, dry_run: bool) -> Result<DeploySummary> {
+    let config = settings.deploy.as_ref().ok_or_else(|| {
+        eyre!("There's nowhere to deploy to, add a `[deploy]` section to `toast.config.toml`")
+    })?;
+    if !settings.output_dir.exists() {
+        return Err(eyre!(
+            "There's no output directory at `{}` to deploy, build the site first",
+            settings.output_dir.display()
+        ));
+    }
+    let state = config.state_path(&settings.input_dir);
+    let previous = if all {
+        OutputManifest::default()
+    } else {
+        read_state(&state)?
+    };
+    let manifest = output_manifest(&settings.output_dir)?;
+    let diff = BuildDiff::new(String::new(), String::new(), &previous, &manifest);
+    let upload = Upload {
+        project_root_dir: &settings.input_dir,
+        output_dir: &settings.output_dir,
+        manifest: &manifest,
+        changes: &diff.changes,
+    };
+    let summary = DeploySummary {
+        target: config.to_string(),
+        dry_run,
+        uploaded: upload.uploads().map(String::from).collect(),
+        deleted: upload.deletes().map(String::from).collect(),
+        unchanged: diff.unchanged,
+    };
+    if dry_run || diff.changes.is_empty() {
+        return Ok(summary);
+    }
+    config
+        .target()
+        .deploy(&upload)
+        .wrap_err_with(|| format!("Failed to deploy to {}", config))
+        .wrap_err(Failure::Deploy)?;
+    write_state(&state, &manifest)?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config: DeployConfig = toml::from_str(
+            r#"
+target = "s3"
+bucket = "my-site"
+region = "eu-west-1"
+cloudfront_distribution = "E2EXAMPLE"
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            DeployConfig::S3(S3Config {
+                bucket: String::from("my-site"),
+                region: String::from("eu-west-1"),
+                prefix: String::new(),
+                endpoint: None,
+                cloudfront_distribution: Some(String::from("E2EXAMPLE")),
+            })
+        );
+        assert_eq!(
+            config.state_path(Path::new("site")),
+            Path::new("site/.toast/deploys/s3-my-site-.json")
+        );
+        assert!(toml::from_str::<DeployConfig>("target = \"ftp\"").is_err());
+        assert!(toml::from_str::<DeployConfig>(
+            "target = \"rsync\"\ndestination = \"x:/srv\"\ntypo = 1"
+        )
+        .is_err());
+    }
+}
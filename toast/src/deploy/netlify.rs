@@ -0,0 +1,76 @@
+//! Deploying to Netlify with its file digest API: a deploy is created
+//! with the SHA-1 of every file, and Netlify answers with the ones it
+//! doesn't have yet, which are the only ones uploaded.
+use async_std::task;
+use color_eyre::eyre::{Result, WrapErr};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+
+use super::{credential, encode_path, send, DeployTarget, Upload};
+
+const API_URL: &str = "https://api.netlify.com/api/v1";
+
+#[derive(Debug, Default, Clone, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetlifyConfig {
+    /// the site's id or its `*.netlify.app` domain
+    pub site_id: String,
+    /// deploy a draft with its own url instead of publishing it
+    pub draft: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct CreatedDeploy {
+    id: String,
+    /// the digests of files Netlify doesn't have yet
+    #[serde(default)]
+    required: Vec<String>,
+}
+
+impl DeployTarget for NetlifyConfig {
+    fn deploy(&self, upload: &Upload) -> Result<()> {
+        let token = credential("NETLIFY_AUTH_TOKEN", "netlify")?;
+        let authorization = format!("Bearer {}", token);
+        let digests = upload.sha1_digests()?;
+        // files with the same contents are only asked for once
+        let by_digest: BTreeMap<&str, &str> = digests
+            .iter()
+            .map(|(path, digest)| (digest.as_str(), path.as_str()))
+            .collect();
+        let files: BTreeMap<String, &str> = digests
+            .iter()
+            .map(|(path, digest)| (format!("/{}", path), digest.as_str()))
+            .collect();
+        let client = surf::Client::new();
+        task::block_on(async {
+            let body = json!({ "files": files, "draft": self.draft }).to_string();
+            let request = client
+                .post(format!("{}/sites/{}/deploys", API_URL, self.site_id))
+                .header("Authorization", authorization.as_str())
+                .header("Content-Type", "application/json")
+                .body(body);
+            let response = send(request, "create a Netlify deploy").await?;
+            let deploy: CreatedDeploy = serde_json::from_str(&response)
+                .wrap_err("Failed to parse the deploy Netlify created")?;
+            for digest in &deploy.required {
+                let path = match by_digest.get(digest.as_str()) {
+                    Some(path) => *path,
+                    None => continue,
+                };
+                let request = client
+                    .put(format!(
+                        "{}/deploys/{}/files/{}",
+                        API_URL,
+                        deploy.id,
+                        encode_path(path)
+                    ))
+                    .header("Authorization", authorization.as_str())
+                    .header("Content-Type", "application/octet-stream")
+                    .body(upload.read(path)?);
+                send(request, &format!("upload `{}` to Netlify", path)).await?;
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,61 @@
+//! Deploying with rsync over ssh. Only the files that changed are listed
+//! for rsync, along with the ones that are gone, which
+//! `--delete-missing-args` removes from the destination.
+use color_eyre::eyre::{Result, WrapErr};
+use duct::cmd;
+use serde::Deserialize;
+use std::ffi::OsString;
+
+use super::{DeployTarget, Upload};
+
+#[derive(Debug, Default, Clone, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RsyncConfig {
+    /// where to copy the site to, like `deploy@example.com:/var/www/site`
+    pub destination: String,
+    /// the remote shell to use, like `ssh -p 2222`, defaults to rsync's
+    pub ssh: Option<String>,
+    /// more flags for rsync
+    pub args: Vec<String>,
+}
+
+impl RsyncConfig {
+    fn args(&self, upload: &Upload) -> Vec<OsString> {
+        let mut source = upload.output_dir.as_os_str().to_os_string();
+        // the contents of the output directory, not the directory
+        source.push("/");
+        let mut args: Vec<OsString> = [
+            "--archive",
+            "--compress",
+            "--from0",
+            "--files-from=-",
+            "--delete-missing-args",
+        ]
+        .iter()
+        .map(OsString::from)
+        .collect();
+        if let Some(ssh) = &self.ssh {
+            args.push("--rsh".into());
+            args.push(ssh.into());
+        }
+        args.extend(self.args.iter().map(OsString::from));
+        args.push(source);
+        args.push(self.destination.clone().into());
+        args
+    }
+}
+
+impl DeployTarget for RsyncConfig {
+    fn deploy(&self, upload: &Upload) -> Result<()> {
+        let files: Vec<u8> = upload
+            .changes
+            .iter()
+            .flat_map(|change| change.path.bytes().chain([0]))
+            .collect();
+        cmd("rsync", self.args(upload))
+            .stdin_bytes(files)
+            .run()
+            .wrap_err_with(|| format!("Failed to rsync to `{}`", self.destination))?;
+        Ok(())
+    }
+}
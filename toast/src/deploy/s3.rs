@@ -0,0 +1,152 @@
+//! Deploying to an S3 bucket, or another store that speaks its API, with
+//! the client in `crate::s3`. When there's a CloudFront distribution in
+//! front of the bucket, whatever changed is invalidated once it's
+//! uploaded.
+use async_std::task;
+use chrono::Utc;
+use color_eyre::eyre::Result;
+use serde::Deserialize;
+
+use super::{encode_path, DeployTarget, Upload};
+use crate::{
+    headers::read_cache_control,
+    s3::{self, key_prefix, Bucket, Credentials, Request},
+};
+
+/// CloudFront only takes this many paths in one invalidation, more than
+/// that and everything is invalidated instead
+const MAX_INVALIDATION_PATHS: usize = 1000;
+
+#[derive(Debug, Default, Clone, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct S3Config {
+    pub bucket: String,
+    pub region: String,
+    /// uploaded under this key prefix, like `"docs"`
+    pub prefix: String,
+    /// the url of a store other than S3, like R2 or MinIO
+    pub endpoint: Option<String>,
+    /// CloudFront distribution to invalidate changed files in
+    pub cloudfront_distribution: Option<String>,
+}
+
+impl S3Config {
+    fn bucket(&self) -> Bucket {
+        Bucket {
+            name: self.bucket.clone(),
+            prefix: key_prefix(&self.prefix),
+            region: self.region.clone(),
+            endpoint: self.endpoint.clone(),
+        }
+    }
+
+    async fn invalidate(
+        &self,
+        distribution: &str,
+        paths: Vec<String>,
+        credentials: &Credentials,
+    ) -> Result<()> {
+        let paths = if paths.len() > MAX_INVALIDATION_PATHS {
+            vec![String::from("/*")]
+        } else {
+            paths.iter().map(|path| encode_path(path)).collect()
+        };
+        let items: String = paths
+            .iter()
+            .map(|path| format!("<Path>{}</Path>", path))
+            .collect();
+        let body = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?><InvalidationBatch xmlns="http://cloudfront.amazonaws.com/doc/2020-05-31/"><Paths><Quantity>{}</Quantity><Items>{}</Items></Paths><CallerReference>toast-{}</CallerReference></InvalidationBatch>"#,
+            paths.len(),
+            items,
+            Utc::now().timestamp_millis()
+        );
+        let path = format!("/2020-05-31/distribution/{}/invalidation", distribution);
+        let request = Request {
+            method: "POST",
+            host: "cloudfront.amazonaws.com",
+            path: &path,
+            query: vec![],
+            headers: vec![("content-type", String::from("application/xml"))],
+            body: body.as_bytes(),
+        };
+        // CloudFront is global, and signed for us-east-1
+        s3::send(
+            "https",
+            &request,
+            credentials,
+            "us-east-1",
+            "cloudfront",
+            &format!("invalidate CloudFront distribution `{}`", distribution),
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+/// The urls a file is served at, so `blog/index.html` is invalidated at
+/// `/blog/` too
+fn served_paths(prefix: &str, path: &str) -> Vec<String> {
+    let url = format!("/{}{}", prefix, path);
+    match url.strip_suffix("index.html") {
+        Some(dir) => vec![url.clone(), dir.to_string()],
+        None => vec![url],
+    }
+}
+
+impl DeployTarget for S3Config {
+    fn deploy(&self, upload: &Upload) -> Result<()> {
+        let credentials = Credentials::from_env()?;
+        let bucket = self.bucket();
+        let policies = read_cache_control(upload.project_root_dir);
+        task::block_on(async {
+            for path in upload.uploads() {
+                bucket
+                    .put_object(&credentials, path, &upload.read(path)?, &policies)
+                    .await?;
+            }
+            for path in upload.deletes() {
+                bucket.delete_object(&credentials, path).await?;
+            }
+            if let Some(distribution) = &self.cloudfront_distribution {
+                let paths = upload
+                    .changes
+                    .iter()
+                    .flat_map(|change| served_paths(&bucket.prefix, &change.path))
+                    .collect();
+                self.invalidate(distribution, paths, &credentials).await?;
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_served_paths() {
+        assert_eq!(
+            served_paths("", "blog/index.html"),
+            vec!["/blog/index.html", "/blog/"]
+        );
+        assert_eq!(served_paths("docs/", "a.css"), vec!["/docs/a.css"]);
+    }
+
+    #[test]
+    fn test_prefix_without_slash() {
+        let config = S3Config {
+            bucket: String::from("my-site"),
+            prefix: String::from("docs"),
+            ..S3Config::default()
+        };
+        let bucket = config.bucket();
+        assert_eq!(bucket.prefix, "docs/");
+        assert_eq!(
+            served_paths(&bucket.prefix, "index.html"),
+            vec!["/docs/index.html", "/docs/"]
+        );
+        assert_eq!(key_prefix("/"), "");
+    }
+}
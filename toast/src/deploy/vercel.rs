@@ -0,0 +1,124 @@
+//! Deploying to Vercel: files that changed are uploaded by their SHA-1,
+//! then a deployment is created that lists every file. Vercel answers
+//! with the files it's still missing, like ones uploaded to a different
+//! team, which are uploaded before trying once more.
+use async_std::task;
+use color_eyre::eyre::{eyre, Result};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::{credential, respond, send, DeployTarget, Upload};
+
+const API_URL: &str = "https://api.vercel.com";
+
+#[derive(Debug, Default, Clone, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct VercelConfig {
+    pub project: String,
+    /// for projects that belong to a team
+    pub team_id: Option<String>,
+    /// deploy a preview instead of to production
+    pub preview: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ApiError {
+    code: String,
+    /// digests of the files a deployment needs, for `missing_files`
+    missing: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorResponse {
+    error: ApiError,
+}
+
+impl VercelConfig {
+    fn url(&self, path: &str) -> String {
+        match &self.team_id {
+            Some(team_id) => format!("{}{}?teamId={}", API_URL, path, team_id),
+            None => format!("{}{}", API_URL, path),
+        }
+    }
+}
+
+impl DeployTarget for VercelConfig {
+    fn deploy(&self, upload: &Upload) -> Result<()> {
+        let token = credential("VERCEL_TOKEN", "vercel")?;
+        let authorization = format!("Bearer {}", token);
+        let digests = upload.sha1_digests()?;
+        let client = surf::Client::new();
+        let upload_file = |path: &str| -> Result<surf::RequestBuilder> {
+            Ok(client
+                .post(self.url("/v2/files"))
+                .header("Authorization", authorization.as_str())
+                .header("Content-Type", "application/octet-stream")
+                .header("x-vercel-digest", digests[path].as_str())
+                .body(upload.read(path)?))
+        };
+        let files: Vec<_> = digests
+            .iter()
+            .map(|(path, digest)| {
+                json!({
+                    "file": path,
+                    "sha": digest,
+                    "size": upload.manifest.files[path].bytes,
+                })
+            })
+            .collect();
+        let mut body = json!({
+            "name": self.project,
+            "project": self.project,
+            "files": files,
+            "projectSettings": { "framework": null },
+        });
+        if !self.preview {
+            body["target"] = json!("production");
+        }
+        let body = body.to_string();
+        let create = || {
+            client
+                .post(self.url("/v13/deployments"))
+                .header("Authorization", authorization.as_str())
+                .header("Content-Type", "application/json")
+                .body(body.as_str())
+        };
+        task::block_on(async {
+            for path in upload.uploads() {
+                send(upload_file(path)?, &format!("upload `{}` to Vercel", path)).await?;
+            }
+            let (status, response) = respond(create(), "create a Vercel deployment").await?;
+            if (200..300).contains(&status) {
+                return Ok(());
+            }
+            let missing: BTreeSet<String> = serde_json::from_str::<ErrorResponse>(&response)
+                .ok()
+                .filter(|response| response.error.code == "missing_files")
+                .map(|response| response.error.missing.into_iter().collect())
+                .ok_or_else(|| {
+                    eyre!(
+                        "Failed to create a Vercel deployment, the response was {}: {}",
+                        status,
+                        response
+                    )
+                })?;
+            let paths: BTreeMap<&str, &str> = digests
+                .iter()
+                .filter(|(_, digest)| missing.contains(digest.as_str()))
+                .map(|(path, digest)| (digest.as_str(), path.as_str()))
+                .collect();
+            if paths.len() < missing.len() {
+                return Err(eyre!(
+                    "Vercel is missing files that aren't in the output directory"
+                ));
+            }
+            for path in paths.values() {
+                send(upload_file(path)?, &format!("upload `{}` to Vercel", path)).await?;
+            }
+            send(create(), "create a Vercel deployment").await?;
+            Ok(())
+        })
+    }
+}
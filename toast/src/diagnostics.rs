@@ -0,0 +1,368 @@
+//! Problems found during a build, collected in one place so the build can
+//! decide when they stop it. `--strict` and `--keep-going` pick the
+//! policy:
+//!
+//! - by default, warnings are listed when the build is done and errors
+//!   fail it before it replaces the last good site, see `staging`
+//! - `--strict` makes warnings errors, and the first one stops the build
+//! - `--keep-going` builds everything it can and writes the site even if
+//!   there were errors, which still fail the build once it's done
+//!
+//! Watch mode keeps going too, so a page that's being worked on doesn't
+//! stop the rest of the site from rebuilding.
+//!
+//! Errors leave part of the site unbuilt, like a page that threw while it
+//! was rendered, or a broken link with `--check-links`. Warnings are a
+//! worse site that still works, like a page missing one of the
+//! `required_frontmatter` keys in `toast.config.toml`:
+//!
+//! ```toml
+//! required_frontmatter = ["title", "description"]
+//! ```
+//!
+//! `--strict` also checks internal links, which are warnings unless
+//! `--check-links` is passed.
+//!
+//! Compile errors in pages and components point at where they are in the
+//! file, with a code frame like this one:
+//!
+//! ```text
+//! error: src/pages/index.mdx:4:2
+//!     Expected a closing tag for `<Chart>`
+//!       3 |
+//!     > 4 | <Chart data={sales}>
+//!         |  ^^^^^
+//! ```
+use color_eyre::eyre::Result;
+use serde::Serialize;
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+};
+use thiserror::Error;
+
+use crate::exit_code::Failure;
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// One problem, and the page or file it's in
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub source: String,
+    pub message: String,
+    /// what toast exits with if this fails the build, see `exit_code`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kind: Option<Failure>,
+}
+
+impl Diagnostic {
+    pub fn warning(source: impl Into<String>, message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Warning,
+            source: source.into(),
+            message: message.into(),
+            kind: None,
+        }
+    }
+
+    pub fn error(source: impl Into<String>, message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            source: source.into(),
+            message: message.into(),
+            kind: None,
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    /// The message is indented under the source, since errors from pages
+    /// come with a stack
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let label = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        writeln!(f, "{}: {}", label, self.source)?;
+        for line in self.message.trim_end().lines() {
+            writeln!(f, "    {}", line)?;
+        }
+        Ok(())
+    }
+}
+
+/// A JSX, TypeScript, or MDX file that didn't compile, and where
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompileError {
+    pub file: String,
+    pub message: String,
+    /// the line and column, from 1, if the compiler said where
+    pub position: Option<(usize, usize)>,
+    /// the lines up to the position, with a caret under the error
+    pub frame: String,
+}
+
+impl CompileError {
+    /// The error in `source`, the contents of `file`, at `position`,
+    /// `length` characters long
+    pub fn new(
+        file: impl Into<String>,
+        message: impl Into<String>,
+        source: &str,
+        position: Option<(usize, usize)>,
+        length: usize,
+    ) -> CompileError {
+        CompileError {
+            file: file.into(),
+            message: message.into(),
+            position,
+            frame: position
+                .map(|(line, column)| code_frame(source, line, column, length))
+                .unwrap_or_default(),
+        }
+    }
+
+    pub fn diagnostic(&self) -> Diagnostic {
+        let location = match self.position {
+            Some((line, column)) => format!("{}:{}:{}", self.file, line, column),
+            None => self.file.clone(),
+        };
+        let mut message = self.message.clone();
+        if !self.frame.is_empty() {
+            message.push('\n');
+            message.push_str(&self.frame);
+        }
+        Diagnostic {
+            kind: Some(Failure::Compile),
+            ..Diagnostic::error(location, message)
+        }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.diagnostic().to_string().trim_end())
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+/// The line before `line` and `line` itself, numbered, with carets under
+/// the `length` characters from `column`. Tabs before the column are kept
+/// so the carets line up.
+pub fn code_frame(source: &str, line: usize, column: usize, length: usize) -> String {
+    let lines: Vec<&str> = source.lines().collect();
+    let line = line.clamp(1, lines.len().max(1));
+    let width = line.to_string().len();
+    let mut frame = String::new();
+    if line > 1 {
+        frame.push_str(&format!("  {:>width$} | {}\n", line - 1, lines[line - 2]));
+    }
+    let text = lines.get(line - 1).copied().unwrap_or("");
+    frame.push_str(&format!("> {:>width$} | {}\n", line, text));
+    let before: String = text
+        .chars()
+        .take(column.saturating_sub(1))
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let rest = text
+        .chars()
+        .count()
+        .saturating_sub(column.saturating_sub(1));
+    let carets = "^".repeat(length.min(rest).max(1));
+    frame.push_str(&format!("  {:>width$} | {}{}", "", before, carets));
+    frame
+}
+
+/// When diagnostics stop a build, see the module docs
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum FailurePolicy {
+    #[default]
+    Default,
+    Strict,
+    KeepGoing,
+}
+
+/// The diagnostics that stopped a build
+#[derive(Error, Debug)]
+#[error("{message}")]
+pub struct BuildFailed {
+    pub message: String,
+    /// the earliest kind of failure in them, see `exit_code`
+    pub kind: Option<Failure>,
+}
+
+impl BuildFailed {
+    fn new(message: String, errors: &[&Diagnostic]) -> BuildFailed {
+        BuildFailed {
+            message,
+            kind: errors.iter().filter_map(|diagnostic| diagnostic.kind).min(),
+        }
+    }
+}
+
+fn errors(diagnostics: &[Diagnostic]) -> Vec<&Diagnostic> {
+    diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.severity == Severity::Error)
+        .collect()
+}
+
+/// Every diagnostic reported so far. Clones share the same list, so the
+/// options for a staged build report to the same place as the originals.
+#[derive(Debug, Default, Clone)]
+pub struct Diagnostics {
+    pub policy: FailurePolicy,
+    found: Arc<Mutex<Vec<Diagnostic>>>,
+}
+
+/// Every diagnostic, errors first
+pub fn summary(diagnostics: &[Diagnostic]) -> String {
+    let mut sorted: Vec<&Diagnostic> = diagnostics.iter().collect();
+    sorted.sort_by_key(|diagnostic| std::cmp::Reverse(diagnostic.severity));
+    let count = |severity| {
+        diagnostics
+            .iter()
+            .filter(|diagnostic| diagnostic.severity == severity)
+            .count()
+    };
+    let mut output: String = sorted
+        .iter()
+        .map(|diagnostic| diagnostic.to_string())
+        .collect();
+    output.push_str(&format!(
+        "{} error(s), {} warning(s)",
+        count(Severity::Error),
+        count(Severity::Warning)
+    ));
+    output
+}
+
+impl Diagnostics {
+    pub fn new(policy: FailurePolicy) -> Diagnostics {
+        Diagnostics {
+            policy,
+            ..Diagnostics::default()
+        }
+    }
+
+    /// Record `diagnostic`, failing right away if the policy is strict
+    pub fn report(&self, diagnostic: Diagnostic) -> Result<()> {
+        if self.policy == FailurePolicy::Strict {
+            let message = diagnostic.to_string().trim_end().to_string();
+            return Err(BuildFailed::new(message, &[&diagnostic]).into());
+        }
+        if let Ok(mut found) = self.found.lock() {
+            found.push(diagnostic);
+        }
+        Ok(())
+    }
+
+    fn has_errors(&self) -> bool {
+        self.found.lock().is_ok_and(|found| {
+            found
+                .iter()
+                .any(|diagnostic| diagnostic.severity == Severity::Error)
+        })
+    }
+
+    /// Fail with every diagnostic if there have been errors and the
+    /// policy doesn't keep going, before a build is swapped in
+    pub fn check(&self) -> Result<()> {
+        if self.policy == FailurePolicy::KeepGoing || !self.has_errors() {
+            return Ok(());
+        }
+        let found = self.take();
+        Err(BuildFailed::new(summary(&found), &errors(&found)).into())
+    }
+
+    /// Take every diagnostic reported so far, leaving none, so each
+    /// rebuild in watch mode only lists its own
+    pub fn take(&self) -> Vec<Diagnostic> {
+        self.found
+            .lock()
+            .map(|mut found| std::mem::take(&mut *found))
+            .unwrap_or_default()
+    }
+
+    /// Print every diagnostic once the build is done, failing if any of
+    /// them were errors
+    pub fn finish(&self) -> Result<()> {
+        let found = self.take();
+        if found.is_empty() {
+            return Ok(());
+        }
+        eprintln!("{}", summary(&found));
+        let errors = errors(&found);
+        if !errors.is_empty() {
+            let message = format!("The build had {} error(s)", errors.len());
+            return Err(BuildFailed::new(message, &errors).into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policies() {
+        let strict = Diagnostics::new(FailurePolicy::Strict);
+        assert!(strict
+            .report(Diagnostic::warning("/about", "missing `title`"))
+            .is_err());
+
+        let diagnostics = Diagnostics::new(FailurePolicy::Default);
+        diagnostics
+            .report(Diagnostic::warning("/about", "missing `title`"))
+            .unwrap();
+        assert!(diagnostics.check().is_ok());
+        let staged = diagnostics.clone();
+        staged
+            .report(Diagnostic::error(
+                "src/pages/broken.js",
+                "TypeError: oops\n    at Page (src/pages/broken.js:3:9)",
+            ))
+            .unwrap();
+        let error = diagnostics.check().unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "error: src/pages/broken.js\n    TypeError: oops\n        at Page (src/pages/broken.js:3:9)\nwarning: /about\n    missing `title`\n1 error(s), 1 warning(s)"
+        );
+        assert!(diagnostics.take().is_empty());
+
+        let keep_going = Diagnostics::new(FailurePolicy::KeepGoing);
+        keep_going
+            .report(Diagnostic::error("src/pages/broken.js", "oops"))
+            .unwrap();
+        assert!(keep_going.check().is_ok());
+        assert!(keep_going.finish().is_err());
+    }
+
+    #[test]
+    fn test_code_frame() {
+        let source = "# Sales\n\n<Chart data={sales}>\n";
+        assert_eq!(
+            code_frame(source, 3, 2, 5),
+            "  2 | \n> 3 | <Chart data={sales}>\n    |  ^^^^^"
+        );
+        let error = CompileError::new(
+            "src/pages/index.mdx",
+            "Unexpected `;`",
+            "\tlet x = ;",
+            Some((1, 10)),
+            1,
+        );
+        assert_eq!(
+            error.to_string(),
+            "error: src/pages/index.mdx:1:10\n    Unexpected `;`\n    > 1 | \tlet x = ;\n        | \t        ^"
+        );
+    }
+}
@@ -0,0 +1,361 @@
+//! `toast diff`, which compares what two builds wrote, so a change that
+//! busts the cache of the whole site, like a config value that ends up in
+//! every page's html, shows up before it's deployed instead of after.
+//!
+//! After every full build, other than `toast serve`'s, which has drafts
+//! and source maps, the path, size, and hash of each file in the output
+//! directory is written to `.toast/builds/<n>.json`, numbered from
+//! 1, and the last `KEPT_BUILDS` are kept. `toast diff` compares the last
+//! two, or the builds given with `--from` and `--to`, either by number or
+//! by the path of a manifest, like one saved from a CI run.
+use chrono::Utc;
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+use tracing::instrument;
+use walkdir::WalkDir;
+
+use crate::{
+    cache::{content_hash, format_size, CACHE_DIR},
+    exit_code::Failure,
+};
+
+/// Where output manifests are kept, in `CACHE_DIR`
+pub const BUILDS_DIR: &str = "builds";
+
+/// How many output manifests are kept, the oldest are removed first
+const KEPT_BUILDS: usize = 20;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct OutputFile {
+    pub bytes: u64,
+    pub hash: String,
+}
+
+/// Every file a build wrote to the output directory
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct OutputManifest {
+    /// when the build finished, as RFC 3339
+    pub built_at: Option<String>,
+    /// by path in the output directory, with `/` separators
+    pub files: BTreeMap<String, OutputFile>,
+}
+
+fn builds_dir(project_root_dir: &Path) -> PathBuf {
+    project_root_dir.join(CACHE_DIR).join(BUILDS_DIR)
+}
+
+fn build_path(dir: &Path, number: u64) -> PathBuf {
+    dir.join(format!("{}.json", number))
+}
+
+/// The numbers of the builds recorded in `dir`, oldest first
+fn build_numbers(dir: &Path) -> Vec<u64> {
+    let mut numbers: Vec<u64> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let path = entry.path();
+            if path.extension()? != "json" {
+                return None;
+            }
+            path.file_stem()?.to_str()?.parse().ok()
+        })
+        .collect();
+    numbers.sort_unstable();
+    numbers
+}
+
+/// Hash every file in `output_dir`
+#[instrument]
+pub fn output_manifest(output_dir: &Path) -> Result<OutputManifest> {
+    let mut files = BTreeMap::new();
+    for entry in WalkDir::new(output_dir)
+        .into_iter()
+        .filter_map(|result| result.ok())
+        .filter(|entry| entry.file_type().is_file())
+    {
+        let path = entry.path();
+        let contents =
+            fs::read(path).wrap_err_with(|| format!("Failed to read `{}`", path.display()))?;
+        let relative = path
+            .strip_prefix(output_dir)
+            .wrap_err_with(|| format!("Failed to make `{}` relative", path.display()))?;
+        let relative = relative
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        files.insert(
+            relative,
+            OutputFile {
+                bytes: contents.len() as u64,
+                hash: content_hash(&[&contents]),
+            },
+        );
+    }
+    Ok(OutputManifest {
+        built_at: Some(Utc::now().to_rfc3339()),
+        files,
+    })
+}
+
+/// Record what the finished build in `output_dir` wrote, returning the
+/// build's number
+#[instrument]
+pub fn record_build(project_root_dir: &Path, output_dir: &Path) -> Result<u64> {
+    let dir = builds_dir(project_root_dir);
+    fs::create_dir_all(&dir).wrap_err_with(|| format!("Failed to create `{}`", dir.display()))?;
+    let numbers = build_numbers(&dir);
+    let number = numbers.last().map_or(1, |last| last + 1);
+    let manifest = output_manifest(output_dir)?;
+    let path = build_path(&dir, number);
+    fs::write(&path, serde_json::to_string(&manifest)?)
+        .wrap_err_with(|| format!("Failed to write output manifest `{}`", path.display()))?;
+    for old in numbers.iter().rev().skip(KEPT_BUILDS - 1) {
+        let old = build_path(&dir, *old);
+        fs::remove_file(&old).wrap_err_with(|| format!("Failed to remove `{}`", old.display()))?;
+    }
+    Ok(number)
+}
+
+/// The output manifest of `build`, a build number or the path of a
+/// manifest
+fn read_build(project_root_dir: &Path, build: &str) -> Result<OutputManifest> {
+    let path = match build.parse() {
+        Ok(number) => build_path(&builds_dir(project_root_dir), number),
+        Err(_) => PathBuf::from(build),
+    };
+    let contents = fs::read_to_string(&path)
+        .wrap_err_with(|| format!("Failed to read output manifest `{}`", path.display()))?;
+    serde_json::from_str(&contents)
+        .wrap_err_with(|| format!("Failed to parse output manifest `{}`", path.display()))
+        .wrap_err(Failure::Cache)
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+/// A file that's different between two builds
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct FileChange {
+    pub path: String,
+    pub kind: ChangeKind,
+    /// the size in the first build, `None` if it's new
+    pub before: Option<u64>,
+    /// the size in the second build, `None` if it's gone
+    pub after: Option<u64>,
+}
+
+impl FileChange {
+    /// How many bytes bigger the file got
+    pub fn delta(&self) -> i64 {
+        self.after.unwrap_or(0) as i64 - self.before.unwrap_or(0) as i64
+    }
+}
+
+/// What `toast diff` prints
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct BuildDiff {
+    pub from: String,
+    pub to: String,
+    /// by path
+    pub changes: Vec<FileChange>,
+    pub unchanged: usize,
+}
+
+impl BuildDiff {
+    pub fn new(from: String, to: String, before: &OutputManifest, after: &OutputManifest) -> Self {
+        let mut changes = vec![];
+        let mut unchanged = 0;
+        for (path, file) in &after.files {
+            match before.files.get(path) {
+                Some(old) if old.hash == file.hash => unchanged += 1,
+                old => changes.push(FileChange {
+                    path: path.clone(),
+                    kind: match old {
+                        Some(_) => ChangeKind::Changed,
+                        None => ChangeKind::Added,
+                    },
+                    before: old.map(|old| old.bytes),
+                    after: Some(file.bytes),
+                }),
+            }
+        }
+        for (path, file) in &before.files {
+            if !after.files.contains_key(path) {
+                changes.push(FileChange {
+                    path: path.clone(),
+                    kind: ChangeKind::Removed,
+                    before: Some(file.bytes),
+                    after: None,
+                });
+            }
+        }
+        changes.sort_by(|a, b| a.path.cmp(&b.path));
+        BuildDiff {
+            from,
+            to,
+            changes,
+            unchanged,
+        }
+    }
+
+    pub fn count(&self, kind: ChangeKind) -> usize {
+        self.changes
+            .iter()
+            .filter(|change| change.kind == kind)
+            .count()
+    }
+
+    /// How many bytes bigger the whole site got
+    pub fn delta(&self) -> i64 {
+        self.changes.iter().map(FileChange::delta).sum()
+    }
+}
+
+/// Compare two recorded builds of the project in `project_root_dir`, see
+/// the module docs
+#[instrument]
+pub fn diff_builds(
+    project_root_dir: &Path,
+    from: Option<&str>,
+    to: Option<&str>,
+) -> Result<BuildDiff> {
+    let numbers = build_numbers(&builds_dir(project_root_dir));
+    let to = match to {
+        Some(to) => to.to_string(),
+        None => numbers.last().map(u64::to_string).ok_or_else(|| {
+            eyre!(
+                "No builds have been recorded in `{}` yet, run a full build first",
+                builds_dir(project_root_dir).display()
+            )
+        })?,
+    };
+    let from = match from {
+        Some(from) => from.to_string(),
+        // the build before `to`
+        None => {
+            let to_number: Option<u64> = to.parse().ok();
+            numbers
+                .iter()
+                .rev()
+                .find(|number| to_number.is_none_or(|to| **number < to))
+                .map(u64::to_string)
+                .ok_or_else(|| eyre!("There's no build before build {} to compare it with", to))?
+        }
+    };
+    let before = read_build(project_root_dir, &from)?;
+    let after = read_build(project_root_dir, &to)?;
+    Ok(BuildDiff::new(from, to, &before, &after))
+}
+
+/// A size change like `+1.2KB` or `-300B`
+fn format_delta(delta: i64) -> String {
+    let sign = if delta < 0 { "-" } else { "+" };
+    format!("{}{}", sign, format_size(delta.unsigned_abs()))
+}
+
+/// `build 3` for build numbers, or the path of a manifest
+fn build_name(build: &str) -> String {
+    match build.parse::<u64>() {
+        Ok(number) => format!("build {}", number),
+        Err(_) => format!("`{}`", build),
+    }
+}
+
+impl fmt::Display for BuildDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Comparing {} with {}",
+            build_name(&self.from),
+            build_name(&self.to)
+        )?;
+        for change in &self.changes {
+            match (change.kind, change.before, change.after) {
+                (ChangeKind::Changed, Some(before), Some(after)) => writeln!(
+                    f,
+                    "~ {} ({} -> {}, {})",
+                    change.path,
+                    format_size(before),
+                    format_size(after),
+                    format_delta(change.delta())
+                )?,
+                (ChangeKind::Removed, Some(before), _) => {
+                    writeln!(f, "- {} ({})", change.path, format_size(before))?
+                }
+                (_, _, after) => {
+                    writeln!(f, "+ {} ({})", change.path, format_size(after.unwrap_or(0)))?
+                }
+            }
+        }
+        write!(
+            f,
+            "{} added, {} removed, {} changed, {} unchanged, {} in total",
+            self.count(ChangeKind::Added),
+            self.count(ChangeKind::Removed),
+            self.count(ChangeKind::Changed),
+            self.unchanged,
+            format_delta(self.delta())
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(files: &[(&str, u64, &str)]) -> OutputManifest {
+        OutputManifest {
+            built_at: None,
+            files: files
+                .iter()
+                .map(|(path, bytes, hash)| {
+                    (
+                        path.to_string(),
+                        OutputFile {
+                            bytes: *bytes,
+                            hash: hash.to_string(),
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        let before = manifest(&[
+            ("index.html", 1000, "a"),
+            ("old.js", 400, "b"),
+            ("src/nav.js", 200, "c"),
+        ]);
+        let after = manifest(&[
+            ("index.html", 1200, "d"),
+            ("new/index.html", 300, "e"),
+            ("src/nav.js", 200, "c"),
+        ]);
+        let diff = BuildDiff::new(String::from("1"), String::from("2"), &before, &after);
+        assert_eq!(diff.unchanged, 1);
+        assert_eq!(diff.delta(), 100);
+        assert_eq!(
+            diff.to_string(),
+            "Comparing build 1 with build 2\n\
+             ~ index.html (1.0KB -> 1.2KB, +200B)\n\
+             + new/index.html (300B)\n\
+             - old.js (400B)\n\
+             1 added, 1 removed, 1 changed, 1 unchanged, +100B in total"
+        );
+    }
+}
@@ -0,0 +1,227 @@
+use std::{fmt, fs, path::Path};
+use tracing::instrument;
+
+use crate::{
+    cli_args::BuildArgs,
+    config::{self, BuildSettings},
+    esinstall::has_npm_dependencies,
+    node::{check_node_version, node_bin, DEFAULT_MIN_NODE_VERSION},
+    npm_bin::find_npm_bin_dir,
+    protocol::handshake,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    /// the build might still work, but probably not the way you want
+    Warn,
+    /// the build will fail
+    Fail,
+}
+
+/// The result of one `toast doctor` check, and what to do about it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub message: String,
+    pub fix: Option<String>,
+}
+
+impl Check {
+    fn ok(name: &'static str, message: impl Into<String>) -> Check {
+        Check {
+            name,
+            status: Status::Ok,
+            message: message.into(),
+            fix: None,
+        }
+    }
+    fn warn(name: &'static str, message: impl Into<String>, fix: impl Into<String>) -> Check {
+        Check {
+            name,
+            status: Status::Warn,
+            message: message.into(),
+            fix: Some(fix.into()),
+        }
+    }
+    fn fail(name: &'static str, message: impl Into<String>, fix: impl Into<String>) -> Check {
+        Check {
+            name,
+            status: Status::Fail,
+            message: message.into(),
+            fix: Some(fix.into()),
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let icon = match self.status {
+            Status::Ok => "✓",
+            Status::Warn => "!",
+            Status::Fail => "✗",
+        };
+        write!(f, "{} {}: {}", icon, self.name, self.message)?;
+        if let Some(fix) = &self.fix {
+            write!(f, "\n    fix: {}", fix)?;
+        }
+        Ok(())
+    }
+}
+
+/// Check everything a build needs from the environment and the project in
+/// `dir`, without building anything
+#[instrument]
+pub fn diagnose(dir: &Path, args: &BuildArgs) -> Vec<Check> {
+    let config = config::load(dir);
+    let minimum = match &config {
+        Ok(config) => config.clone().merge(dir, args).min_node_version,
+        Err(_) => args
+            .min_node_version
+            .clone()
+            .unwrap_or_else(|| DEFAULT_MIN_NODE_VERSION.to_string()),
+    };
+    let mut checks = vec![check_node(&minimum), check_package_manager()];
+    checks.push(match find_npm_bin_dir(dir) {
+        Ok(bin_dir) => match handshake(&bin_dir) {
+            Ok(()) => Check::ok(
+                "toast npm package",
+                format!("found in `{}`", bin_dir.display()),
+            ),
+            Err(err) => Check::fail(
+                "toast npm package",
+                err.to_string(),
+                "install the versions of the toast binary and npm package that were released together",
+            ),
+        },
+        Err(err) => Check::fail(
+            "toast npm package",
+            err.to_string(),
+            "run `npm install toast` in your project",
+        ),
+    });
+    match config {
+        Ok(config) => {
+            checks.push(Check::ok("config", "loaded"));
+            let settings = config.merge(dir, args);
+            checks.extend(check_project(&settings));
+        }
+        Err(err) => checks.push(Check::fail(
+            "config",
+            format!("{:#}", err),
+            format!(
+                "fix the error in `{}` or `{}`",
+                config::TOML_CONFIG_FILE,
+                config::JS_CONFIG_FILE
+            ),
+        )),
+    }
+    checks
+}
+
+fn check_node(minimum: &str) -> Check {
+    let fix = format!("install node {} or newer from https://nodejs.org", minimum);
+    if which::which(node_bin()).is_err() {
+        return Check::fail(
+            "node",
+            format!("`{}` isn't on your PATH", node_bin().display()),
+            fix,
+        );
+    }
+    match check_node_version(minimum) {
+        Ok(()) => Check::ok("node", format!("version {} or newer", minimum)),
+        Err(err) => Check::fail("node", format!("{:#}", err), fix),
+    }
+}
+
+fn check_package_manager() -> Check {
+    let found: Vec<&str> = ["npm", "yarn", "pnpm"]
+        .iter()
+        .copied()
+        .filter(|program| which::which(program).is_ok())
+        .collect();
+    if found.is_empty() {
+        Check::warn(
+            "package manager",
+            "none of npm, yarn, or pnpm are on your PATH",
+            "install npm (it comes with node) to install your project's dependencies",
+        )
+    } else {
+        Check::ok("package manager", found.join(", "))
+    }
+}
+
+fn check_project(settings: &BuildSettings) -> Vec<Check> {
+    let mut checks = vec![];
+    for root in &settings.page_roots {
+        let pages_dir = settings.input_dir.join(&root.dir);
+        checks.push(if pages_dir.is_dir() {
+            Check::ok("pages", format!("`{}` exists", pages_dir.display()))
+        } else {
+            Check::warn(
+                "pages",
+                format!(
+                    "`{}` doesn't exist, so no pages will be built from it",
+                    pages_dir.display()
+                ),
+                "create an `index.js` page there, or run `toast new` to start a project",
+            )
+        });
+    }
+
+    checks.push(if settings.import_map.exists() {
+        Check::ok(
+            "import map",
+            format!("`{}` exists", settings.import_map.display()),
+        )
+    } else if has_npm_dependencies(&settings.input_dir) {
+        Check::fail(
+            "import map",
+            format!(
+                "`{}` doesn't exist, but package.json has dependencies that need one",
+                settings.import_map.display()
+            ),
+            "run `toast import-map`, or build with `--create-import-map`",
+        )
+    } else {
+        Check::ok(
+            "import map",
+            "not needed because package.json has no dependencies",
+        )
+    });
+
+    checks.push(check_writable(&settings.output_dir));
+    checks
+}
+
+/// Make sure files can be written to the output directory by writing one
+fn check_writable(output_dir: &Path) -> Check {
+    let probe = output_dir.join(".toast-doctor");
+    let result = fs::create_dir_all(output_dir)
+        .and_then(|_| fs::write(&probe, b""))
+        .and_then(|_| fs::remove_file(&probe));
+    match result {
+        Ok(()) => Check::ok(
+            "output directory",
+            format!("`{}` is writable", output_dir.display()),
+        ),
+        Err(err) => Check::fail(
+            "output directory",
+            format!("can't write to `{}`: {}", output_dir.display(), err),
+            "check the permissions on the output directory, or pass a different one",
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_includes_fix() {
+        let check = Check::fail("pages", "missing", "create it");
+        assert_eq!(check.to_string(), "✗ pages: missing\n    fix: create it");
+        assert_eq!(Check::ok("node", "fine").to_string(), "✓ node: fine");
+    }
+}
@@ -0,0 +1,74 @@
+//! `--dry-run`, which finds every source, compiles content enough to hash
+//! it, and compares it with the cache manifest, then prints what a build
+//! would do instead of doing it. Nothing is written, so it's a cheap way
+//! to check that a change only rebuilds what it should before starting a
+//! build that takes minutes.
+//!
+//! Pages created by `toast.js` are left out, since finding them means
+//! running the data file, and so are outputs of images, fonts, and other
+//! entries that only a full build checks, which are never listed as
+//! deleted.
+use std::{fmt, path::PathBuf};
+
+/// What a build would do, see `incremental::plan_build`
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct BuildPlan {
+    /// sources whose inputs changed since the last build, by source id
+    pub compile: Vec<String>,
+    /// sources the cache already has up to date outputs for
+    pub fresh: Vec<String>,
+    /// page modules to render
+    pub render: Vec<String>,
+    /// files in `static/`, relative to it
+    pub copy: Vec<PathBuf>,
+    /// outputs of sources that are gone, which pruning removes
+    pub delete: Vec<PathBuf>,
+    /// pages whose outputs are removed because they're drafts now
+    pub unpublish: Vec<String>,
+}
+
+impl fmt::Display for BuildPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for source in &self.compile {
+            writeln!(f, "Would compile {}", source)?;
+        }
+        for output in &self.delete {
+            writeln!(f, "Would delete {}", output.display())?;
+        }
+        for page in &self.unpublish {
+            writeln!(f, "Would unpublish {}", page)?;
+        }
+        write!(
+            f,
+            "Would compile {} of {} sources, render {} pages, copy {} static files, and delete {} outputs",
+            self.compile.len(),
+            self.compile.len() + self.fresh.len(),
+            self.render.len(),
+            self.copy.len(),
+            self.delete.len() + self.unpublish.len()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let plan = BuildPlan {
+            compile: vec![String::from("src/pages/index.js")],
+            fresh: vec![String::from("src/components/nav.js")],
+            render: vec![String::from("src/pages/index.js")],
+            copy: vec![PathBuf::from("favicon.ico")],
+            delete: vec![PathBuf::from("public/src/pages/old.js")],
+            unpublish: vec![],
+        };
+        assert_eq!(
+            plan.to_string(),
+            "Would compile src/pages/index.js\n\
+             Would delete public/src/pages/old.js\n\
+             Would compile 1 of 2 sources, render 1 pages, copy 1 static files, and delete 1 outputs"
+        );
+    }
+}
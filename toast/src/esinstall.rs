@@ -1,24 +1,229 @@
+use color_eyre::eyre::{self, eyre, WrapErr};
 use serde::{Deserialize, Serialize};
 use serde_json::Result;
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, fs, path::Path};
 use string_cache::Atom;
+use tracing::instrument;
 
-#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Hash, Clone)]
+use crate::{base_path::prefix_url, web_modules::browser_dependencies};
+
+type SpecifierMap = BTreeMap<Atom<swc_atoms::JsWordStaticSet>, Atom<swc_atoms::JsWordStaticSet>>;
+
+/// An [import map](https://github.com/WICG/import-maps). A module gets
+/// the `scopes` entry with the longest url prefix of its own url, and
+/// anything that scope doesn't map falls back to the next matching
+/// scope and then to `imports`.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Hash, Clone, Default)]
 pub struct ImportMap {
-    pub imports: BTreeMap<Atom<swc_atoms::JsWordStaticSet>, Atom<swc_atoms::JsWordStaticSet>>,
+    pub imports: SpecifierMap,
+    /// url prefix to the specifiers mapped differently for modules under it
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub scopes: BTreeMap<Atom<swc_atoms::JsWordStaticSet>, SpecifierMap>,
+}
+
+/// relative paths in import-map.json mean relative to the web_modules
+/// directory
+fn resolve_relative(url: &str) -> Atom<swc_atoms::JsWordStaticSet> {
+    match url.strip_prefix("./") {
+        Some(path) => Atom::from(format!("/web_modules/{}", path)),
+        None => Atom::from(url),
+    }
+}
+
+fn resolve_relative_values(map: &mut SpecifierMap) {
+    for (_key, value) in map.iter_mut() {
+        *value = resolve_relative(value);
+    }
+}
+
+/// Look `specifier` up in one specifier map. Keys ending in `/` map every
+/// specifier that starts with them, and the longest one wins.
+fn resolve_in(map: &SpecifierMap, specifier: &str) -> Option<Atom<swc_atoms::JsWordStaticSet>> {
+    if let Some(value) = map.get(&Atom::from(specifier)) {
+        return Some(value.clone());
+    }
+    map.iter()
+        .filter(|(key, _)| key.ends_with('/') && specifier.starts_with(key.as_ref() as &str))
+        .max_by_key(|(key, _)| key.len())
+        .map(|(key, value)| Atom::from(format!("{}{}", value, &specifier[key.len()..])))
+}
+
+impl ImportMap {
+    /// What `specifier` maps to when it's imported by the module at the
+    /// url `referrer`, like `/src/pages/index.js`
+    pub fn resolve(
+        &self,
+        specifier: &str,
+        referrer: &str,
+    ) -> Option<Atom<swc_atoms::JsWordStaticSet>> {
+        let mut scopes: Vec<(&Atom<swc_atoms::JsWordStaticSet>, &SpecifierMap)> = self
+            .scopes
+            .iter()
+            .filter(|(prefix, _)| {
+                let prefix: &str = prefix.as_ref();
+                referrer == prefix || (prefix.ends_with('/') && referrer.starts_with(prefix))
+            })
+            .collect();
+        scopes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        scopes
+            .into_iter()
+            .find_map(|(_, map)| resolve_in(map, specifier))
+            .or_else(|| resolve_in(&self.imports, specifier))
+    }
+
+    /// This import map for a site served under `base_path`, see
+    /// `base_path`. Mapped urls get the prefix, and `/` is mapped to the
+    /// base path so root-relative imports of other modules get it too.
+    pub fn with_base_path(&self, base_path: &str) -> ImportMap {
+        let prefix = |map: &SpecifierMap| -> SpecifierMap {
+            map.iter()
+                .map(|(key, value)| (key.clone(), Atom::from(prefix_url(base_path, value))))
+                .collect()
+        };
+        let mut imports = prefix(&self.imports);
+        imports
+            .entry(Atom::from("/"))
+            .or_insert_with(|| Atom::from(format!("{}/", base_path)));
+        ImportMap {
+            imports,
+            scopes: self
+                .scopes
+                .iter()
+                .map(|(scope, map)| (scope.clone(), prefix(map)))
+                .collect(),
+        }
+    }
 }
 
 pub fn parse_import_map(data: &str) -> Result<ImportMap> {
     let mut map: ImportMap = serde_json::from_str(data)?;
-    for (_key, value) in map.imports.iter_mut() {
-        // relative paths in the import-map.json values
-        // mean relative to the web_modules directory
-        if value.starts_with("./") {
-            let v = value.trim_start_matches("./");
-            *value = Atom::from(format!("/web_modules/{}", v));
+    resolve_relative_values(&mut map.imports);
+    map.scopes = std::mem::take(&mut map.scopes)
+        .into_iter()
+        .map(|(prefix, mut specifiers)| {
+            resolve_relative_values(&mut specifiers);
+            (resolve_relative(&prefix), specifiers)
+        })
+        .collect();
+    Ok(map)
+}
+
+/// Whether `package.json` in `project_root_dir` lists any dependencies
+/// that would need to be in an import map. `toast` itself is only used
+/// at build time, so it doesn't count.
+pub fn has_npm_dependencies(project_root_dir: &Path) -> bool {
+    fs::read_to_string(project_root_dir.join("package.json"))
+        .ok()
+        .and_then(|contents| browser_dependencies(&contents).ok())
+        .is_some_and(|dependencies| !dependencies.is_empty())
+}
+
+/// Read the import map at `import_map_filepath`. If it doesn't exist, the
+/// build can still go ahead with an empty import map when the project has
+/// no npm dependencies, or when `create` is set, in which case an empty
+/// import map is written so later builds find it.
+#[instrument]
+pub fn load_import_map(
+    import_map_filepath: &Path,
+    project_root_dir: &Path,
+    create: bool,
+) -> eyre::Result<ImportMap> {
+    if !import_map_filepath.exists() {
+        if create {
+            if let Some(dir) = import_map_filepath.parent() {
+                fs::create_dir_all(dir).wrap_err_with(|| {
+                    format!("Failed to create directories for `{}`", dir.display())
+                })?;
+            }
+            let empty = ImportMap::default();
+            fs::write(import_map_filepath, serde_json::to_string_pretty(&empty)?).wrap_err_with(
+                || {
+                    format!(
+                        "Failed to write import map to `{}`",
+                        import_map_filepath.display()
+                    )
+                },
+            )?;
+            return Ok(empty);
         }
+        if !has_npm_dependencies(project_root_dir) {
+            return Ok(ImportMap::default());
+        }
+        return Err(eyre!(
+            "No import map at `{}`, but package.json has dependencies that need one. Run `toast import-map` to generate it, or pass `--create-import-map` to start with an empty one.",
+            import_map_filepath.display()
+        ));
     }
-    Ok(map)
+    let contents = fs::read_to_string(import_map_filepath).wrap_err_with(|| {
+        format!(
+            "Failed to read `import-map.json` from `{}`",
+            import_map_filepath.display()
+        )
+    })?;
+    parse_import_map(&contents).wrap_err_with(|| {
+        format!(
+            "Failed to parse import map from content `{}` at `{}`",
+            contents,
+            import_map_filepath.display()
+        )
+    })
 }
 
-// pub let empty =
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scopes() -> Result<()> {
+        let map = parse_import_map(
+            r#"{
+              "imports": {
+                "preact": "./preact.js",
+                "lodash/": "./lodash/"
+              },
+              "scopes": {
+                "./legacy-widget/": { "preact": "./preact-8.js" },
+                "/src/pages/old/": { "lodash/": "./lodash-3/" }
+              }
+            }"#,
+        )?;
+        assert_eq!(
+            map.resolve("preact", "/src/pages/index.js").as_deref(),
+            Some("/web_modules/preact.js")
+        );
+        assert_eq!(
+            map.resolve("preact", "/web_modules/legacy-widget/index.js")
+                .as_deref(),
+            Some("/web_modules/preact-8.js")
+        );
+        // not mapped by the scope, so it falls back to `imports`
+        assert_eq!(
+            map.resolve("preact", "/src/pages/old/post.js").as_deref(),
+            Some("/web_modules/preact.js")
+        );
+        assert_eq!(
+            map.resolve("lodash/get.js", "/src/pages/old/post.js")
+                .as_deref(),
+            Some("/web_modules/lodash-3/get.js")
+        );
+        assert_eq!(map.resolve("react", "/src/pages/index.js"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_base_path() -> Result<()> {
+        let map = parse_import_map(r#"{ "imports": { "preact": "./preact.js" } }"#)?
+            .with_base_path("/my-project");
+        assert_eq!(
+            map.resolve("preact", "/src/pages/index.js").as_deref(),
+            Some("/my-project/web_modules/preact.js")
+        );
+        assert_eq!(
+            map.resolve("/src/components/nav.js", "/src/pages/index.js")
+                .as_deref(),
+            Some("/my-project/src/components/nav.js")
+        );
+        assert_eq!(map.resolve("./nav.js", "/src/pages/index.js"), None);
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+//! Excerpts of `.mdx` and `.md` pages for listing pages and feeds. Every
+//! content page gets an `excerpt` prop, plain text without any markdown,
+//! which is also in its entry in the content index and is the summary of
+//! its feed item when it has no `description`.
+//!
+//! ```toml
+//! [excerpt]
+//! # `description`, `more`, `paragraph`, or `auto` for the first of them
+//! # that the page has
+//! from = "auto"
+//! separator = "<!-- more -->"
+//! ```
+//!
+//! `description` is the frontmatter key, `more` is everything before the
+//! separator, and `paragraph` is the first paragraph after any headings.
+//! An `excerpt` in a page's frontmatter is used as it is. MDX doesn't
+//! allow html comments, so `.mdx` sites can use `separator = "{/* more */}"`.
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::search::plain_text;
+
+const DEFAULT_SEPARATOR: &str = "<!-- more -->";
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExcerptFrom {
+    #[default]
+    Auto,
+    Description,
+    More,
+    Paragraph,
+}
+
+/// `[excerpt]` in `toast.config.toml`
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExcerptConfig {
+    pub from: ExcerptFrom,
+    /// ends the excerpt for `more`, `<!-- more -->` by default
+    pub separator: Option<String>,
+}
+
+fn description(frontmatter: Option<&Value>) -> Option<String> {
+    frontmatter?.get("description")?.as_str().map(String::from)
+}
+
+/// Everything before `separator`, without headings
+fn before_separator(body: &str, separator: &str) -> Option<String> {
+    let (before, _) = body.split_once(separator)?;
+    let lines: Vec<&str> = before
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .collect();
+    Some(plain_text(&lines.join("\n")))
+}
+
+/// The first run of lines that isn't a heading, code, JSX, or an import
+fn first_paragraph(body: &str) -> Option<String> {
+    let mut paragraph: Vec<&str> = vec![];
+    let mut in_fence = false;
+    let mut in_esm = false;
+    for line in body.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_fence = !in_fence;
+            continue;
+        }
+        if in_fence {
+            continue;
+        }
+        if in_esm || line.starts_with("import ") || line.starts_with("export ") {
+            in_esm = !trimmed.is_empty();
+            continue;
+        }
+        if trimmed.is_empty() && !paragraph.is_empty() {
+            break;
+        }
+        if !trimmed.is_empty()
+            && !(paragraph.is_empty() && (trimmed.starts_with('#') || trimmed.starts_with('<')))
+        {
+            paragraph.push(line);
+        }
+    }
+    Some(plain_text(&paragraph.join("\n"))).filter(|text| !text.is_empty())
+}
+
+/// The excerpt of a page from its frontmatter and body
+pub fn excerpt(frontmatter: Option<&Value>, body: &str, config: &ExcerptConfig) -> Option<String> {
+    if let Some(excerpt) = frontmatter
+        .and_then(|frontmatter| frontmatter.get("excerpt"))
+        .and_then(|excerpt| excerpt.as_str())
+    {
+        return Some(excerpt.to_string());
+    }
+    let separator = config.separator.as_deref().unwrap_or(DEFAULT_SEPARATOR);
+    match config.from {
+        ExcerptFrom::Description => description(frontmatter),
+        ExcerptFrom::More => before_separator(body, separator),
+        ExcerptFrom::Paragraph => first_paragraph(body),
+        ExcerptFrom::Auto => description(frontmatter)
+            .or_else(|| before_separator(body, separator))
+            .or_else(|| first_paragraph(body)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const BODY: &str = "import Chart from \"../chart.js\";\n\n# Hello\n\nThe **first** paragraph,\nwith a [link](/about).\n\nMore.\n\n<!-- more -->\n\nThe rest.\n";
+
+    #[test]
+    fn test_excerpt() {
+        let config = ExcerptConfig::default();
+        let frontmatter = json!({ "description": "About hello" });
+        assert_eq!(
+            excerpt(Some(&frontmatter), BODY, &config).as_deref(),
+            Some("About hello")
+        );
+        assert_eq!(
+            excerpt(None, BODY, &config).as_deref(),
+            Some("The first paragraph, with a link. More.")
+        );
+        let config = ExcerptConfig {
+            from: ExcerptFrom::Paragraph,
+            ..ExcerptConfig::default()
+        };
+        assert_eq!(
+            excerpt(Some(&frontmatter), BODY, &config).as_deref(),
+            Some("The first paragraph, with a link.")
+        );
+    }
+}
@@ -0,0 +1,109 @@
+//! The codes toast exits with, so CI can tell kinds of failures apart,
+//! like retrying a build only when rendering failed:
+//!
+//! | code      | meaning                                                       |
+//! |-----------|---------------------------------------------------------------|
+//! | 0         | success                                                       |
+//! | 1         | any other failure, including flags that don't parse           |
+//! | 2         | the config file couldn't be loaded or parsed                  |
+//! | 3         | a page or component failed to compile                         |
+//! | 4         | a page threw while rendering, or the renderer crashed or hung |
+//! | 5         | broken links were found by `--check-links` or `--strict`      |
+//! | 6         | a cache manifest couldn't be read                             |
+//! | 7         | `toast deploy` or sending a build to `s3://` failed           |
+//! | 128 + sig | the build was cancelled by a signal, see `cancel`             |
+//!
+//! When a build fails in more than one way, the code is of the earliest
+//! step, so a page that didn't compile wins over a broken link it would
+//! have fixed.
+//!
+//! Errors are tagged by wrapping them with a `Failure`, or, for what the
+//! build reports to `diagnostics`, by the diagnostic's `kind`.
+use color_eyre::eyre::Report;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::diagnostics::{BuildFailed, CompileError};
+
+/// Anything that doesn't have its own code
+pub const OTHER_FAILURE: i32 = 1;
+
+#[derive(Error, Debug, Clone, Copy, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub enum Failure {
+    #[error("The config is invalid")]
+    Config,
+    #[error("A module failed to compile")]
+    Compile,
+    #[error("Pages failed to render")]
+    Render,
+    #[error("The link check failed")]
+    LinkCheck,
+    #[error("The cache is corrupt")]
+    Cache,
+    #[error("The deploy failed")]
+    Deploy,
+}
+
+impl Failure {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Failure::Config => 2,
+            Failure::Compile => 3,
+            Failure::Render => 4,
+            Failure::LinkCheck => 5,
+            Failure::Cache => 6,
+            Failure::Deploy => 7,
+        }
+    }
+}
+
+/// The kind of failure `report` is, if it's been tagged with one
+pub fn classify(report: &Report) -> Option<Failure> {
+    if let Some(failed) = report.downcast_ref::<BuildFailed>() {
+        return failed.kind;
+    }
+    if let Some(failure) = report.downcast_ref::<Failure>() {
+        return Some(*failure);
+    }
+    report.chain().find_map(|error| {
+        if let Some(failure) = error.downcast_ref::<Failure>() {
+            Some(*failure)
+        } else if error.is::<CompileError>() {
+            Some(Failure::Compile)
+        } else {
+            None
+        }
+    })
+}
+
+/// The code to exit with after failing with `report`
+pub fn exit_code(report: &Report) -> i32 {
+    classify(report).map_or(OTHER_FAILURE, Failure::exit_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use color_eyre::eyre::{eyre, WrapErr};
+
+    #[test]
+    fn test_exit_codes() {
+        let config: Result<(), Report> = Err(eyre!("expected `=`"))
+            .wrap_err(Failure::Config)
+            .wrap_err("Failed to load settings");
+        assert_eq!(exit_code(&config.unwrap_err()), 2);
+
+        let compile: Result<(), Report> =
+            Err(CompileError::new("src/pages/index.js", "oops", "", None, 0))
+                .wrap_err("Failed to compile `src/pages/index.js`");
+        assert_eq!(exit_code(&compile.unwrap_err()), 3);
+
+        let build = Report::new(BuildFailed {
+            message: String::from("The build had 2 error(s)"),
+            kind: Some(Failure::Render),
+        });
+        assert_eq!(exit_code(&build), 4);
+        assert_eq!(exit_code(&eyre!("something else")), OTHER_FAILURE);
+    }
+}
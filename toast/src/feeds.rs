@@ -0,0 +1,332 @@
+use chrono::{DateTime, FixedOffset, NaiveDate, TimeZone, Utc};
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use serde::Deserialize;
+use serde_json::Value;
+use std::{fs, path::Path};
+use tracing::instrument;
+
+use crate::url_style::UrlStyle;
+
+/// A feed for one collection of pages, configured with `[[feeds]]` in
+/// `toast.config.toml`
+///
+/// ```toml
+/// [[feeds]]
+/// collection = "blog/*"
+/// title = "My Blog"
+/// ```
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FeedConfig {
+    /// glob matched against page slugs, without the leading `/`
+    pub collection: String,
+    pub title: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// directory the feed files are written to, relative to the output
+    /// directory. Defaults to the part of `collection` before any glob.
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default = "default_formats")]
+    pub formats: Vec<FeedFormat>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FeedFormat {
+    Rss,
+    Atom,
+}
+
+fn default_formats() -> Vec<FeedFormat> {
+    vec![FeedFormat::Rss, FeedFormat::Atom]
+}
+
+/// The page metadata a feed entry is made from
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeedItem {
+    pub slug: String,
+    pub title: String,
+    pub date: Option<DateTime<FixedOffset>>,
+    pub description: Option<String>,
+}
+
+impl FeedItem {
+    /// Pages take part in feeds if their data has a `title`. `date` can
+    /// be a plain `2020-09-01` date or a full RFC 3339 timestamp.
+    pub fn from_page_data(slug: &str, data: &Value) -> Option<FeedItem> {
+        let title = data.get("title")?.as_str()?.to_string();
+        let date = data
+            .get("date")
+            .and_then(|date| date.as_str())
+            .and_then(parse_date);
+        // content pages have an excerpt, see `excerpt`
+        let description = data
+            .get("description")
+            .or_else(|| data.get("excerpt"))
+            .and_then(|description| description.as_str())
+            .map(String::from);
+        Some(FeedItem {
+            slug: slug.to_string(),
+            title,
+            date,
+            description,
+        })
+    }
+}
+
+pub fn parse_date(date: &str) -> Option<DateTime<FixedOffset>> {
+    DateTime::parse_from_rfc3339(date).ok().or_else(|| {
+        let naive = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+            .ok()?
+            .and_hms_opt(0, 0, 0)?;
+        Some(Utc.from_utc_datetime(&naive).into())
+    })
+}
+
+impl FeedConfig {
+    pub fn matches(&self, slug: &str) -> bool {
+        glob::Pattern::new(&self.collection)
+            .map(|pattern| {
+                pattern.matches_with(
+                    slug.trim_start_matches('/'),
+                    glob::MatchOptions {
+                        require_literal_separator: true,
+                        ..glob::MatchOptions::new()
+                    },
+                )
+            })
+            .unwrap_or(false)
+    }
+
+    /// Directory the feed files for this collection are written to
+    pub fn output_path(&self) -> String {
+        match &self.path {
+            Some(path) => path.trim_matches('/').to_string(),
+            None => self
+                .collection
+                .split('/')
+                .take_while(|segment| !segment.contains(&['*', '?', '['][..]))
+                .collect::<Vec<&str>>()
+                .join("/"),
+        }
+    }
+
+    /// All of the items in this collection, newest first
+    pub fn collect_items(&self, items: &[FeedItem]) -> Vec<FeedItem> {
+        let mut matching: Vec<FeedItem> = items
+            .iter()
+            .filter(|item| self.matches(&item.slug))
+            .cloned()
+            .collect();
+        matching.sort_by(|a, b| b.date.cmp(&a.date).then_with(|| a.slug.cmp(&b.slug)));
+        matching
+    }
+}
+
+/// Write every configured feed into `output_dir`
+#[instrument(skip(items))]
+pub fn write_feeds(
+    feeds: &[FeedConfig],
+    base_url: Option<&str>,
+    url_style: UrlStyle,
+    items: &[FeedItem],
+    output_dir: &Path,
+) -> Result<()> {
+    if feeds.is_empty() {
+        return Ok(());
+    }
+    let base_url =
+        base_url.ok_or_else(|| eyre!("`base_url` must be set in the config to generate feeds"))?;
+    for feed in feeds {
+        let feed_items = feed.collect_items(items);
+        let dir = output_dir.join(feed.output_path());
+        fs::create_dir_all(&dir)
+            .wrap_err_with(|| format!("Failed to create feed directory `{}`", dir.display()))?;
+        for format in &feed.formats {
+            let (filename, contents) = match format {
+                FeedFormat::Rss => (
+                    "rss.xml",
+                    render_rss(feed, base_url, url_style, &feed_items),
+                ),
+                FeedFormat::Atom => (
+                    "atom.xml",
+                    render_atom(feed, base_url, url_style, &feed_items),
+                ),
+            };
+            let file = dir.join(filename);
+            fs::write(&file, contents)
+                .wrap_err_with(|| format!("Failed to write feed `{}`", file.display()))?;
+        }
+    }
+    Ok(())
+}
+
+fn absolute_url(base_url: &str, path: &str) -> String {
+    format!(
+        "{}/{}",
+        base_url.trim_end_matches('/'),
+        path.trim_start_matches('/')
+    )
+}
+
+pub fn render_rss(
+    feed: &FeedConfig,
+    base_url: &str,
+    url_style: UrlStyle,
+    items: &[FeedItem],
+) -> String {
+    let mut xml = String::from(r#"<?xml version="1.0" encoding="utf-8"?>"#);
+    xml.push_str(r#"<rss version="2.0"><channel>"#);
+    xml.push_str(&format!("<title>{}</title>", escape_xml(&feed.title)));
+    xml.push_str(&format!(
+        "<link>{}</link>",
+        escape_xml(&absolute_url(base_url, &feed.output_path()))
+    ));
+    xml.push_str(&format!(
+        "<description>{}</description>",
+        escape_xml(feed.description.as_deref().unwrap_or(&feed.title))
+    ));
+    for item in items {
+        let link = escape_xml(&absolute_url(base_url, &url_style.url(&item.slug)));
+        xml.push_str("<item>");
+        xml.push_str(&format!("<title>{}</title>", escape_xml(&item.title)));
+        xml.push_str(&format!("<link>{}</link>", link));
+        xml.push_str(&format!("<guid>{}</guid>", link));
+        if let Some(date) = &item.date {
+            xml.push_str(&format!("<pubDate>{}</pubDate>", date.to_rfc2822()));
+        }
+        if let Some(description) = &item.description {
+            xml.push_str(&format!(
+                "<description>{}</description>",
+                escape_xml(description)
+            ));
+        }
+        xml.push_str("</item>");
+    }
+    xml.push_str("</channel></rss>");
+    xml
+}
+
+pub fn render_atom(
+    feed: &FeedConfig,
+    base_url: &str,
+    url_style: UrlStyle,
+    items: &[FeedItem],
+) -> String {
+    let feed_url = absolute_url(base_url, &format!("{}/atom.xml", feed.output_path()));
+    // atom requires an updated timestamp, which is the newest entry
+    let updated = items
+        .iter()
+        .filter_map(|item| item.date)
+        .max()
+        .map(|date| date.to_rfc3339())
+        .unwrap_or_else(|| "1970-01-01T00:00:00+00:00".to_string());
+    let mut xml = String::from(r#"<?xml version="1.0" encoding="utf-8"?>"#);
+    xml.push_str(r#"<feed xmlns="http://www.w3.org/2005/Atom">"#);
+    xml.push_str(&format!("<title>{}</title>", escape_xml(&feed.title)));
+    xml.push_str(&format!("<id>{}</id>", escape_xml(&feed_url)));
+    xml.push_str(&format!(
+        r#"<link rel="self" href="{}"/>"#,
+        escape_xml(&feed_url)
+    ));
+    xml.push_str(&format!("<updated>{}</updated>", updated));
+    for item in items {
+        let link = escape_xml(&absolute_url(base_url, &url_style.url(&item.slug)));
+        xml.push_str("<entry>");
+        xml.push_str(&format!("<title>{}</title>", escape_xml(&item.title)));
+        xml.push_str(&format!(r#"<link href="{}"/>"#, link));
+        xml.push_str(&format!("<id>{}</id>", link));
+        if let Some(date) = &item.date {
+            xml.push_str(&format!("<updated>{}</updated>", date.to_rfc3339()));
+        }
+        if let Some(description) = &item.description {
+            xml.push_str(&format!("<summary>{}</summary>", escape_xml(description)));
+        }
+        xml.push_str("</entry>");
+    }
+    xml.push_str("</feed>");
+    xml
+}
+
+pub fn escape_xml(input: &str) -> String {
+    input
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn blog_feed() -> FeedConfig {
+        FeedConfig {
+            collection: String::from("blog/*"),
+            title: String::from("Blog & Things"),
+            description: None,
+            path: None,
+            formats: default_formats(),
+        }
+    }
+
+    fn item(slug: &str, date: &str) -> FeedItem {
+        FeedItem::from_page_data(
+            slug,
+            &json!({ "title": format!("post {}", slug), "date": date }),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_collection_matching() {
+        let feed = blog_feed();
+        assert!(feed.matches("/blog/first-post"));
+        assert!(!feed.matches("/blog/nested/post"));
+        assert!(!feed.matches("/garden/first-post"));
+        assert_eq!(feed.output_path(), "blog");
+    }
+
+    #[test]
+    fn test_pages_without_titles_are_skipped() {
+        assert_eq!(
+            FeedItem::from_page_data("/blog/a", &json!({ "date": "2020-09-01" })),
+            None
+        );
+    }
+
+    #[test]
+    fn test_items_are_newest_first() {
+        let items = vec![
+            item("/blog/old", "2019-01-01"),
+            item("/blog/new", "2020-09-01T10:00:00Z"),
+            item("/garden/other", "2021-01-01"),
+        ];
+        let slugs: Vec<String> = blog_feed()
+            .collect_items(&items)
+            .into_iter()
+            .map(|item| item.slug)
+            .collect();
+        assert_eq!(slugs, vec!["/blog/new", "/blog/old"]);
+    }
+
+    #[test]
+    fn test_render_rss() {
+        let items = vec![item("/blog/a", "2020-09-01")];
+        let rss = render_rss(&blog_feed(), "https://toast.dev/", UrlStyle::Bare, &items);
+        assert!(rss.contains("<title>Blog &amp; Things</title>"));
+        assert!(rss.contains("<link>https://toast.dev/blog/a</link>"));
+        assert!(rss.contains("Sep 2020 00:00:00 +0000</pubDate>"));
+    }
+
+    #[test]
+    fn test_render_atom() {
+        let items = vec![item("/blog/a", "2020-09-01")];
+        let atom = render_atom(&blog_feed(), "https://toast.dev", UrlStyle::Bare, &items);
+        assert!(atom.contains(r#"<link rel="self" href="https://toast.dev/blog/atom.xml"/>"#));
+        assert!(atom.contains("<updated>2020-09-01T00:00:00+00:00</updated>"));
+    }
+}
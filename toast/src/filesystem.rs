@@ -0,0 +1,298 @@
+//! Where `incremental` finds sources and writes what it compiles. Finding
+//! pages and sources, compiling them, writing page props and frontmatter
+//! json, copying `static/`, and the cache's pruning of the outputs of
+//! deleted files go through `IncrementalOpts::fs` instead of `std::fs`.
+//! That's `RealFs` for builds from the CLI, or `S3Fs`, which is the disk
+//! with each finished build sent to a bucket. `MemoryFs` keeps files in
+//! memory, so those steps can be tested without touching the disk, and
+//! other backends only have to implement `Filesystem`.
+//!
+//! Only those steps, up to rendering, use `fs`. The cache manifest is
+//! always on disk. Rendering, and the node scripts that bundle web
+//! modules, process images and fonts, and subset them, run in other
+//! processes that read and write the disk. The steps after rendering,
+//! like css, feeds, search, redirects, fingerprinting, base paths,
+//! integrity, headers, and precompression, read and rewrite the output
+//! directory with `std::fs`. So a build with `MemoryFs` can compile, but
+//! it can't run to the end.
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+use walkdir::WalkDir;
+
+pub trait Filesystem: fmt::Debug + Send + Sync {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        String::from_utf8(self.read(path)?)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()>;
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let contents = self.read(from)?;
+        self.write(to, &contents)
+    }
+
+    /// Whether there's a file or a directory at `path`
+    fn exists(&self, path: &Path) -> bool;
+
+    fn is_file(&self, path: &Path) -> bool;
+
+    /// When the file at `path` was last written
+    fn mtime(&self, path: &Path) -> io::Result<SystemTime>;
+
+    /// How many bytes the file at `path` has
+    fn size(&self, path: &Path) -> io::Result<u64>;
+
+    /// Every file under `dir`, sorted by path so builds see them in the
+    /// same order everywhere
+    fn walk(&self, dir: &Path) -> Vec<PathBuf>;
+
+    /// Called once a build has been swapped into the output directory, for
+    /// backends that send it somewhere else, like `s3::S3Fs`
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The disk
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RealFs {
+    /// walk into symlinked directories and list symlinked files, see
+    /// `pages`
+    pub follow_symlinks: bool,
+}
+
+impl Filesystem for RealFs {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        fs::read_to_string(path)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        fs::write(path, contents)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::copy(from, to).map(|_| ())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn mtime(&self, path: &Path) -> io::Result<SystemTime> {
+        fs::metadata(path)?.modified()
+    }
+
+    fn size(&self, path: &Path) -> io::Result<u64> {
+        Ok(fs::metadata(path)?.len())
+    }
+
+    fn walk(&self, dir: &Path) -> Vec<PathBuf> {
+        // walkdir reports a link to one of its own ancestors as an error
+        // instead of following it, so loops are skipped with the other
+        // errors
+        WalkDir::new(dir)
+            .follow_links(self.follow_symlinks)
+            .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+            .into_iter()
+            .filter_map(|result| result.ok())
+            .filter(|entry| entry.file_type().is_file())
+            .map(|entry| entry.into_path())
+            .collect()
+    }
+}
+
+#[derive(Debug, Default)]
+struct MemoryFiles {
+    files: BTreeMap<PathBuf, (Vec<u8>, SystemTime)>,
+    dirs: BTreeSet<PathBuf>,
+}
+
+impl MemoryFiles {
+    fn has_dir(&self, path: &Path) -> bool {
+        self.dirs.contains(path) || self.files.keys().any(|file| file.starts_with(path))
+    }
+}
+
+/// Files kept in memory. Like the disk, files can only be written to
+/// directories that exist.
+#[derive(Debug, Default)]
+pub struct MemoryFs {
+    files: Mutex<MemoryFiles>,
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("`{}` doesn't exist", path.display()),
+    )
+}
+
+impl MemoryFs {
+    pub fn new() -> MemoryFs {
+        MemoryFs::default()
+    }
+
+    /// A filesystem with `files` in it, and the directories they're in
+    pub fn with_files<P: Into<PathBuf>, C: Into<Vec<u8>>>(
+        files: impl IntoIterator<Item = (P, C)>,
+    ) -> MemoryFs {
+        let fs = MemoryFs::new();
+        for (path, contents) in files {
+            let path = path.into();
+            if let Some(parent) = path.parent() {
+                let _ = fs.create_dir_all(parent);
+            }
+            let _ = fs.write(&path, &contents.into());
+        }
+        fs
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MemoryFiles> {
+        // a panic while holding the lock can't leave the maps half-updated
+        self.files
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Filesystem for MemoryFs {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.lock()
+            .files
+            .get(path)
+            .map(|(contents, _)| contents.clone())
+            .ok_or_else(|| not_found(path))
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        let mut files = self.lock();
+        match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() && !files.has_dir(parent) => {
+                Err(not_found(parent))
+            }
+            _ => {
+                files
+                    .files
+                    .insert(path.to_path_buf(), (contents.to_vec(), SystemTime::now()));
+                Ok(())
+            }
+        }
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut files = self.lock();
+        for ancestor in path.ancestors() {
+            files.dirs.insert(ancestor.to_path_buf());
+        }
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.lock()
+            .files
+            .remove(path)
+            .map(|_| ())
+            .ok_or_else(|| not_found(path))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        let files = self.lock();
+        files.files.contains_key(path) || files.has_dir(path)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.lock().files.contains_key(path)
+    }
+
+    fn mtime(&self, path: &Path) -> io::Result<SystemTime> {
+        self.lock()
+            .files
+            .get(path)
+            .map(|(_, modified)| *modified)
+            .ok_or_else(|| not_found(path))
+    }
+
+    fn size(&self, path: &Path) -> io::Result<u64> {
+        self.lock()
+            .files
+            .get(path)
+            .map(|(contents, _)| contents.len() as u64)
+            .ok_or_else(|| not_found(path))
+    }
+
+    fn walk(&self, dir: &Path) -> Vec<PathBuf> {
+        self.lock()
+            .files
+            .keys()
+            .filter(|path| path.starts_with(dir))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_fs() {
+        let fs = MemoryFs::with_files(vec![
+            ("site/src/pages/index.js", "export default () => null;"),
+            ("site/src/nav.js", "export const nav = [];"),
+        ]);
+        assert_eq!(
+            fs.walk(Path::new("site/src")),
+            vec![
+                PathBuf::from("site/src/nav.js"),
+                PathBuf::from("site/src/pages/index.js")
+            ]
+        );
+        assert!(fs.exists(Path::new("site/src/pages")));
+        assert!(!fs.is_file(Path::new("site/src/pages")));
+
+        assert!(fs
+            .write(Path::new("site/public/index.js"), b"compiled")
+            .is_err());
+        fs.create_dir_all(Path::new("site/public")).unwrap();
+        fs.copy(
+            Path::new("site/src/nav.js"),
+            Path::new("site/public/nav.js"),
+        )
+        .unwrap();
+        assert_eq!(
+            fs.read_to_string(Path::new("site/public/nav.js")).unwrap(),
+            "export const nav = [];"
+        );
+        assert_eq!(fs.size(Path::new("site/public/nav.js")).unwrap(), 22);
+        fs.remove_file(Path::new("site/public/nav.js")).unwrap();
+        assert!(fs.read(Path::new("site/public/nav.js")).is_err());
+    }
+}
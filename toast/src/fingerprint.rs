@@ -0,0 +1,500 @@
+//! Content-hashed copies of every JS and CSS file in the output directory,
+//! so they can be cached forever by a CDN. Turned on with
+//! `fingerprint = true` in `toast.config.toml`.
+//!
+//! `src/pages/index.js` gets a copy at `src/pages/index.3f2a1b6c9d0e4f5a.js`.
+//! Imports between modules, `url()`s in CSS, and references from html
+//! pages are all rewritten to the hashed copies. A file's hash includes
+//! the hashes of everything it imports, so changing a module changes the
+//! name of every module that depends on it. The original files are left
+//! in place for the dev server and incremental rebuilds, and
+//! `asset-manifest.json` maps each original url to its hashed url.
+use color_eyre::eyre::{Result, WrapErr};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    path::{Component, Path, PathBuf},
+};
+use tracing::instrument;
+use walkdir::WalkDir;
+
+use crate::{base_path::strip_base_path, cache::content_hash};
+
+pub const MANIFEST_FILE: &str = "asset-manifest.json";
+
+/// Directories that hold data, not assets
+const SKIPPED_DIRS: &[&str] = &["toast-data", "_images"];
+
+/// A url in a file that might point at another asset, with its position
+/// so it can be replaced
+#[derive(Debug, Clone, PartialEq)]
+pub struct Specifier {
+    pub start: usize,
+    pub end: usize,
+    pub url: String,
+}
+
+fn is_asset(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|ext| ext.to_str()),
+        Some("js") | Some("mjs") | Some("css")
+    )
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '$' || c == '.'
+}
+
+/// The quoted string starting at the beginning of `rest`, if there is one
+fn quoted(rest: &str) -> Option<(usize, &str)> {
+    let quote = rest
+        .chars()
+        .next()
+        .filter(|c| matches!(c, '"' | '\'' | '`'))?;
+    let end = rest[1..].find(quote)?;
+    Some((1, &rest[1..end + 1]))
+}
+
+/// Module specifiers in `import ... from "x"`, `export ... from "x"`,
+/// `import "x"`, and `import("x")`. This is a scan of the source rather
+/// than a parse, so a string that looks like an import inside a comment
+/// can show up too. Those only get rewritten if they point at a real file.
+pub fn js_specifiers(source: &str) -> Vec<Specifier> {
+    let mut specifiers = vec![];
+    for keyword in &["from", "import"] {
+        for (index, _) in source.match_indices(keyword) {
+            let before = source[..index].chars().next_back();
+            if before.is_some_and(is_ident_char) {
+                continue;
+            }
+            let after = index + keyword.len();
+            let rest = &source[after..];
+            let trimmed = rest.trim_start();
+            let (trimmed, call) = match trimmed.strip_prefix('(') {
+                Some(inner) => (inner.trim_start(), true),
+                None => (trimmed, false),
+            };
+            if call && *keyword != "import" {
+                continue;
+            }
+            if let Some((offset, url)) = quoted(trimmed) {
+                let start = after + (rest.len() - trimmed.len()) + offset;
+                specifiers.push(Specifier {
+                    start,
+                    end: start + url.len(),
+                    url: url.to_string(),
+                });
+            }
+        }
+    }
+    specifiers.sort_by_key(|specifier| specifier.start);
+    specifiers.dedup_by_key(|specifier| specifier.start);
+    specifiers
+}
+
+/// Urls in `url(...)` and `@import "..."`
+pub fn css_specifiers(source: &str) -> Vec<Specifier> {
+    let mut specifiers = vec![];
+    for (index, _) in source.match_indices("url(") {
+        let after = index + "url(".len();
+        let rest = &source[after..];
+        let trimmed = rest.trim_start();
+        let leading = rest.len() - trimmed.len();
+        let (offset, url) = match quoted(trimmed) {
+            Some(found) => found,
+            None => match trimmed.find(')') {
+                Some(end) => (0, trimmed[..end].trim_end()),
+                None => continue,
+            },
+        };
+        let start = after + leading + offset;
+        specifiers.push(Specifier {
+            start,
+            end: start + url.len(),
+            url: url.to_string(),
+        });
+    }
+    for (index, _) in source.match_indices("@import") {
+        let after = index + "@import".len();
+        let rest = &source[after..];
+        let trimmed = rest.trim_start();
+        if let Some((offset, url)) = quoted(trimmed) {
+            let start = after + (rest.len() - trimmed.len()) + offset;
+            specifiers.push(Specifier {
+                start,
+                end: start + url.len(),
+                url: url.to_string(),
+            });
+        }
+    }
+    specifiers.sort_by_key(|specifier| specifier.start);
+    specifiers.dedup_by_key(|specifier| specifier.start);
+    specifiers
+}
+
+/// Root-relative urls in quotes, which is how pages refer to their
+/// component, page wrapper, stylesheets, and scripts
+pub fn html_specifiers(source: &str) -> Vec<Specifier> {
+    let mut specifiers = vec![];
+    for quote in &['"', '\''] {
+        let opening = format!("{}/", quote);
+        for (index, _) in source.match_indices(&opening) {
+            let start = index + 1;
+            if let Some(len) = source[start..].find(*quote) {
+                let url = &source[start..start + len];
+                if !url.contains(char::is_whitespace) {
+                    specifiers.push(Specifier {
+                        start,
+                        end: start + len,
+                        url: url.to_string(),
+                    });
+                }
+            }
+        }
+    }
+    specifiers.sort_by_key(|specifier| specifier.start);
+    specifiers
+}
+
+/// The file in `output_dir` that `url` points at from `from_dir`, which
+/// is relative to the output directory. Bare specifiers and other
+/// origins aren't files we know about.
+fn resolve(url: &str, from_dir: &Path) -> Option<PathBuf> {
+    let path = url.split(&['?', '#'][..]).next()?;
+    let joined = if let Some(absolute) = path.strip_prefix('/') {
+        if absolute.starts_with('/') {
+            // protocol-relative, like //cdn.example.com
+            return None;
+        }
+        PathBuf::from(absolute)
+    } else if path.starts_with("./") || path.starts_with("../") {
+        from_dir.join(path)
+    } else {
+        return None;
+    };
+    let mut normalized = PathBuf::new();
+    for component in joined.components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    return None;
+                }
+            }
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    Some(normalized)
+}
+
+/// `index.js` with hash `abc` is `index.abc.js`
+pub fn hashed_path(path: &Path, hash: &str) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("");
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => path.with_file_name(format!("{}.{}.{}", stem, hash, ext)),
+        None => path.with_file_name(format!("{}.{}", stem, hash)),
+    }
+}
+
+/// Swap the file name at the end of `url` for the hashed one, keeping
+/// whether it was relative and any query or fragment
+fn rewrite_url(url: &str, hashed: &Path) -> String {
+    let split = url.find(&['?', '#'][..]).unwrap_or(url.len());
+    let (path, suffix) = url.split_at(split);
+    let file_name = hashed
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+    match path.rfind('/') {
+        Some(slash) => format!("{}{}{}", &path[..=slash], file_name, suffix),
+        None => format!("{}{}", file_name, suffix),
+    }
+}
+
+fn replace_specifiers(
+    source: &str,
+    specifiers: &[Specifier],
+    from_dir: &Path,
+    base_path: Option<&str>,
+    hashed: &HashMap<PathBuf, PathBuf>,
+) -> String {
+    let mut output = String::with_capacity(source.len());
+    let mut last = 0;
+    for specifier in specifiers {
+        if let Some(target) = resolve(strip_base_path(base_path, &specifier.url), from_dir)
+            .and_then(|path| hashed.get(&path))
+        {
+            output.push_str(&source[last..specifier.start]);
+            output.push_str(&rewrite_url(&specifier.url, target));
+            last = specifier.end;
+        }
+    }
+    output.push_str(&source[last..]);
+    output
+}
+
+fn specifiers_for(path: &Path, source: &str) -> Vec<Specifier> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("css") => css_specifiers(source),
+        _ => js_specifiers(source),
+    }
+}
+
+struct Asset {
+    source: String,
+    dependencies: Vec<PathBuf>,
+}
+
+/// Hash an asset along with the hashes of its dependencies. An import
+/// cycle is broken by leaving the module that closes it out of the hash.
+fn hash_asset(
+    path: &Path,
+    assets: &HashMap<PathBuf, Asset>,
+    hashes: &mut HashMap<PathBuf, String>,
+    visiting: &mut HashSet<PathBuf>,
+) -> String {
+    if let Some(hash) = hashes.get(path) {
+        return hash.clone();
+    }
+    visiting.insert(path.to_path_buf());
+    let asset = &assets[path];
+    let mut dependency_hashes = vec![];
+    for dependency in &asset.dependencies {
+        if !visiting.contains(dependency) {
+            dependency_hashes.push(hash_asset(dependency, assets, hashes, visiting));
+        }
+    }
+    visiting.remove(path);
+    let mut inputs: Vec<&[u8]> = vec![asset.source.as_bytes()];
+    inputs.extend(dependency_hashes.iter().map(|hash| hash.as_bytes()));
+    let hash = content_hash(&inputs);
+    hashes.insert(path.to_path_buf(), hash.clone());
+    hash
+}
+
+fn url_for(path: &Path) -> String {
+    format!(
+        "/{}",
+        path.components()
+            .filter_map(|component| component.as_os_str().to_str())
+            .collect::<Vec<_>>()
+            .join("/")
+    )
+}
+
+/// Write a hashed copy of every JS and CSS file in `output_dir`, rewrite
+/// references to them, and write `asset-manifest.json`. Hashed copies from
+/// the previous build are removed first. Imports in browser modules
+/// include the `base_path` the site is served under, if there is one.
+#[instrument]
+pub fn fingerprint_assets(
+    output_dir: &Path,
+    base_path: Option<&str>,
+) -> Result<BTreeMap<String, String>> {
+    remove_previous(output_dir)?;
+
+    let files: Vec<PathBuf> = WalkDir::new(output_dir)
+        .into_iter()
+        .filter_entry(|entry| {
+            entry.depth() != 1
+                || !SKIPPED_DIRS.contains(&entry.file_name().to_str().unwrap_or_default())
+        })
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| {
+            entry
+                .path()
+                .strip_prefix(output_dir)
+                .ok()
+                .map(Path::to_path_buf)
+        })
+        .collect();
+
+    let mut assets: HashMap<PathBuf, Asset> = HashMap::new();
+    for path in files.iter().filter(|path| is_asset(path)) {
+        let full_path = output_dir.join(path);
+        let source = fs::read_to_string(&full_path)
+            .wrap_err_with(|| format!("Failed to read `{}`", full_path.display()))?;
+        let from_dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let dependencies = specifiers_for(path, &source)
+            .iter()
+            .filter_map(|specifier| resolve(strip_base_path(base_path, &specifier.url), from_dir))
+            .collect();
+        assets.insert(
+            path.clone(),
+            Asset {
+                source,
+                dependencies,
+            },
+        );
+    }
+    // only files that exist can be depended on
+    let known: HashSet<PathBuf> = assets.keys().cloned().collect();
+    for asset in assets.values_mut() {
+        asset
+            .dependencies
+            .retain(|dependency| known.contains(dependency));
+    }
+
+    // which module in a cycle gets left out of a hash depends on the order
+    // they're visited in, so the order has to be the same every build
+    let mut paths: Vec<&PathBuf> = assets.keys().collect();
+    paths.sort();
+    let mut hashes = HashMap::new();
+    let mut hashed: HashMap<PathBuf, PathBuf> = HashMap::new();
+    for path in paths {
+        let hash = hash_asset(path, &assets, &mut hashes, &mut HashSet::new());
+        hashed.insert(path.clone(), hashed_path(path, &hash));
+    }
+
+    for (path, asset) in &assets {
+        let from_dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let specifiers = specifiers_for(path, &asset.source);
+        let rewritten =
+            replace_specifiers(&asset.source, &specifiers, from_dir, base_path, &hashed);
+        let hashed_file = output_dir.join(&hashed[path]);
+        fs::write(&hashed_file, rewritten)
+            .wrap_err_with(|| format!("Failed to write `{}`", hashed_file.display()))?;
+    }
+
+    for path in files
+        .iter()
+        .filter(|path| path.extension().and_then(|ext| ext.to_str()) == Some("html"))
+    {
+        let full_path = output_dir.join(path);
+        let html = fs::read_to_string(&full_path)
+            .wrap_err_with(|| format!("Failed to read `{}`", full_path.display()))?;
+        let rewritten = replace_specifiers(
+            &html,
+            &html_specifiers(&html),
+            Path::new(""),
+            base_path,
+            &hashed,
+        );
+        if rewritten != html {
+            fs::write(&full_path, rewritten)
+                .wrap_err_with(|| format!("Failed to write `{}`", full_path.display()))?;
+        }
+    }
+
+    let manifest: BTreeMap<String, String> = hashed
+        .iter()
+        .map(|(path, hashed)| (url_for(path), url_for(hashed)))
+        .collect();
+    let manifest_path = output_dir.join(MANIFEST_FILE);
+    fs::write(&manifest_path, serde_json::to_string_pretty(&manifest)?)
+        .wrap_err_with(|| format!("Failed to write `{}`", manifest_path.display()))?;
+    Ok(manifest)
+}
+
+/// Hashed files from the last build would otherwise be hashed again
+fn remove_previous(output_dir: &Path) -> Result<()> {
+    let manifest_path = output_dir.join(MANIFEST_FILE);
+    let previous: BTreeMap<String, String> = match fs::read_to_string(&manifest_path) {
+        Ok(contents) => serde_json::from_str(&contents).unwrap_or_default(),
+        Err(_) => return Ok(()),
+    };
+    for hashed in previous.values() {
+        let file = output_dir.join(hashed.trim_start_matches('/'));
+        if file.exists() {
+            fs::remove_file(&file)
+                .wrap_err_with(|| format!("Failed to remove `{}`", file.display()))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn urls(specifiers: Vec<Specifier>) -> Vec<String> {
+        specifiers
+            .into_iter()
+            .map(|specifier| specifier.url)
+            .collect()
+    }
+
+    #[test]
+    fn test_js_specifiers() {
+        let source = r#"import { h } from "/web_modules/preact.js";
+import "./side-effect.js";
+export * from '../shared.js';
+const lazy = import("./lazy.js");
+const fromage = "not an import";
+"#;
+        assert_eq!(
+            urls(js_specifiers(source)),
+            vec![
+                "/web_modules/preact.js",
+                "./side-effect.js",
+                "../shared.js",
+                "./lazy.js"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_css_specifiers() {
+        let source = r#"@import "./base.css"; body { background: url(/img/bg.png); }"#;
+        assert_eq!(
+            urls(css_specifiers(source)),
+            vec!["./base.css", "/img/bg.png"]
+        );
+    }
+
+    #[test]
+    fn test_resolve_and_rewrite() {
+        let from = Path::new("src/pages");
+        assert_eq!(
+            resolve("../components/nav.js?v=1", from),
+            Some(PathBuf::from("src/components/nav.js"))
+        );
+        assert_eq!(resolve("preact", from), None);
+        assert_eq!(resolve("//cdn.example.com/a.js", from), None);
+        assert_eq!(
+            rewrite_url(
+                "../components/nav.js?v=1",
+                &hashed_path(Path::new("src/components/nav.js"), "abc")
+            ),
+            "../components/nav.abc.js?v=1"
+        );
+    }
+
+    #[test]
+    fn test_dependency_changes_hash() {
+        let mut assets = HashMap::new();
+        assets.insert(
+            PathBuf::from("a.js"),
+            Asset {
+                source: String::from("import './b.js';"),
+                dependencies: vec![PathBuf::from("b.js")],
+            },
+        );
+        assets.insert(
+            PathBuf::from("b.js"),
+            Asset {
+                source: String::from("import './a.js';"),
+                dependencies: vec![PathBuf::from("a.js")],
+            },
+        );
+        let before = hash_asset(
+            Path::new("a.js"),
+            &assets,
+            &mut HashMap::new(),
+            &mut HashSet::new(),
+        );
+        assets.get_mut(Path::new("b.js")).unwrap().source = String::from("changed");
+        let after = hash_asset(
+            Path::new("a.js"),
+            &assets,
+            &mut HashMap::new(),
+            &mut HashSet::new(),
+        );
+        assert_ne!(before, after);
+    }
+}
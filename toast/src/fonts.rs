@@ -0,0 +1,314 @@
+//! Webfonts, configured with `[[fonts]]` in `toast.config.toml`:
+//!
+//! ```toml
+//! [[fonts]]
+//! family = "Inter"
+//! src = "fonts/Inter-Regular.woff2"
+//! weight = "400"
+//! # `swap` by default
+//! display = "swap"
+//! # preload it on every page
+//! preload = true
+//! # only keep the characters the site uses
+//! subset = true
+//! ```
+//!
+//! Each font is copied from the project, or subset by `toast-fonts` (which
+//! uses `subset-font`), to `_fonts/` with a content hash in its name, and
+//! gets an `@font-face` rule in `_fonts/fonts.css`. Every page links to
+//! that stylesheet, and to a `<link rel="preload">` for each font with
+//! `preload = true`, so pages only have to use the `font-family`.
+//!
+//! Subset fonts keep the characters in the text of every built page, plus
+//! printable ASCII, so text that's rendered in the browser still has the
+//! common characters. Fonts are only subset again when their file, their
+//! config, or the characters the site uses change.
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use duct::cmd;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Path, PathBuf},
+};
+use tracing::instrument;
+use walkdir::WalkDir;
+
+use crate::{
+    cache::{content_hash, Cache},
+    node::node_bin,
+    npm_bin::node_script,
+    pages::PageRoot,
+    renderer::html_output_path,
+    supervisor::{batch_timeout, run_node},
+};
+
+/// Directory in the output directory that fonts are written to
+pub const FONTS_DIR: &str = "_fonts";
+
+const STYLESHEET: &str = "fonts.css";
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FontConfig {
+    pub family: String,
+    /// the font file, relative to the project root
+    pub src: String,
+    /// a weight like `400`, or a range like `100 900` for variable fonts
+    #[serde(default)]
+    pub weight: Option<String>,
+    /// `normal` or `italic`
+    #[serde(default)]
+    pub style: Option<String>,
+    /// `font-display`, `swap` by default
+    #[serde(default)]
+    pub display: Option<String>,
+    #[serde(default)]
+    pub preload: bool,
+    #[serde(default)]
+    pub subset: bool,
+}
+
+/// What `toast-fonts` is asked to subset
+#[derive(Debug, Serialize)]
+struct SubsetJob {
+    input: PathBuf,
+    output: PathBuf,
+    text: String,
+    /// `woff2`, `woff`, or `sfnt` for `.ttf` and `.otf`
+    format: &'static str,
+}
+
+/// The `format()` of `@font-face`, the type of preloads, and the format
+/// `toast-fonts` writes, for a font file's extension
+fn font_format(extension: &str) -> Option<(&'static str, &'static str, &'static str)> {
+    match extension {
+        "woff2" => Some(("woff2", "font/woff2", "woff2")),
+        "woff" => Some(("woff", "font/woff", "woff")),
+        "ttf" => Some(("truetype", "font/ttf", "sfnt")),
+        "otf" => Some(("opentype", "font/otf", "sfnt")),
+        _ => None,
+    }
+}
+
+/// Every character outside of tags in `html`
+fn text_characters(html: &str, characters: &mut BTreeSet<char>) {
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            c if !in_tag && !c.is_whitespace() => {
+                characters.insert(c);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The characters used by every html file in `output_dir`, and printable
+/// ASCII
+fn site_characters(output_dir: &Path) -> String {
+    let mut characters: BTreeSet<char> = (' '..='~').collect();
+    for entry in WalkDir::new(output_dir)
+        .into_iter()
+        .filter_map(|result| result.ok())
+        .filter(|entry| entry.path().extension().and_then(|ext| ext.to_str()) == Some("html"))
+    {
+        if let Ok(html) = fs::read_to_string(entry.path()) {
+            text_characters(&html, &mut characters);
+        }
+    }
+    characters.into_iter().collect()
+}
+
+fn font_face(font: &FontConfig, href: &str, format: &str) -> String {
+    let mut rule = format!(
+        "@font-face {{\n  font-family: \"{}\";\n  src: url(\"{}\") format(\"{}\");\n",
+        font.family.replace('"', "\\\""),
+        href,
+        format
+    );
+    if let Some(weight) = &font.weight {
+        rule.push_str(&format!("  font-weight: {};\n", weight));
+    }
+    if let Some(style) = &font.style {
+        rule.push_str(&format!("  font-style: {};\n", style));
+    }
+    rule.push_str(&format!(
+        "  font-display: {};\n}}\n",
+        font.display.as_deref().unwrap_or("swap")
+    ));
+    rule
+}
+
+/// Add the font stylesheet and preloads to the head of `html`, leaving out
+/// any it already has
+pub fn inject_fonts(html: &str, preloads: &[(String, &str)]) -> String {
+    let tags: String = preloads
+        .iter()
+        .map(|(href, mime)| {
+            format!(
+                "<link rel=\"preload\" href=\"{}\" as=\"font\" type=\"{}\" crossorigin>",
+                href, mime
+            )
+        })
+        .chain(std::iter::once(format!(
+            "<link rel=\"stylesheet\" href=\"/{}/{}\">",
+            FONTS_DIR, STYLESHEET
+        )))
+        .filter(|tag| !html.contains(tag.trim_end_matches('>')))
+        .collect();
+    let mut html = html.to_string();
+    if let Some(head_end) = html.find("</head>") {
+        html.insert_str(head_end, &tags);
+    }
+    html
+}
+
+/// Copy or subset every font in `fonts`, write their stylesheet, and link
+/// it from the rendered html of `pages`
+#[instrument(skip(cache))]
+pub fn write_fonts(
+    project_root_dir: &Path,
+    output_dir: &Path,
+    npm_bin_dir: &Path,
+    page_roots: &[PageRoot],
+    pages: &[String],
+    fonts: &[FontConfig],
+    cache: &mut Cache,
+) -> Result<()> {
+    if fonts.is_empty() {
+        return Ok(());
+    }
+    let fonts_dir = output_dir.join(FONTS_DIR);
+    fs::create_dir_all(&fonts_dir)
+        .wrap_err_with(|| format!("Failed to create font directory `{}`", fonts_dir.display()))?;
+    let text = if fonts.iter().any(|font| font.subset) {
+        site_characters(output_dir)
+    } else {
+        String::new()
+    };
+
+    let mut stylesheet = String::new();
+    let mut preloads = vec![];
+    let mut jobs: Vec<(String, String, SubsetJob)> = vec![];
+    for font in fonts {
+        let input = project_root_dir.join(&font.src);
+        let extension = input
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_lowercase())
+            .unwrap_or_default();
+        let (css_format, mime, subset_format) = font_format(&extension).ok_or_else(|| {
+            eyre!(
+                "Unsupported font `{}`, fonts have to be `.woff2`, `.woff`, `.ttf`, or `.otf`",
+                font.src
+            )
+        })?;
+        let contents = fs::read(&input)
+            .wrap_err_with(|| format!("Failed to read font `{}`", input.display()))?;
+        let config_json = serde_json::to_string(font)?;
+        let subset_text = if font.subset { text.as_str() } else { "" };
+        let hash = content_hash(&[&contents, config_json.as_bytes(), subset_text.as_bytes()]);
+        let stem = input
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or("font");
+        let name = format!("{}-{}.{}", stem, &hash[..8], extension);
+        let output = fonts_dir.join(&name);
+        let href = format!("/{}/{}", FONTS_DIR, name);
+        stylesheet.push_str(&font_face(font, &href, css_format));
+        if font.preload {
+            preloads.push((href, mime));
+        }
+
+        let cache_key = format!("font:{}", font.src);
+        if cache.is_fresh(&cache_key, &hash) && output.exists() {
+            continue;
+        }
+        if font.subset {
+            jobs.push((
+                cache_key,
+                hash,
+                SubsetJob {
+                    input,
+                    output,
+                    text: text.clone(),
+                    format: subset_format,
+                },
+            ));
+        } else {
+            fs::write(&output, &contents)
+                .wrap_err_with(|| format!("Failed to write font `{}`", output.display()))?;
+            cache.record_outputs(&cache_key, hash, vec![output]);
+        }
+    }
+    if !jobs.is_empty() {
+        run_toast_fonts(
+            &jobs.iter().map(|(_, _, job)| job).collect::<Vec<_>>(),
+            npm_bin_dir,
+        )?;
+        for (cache_key, hash, job) in jobs {
+            cache.record_outputs(&cache_key, hash, vec![job.output]);
+        }
+    }
+
+    let stylesheet_path = fonts_dir.join(STYLESHEET);
+    fs::write(&stylesheet_path, stylesheet).wrap_err_with(|| {
+        format!(
+            "Failed to write font stylesheet `{}`",
+            stylesheet_path.display()
+        )
+    })?;
+    for page in pages {
+        let html_path = html_output_path(output_dir, page_roots, page);
+        let html = match fs::read_to_string(&html_path) {
+            Ok(html) => html,
+            // pages that failed to render
+            Err(_) => continue,
+        };
+        let linked = inject_fonts(&html, &preloads);
+        if linked != html {
+            fs::write(&html_path, linked)
+                .wrap_err_with(|| format!("Failed to write `{}`", html_path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// All of the jobs are sent to one node process as JSON on stdin
+fn run_toast_fonts(jobs: &[&SubsetJob], npm_bin_dir: &Path) -> Result<()> {
+    let bin = node_script(npm_bin_dir, "toast-fonts");
+    let bin_str = bin
+        .to_str()
+        .ok_or_else(|| eyre!("failed to make npm bin into str"))?;
+    run_node(
+        "toast-fonts",
+        cmd!(node_bin(), "--unhandled-rejections", "strict", bin_str)
+            .stdin_bytes(serde_json::to_vec(jobs)?),
+        batch_timeout(jobs.len()),
+    )
+    .wrap_err("Failed to subset fonts with `toast-fonts`")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inject_fonts() {
+        let preloads = vec![(String::from("/_fonts/inter-1a2b3c4d.woff2"), "font/woff2")];
+        let html = inject_fonts("<html><head></head><body>Hi</body></html>", &preloads);
+        assert_eq!(
+            html,
+            "<html><head><link rel=\"preload\" href=\"/_fonts/inter-1a2b3c4d.woff2\" as=\"font\" type=\"font/woff2\" crossorigin><link rel=\"stylesheet\" href=\"/_fonts/fonts.css\"></head><body>Hi</body></html>"
+        );
+        assert_eq!(inject_fonts(&html, &preloads), html);
+
+        let mut characters = BTreeSet::new();
+        text_characters("<p class=\"x\">héllo</p>", &mut characters);
+        assert_eq!(characters.into_iter().collect::<String>(), "hlo\u{e9}");
+    }
+}
@@ -0,0 +1,241 @@
+//! Frontmatter at the top of `.mdx` and `.md` pages.
+//!
+//! YAML goes between `---` fences and TOML between `+++` fences:
+//!
+//! ```md
+//! ---
+//! title: Hello
+//! date: 2020-09-01
+//! tags: [intro]
+//! ---
+//!
+//! # Hello
+//! ```
+//!
+//! Each key is passed to the page component (and page wrapper) as a prop,
+//! is exported from the compiled module as `frontmatter`, and is added to
+//! the site-wide index at `toast-data/frontmatter.json`.
+//!
+//! Pages with `draft: true`, or a `date` that hasn't come yet, are only
+//! built by `toast serve` or with `--drafts`.
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use serde_json::Value;
+use std::{collections::BTreeMap, fs, path::Path};
+
+use crate::{
+    feeds::parse_date,
+    pages::{is_page, page_path, PageRoot},
+};
+
+/// Frontmatter for every page that has any, keyed by page slug
+pub type FrontmatterIndex = BTreeMap<String, Value>;
+
+/// Split a source file into its frontmatter, if it has any, and the rest
+/// of the file
+pub fn extract(source: &str) -> Result<(Option<Value>, &str)> {
+    for (fence, format) in &[("---", Format::Yaml), ("+++", Format::Toml)] {
+        if let Some((raw, body)) = split_fenced(source, fence) {
+            let frontmatter = format.parse(raw)?;
+            if !frontmatter.is_object() {
+                return Err(eyre!(
+                    "frontmatter must be a map of keys to values, found `{}`",
+                    frontmatter
+                ));
+            }
+            return Ok((Some(frontmatter), body));
+        }
+    }
+    Ok((None, source))
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    Yaml,
+    Toml,
+}
+
+impl Format {
+    fn parse(&self, raw: &str) -> Result<Value> {
+        match self {
+            Format::Yaml => {
+                // an empty block is valid, but yaml parses it as null
+                if raw.trim().is_empty() {
+                    return Ok(Value::Object(Default::default()));
+                }
+                serde_yaml::from_str(raw).wrap_err("Failed to parse YAML frontmatter")
+            }
+            Format::Toml => {
+                let value: toml::Value =
+                    toml::from_str(raw).wrap_err("Failed to parse TOML frontmatter")?;
+                Ok(toml_to_json(value))
+            }
+        }
+    }
+}
+
+/// `value` as JSON, with datetimes like `date = 2020-09-01` as the
+/// string they were written as, instead of the tagged object serde makes
+/// of them, so they're read like quoted dates in YAML
+fn toml_to_json(value: toml::Value) -> Value {
+    match value {
+        toml::Value::String(string) => Value::String(string),
+        toml::Value::Integer(integer) => Value::from(integer),
+        toml::Value::Float(float) => Value::from(float),
+        toml::Value::Boolean(boolean) => Value::Bool(boolean),
+        toml::Value::Datetime(datetime) => Value::String(datetime.to_string()),
+        toml::Value::Array(array) => Value::Array(array.into_iter().map(toml_to_json).collect()),
+        toml::Value::Table(table) => Value::Object(
+            table
+                .into_iter()
+                .map(|(key, value)| (key, toml_to_json(value)))
+                .collect(),
+        ),
+    }
+}
+
+/// `source` has frontmatter if its first line is exactly `fence`, and it
+/// ends at the next line that is exactly `fence`
+fn split_fenced<'a>(source: &'a str, fence: &str) -> Option<(&'a str, &'a str)> {
+    let rest = source.strip_prefix(fence)?;
+    let rest = rest
+        .strip_prefix("\r\n")
+        .or_else(|| rest.strip_prefix('\n'))?;
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        if line.trim_end_matches(&['\r', '\n'][..]) == fence {
+            return Some((&rest[..offset], &rest[offset + line.len()..]));
+        }
+        offset += line.len();
+    }
+    None
+}
+
+/// Slug for a page compiled to `dest`, like `/blog/post` for
+/// `src/pages/blog/post.js`. Index pages are the directory they're in.
+pub fn page_slug(page_roots: &[PageRoot], dest: &str) -> String {
+    let path = if is_page(page_roots, dest) {
+        format!("/{}", page_path(page_roots, dest))
+    } else {
+        dest.to_string()
+    };
+    let path = path.as_str();
+    let path = path.strip_suffix(".js").unwrap_or(path);
+    let path = path.strip_suffix("index").unwrap_or(path);
+    if path.len() > 1 {
+        path.trim_end_matches('/').to_string()
+    } else {
+        String::from("/")
+    }
+}
+
+/// Whether a page should be in a production build as of `now`: it isn't a
+/// draft, and it doesn't have a date in the future
+pub fn is_published(frontmatter: &Value, now: DateTime<Utc>) -> bool {
+    let draft = frontmatter.get("draft").and_then(Value::as_bool) == Some(true);
+    let future = frontmatter
+        .get("date")
+        .and_then(Value::as_str)
+        .and_then(parse_date)
+        .is_some_and(|date| date > now);
+    !draft && !future
+}
+
+/// The keys in `required` that `frontmatter` doesn't have, or has as
+/// `null` or an empty string
+pub fn missing_keys<'a>(frontmatter: &Value, required: &'a [String]) -> Vec<&'a str> {
+    required
+        .iter()
+        .filter(|key| match frontmatter.get(key.as_str()) {
+            None | Some(Value::Null) => true,
+            Some(Value::String(value)) => value.trim().is_empty(),
+            Some(_) => false,
+        })
+        .map(String::as_str)
+        .collect()
+}
+
+/// Write the index of every page's frontmatter to `toast-data/frontmatter.json`
+pub fn write_index(index: &FrontmatterIndex, output_dir: &Path) -> Result<()> {
+    let dir = output_dir.join("toast-data");
+    fs::create_dir_all(&dir)
+        .wrap_err_with(|| format!("Failed to create directory `{}`", dir.display()))?;
+    let file = dir.join("frontmatter.json");
+    fs::write(&file, serde_json::to_string(index)?)
+        .wrap_err_with(|| format!("Failed to write frontmatter index `{}`", file.display()))
+}
+
+/// Read the index written by a previous build
+pub fn read_index(output_dir: &Path) -> FrontmatterIndex {
+    fs::read_to_string(output_dir.join("toast-data").join("frontmatter.json"))
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pages::DEFAULT_PAGES_DIR;
+    use serde_json::json;
+
+    #[test]
+    fn test_yaml_frontmatter() -> Result<()> {
+        let (frontmatter, body) = extract("---\ntitle: Hello\ntags: [a, b]\n---\n# Hello\n")?;
+        assert_eq!(
+            frontmatter,
+            Some(json!({"title": "Hello", "tags": ["a", "b"]}))
+        );
+        assert_eq!(body, "# Hello\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_toml_frontmatter() -> Result<()> {
+        let (frontmatter, body) = extract("+++\r\ntitle = \"Hello\"\r\n+++\r\nbody")?;
+        assert_eq!(frontmatter, Some(json!({"title": "Hello"})));
+        assert_eq!(body, "body");
+
+        let (frontmatter, _) =
+            extract("+++\ndate = 2999-01-01\n[seo]\nupdated = 2020-09-01T10:00:00Z\n+++\n")?;
+        let frontmatter = frontmatter.unwrap();
+        assert_eq!(
+            frontmatter,
+            json!({"date": "2999-01-01", "seo": {"updated": "2020-09-01T10:00:00Z"}})
+        );
+        assert!(!is_published(&frontmatter, Utc::now()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_frontmatter() -> Result<()> {
+        let source = "# Hello\n---\nnot frontmatter\n---\n";
+        assert_eq!(extract(source)?, (None, source));
+        // an opening fence that's never closed isn't frontmatter either
+        assert_eq!(extract("---\ntitle: a\n")?.0, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_published() {
+        let now = Utc::now();
+        assert!(is_published(
+            &json!({"title": "a", "date": "2020-09-01"}),
+            now
+        ));
+        assert!(!is_published(&json!({"draft": true}), now));
+        assert!(!is_published(&json!({"date": "2999-01-01"}), now));
+    }
+
+    #[test]
+    fn test_page_slug() {
+        let roots = [
+            PageRoot::new(DEFAULT_PAGES_DIR, ""),
+            PageRoot::new("docs", "/docs"),
+        ];
+        assert_eq!(page_slug(&roots, "src/pages/index.js"), "/");
+        assert_eq!(page_slug(&roots, "src/pages/blog/index.js"), "/blog");
+        assert_eq!(page_slug(&roots, "src/pages/blog/post.js"), "/blog/post");
+        assert_eq!(page_slug(&roots, "docs/index.js"), "/docs");
+    }
+}
@@ -0,0 +1,242 @@
+//! `<title>`, description, canonical url, and Open Graph tags from a
+//! page's `meta` export.
+//!
+//! ```js
+//! export const meta = {
+//!   title: "Hello",
+//!   description: "The first post",
+//!   canonical: "/blog/hello",
+//!   og: { type: "article" },
+//! };
+//! ```
+//!
+//! `meta` can also be a function, which is called with the page's props.
+//! It's written to `toast-data/meta/` when the page is rendered, and its
+//! tags are added to the head of the page's html here. Pages without a
+//! `meta` export use the `title` and `description` in their frontmatter.
+//!
+//! Tags a page already sets itself, with `Helmet` or in its page wrapper,
+//! are left alone. Root-relative canonical and `og:` urls are joined to
+//! `base_url` so they're absolute like crawlers expect.
+use color_eyre::eyre::{Result, WrapErr};
+use serde::Deserialize;
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+use tracing::instrument;
+
+use crate::{
+    feeds::escape_xml,
+    frontmatter::{self, page_slug},
+    pages::{page_path, PageRoot},
+    renderer::html_output_path,
+};
+
+/// Directory in the output directory that `toast-render` writes each
+/// page's `meta` export to
+pub const META_DIR: &str = "toast-data/meta";
+
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct PageMeta {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub canonical: Option<String>,
+    /// `og:` properties without the prefix, like `type` or `image`
+    pub og: BTreeMap<String, String>,
+}
+
+impl PageMeta {
+    fn from_frontmatter(frontmatter: &Value) -> PageMeta {
+        let string = |key: &str| {
+            frontmatter
+                .get(key)
+                .and_then(Value::as_str)
+                .map(String::from)
+        };
+        PageMeta {
+            title: string("title"),
+            description: string("description"),
+            ..PageMeta::default()
+        }
+    }
+}
+
+/// Where `toast-render` writes the `meta` export of `page`
+fn meta_path(output_dir: &Path, page_roots: &[PageRoot], page: &str) -> PathBuf {
+    output_dir
+        .join(META_DIR)
+        .join(page_path(page_roots, page).replace(".js", ".json"))
+}
+
+pub fn absolute(url: &str, base_url: Option<&str>) -> String {
+    match base_url {
+        Some(base_url) if url.starts_with('/') => {
+            format!("{}{}", base_url.trim_end_matches('/'), url)
+        }
+        _ => url.to_string(),
+    }
+}
+
+/// Each tag for `meta`, with the text that means the page already has it
+fn head_tags(meta: &PageMeta, base_url: Option<&str>) -> Vec<(String, String)> {
+    let mut tags = vec![];
+    let mut tag = |marker: String, tag: String| tags.push((marker, tag));
+    let mut og = meta.og.clone();
+    if let Some(title) = &meta.title {
+        og.entry(String::from("title"))
+            .or_insert_with(|| title.clone());
+    }
+    if let Some(description) = &meta.description {
+        tag(
+            String::from(r#"name="description""#),
+            format!(
+                r#"<meta name="description" content="{}">"#,
+                escape_xml(description)
+            ),
+        );
+        og.entry(String::from("description"))
+            .or_insert_with(|| description.clone());
+    }
+    if let Some(canonical) = &meta.canonical {
+        let canonical = absolute(canonical, base_url);
+        og.entry(String::from("url"))
+            .or_insert_with(|| canonical.clone());
+        tag(
+            String::from(r#"rel="canonical""#),
+            format!(
+                r#"<link rel="canonical" href="{}">"#,
+                escape_xml(&canonical)
+            ),
+        );
+    }
+    for (property, content) in og {
+        let content = match property.as_str() {
+            "image" | "url" => absolute(&content, base_url),
+            _ => content,
+        };
+        tag(
+            format!(r#"property="og:{}""#, property),
+            format!(
+                r#"<meta property="og:{}" content="{}">"#,
+                escape_xml(&property),
+                escape_xml(&content)
+            ),
+        );
+    }
+    tags
+}
+
+/// Add the tags for `meta` to the head of `html`. An empty `<title>`, which
+/// `Helmet` renders when a page doesn't set one, is replaced.
+pub fn inject_head(html: &str, meta: &PageMeta, base_url: Option<&str>) -> String {
+    let mut html = html.to_string();
+    if let Some(title) = &meta.title {
+        let title_tag = format!("<title>{}</title>", escape_xml(title));
+        let existing = html.find("<title").and_then(|start| {
+            let end = html[start..].find("</title>")? + start + "</title>".len();
+            let content_start = html[start..end].find('>')? + start + 1;
+            Some((start, end, content_start))
+        });
+        match existing {
+            Some((start, end, content_start)) => {
+                let content = &html[content_start..end - "</title>".len()];
+                if content.trim().is_empty() {
+                    html.replace_range(start..end, &title_tag);
+                }
+            }
+            None => {
+                if let Some(head_end) = html.find("</head>") {
+                    html.insert_str(head_end, &title_tag);
+                }
+            }
+        }
+    }
+    let tags: String = head_tags(meta, base_url)
+        .into_iter()
+        .filter(|(marker, _)| !html.contains(marker.as_str()))
+        .map(|(_, tag)| tag)
+        .collect();
+    if let Some(head_end) = html.find("</head>") {
+        html.insert_str(head_end, &tags);
+    }
+    html
+}
+
+/// Add head tags to the rendered html of `pages`
+#[instrument]
+pub fn apply_page_meta(
+    output_dir: &Path,
+    page_roots: &[PageRoot],
+    pages: &[String],
+    base_url: Option<&str>,
+) -> Result<()> {
+    let frontmatter_index = frontmatter::read_index(output_dir);
+    for page in pages {
+        let meta_path = meta_path(output_dir, page_roots, page);
+        let meta = match fs::read_to_string(&meta_path) {
+            Ok(contents) => serde_json::from_str(&contents).wrap_err_with(|| {
+                format!(
+                    "Failed to parse the `meta` export of `{}` from `{}`",
+                    page,
+                    meta_path.display()
+                )
+            })?,
+            Err(_) => match frontmatter_index.get(&page_slug(page_roots, page)) {
+                Some(frontmatter) => PageMeta::from_frontmatter(frontmatter),
+                None => continue,
+            },
+        };
+        let html_path = html_output_path(output_dir, page_roots, page);
+        let html = match fs::read_to_string(&html_path) {
+            Ok(html) => html,
+            // pages that failed to render
+            Err(_) => continue,
+        };
+        let with_head = inject_head(&html, &meta, base_url);
+        if with_head != html {
+            fs::write(&html_path, with_head)
+                .wrap_err_with(|| format!("Failed to write `{}`", html_path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HTML: &str = r#"<html><head><title data-react-helmet="true"></title><meta name="description" content="from helmet"></head><body></body></html>"#;
+
+    #[test]
+    fn test_inject_head() {
+        let meta = PageMeta {
+            title: Some(String::from("Hello & welcome")),
+            description: Some(String::from("The first post")),
+            canonical: Some(String::from("/blog/hello")),
+            ..PageMeta::default()
+        };
+        let html = inject_head(HTML, &meta, Some("https://toast.dev/"));
+        assert!(html.contains("<head><title>Hello &amp; welcome</title>"));
+        // helmet's description wins
+        assert!(!html.contains(r#"<meta name="description" content="The first post">"#));
+        assert!(html.contains(r#"<link rel="canonical" href="https://toast.dev/blog/hello">"#));
+        assert!(html.contains(r#"<meta property="og:url" content="https://toast.dev/blog/hello">"#));
+        assert!(html.contains(r#"<meta property="og:title" content="Hello &amp; welcome">"#));
+        // running it again doesn't add anything
+        assert_eq!(inject_head(&html, &meta, Some("https://toast.dev/")), html);
+    }
+
+    #[test]
+    fn test_title_set_by_page_is_kept() {
+        let html = "<html><head><title>Mine</title></head></html>";
+        let meta = PageMeta {
+            title: Some(String::from("Theirs")),
+            ..PageMeta::default()
+        };
+        assert!(inject_head(html, &meta, None).contains("<title>Mine</title>"));
+    }
+}
@@ -0,0 +1,585 @@
+//! Response headers for static hosts, with a Content-Security-Policy for
+//! each page that allows the inline scripts in its html, like the
+//! hydration script toast adds, by their hashes, and a Cache-Control for
+//! each file. Turned on by picking the files to write in
+//! `toast.config.toml`:
+//!
+//! ```toml
+//! [headers]
+//! # any of "netlify" (`_headers`), "vercel" (`vercel.json`), "nginx"
+//! # (`nginx-headers.conf`, to `include` in a `server` block), and
+//! # "cloudfront" (`.toast/cache-control.json`)
+//! formats = ["netlify"]
+//!
+//! # replaces the default policy of `script-src 'self'; object-src 'none';
+//! # base-uri 'self'`. Hashes are added to `script-src`, or to a copy of
+//! # `default-src` if there's no `script-src`.
+//! [headers.csp]
+//! default-src = "'self'"
+//! img-src = "'self' data:"
+//!
+//! # sent with every response, replacing the defaults of
+//! # `X-Content-Type-Options: nosniff` and
+//! # `Referrer-Policy: strict-origin-when-cross-origin`
+//! [headers.custom]
+//! X-Frame-Options = "DENY"
+//!
+//! # turns on Cache-Control, with these defaults
+//! [headers.cache_control]
+//! immutable = "public, max-age=31536000, immutable"
+//! html = "public, max-age=300, must-revalidate"
+//! other = "public, max-age=3600"
+//! ```
+//!
+//! The hashed copies listed in `asset-manifest.json` are `immutable`,
+//! since a file that changes gets a new name, see `fingerprint`. Pages get
+//! a short TTL, so a new build shows up soon, and every other file,
+//! which can change without its url changing, gets `other`. CloudFront
+//! passes on, and caches by, the Cache-Control its origin sends, so
+//! `cloudfront` is a map of each file's path to its policy, which
+//! `toast deploy` to S3 and `s3://` output directories store with each
+//! object. It's kept in `.toast` rather than the output directory, so it
+//! isn't published with the site. A policy that changed is only stored with files that are sent
+//! again, `toast deploy --all` sends everything.
+//!
+//! Headers are written after everything else that changes page html, so
+//! the hashes match what's served. Like `redirects`, anything in the
+//! project's own `static/_headers` and `static/vercel.json` is kept.
+use color_eyre::eyre::{Result, WrapErr};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::Path,
+};
+use tracing::instrument;
+use walkdir::WalkDir;
+
+use crate::{cache::CACHE_DIR, fingerprint::MANIFEST_FILE};
+
+/// The nginx snippet, written to the output directory
+pub const NGINX_FILE: &str = "nginx-headers.conf";
+
+/// Each file's Cache-Control, written to `CACHE_DIR` for `cloudfront`
+pub const CACHE_CONTROL_FILE: &str = "cache-control.json";
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HeadersFormat {
+    Netlify,
+    Vercel,
+    Nginx,
+    Cloudfront,
+}
+
+/// `[headers.cache_control]`, see the module docs
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheControlConfig {
+    pub immutable: String,
+    pub html: String,
+    pub other: String,
+}
+
+impl Default for CacheControlConfig {
+    fn default() -> Self {
+        CacheControlConfig {
+            immutable: String::from("public, max-age=31536000, immutable"),
+            html: String::from("public, max-age=300, must-revalidate"),
+            other: String::from("public, max-age=3600"),
+        }
+    }
+}
+
+/// `[headers]` in `toast.config.toml`
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeadersConfig {
+    /// which files to write, none by default
+    pub formats: Vec<HeadersFormat>,
+    /// Content-Security-Policy directives to their values
+    pub csp: BTreeMap<String, String>,
+    /// headers for every response
+    pub custom: BTreeMap<String, String>,
+    /// Cache-Control for each file, off unless there's a table for it
+    pub cache_control: Option<CacheControlConfig>,
+}
+
+impl Default for HeadersConfig {
+    fn default() -> Self {
+        let map = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect()
+        };
+        HeadersConfig {
+            formats: vec![],
+            csp: map(&[
+                ("script-src", "'self'"),
+                ("object-src", "'none'"),
+                ("base-uri", "'self'"),
+            ]),
+            custom: map(&[
+                ("X-Content-Type-Options", "nosniff"),
+                ("Referrer-Policy", "strict-origin-when-cross-origin"),
+            ]),
+            cache_control: None,
+        }
+    }
+}
+
+/// The headers for the html at one url
+#[derive(Debug, Clone, PartialEq)]
+pub struct PageHeaders {
+    pub url: String,
+    pub csp: String,
+    pub cache_control: Option<String>,
+}
+
+/// The Cache-Control for a file that isn't a page
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssetHeaders {
+    pub url: String,
+    pub cache_control: String,
+}
+
+/// The CSP source for each inline `<script>` in `html`, like
+/// `'sha256-...'`. Scripts with a `src` are covered by `'self'` or the
+/// policy's other sources.
+pub fn inline_script_hashes(html: &str) -> BTreeSet<String> {
+    let mut hashes = BTreeSet::new();
+    let lower = html.to_ascii_lowercase();
+    let mut rest = 0;
+    while let Some(start) = lower[rest..].find("<script").map(|index| rest + index) {
+        let open_end = match lower[start..].find('>') {
+            Some(index) => start + index + 1,
+            None => break,
+        };
+        let close = match lower[open_end..].find("</script") {
+            Some(index) => open_end + index,
+            None => break,
+        };
+        let has_src = lower[start..open_end]
+            .split_whitespace()
+            .any(|attribute| attribute.starts_with("src="));
+        let content = &html[open_end..close];
+        if !has_src && !content.is_empty() {
+            hashes.insert(format!(
+                "'sha256-{}'",
+                base64::encode(Sha256::digest(content.as_bytes()))
+            ));
+        }
+        rest = close;
+    }
+    hashes
+}
+
+/// The policy in `config` with `hashes` allowed as script sources
+pub fn render_csp(config: &HeadersConfig, hashes: &BTreeSet<String>) -> String {
+    let mut directives = config.csp.clone();
+    let script_src = directives
+        .get("script-src")
+        .or_else(|| directives.get("default-src"))
+        .cloned()
+        .unwrap_or_else(|| String::from("'self'"));
+    // `'none'` can't be combined with other sources
+    let sources: Vec<&str> = script_src
+        .split_whitespace()
+        .filter(|source| hashes.is_empty() || *source != "'none'")
+        .chain(hashes.iter().map(String::as_str))
+        .collect();
+    directives.insert(String::from("script-src"), sources.join(" "));
+    directives
+        .iter()
+        .map(|(directive, value)| format!("{} {}", directive, value).trim().to_string())
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// Every url the html at `html_path`, relative to the output directory,
+/// can be requested at
+fn page_urls(html_path: &str, base_path: Option<&str>) -> Vec<String> {
+    let base = base_path.unwrap_or_default();
+    let path = html_path.replace('\\', "/");
+    let mut urls = vec![format!("{}/{}", base, path)];
+    match path.strip_suffix("index.html") {
+        Some("") => urls.push(format!("{}/", base)),
+        Some(dir) => {
+            urls.push(format!("{}/{}", base, dir));
+            urls.push(format!("{}/{}", base, dir.trim_end_matches('/')));
+        }
+        None => urls.push(format!("{}/{}", base, path.trim_end_matches(".html"))),
+    }
+    urls.sort();
+    urls
+}
+
+/// The Cache-Control for each file in `output_dir`, by its path in it
+#[instrument(skip(config))]
+pub fn cache_control_policies(
+    output_dir: &Path,
+    config: &CacheControlConfig,
+) -> Result<BTreeMap<String, String>> {
+    let hashed: BTreeSet<String> = match fs::read_to_string(output_dir.join(MANIFEST_FILE)) {
+        Ok(contents) => serde_json::from_str::<BTreeMap<String, String>>(&contents)
+            .wrap_err_with(|| format!("Failed to parse `{}`", MANIFEST_FILE))?
+            .into_values()
+            .map(|url| url.trim_start_matches('/').to_string())
+            .collect(),
+        Err(_) => BTreeSet::new(),
+    };
+    let mut policies = BTreeMap::new();
+    for entry in WalkDir::new(output_dir)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+    {
+        let relative = entry
+            .path()
+            .strip_prefix(output_dir)
+            .unwrap_or(entry.path())
+            .iter()
+            .map(|part| part.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        let policy = if hashed.contains(&relative) {
+            &config.immutable
+        } else if relative.ends_with(".html") {
+            &config.html
+        } else {
+            &config.other
+        };
+        policies.insert(relative, policy.clone());
+    }
+    Ok(policies)
+}
+
+/// What `cloudfront` wrote for the last build of the project in
+/// `project_root_dir`, by path, empty if it didn't
+pub fn read_cache_control(project_root_dir: &Path) -> BTreeMap<String, String> {
+    fs::read_to_string(project_root_dir.join(CACHE_DIR).join(CACHE_CONTROL_FILE))
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+/// The Cache-Control of every file in `policies` that isn't a page
+fn collect_asset_headers(
+    policies: &BTreeMap<String, String>,
+    base_path: Option<&str>,
+) -> Vec<AssetHeaders> {
+    policies
+        .iter()
+        .filter(|(path, _)| !path.ends_with(".html"))
+        .map(|(path, policy)| AssetHeaders {
+            url: format!("{}/{}", base_path.unwrap_or_default(), path),
+            cache_control: policy.clone(),
+        })
+        .collect()
+}
+
+/// The policy for every url of every html file in `output_dir`, with
+/// the Cache-Control from `policies`
+#[instrument(skip(config, policies))]
+pub fn collect_page_headers(
+    output_dir: &Path,
+    base_path: Option<&str>,
+    config: &HeadersConfig,
+    policies: &BTreeMap<String, String>,
+) -> Result<Vec<PageHeaders>> {
+    let mut pages = vec![];
+    for entry in WalkDir::new(output_dir)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension().and_then(|ext| ext.to_str()) == Some("html"))
+    {
+        let html = fs::read_to_string(entry.path())
+            .wrap_err_with(|| format!("Failed to read `{}`", entry.path().display()))?;
+        let csp = render_csp(config, &inline_script_hashes(&html));
+        let relative = entry
+            .path()
+            .strip_prefix(output_dir)
+            .unwrap_or(entry.path())
+            .to_string_lossy()
+            .replace('\\', "/");
+        for url in page_urls(&relative, base_path) {
+            pages.push(PageHeaders {
+                url,
+                csp: csp.clone(),
+                cache_control: policies.get(&relative).cloned(),
+            });
+        }
+    }
+    Ok(pages)
+}
+
+/// The Netlify `_headers` format, the custom headers for every path, then
+/// each page's policy, then each other file's Cache-Control
+pub fn render_netlify(
+    config: &HeadersConfig,
+    pages: &[PageHeaders],
+    assets: &[AssetHeaders],
+) -> String {
+    let mut out = String::new();
+    if !config.custom.is_empty() {
+        out.push_str("/*\n");
+        for (name, value) in &config.custom {
+            out.push_str(&format!("  {}: {}\n", name, value));
+        }
+    }
+    for page in pages {
+        out.push_str(&format!(
+            "{}\n  Content-Security-Policy: {}\n",
+            page.url, page.csp
+        ));
+        if let Some(cache_control) = &page.cache_control {
+            out.push_str(&format!("  Cache-Control: {}\n", cache_control));
+        }
+    }
+    for asset in assets {
+        out.push_str(&format!(
+            "{}\n  Cache-Control: {}\n",
+            asset.url, asset.cache_control
+        ));
+    }
+    out
+}
+
+/// Headers in the shape of `vercel.json`'s `headers` key
+pub fn render_vercel(
+    config: &HeadersConfig,
+    pages: &[PageHeaders],
+    assets: &[AssetHeaders],
+) -> Vec<Value> {
+    let custom: Vec<Value> = config
+        .custom
+        .iter()
+        .map(|(name, value)| json!({ "key": name, "value": value }))
+        .collect();
+    let mut headers = vec![];
+    if !custom.is_empty() {
+        headers.push(json!({ "source": "/(.*)", "headers": custom }));
+    }
+    headers.extend(pages.iter().map(|page| {
+        let mut page_headers = vec![json!({ "key": "Content-Security-Policy", "value": page.csp })];
+        if let Some(cache_control) = &page.cache_control {
+            page_headers.push(json!({ "key": "Cache-Control", "value": cache_control }));
+        }
+        json!({ "source": page.url, "headers": page_headers })
+    }));
+    headers.extend(assets.iter().map(|asset| {
+        json!({
+            "source": asset.url,
+            "headers": [{ "key": "Cache-Control", "value": asset.cache_control }],
+        })
+    }));
+    headers
+}
+
+fn nginx_header(name: &str, value: &str) -> String {
+    format!(
+        "add_header {} \"{}\" always;",
+        name,
+        value.replace('\\', "\\\\").replace('"', "\\\"")
+    )
+}
+
+/// An nginx snippet for a `server` block. `add_header` in a `location`
+/// replaces the server's headers instead of adding to them, so each page's
+/// and file's block repeats the custom headers.
+pub fn render_nginx(
+    config: &HeadersConfig,
+    pages: &[PageHeaders],
+    assets: &[AssetHeaders],
+) -> String {
+    let custom: Vec<String> = config
+        .custom
+        .iter()
+        .map(|(name, value)| nginx_header(name, value))
+        .collect();
+    let mut out = String::from("# written by toast, `include` this in a `server` block\n");
+    for header in &custom {
+        out.push_str(&format!("{}\n", header));
+    }
+    for page in pages {
+        out.push_str(&format!("\nlocation = {} {{\n", page.url));
+        out.push_str("    try_files $uri $uri.html $uri/index.html =404;\n");
+        for header in &custom {
+            out.push_str(&format!("    {}\n", header));
+        }
+        if let Some(cache_control) = &page.cache_control {
+            out.push_str(&format!(
+                "    {}\n",
+                nginx_header("Cache-Control", cache_control)
+            ));
+        }
+        out.push_str(&format!(
+            "    {}\n}}\n",
+            nginx_header("Content-Security-Policy", &page.csp)
+        ));
+    }
+    for asset in assets {
+        out.push_str(&format!("\nlocation = {} {{\n", asset.url));
+        for header in &custom {
+            out.push_str(&format!("    {}\n", header));
+        }
+        out.push_str(&format!(
+            "    {}\n}}\n",
+            nginx_header("Cache-Control", &asset.cache_control)
+        ));
+    }
+    out
+}
+
+/// Write the headers files `config` asks for
+#[instrument(skip(config))]
+pub fn write_headers(
+    project_root_dir: &Path,
+    output_dir: &Path,
+    base_path: Option<&str>,
+    config: &HeadersConfig,
+) -> Result<()> {
+    if config.formats.is_empty() {
+        return Ok(());
+    }
+    // `cloudfront` is only Cache-Control, so it's on with the defaults
+    let cache_control = match (
+        &config.cache_control,
+        config.formats.contains(&HeadersFormat::Cloudfront),
+    ) {
+        (Some(cache_control), _) => Some(cache_control.clone()),
+        (None, true) => Some(CacheControlConfig::default()),
+        (None, false) => None,
+    };
+    let policies = match &cache_control {
+        Some(cache_control) => cache_control_policies(output_dir, cache_control)?,
+        None => BTreeMap::new(),
+    };
+    let pages = collect_page_headers(output_dir, base_path, config, &policies)?;
+    let assets = collect_asset_headers(&policies, base_path);
+    let static_dir = project_root_dir.join("static");
+    for format in &config.formats {
+        match format {
+            HeadersFormat::Netlify => {
+                let mut netlify =
+                    fs::read_to_string(static_dir.join("_headers")).unwrap_or_default();
+                if !netlify.is_empty() && !netlify.ends_with('\n') {
+                    netlify.push('\n');
+                }
+                netlify.push_str(&render_netlify(config, &pages, &assets));
+                let netlify_path = output_dir.join("_headers");
+                fs::write(&netlify_path, netlify)
+                    .wrap_err_with(|| format!("Failed to write `{}`", netlify_path.display()))?;
+            }
+            HeadersFormat::Vercel => {
+                let vercel_path = output_dir.join("vercel.json");
+                // the output directory's copy can already have redirects
+                let mut vercel: Value = fs::read_to_string(&vercel_path)
+                    .ok()
+                    .and_then(|contents| serde_json::from_str(&contents).ok())
+                    .filter(Value::is_object)
+                    .unwrap_or_else(|| json!({}));
+                let mut headers = fs::read_to_string(static_dir.join("vercel.json"))
+                    .ok()
+                    .and_then(|contents| serde_json::from_str::<Value>(&contents).ok())
+                    .and_then(|project| project.get("headers").cloned())
+                    .and_then(|headers| match headers {
+                        Value::Array(headers) => Some(headers),
+                        _ => None,
+                    })
+                    .unwrap_or_default();
+                headers.extend(render_vercel(config, &pages, &assets));
+                vercel["headers"] = Value::Array(headers);
+                fs::write(&vercel_path, serde_json::to_string_pretty(&vercel)?)
+                    .wrap_err_with(|| format!("Failed to write `{}`", vercel_path.display()))?;
+            }
+            HeadersFormat::Nginx => {
+                let nginx_path = output_dir.join(NGINX_FILE);
+                fs::write(&nginx_path, render_nginx(config, &pages, &assets))
+                    .wrap_err_with(|| format!("Failed to write `{}`", nginx_path.display()))?;
+            }
+            HeadersFormat::Cloudfront => {
+                let cache_dir = project_root_dir.join(CACHE_DIR);
+                fs::create_dir_all(&cache_dir)
+                    .wrap_err_with(|| format!("Failed to create `{}`", cache_dir.display()))?;
+                let cloudfront_path = cache_dir.join(CACHE_CONTROL_FILE);
+                fs::write(&cloudfront_path, serde_json::to_string_pretty(&policies)?)
+                    .wrap_err_with(|| format!("Failed to write `{}`", cloudfront_path.display()))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inline_script_hashes() {
+        let html = r#"<script>window.componentPath = "/src/pages/index.js";</script><script type="module" src="/a.js"></script><SCRIPT type="module">renderPage();</SCRIPT>"#;
+        let hashes = inline_script_hashes(html);
+        assert_eq!(hashes.len(), 2);
+        assert!(hashes.contains(&format!(
+            "'sha256-{}'",
+            base64::encode(Sha256::digest(b"renderPage();"))
+        )));
+    }
+
+    #[test]
+    fn test_render_csp() {
+        let hashes: BTreeSet<String> = vec![String::from("'sha256-abc='")].into_iter().collect();
+        assert_eq!(
+            render_csp(&HeadersConfig::default(), &hashes),
+            "base-uri 'self'; object-src 'none'; script-src 'self' 'sha256-abc='"
+        );
+        let config = HeadersConfig {
+            csp: vec![(String::from("default-src"), String::from("'none'"))]
+                .into_iter()
+                .collect(),
+            ..HeadersConfig::default()
+        };
+        assert_eq!(
+            render_csp(&config, &hashes),
+            "default-src 'none'; script-src 'sha256-abc='"
+        );
+    }
+
+    #[test]
+    fn test_page_urls() {
+        assert_eq!(page_urls("index.html", None), vec!["/", "/index.html"]);
+        assert_eq!(
+            page_urls("blog/index.html", Some("/docs")),
+            vec!["/docs/blog", "/docs/blog/", "/docs/blog/index.html"]
+        );
+        assert_eq!(page_urls("about.html", None), vec!["/about", "/about.html"]);
+    }
+
+    #[test]
+    fn test_render_cache_control() {
+        let config = HeadersConfig {
+            custom: BTreeMap::new(),
+            ..HeadersConfig::default()
+        };
+        let pages = vec![PageHeaders {
+            url: String::from("/"),
+            csp: String::from("script-src 'self'"),
+            cache_control: Some(String::from("no-cache")),
+        }];
+        let policies: BTreeMap<String, String> = vec![
+            (String::from("index.html"), String::from("no-cache")),
+            (String::from("app.3f2a.js"), String::from("immutable")),
+        ]
+        .into_iter()
+        .collect();
+        let assets = collect_asset_headers(&policies, Some("/docs"));
+        assert_eq!(
+            render_netlify(&config, &pages, &assets),
+            "/\n  Content-Security-Policy: script-src 'self'\n  Cache-Control: no-cache\n/docs/app.3f2a.js\n  Cache-Control: immutable\n"
+        );
+    }
+}
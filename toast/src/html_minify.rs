@@ -0,0 +1,118 @@
+//! Smaller html for rendered pages, turned on with `minify_html = true` in
+//! `toast.config.toml` or `--minify-html`.
+//!
+//! Comments are removed, except for conditional comments, and runs of
+//! whitespace are collapsed into a single space. Whitespace is never
+//! removed outright because a space between inline elements is visible.
+//! The contents of `<pre>`, `<textarea>`, `<script>`, and `<style>` are
+//! left as they are.
+use color_eyre::eyre::{Result, WrapErr};
+use std::{fs, path::Path};
+use tracing::instrument;
+
+use crate::{pages::PageRoot, renderer::html_output_path};
+
+/// Elements whose contents are copied verbatim
+const RAW_ELEMENTS: &[&str] = &["pre", "textarea", "script", "style"];
+
+/// The raw element that the tag at the start of `html` opens, if any
+fn opens_raw_element(html: &str) -> Option<&'static str> {
+    let name: String = html[1..]
+        .chars()
+        .take_while(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_ascii_lowercase();
+    RAW_ELEMENTS.iter().copied().find(|raw| *raw == name)
+}
+
+/// Find the closing tag for `element`, ignoring case
+fn find_closing(html: &str, element: &str) -> Option<usize> {
+    let closing = format!("</{}", element);
+    html.to_ascii_lowercase().find(&closing)
+}
+
+pub fn minify_html(html: &str) -> String {
+    let mut output = String::with_capacity(html.len());
+    let mut rest = html.trim();
+    while !rest.is_empty() {
+        if rest.starts_with("<!--") {
+            let end = rest.find("-->").map_or(rest.len(), |end| end + "-->".len());
+            if rest.starts_with("<!--[if") {
+                output.push_str(&rest[..end]);
+            }
+            rest = &rest[end..];
+            continue;
+        }
+        if rest.starts_with('<') {
+            let tag_end = rest.find('>').map_or(rest.len(), |end| end + 1);
+            output.push_str(&rest[..tag_end]);
+            let raw = opens_raw_element(rest);
+            rest = &rest[tag_end..];
+            if let Some(element) = raw {
+                let end = find_closing(rest, element).unwrap_or(rest.len());
+                output.push_str(&rest[..end]);
+                rest = &rest[end..];
+            }
+            continue;
+        }
+        // text up to the next tag, which continues any space left before
+        // a removed comment
+        let end = rest.find('<').unwrap_or(rest.len());
+        let mut last_was_space = output.ends_with(' ');
+        for c in rest[..end].chars() {
+            if c.is_whitespace() {
+                if !last_was_space {
+                    output.push(' ');
+                }
+                last_was_space = true;
+            } else {
+                output.push(c);
+                last_was_space = false;
+            }
+        }
+        rest = &rest[end..];
+    }
+    output
+}
+
+/// Minify the html for every page in `pages` in place
+#[instrument(skip(pages))]
+pub fn minify_pages(output_dir: &Path, page_roots: &[PageRoot], pages: &[String]) -> Result<()> {
+    for page in pages {
+        let path = html_output_path(output_dir, page_roots, page);
+        let html = match fs::read_to_string(&path) {
+            Ok(html) => html,
+            // pages that failed to render don't have html
+            Err(_) => continue,
+        };
+        let minified = minify_html(&html);
+        if minified != html {
+            fs::write(&path, minified)
+                .wrap_err_with(|| format!("Failed to write `{}`", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collapses_whitespace_and_comments() {
+        let html = "<!DOCTYPE html>\n<html>\n  <body>\n    <!-- nav -->\n    <a>one</a>   <a>two</a>\n  </body>\n</html>\n";
+        assert_eq!(
+            minify_html(html),
+            "<!DOCTYPE html> <html> <body> <a>one</a> <a>two</a> </body> </html>"
+        );
+    }
+
+    #[test]
+    fn test_keeps_raw_elements() {
+        let html = "<pre>\n  a\n    b\n</pre>\n<script>\nconst a = \"<b>  </b>\";\n</script>";
+        assert_eq!(
+            minify_html(html),
+            "<pre>\n  a\n    b\n</pre> <script>\nconst a = \"<b>  </b>\";\n</script>"
+        );
+    }
+}
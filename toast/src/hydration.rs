@@ -0,0 +1,90 @@
+//! When a page's client-side JS is loaded and hydrated, chosen by the page
+//! module:
+//!
+//! ```js
+//! // "load" (the default), "idle", "visible", or "none"
+//! export const hydrate = "visible";
+//! ```
+//!
+//! `idle` waits for `requestIdleCallback` and `visible` waits until the
+//! page section scrolls into view. Pages that are `none` are static html:
+//! the browser build of the page module isn't written at all and no
+//! hydration script is added to the page.
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HydrateMode {
+    #[default]
+    Load,
+    Idle,
+    Visible,
+    None,
+}
+
+impl HydrateMode {
+    pub fn ships_browser_js(self) -> bool {
+        self != HydrateMode::None
+    }
+}
+
+/// Find `export const hydrate = "..."` in a page's source. Anything that
+/// isn't a known mode falls back to hydrating on load, and the renderer
+/// warns about it.
+pub fn hydrate_mode(source: &str) -> HydrateMode {
+    for line in source.lines() {
+        let rest = match line.trim_start().strip_prefix("export") {
+            Some(rest) => rest.trim_start(),
+            None => continue,
+        };
+        let rest = match ["const", "let", "var"]
+            .iter()
+            .find_map(|keyword| rest.strip_prefix(keyword))
+        {
+            Some(rest) => rest.trim_start(),
+            None => continue,
+        };
+        let value = match rest
+            .strip_prefix("hydrate")
+            .map(str::trim_start)
+            .and_then(|rest| rest.strip_prefix('='))
+        {
+            Some(value) => value.trim().trim_end_matches(';').trim(),
+            None => continue,
+        };
+        let mode = value.trim_matches(&['"', '\'', '`'][..]);
+        return match mode {
+            "idle" => HydrateMode::Idle,
+            "visible" => HydrateMode::Visible,
+            "none" => HydrateMode::None,
+            _ => HydrateMode::Load,
+        };
+    }
+    HydrateMode::default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hydrate_mode() {
+        assert_eq!(
+            hydrate_mode("import { h } from \"preact\";\nexport const hydrate = \"none\";\n"),
+            HydrateMode::None
+        );
+        assert_eq!(
+            hydrate_mode("export const hydrate='idle'"),
+            HydrateMode::Idle
+        );
+        assert_eq!(
+            hydrate_mode("export default () => null;"),
+            HydrateMode::Load
+        );
+        // a different export that happens to start the same way
+        assert_eq!(
+            hydrate_mode("export const hydrated = \"none\";"),
+            HydrateMode::Load
+        );
+    }
+}
@@ -0,0 +1,344 @@
+//! Sites in more than one language, with a directory of pages for each
+//! locale:
+//!
+//! ```toml
+//! [i18n]
+//! locales = ["en", "fr"]
+//! # served from the root of the site rather than under `/en`, unless
+//! # `prefix_default_locale = true`
+//! default_locale = "en"
+//! # where the locale directories are, `content` if it isn't set
+//! dir = "content"
+//! ```
+//!
+//! Each locale's directory is a page root served under its locale, so
+//! `content/fr/about.mdx` is rendered to `/fr/about`. Pages at the same
+//! path in different locales are translations of each other: each gets
+//! `<link rel="alternate" hreflang="...">` tags for the others, with an
+//! `x-default` for the default locale, and `<html lang="...">` if it
+//! doesn't set a `lang` itself. With `base_url` set, every locale also
+//! gets a sitemap, `sitemap-<locale>.xml`, listing its pages and their
+//! translations.
+use color_eyre::eyre::{Result, WrapErr};
+use serde::Deserialize;
+use std::{collections::BTreeMap, fs, path::Path};
+use tracing::instrument;
+
+use crate::{
+    feeds::escape_xml, frontmatter::page_slug, head::absolute, pages::PageRoot,
+    renderer::html_output_path, url_style::UrlStyle,
+};
+
+/// `[i18n]` in `toast.config.toml`
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct I18nConfig {
+    pub locales: Vec<String>,
+    pub default_locale: Option<String>,
+    /// relative to the input directory
+    pub dir: String,
+    /// serve the default locale under its prefix too
+    pub prefix_default_locale: bool,
+}
+
+impl Default for I18nConfig {
+    fn default() -> Self {
+        I18nConfig {
+            locales: vec![],
+            default_locale: None,
+            dir: String::from("content"),
+            prefix_default_locale: false,
+        }
+    }
+}
+
+/// A page and the urls of its translations, by locale
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalizedPage {
+    pub locale: String,
+    pub url: String,
+    pub alternates: BTreeMap<String, String>,
+}
+
+impl I18nConfig {
+    fn is_default(&self, locale: &str) -> bool {
+        self.default_locale.as_deref() == Some(locale)
+    }
+
+    /// The page root for each locale
+    pub fn page_roots(&self) -> Vec<PageRoot> {
+        self.locales
+            .iter()
+            .map(|locale| {
+                let prefix = if self.is_default(locale) && !self.prefix_default_locale {
+                    ""
+                } else {
+                    locale.as_str()
+                };
+                PageRoot::new(
+                    &format!("{}/{}", self.dir.trim_matches('/'), locale),
+                    prefix,
+                )
+            })
+            .collect()
+    }
+
+    /// The locale `page` is in, and its path inside that locale's
+    /// directory
+    fn split<'a>(&'a self, page: &'a str) -> Option<(&'a str, &'a str)> {
+        let dir = self.dir.trim_start_matches("./").trim_matches('/');
+        let rest = page.strip_prefix(dir)?.strip_prefix('/')?;
+        self.locales.iter().find_map(|locale| {
+            let path = rest.strip_prefix(locale.as_str())?.strip_prefix('/')?;
+            Some((locale.as_str(), path))
+        })
+    }
+
+    /// Every page in `pages` that's in a locale, with the urls of its
+    /// translations in the others
+    pub fn localize(
+        &self,
+        page_roots: &[PageRoot],
+        pages: &[String],
+        url_style: UrlStyle,
+        base_url: Option<&str>,
+    ) -> BTreeMap<String, LocalizedPage> {
+        let url = |page: &str| absolute(&url_style.url(&page_slug(page_roots, page)), base_url);
+        let mut translations: BTreeMap<&str, BTreeMap<String, String>> = BTreeMap::new();
+        for page in pages {
+            if let Some((locale, path)) = self.split(page) {
+                translations
+                    .entry(path)
+                    .or_default()
+                    .insert(locale.to_string(), url(page));
+            }
+        }
+        pages
+            .iter()
+            .filter_map(|page| {
+                let (locale, path) = self.split(page)?;
+                Some((
+                    page.clone(),
+                    LocalizedPage {
+                        locale: locale.to_string(),
+                        url: url(page),
+                        alternates: translations.get(path).cloned().unwrap_or_default(),
+                    },
+                ))
+            })
+            .collect()
+    }
+
+    /// The `hreflang` tags for `page`
+    fn alternate_tags(&self, page: &LocalizedPage) -> Vec<String> {
+        if page.alternates.len() < 2 {
+            return vec![];
+        }
+        let mut tags: Vec<String> = page
+            .alternates
+            .iter()
+            .map(|(locale, url)| alternate_tag(locale, url))
+            .collect();
+        if let Some(url) = self
+            .default_locale
+            .as_ref()
+            .and_then(|locale| page.alternates.get(locale))
+        {
+            tags.push(alternate_tag("x-default", url));
+        }
+        tags
+    }
+}
+
+fn alternate_tag(hreflang: &str, url: &str) -> String {
+    format!(
+        r#"<link rel="alternate" hreflang="{}" href="{}">"#,
+        escape_xml(hreflang),
+        escape_xml(url)
+    )
+}
+
+/// Add `lang` to the `<html>` tag of `html` if it doesn't have one, and
+/// `tags` to its head, leaving out any it already has
+pub fn inject_locale(html: &str, locale: &str, tags: &[String]) -> String {
+    let mut html = html.to_string();
+    if let Some(start) = html.find("<html") {
+        let end = html[start..].find('>').map(|index| start + index);
+        if let Some(end) = end {
+            if !html[start..end].contains(" lang=") {
+                html.insert_str(
+                    start + "<html".len(),
+                    &format!(r#" lang="{}""#, escape_xml(locale)),
+                );
+            }
+        }
+    }
+    let tags: String = tags
+        .iter()
+        .filter(|tag| !html.contains(tag.as_str()))
+        .cloned()
+        .collect();
+    if let Some(head_end) = html.find("</head>") {
+        html.insert_str(head_end, &tags);
+    }
+    html
+}
+
+/// A sitemap of the pages in `locale`
+pub fn render_sitemap(locale: &str, pages: &BTreeMap<String, LocalizedPage>) -> String {
+    let mut out = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\" xmlns:xhtml=\"http://www.w3.org/1999/xhtml\">\n",
+    );
+    for page in pages.values().filter(|page| page.locale == locale) {
+        out.push_str(&format!(
+            "  <url>\n    <loc>{}</loc>\n",
+            escape_xml(&page.url)
+        ));
+        if page.alternates.len() > 1 {
+            for (hreflang, url) in &page.alternates {
+                out.push_str(&format!(
+                    "    <xhtml:link rel=\"alternate\" hreflang=\"{}\" href=\"{}\"/>\n",
+                    escape_xml(hreflang),
+                    escape_xml(url)
+                ));
+            }
+        }
+        out.push_str("  </url>\n");
+    }
+    out.push_str("</urlset>\n");
+    out
+}
+
+/// Add `lang` and `hreflang` tags to the rendered html of `pages`, finding
+/// their translations in `all_pages`
+#[instrument(skip(config))]
+pub fn apply_locales(
+    output_dir: &Path,
+    page_roots: &[PageRoot],
+    pages: &[String],
+    all_pages: &[String],
+    config: &I18nConfig,
+    url_style: UrlStyle,
+    base_url: Option<&str>,
+) -> Result<()> {
+    if config.locales.is_empty() {
+        return Ok(());
+    }
+    let localized = config.localize(page_roots, all_pages, url_style, base_url);
+    for page in pages {
+        let localized = match localized.get(page) {
+            Some(localized) => localized,
+            None => continue,
+        };
+        let html_path = html_output_path(output_dir, page_roots, page);
+        let html = match fs::read_to_string(&html_path) {
+            Ok(html) => html,
+            // pages that failed to render
+            Err(_) => continue,
+        };
+        let with_locale =
+            inject_locale(&html, &localized.locale, &config.alternate_tags(localized));
+        if with_locale != html {
+            fs::write(&html_path, with_locale)
+                .wrap_err_with(|| format!("Failed to write `{}`", html_path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// The name of `locale`'s sitemap in the output directory
+pub fn sitemap_file(locale: &str) -> String {
+    format!("sitemap-{}.xml", locale)
+}
+
+/// Write `sitemap-<locale>.xml` for every locale. Sitemaps need absolute
+/// urls, so there aren't any without `base_url`.
+#[instrument(skip(config))]
+pub fn write_sitemaps(
+    output_dir: &Path,
+    page_roots: &[PageRoot],
+    pages: &[String],
+    config: &I18nConfig,
+    url_style: UrlStyle,
+    base_url: Option<&str>,
+) -> Result<()> {
+    if base_url.is_none() {
+        return Ok(());
+    }
+    let localized = config.localize(page_roots, pages, url_style, base_url);
+    for locale in &config.locales {
+        let sitemap_path = output_dir.join(sitemap_file(locale));
+        fs::write(&sitemap_path, render_sitemap(locale, &localized))
+            .wrap_err_with(|| format!("Failed to write `{}`", sitemap_path.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> I18nConfig {
+        I18nConfig {
+            locales: vec![String::from("en"), String::from("fr")],
+            default_locale: Some(String::from("en")),
+            ..I18nConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_page_roots() {
+        assert_eq!(
+            config().page_roots(),
+            vec![
+                PageRoot::new("content/en", ""),
+                PageRoot::new("content/fr", "fr")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_localize_and_inject() {
+        let config = config();
+        let mut page_roots = vec![PageRoot::new("src/pages", "")];
+        page_roots.extend(config.page_roots());
+        let pages: Vec<String> = vec![
+            "content/en/about.js",
+            "content/fr/about.js",
+            "content/fr/only.js",
+            "src/pages/index.js",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect();
+        let localized = config.localize(
+            &page_roots,
+            &pages,
+            UrlStyle::Bare,
+            Some("https://toast.dev/"),
+        );
+        assert_eq!(localized.len(), 3);
+        let about = &localized["content/fr/about.js"];
+        assert_eq!(about.url, "https://toast.dev/fr/about");
+        assert_eq!(about.alternates["en"], "https://toast.dev/about");
+
+        let html = inject_locale(
+            "<html><head></head></html>",
+            &about.locale,
+            &config.alternate_tags(about),
+        );
+        assert!(html.starts_with(r#"<html lang="fr"><head><link rel="alternate" hreflang="en" href="https://toast.dev/about">"#));
+        assert!(html.contains(
+            r#"<link rel="alternate" hreflang="x-default" href="https://toast.dev/about">"#
+        ));
+        // a page without translations is only given its lang
+        assert_eq!(
+            inject_locale(
+                "<html lang=\"fr-CA\"><head></head></html>",
+                "fr",
+                &config.alternate_tags(&localized["content/fr/only.js"])
+            ),
+            "<html lang=\"fr-CA\"><head></head></html>"
+        );
+    }
+}
@@ -0,0 +1,322 @@
+//! Responsive image variants for everything under `static/images`.
+//!
+//! Each image is resized to the configured widths and re-encoded to the
+//! configured formats by `toast-images` (which uses sharp), and written to
+//! `_images/` in the output directory. A manifest of every variant is
+//! written to `_images/manifest.json`, keyed by the public path of the
+//! original image, so components can build a `srcset`:
+//!
+//! ```json
+//! {
+//!   "/images/hero.jpg": {
+//!     "width": 2400,
+//!     "height": 1600,
+//!     "variants": [{ "src": "/_images/hero-1a2b3c4d-480.webp", "width": 480, "format": "webp" }],
+//!     "srcset": { "webp": "/_images/hero-1a2b3c4d-480.webp 480w" }
+//!   }
+//! }
+//! ```
+//!
+//! Images are only processed again when their contents or the image
+//! config change.
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use duct::cmd;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+use tracing::instrument;
+use walkdir::WalkDir;
+
+use crate::{
+    cache::{content_hash, Cache},
+    node::node_bin,
+    npm_bin::node_script,
+    supervisor::{batch_timeout, run_node},
+};
+
+/// Directory in the output directory that variants are written to
+pub const IMAGES_OUTPUT_DIR: &str = "_images";
+
+const IMAGE_EXTENSIONS: [&str; 5] = ["jpg", "jpeg", "png", "webp", "avif"];
+
+/// `[images]` in `toast.config.toml`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ImageConfig {
+    /// widths to resize to. Images are never enlarged, so widths larger
+    /// than the original are skipped.
+    pub widths: Vec<u32>,
+    pub formats: Vec<ImageFormat>,
+}
+
+impl Default for ImageConfig {
+    fn default() -> Self {
+        ImageConfig {
+            widths: vec![480, 960, 1920],
+            formats: vec![ImageFormat::Webp, ImageFormat::Avif],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    Webp,
+    Avif,
+    Jpeg,
+    Png,
+}
+
+impl ImageFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Webp => "webp",
+            ImageFormat::Avif => "avif",
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Png => "png",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ImageVariant {
+    pub src: String,
+    pub width: u32,
+    pub format: ImageFormat,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ImageEntry {
+    pub width: u32,
+    pub height: u32,
+    pub variants: Vec<ImageVariant>,
+    pub srcset: BTreeMap<ImageFormat, String>,
+}
+
+pub type ImageManifest = BTreeMap<String, ImageEntry>;
+
+/// What `toast-images` is asked to do for one image
+#[derive(Debug, Serialize)]
+struct ImageJob {
+    input: PathBuf,
+    /// output path without the width and extension
+    output_prefix: PathBuf,
+    src_prefix: String,
+    widths: Vec<u32>,
+    formats: Vec<ImageFormat>,
+}
+
+/// What `toast-images` reports back for one image
+#[derive(Debug, Deserialize)]
+struct ProcessedImage {
+    width: u32,
+    height: u32,
+    variants: Vec<ImageVariant>,
+}
+
+pub fn is_image(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| IMAGE_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+/// Comma separated `srcset` for one format, smallest first
+pub fn srcset(variants: &[ImageVariant], format: ImageFormat) -> String {
+    let mut matching: Vec<&ImageVariant> = variants
+        .iter()
+        .filter(|variant| variant.format == format)
+        .collect();
+    matching.sort_by_key(|variant| variant.width);
+    matching
+        .iter()
+        .map(|variant| format!("{} {}w", variant.src, variant.width))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Generate variants for every image in `static/images` that has changed
+/// since the last build and write the image manifest.
+#[instrument(skip(cache))]
+pub fn process_images(
+    project_root_dir: &Path,
+    output_dir: &Path,
+    npm_bin_dir: &Path,
+    config: &ImageConfig,
+    cache: &mut Cache,
+) -> Result<()> {
+    let static_dir = project_root_dir.join("static");
+    let images_dir = static_dir.join("images");
+    if !images_dir.exists() {
+        return Ok(());
+    }
+    let variants_dir = output_dir.join(IMAGES_OUTPUT_DIR);
+    fs::create_dir_all(&variants_dir).wrap_err_with(|| {
+        format!(
+            "Failed to create image directory `{}`",
+            variants_dir.display()
+        )
+    })?;
+    let manifest_path = variants_dir.join("manifest.json");
+    let previous: ImageManifest = fs::read_to_string(&manifest_path)
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .unwrap_or_default();
+    let config_json = serde_json::to_string(config)?;
+
+    let mut manifest = ImageManifest::new();
+    let mut jobs: Vec<(String, String, ImageJob)> = vec![];
+    for entry in WalkDir::new(&images_dir)
+        .into_iter()
+        .filter_map(|result| result.ok())
+        .filter(|entry| entry.file_type().is_file() && is_image(entry.path()))
+    {
+        let path = entry.path();
+        let relative = path
+            .strip_prefix(&static_dir)
+            .wrap_err_with(|| format!("Failed to make `{}` relative", path.display()))?;
+        let public_path = format!(
+            "/{}",
+            relative
+                .to_str()
+                .ok_or_else(|| eyre!("`{}` is not a valid utf8 path", path.display()))?
+                .replace('\\', "/")
+        );
+        let contents =
+            fs::read(path).wrap_err_with(|| format!("Failed to read `{}`", path.display()))?;
+        let hash = content_hash(&[&contents, config_json.as_bytes()]);
+        let cache_key = format!("image:{}", public_path);
+        match previous.get(&public_path) {
+            Some(entry) if cache.is_fresh(&cache_key, &hash) => {
+                manifest.insert(public_path, entry.clone());
+            }
+            _ => {
+                let stem = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .unwrap_or("image");
+                let name = format!("{}-{}", stem, &hash[..8]);
+                // keep subdirectories of static/images so equal file
+                // names in different directories don't collide
+                let sub_dir = relative
+                    .strip_prefix("images")
+                    .ok()
+                    .and_then(|rel| rel.parent())
+                    .unwrap_or_else(|| Path::new(""));
+                let sub_dir_url = sub_dir.to_str().unwrap_or("").replace('\\', "/");
+                let src_prefix = if sub_dir_url.is_empty() {
+                    format!("/{}/{}", IMAGES_OUTPUT_DIR, name)
+                } else {
+                    format!("/{}/{}/{}", IMAGES_OUTPUT_DIR, sub_dir_url, name)
+                };
+                let job = ImageJob {
+                    input: path.to_path_buf(),
+                    output_prefix: variants_dir.join(sub_dir).join(&name),
+                    src_prefix,
+                    widths: config.widths.clone(),
+                    formats: config.formats.clone(),
+                };
+                jobs.push((public_path, hash, job));
+            }
+        }
+    }
+
+    if !jobs.is_empty() {
+        let processed = run_toast_images(
+            &jobs.iter().map(|(_, _, job)| job).collect::<Vec<_>>(),
+            npm_bin_dir,
+        )?;
+        for ((public_path, hash, _), image) in jobs.into_iter().zip(processed) {
+            let outputs = image
+                .variants
+                .iter()
+                .map(|variant| output_dir.join(variant.src.trim_start_matches('/')))
+                .collect();
+            cache.record_outputs(&format!("image:{}", public_path), hash, outputs);
+            let srcset = config
+                .formats
+                .iter()
+                .map(|format| (*format, srcset(&image.variants, *format)))
+                .collect();
+            manifest.insert(
+                public_path,
+                ImageEntry {
+                    width: image.width,
+                    height: image.height,
+                    variants: image.variants,
+                    srcset,
+                },
+            );
+        }
+    }
+
+    fs::write(&manifest_path, serde_json::to_string_pretty(&manifest)?).wrap_err_with(|| {
+        format!(
+            "Failed to write image manifest `{}`",
+            manifest_path.display()
+        )
+    })?;
+    Ok(())
+}
+
+/// All of the jobs are sent to one node process as JSON on stdin, and it
+/// answers with one result per job, in the same order.
+#[instrument]
+fn run_toast_images(jobs: &[&ImageJob], npm_bin_dir: &Path) -> Result<Vec<ProcessedImage>> {
+    let bin = node_script(npm_bin_dir, "toast-images");
+    let bin_str = bin
+        .to_str()
+        .ok_or_else(|| eyre!("failed to make npm bin into str"))?;
+    let output = run_node(
+        "toast-images",
+        cmd!(node_bin(), "--unhandled-rejections", "strict", bin_str)
+            .stdin_bytes(serde_json::to_vec(jobs)?),
+        batch_timeout(jobs.len()),
+    )
+    .wrap_err("Failed to process images with `toast-images`")?;
+    let processed: Vec<ProcessedImage> = serde_json::from_str(&output)
+        .wrap_err_with(|| format!("Failed to parse output from `toast-images`: {}", output))?;
+    if processed.len() != jobs.len() {
+        return Err(eyre!(
+            "`toast-images` processed {} images but was given {}",
+            processed.len(),
+            jobs.len()
+        ));
+    }
+    Ok(processed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variant(width: u32, format: ImageFormat) -> ImageVariant {
+        ImageVariant {
+            src: format!("/_images/hero-{}.{}", width, format.extension()),
+            width,
+            format,
+        }
+    }
+
+    #[test]
+    fn test_srcset_is_sorted_by_width() {
+        let variants = vec![
+            variant(960, ImageFormat::Webp),
+            variant(480, ImageFormat::Avif),
+            variant(480, ImageFormat::Webp),
+        ];
+        assert_eq!(
+            srcset(&variants, ImageFormat::Webp),
+            "/_images/hero-480.webp 480w, /_images/hero-960.webp 960w"
+        );
+    }
+
+    #[test]
+    fn test_is_image() {
+        assert!(is_image(Path::new("static/images/hero.JPG")));
+        assert!(!is_image(Path::new("static/images/logo.svg")));
+    }
+}
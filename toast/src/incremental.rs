@@ -1,47 +1,154 @@
 use crate::{
+    base_path::apply_base_path,
     cache::init,
     cache::Cache,
+    cache::CACHE_DIR,
+    cancel,
+    content_index::{self, PageEntry},
+    css::{link_stylesheets, split_css_imports, write_stylesheets, CssConfig},
+    diagnostics::{Diagnostic, Diagnostics},
+    dry_run::BuildPlan,
     esinstall::ImportMap,
+    excerpt::ExcerptConfig,
+    exit_code::Failure,
+    feeds::{write_feeds, FeedConfig, FeedItem},
+    filesystem::Filesystem,
+    fingerprint::fingerprint_assets,
+    fonts::{write_fonts, FontConfig},
+    frontmatter::{self, FrontmatterIndex},
+    head::{apply_page_meta, META_DIR},
+    headers::{write_headers, HeadersConfig},
+    html_minify::minify_pages,
+    hydration::HydrateMode,
+    i18n::{apply_locales, write_sitemaps, I18nConfig},
+    images::{process_images, ImageConfig},
+    integrity::apply_integrity,
     internal_api::{ModuleSpec, SetDataForSlug},
-    node::{render_to_html, source_data},
+    markdown::{compile_markdown, MarkdownConfig},
+    mdx::{compile_mdx, CompiledMdx, HighlightConfig},
+    pages::{case_collisions, is_page, matches_filter, page_path, source_id, PageRoot, Shard},
+    pagination::{paginate, reexport_source, PaginateConfig},
+    plugins::Plugins,
+    precompress::precompress,
+    preload::{link_module_preloads, PreloadConfig},
+    progress::Progress,
+    redirects::{collect_redirects, write_redirects},
+    related::{self, related_pages, RelatedConfig},
+    remote_data::fetch_remote_data,
+    renderer::{html_output_path, RenderJob, Renderer},
+    report::{as_ms, log_page_timings, BuildReport, FileReport, RenderFailure},
+    reproducible::{build_time, normalize_timestamps},
+    robots::{write_robots, RobotsConfig},
+    search::{self, page_text, SearchConfig},
+    social_cards::{write_social_cards, SocialCardConfig},
+    source_data::{find_data_file, source_data},
+    source_maps::{map_path, with_mapping_url},
     sources::{Source, SourceKind},
+    swc_ops::{BrowserJsOptions, CompiledJs},
+    taxonomy::{taxonomy_pages, write_terms, TaxonomyConfig},
+    toc::{page_props, TocConfig, TocEntry},
+    url_style::{apply_url_style, UrlStyle},
 };
-use async_std::task;
+use chrono::{DateTime, Utc};
 use color_eyre::eyre::{eyre, Result, WrapErr};
-use crossbeam::{unbounded, Sender};
-use fs_extra::dir::{copy, CopyOptions};
-use indicatif::{ProgressBar, ProgressStyle};
+use crossbeam::unbounded;
+use glob::Pattern;
 use serde_json::value::Value;
-use std::sync::Arc;
 use std::{
-    collections::HashMap,
-    fs,
+    collections::{BTreeMap, BTreeSet, HashMap},
     path::{Path, PathBuf},
-    process,
 };
-use tracing::instrument;
-use walkdir::WalkDir;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+use tracing::{info, instrument};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct IncrementalOpts<'a> {
     pub debug: bool,
     pub project_root_dir: &'a PathBuf,
     pub output_dir: PathBuf,
+    /// where page modules are, see `pages::PageRoot`
+    pub page_roots: Vec<PageRoot>,
+    pub i18n: I18nConfig,
+    /// build pages with `draft: true` or a future `date`
+    pub drafts: bool,
     pub npm_bin_dir: PathBuf,
     pub import_map: ImportMap,
+    /// how many files to compile at the same time
+    pub jobs: usize,
+    /// `--render-timeout`, which also stops a `sourceData` that goes quiet
+    pub render_timeout: Option<Duration>,
+    pub base_url: Option<String>,
+    /// see `base_path`
+    pub base_path: Option<String>,
+    pub feeds: Vec<FeedConfig>,
+    pub fonts: Vec<FontConfig>,
+    pub images: ImageConfig,
+    pub paginate: Vec<PaginateConfig>,
+    pub taxonomies: Vec<TaxonomyConfig>,
+    pub search: Vec<SearchConfig>,
+    pub related: Vec<RelatedConfig>,
+    /// old url to new url
+    pub redirects: BTreeMap<String, String>,
+    pub headers: HeadersConfig,
+    pub required_frontmatter: Vec<String>,
+    pub robots: RobotsConfig,
+    /// write content-hashed copies of JS and CSS after full builds
+    pub fingerprint: bool,
+    /// `integrity` attributes on stylesheets and scripts, see `integrity`
+    pub integrity: bool,
+    pub css: CssConfig,
+    pub highlight: HighlightConfig,
+    pub markdown: MarkdownConfig,
+    pub toc: TocConfig,
+    pub excerpt: ExcerptConfig,
+    pub preload: PreloadConfig,
+    pub minify_html: bool,
+    /// write `.br` and `.gz` copies of html, JS, and CSS outputs
+    pub precompress: bool,
+    /// write a source map for every browser module, see `source_maps`
+    pub source_maps: bool,
+    /// minify browser modules
+    pub minify_js: bool,
+    pub social_cards: SocialCardConfig,
+    pub url_style: UrlStyle,
+    /// sorted traversal and fixed timestamps, see `reproducible`
+    pub reproducible: bool,
+    /// use cached remote data only, see `remote_data`
+    pub offline: bool,
+    /// delete outputs of sources that no longer exist after full builds
+    pub prune: bool,
+    /// only render pages that match, see `pages::matches_filter`
+    pub filter: Option<Pattern>,
+    /// only render this part of the pages, see `pages::Shard`
+    pub shard: Option<Shard>,
+    pub renderer: Arc<dyn Renderer>,
+    /// where sources are read from and compiled outputs written to, but
+    /// not the steps after rendering, see `filesystem`
+    pub fs: Arc<dyn Filesystem>,
+    pub diagnostics: Diagnostics,
+    pub plugins: Plugins,
+}
+
+impl IncrementalOpts<'_> {
+    fn browser_js(&self) -> BrowserJsOptions {
+        BrowserJsOptions {
+            source_maps: self.source_maps,
+            minify: self.minify_js,
+        }
+    }
 }
 
 #[derive(Debug)]
 struct OutputFile {
     dest: String,
+    /// from the top of `.mdx` and `.md` files
+    frontmatter: Option<Value>,
+    toc: Vec<TocEntry>,
 }
 
-#[derive(Clone)]
-struct TideSharedState {
-    tx: Sender<Event>,
-    output_dir: PathBuf,
-    create_page_progress_bar: Arc<ProgressBar>,
-}
 #[derive(Debug, Clone)]
 enum Event {
     Set(SetDataForSlug),
@@ -49,117 +156,105 @@ enum Event {
 
 #[instrument]
 pub async fn incremental_compile(opts: IncrementalOpts<'_>) -> Result<()> {
+    let mut cache = init(opts.npm_bin_dir.clone(), opts.project_root_dir);
+    build_with_cache(opts, &mut cache, &mut BuildReport::default()).await?;
+    Ok(())
+}
+
+/// Run a full build using an existing incremental cache, returning the
+/// list of page modules that were rendered to html so that later
+/// rebuilds know which pages exist. What the build did is recorded in
+/// `report`.
+#[instrument(skip(cache, report))]
+pub async fn build_with_cache(
+    opts: IncrementalOpts<'_>,
+    cache: &mut Cache,
+    report: &mut BuildReport,
+) -> Result<Vec<String>> {
+    let start = Instant::now();
+    report.started_at = Some(SystemTime::now());
     let IncrementalOpts {
-        debug,
         project_root_dir,
         output_dir,
         npm_bin_dir,
-        import_map,
-    } = opts;
+        ..
+    } = opts.clone();
     let tmp_dir = {
         let mut dir = project_root_dir.clone();
         dir.push(".tmp");
         dir
     };
-    std::fs::create_dir_all(&tmp_dir).wrap_err_with(|| {
+    opts.fs.create_dir_all(&tmp_dir).wrap_err_with(|| {
         format!(
             "Failed to create directories for tmp_dir `{}`. Can not compile files into directory that doesn't exist, exiting.",
             &tmp_dir.display()
         )
     })?;
 
-    let create_pages_pb = Arc::new(ProgressBar::new_spinner());
-    create_pages_pb.enable_steady_tick(120);
-    create_pages_pb.set_style(
-        ProgressStyle::default_spinner()
-            // For more spinners check out the cli-spinners project:
-            // https://github.com/sindresorhus/cli-spinners/blob/master/spinners.json
-            .tick_strings(&["▹▹▹", "▸▹▹", "▹▸▹", "▹▹▸", "▪▪▪"])
-            .template("{spinner:.blue} [{elapsed}] {pos} {wide_msg}"),
-    );
-    create_pages_pb.set_message("fetching data...");
-    create_pages_pb.tick();
-    // channel to listen for createPage events
-    let (tx, rx) = unbounded();
-    // create incremental cache db
-    let mut cache = init(npm_bin_dir.clone());
-
-    // boot server
-    let mut app = tide::with_state(TideSharedState {
-        tx,
-        output_dir: output_dir.clone(),
-        create_page_progress_bar: create_pages_pb.clone(),
-    });
-    let sock = format!("http+unix://{}", "/var/tmp/toaster.sock");
-    app.at("/").get(|_| async { Ok("ready") });
-    app.at("/set-data-for-slug")
-        .post(|mut req: tide::Request<TideSharedState>| async move {
-            let mut data: SetDataForSlug = req.body_json().await?;
-            req.state()
-                .create_page_progress_bar
-                .set_message(data.slug.as_str());
-            req.state().create_page_progress_bar.inc(1);
-
-            data.normalize();
-
-            req.state().tx.send(Event::Set(data))?;
-            Ok("ok")
-        });
-    app.at("/terminate").get(|_| async {
-        let _result = fs::remove_file("/var/tmp/toaster.sock");
-        process::exit(0);
-        #[allow(unreachable_code)]
-        Ok("done")
-    });
-    let server = task::spawn(app.listen(sock));
-
-    let files_by_source_id = compile_src_files(
-        IncrementalOpts {
-            debug,
-            project_root_dir: &project_root_dir,
-            output_dir: output_dir.clone(),
-            npm_bin_dir: npm_bin_dir.clone(),
-            import_map: import_map.clone(),
-        },
-        &mut cache,
-        &tmp_dir,
-    )?;
+    let progress = Progress::new();
+    progress.status("compiling...");
+    let compile_start = Instant::now();
+    let files_by_source_id = compile_src_files(opts.clone(), cache, &tmp_dir, report, &progress)?;
+    report.record_phase("compile", start, compile_start);
     // render_src_pages()?;
     let file_list = files_by_source_id
         .iter()
         .map(|(_, output_file)| output_file.dest.clone())
         .collect::<Vec<String>>();
-    let _data_from_user = source_data(
-        &project_root_dir.join("toast.js"),
-        npm_bin_dir.clone(),
-        create_pages_pb.clone(),
-    )
-    .await?;
-
-    let _maybe_gone = server.cancel();
-    let _result = fs::remove_file("/var/tmp/toaster.sock");
-    create_pages_pb.abandon_with_message("pages created");
-
-    let set_data_events: Vec<Event> = rx.try_iter().collect();
-    let event_len: u64 = set_data_events.len() as u64;
-    let compile_pb = Arc::new(ProgressBar::new_spinner());
-    compile_pb.enable_steady_tick(120);
-    compile_pb.set_length(event_len);
-    compile_pb.set_style(
-        ProgressStyle::default_spinner()
-            // For more spinners check out the cli-spinners project:
-            // https://github.com/sindresorhus/cli-spinners/blob/master/spinners.json
-            .tick_strings(&["▹▹▹", "▸▹▹", "▹▸▹", "▹▹▸", "▪▪▪"])
-            .template("{spinner:.blue} [{elapsed}] {pos}/{len} {wide_msg}"),
-    );
-    compile_pb.set_message("compiling...");
-    compile_pb.tick();
+    let mut frontmatter_index = FrontmatterIndex::new();
+    for output_file in files_by_source_id.values() {
+        if !is_page(&opts.page_roots, &output_file.dest) {
+            continue;
+        }
+        if let Some(props) = page_props(output_file.frontmatter.as_ref(), &output_file.toc) {
+            write_page_props(
+                opts.fs.as_ref(),
+                &output_dir,
+                &opts.page_roots,
+                &output_file.dest,
+                &props,
+            )?;
+        }
+        if let Some(frontmatter) = &output_file.frontmatter {
+            frontmatter_index.insert(
+                frontmatter::page_slug(&opts.page_roots, &output_file.dest),
+                frontmatter.clone(),
+            );
+        }
+    }
+    frontmatter::write_index(&frontmatter_index, &output_dir)?;
+    report_missing_frontmatter(&opts, &frontmatter_index)?;
+    let set_data_events: Vec<Event> = match find_data_file(project_root_dir) {
+        Some(data_file) => {
+            progress.status("fetching data...");
+            let remote_data = fetch_remote_data(
+                project_root_dir,
+                &data_file,
+                &npm_bin_dir,
+                &tmp_dir,
+                opts.offline,
+                &opts.diagnostics,
+            )
+            .await?;
+            source_data(
+                &data_file,
+                &npm_bin_dir,
+                remote_data.as_deref(),
+                opts.render_timeout,
+                &progress,
+            )
+            .wrap_err_with(|| format!("Failed to source data from `{}`", data_file.display()))?
+            .into_iter()
+            .map(Event::Set)
+            .collect()
+        }
+        None => vec![],
+    };
 
     for x in set_data_events.clone() {
         match x {
             Event::Set(set) => {
-                compile_pb.inc(1);
-                compile_pb.set_message(set.slug.as_str());
+                progress.status(set.slug.as_str());
                 let slug_filepath = set.slug_as_relative_filepath();
                 let mut output_path_js = set.slug_as_relative_filepath();
                 output_path_js.set_extension("js");
@@ -189,17 +284,15 @@ pub async fn incremental_compile(opts: IncrementalOpts<'_>) -> Result<()> {
                             &set.slug,
                             &OutputFile {
                                 dest: output_path_js.display().to_string(),
+                                frontmatter: None,
+                                toc: vec![],
                             },
-                            IncrementalOpts {
-                                debug,
-                                project_root_dir: &project_root_dir,
-                                output_dir: output_dir.clone(),
-                                npm_bin_dir: npm_bin_dir.clone(),
-                                import_map: import_map.clone(),
-                            },
-                            &mut cache,
+                            opts.clone(),
+                            cache,
                             &tmp_dir,
+                            report,
                         )?;
+                        progress.compiled(&set.slug);
                     }
                 }
                 match &set.data {
@@ -215,8 +308,11 @@ pub async fn incremental_compile(opts: IncrementalOpts<'_>) -> Result<()> {
                         // so that files can depend on them via derived queries
                         let mut json_path = output_dir.join(slug_filepath);
                         json_path.set_extension("json");
-                        std::fs::create_dir_all(&json_path.parent().unwrap())?;
-                        fs::write(json_path, v.to_string())?
+                        opts.fs.create_dir_all(json_path.parent().unwrap())?;
+                        let json = v.to_string();
+                        report.bytes_written += json.len() as u64;
+                        opts.fs.write(&json_path, json.as_bytes())?;
+                        progress.written(1);
                     }
                     None => {}
                 }
@@ -229,7 +325,65 @@ pub async fn incremental_compile(opts: IncrementalOpts<'_>) -> Result<()> {
             }
         };
     }
-    compile_pb.abandon_with_message("remote sources compiled");
+
+    // written before rendering so pages can list other pages
+    let page_entries: Vec<PageEntry> = frontmatter_index
+        .iter()
+        .map(|(slug, frontmatter)| PageEntry {
+            slug: slug.clone(),
+            data: frontmatter.clone(),
+        })
+        .chain(
+            set_data_events
+                .iter()
+                .filter_map(|Event::Set(set)| match &set.data {
+                    Some(Value::Null) | None => None,
+                    Some(data) => Some(PageEntry {
+                        slug: set.slug.clone(),
+                        data: data.clone(),
+                    }),
+                }),
+        )
+        .collect();
+    let paginated_pages = build_pagination(
+        opts.clone(),
+        cache,
+        &tmp_dir,
+        &page_entries,
+        &frontmatter_index,
+        report,
+    )?;
+    let taxonomy_pages =
+        build_taxonomies(opts.clone(), cache, &tmp_dir, &page_entries, report)?.pages;
+    content_index::write_index(page_entries.clone(), &output_dir)?;
+    let mut text = BTreeMap::new();
+    if needs_page_text(&opts) {
+        for (source_id, output_file) in &files_by_source_id {
+            if is_mdx(Path::new(source_id)) && is_page(&opts.page_roots, &output_file.dest) {
+                let path = project_root_dir.join(source_id);
+                let source = opts
+                    .fs
+                    .read_to_string(&path)
+                    .wrap_err_with(|| format!("Failed to read `{}`", path.display()))?;
+                text.insert(
+                    frontmatter::page_slug(&opts.page_roots, &output_file.dest),
+                    page_text(&source),
+                );
+            }
+        }
+        search::write_text(project_root_dir, &text)?;
+    }
+    if !opts.search.is_empty() {
+        search::write_search_indexes(
+            &opts.search,
+            &page_entries,
+            &text,
+            opts.url_style,
+            opts.base_path.as_deref(),
+            &output_dir,
+        )?;
+    }
+    write_related_props(&opts, &file_list, &page_entries, &text)?;
 
     let remote_file_list: Vec<String> = set_data_events
         .iter()
@@ -247,176 +401,1692 @@ pub async fn incremental_compile(opts: IncrementalOpts<'_>) -> Result<()> {
     let mut list: Vec<String> = file_list
         .clone()
         .iter()
-        .filter(|f| f.starts_with("src/pages"))
+        .filter(|f| is_page(&opts.page_roots, f))
         .cloned()
         .collect();
     list.extend(remote_file_list);
+    for page in paginated_pages.into_iter().chain(taxonomy_pages) {
+        if !list.contains(&page) {
+            list.push(page);
+        }
+    }
+    if opts.reproducible {
+        list.sort();
+    }
+    opts.plugins.on_pages_discovered(&mut list)?;
+    if let Some(filter) = &opts.filter {
+        list.retain(|page| matches_filter(&opts.page_roots, filter, page));
+    }
+    if let Some(shard) = &opts.shard {
+        list.retain(|page| shard.contains(page));
+    }
 
-    let render_pb = Arc::new(ProgressBar::new_spinner());
-    render_pb.enable_steady_tick(120);
-    render_pb.set_style(
-        ProgressStyle::default_spinner()
-            // For more spinners check out the cli-spinners project:
-            // https://github.com/sindresorhus/cli-spinners/blob/master/spinners.json
-            .tick_strings(&["▹▹▹", "▸▹▹", "▹▸▹", "▹▹▸", "▪▪▪"])
-            .template("{spinner:.blue} [{elapsed}] {wide_msg}"),
+    progress.pages(list.len() as u64);
+    progress.status("rendering html...");
+    cancel::check()?;
+    let render_start = Instant::now();
+    let rendered = opts
+        .renderer
+        .render(RenderJob {
+            dir_of_input_files: &tmp_dir,
+            output_dir: &output_dir,
+            pages: &list,
+            page_roots: &opts.page_roots,
+            progress: progress.clone(),
+        })
+        .wrap_err(Failure::Render)?;
+    // the rest of the site is still written, unless the failure policy is
+    // strict
+    report_render_failures(&opts, &rendered.failures)?;
+    report.page_timings = rendered.timings;
+    report.render_failures = rendered.failures;
+    report.record_phase("render", start, render_start);
+    // everything after rendering rewrites or writes out the site
+    let write_start = Instant::now();
+    log_page_timings(&report.page_timings);
+    progress.status("writing the site...");
+    cancel::check()?;
+    apply_page_meta(
+        &output_dir,
+        &opts.page_roots,
+        &list,
+        opts.base_url.as_deref(),
+    )?;
+    apply_locales(
+        &output_dir,
+        &opts.page_roots,
+        &list,
+        &list,
+        &opts.i18n,
+        opts.url_style,
+        opts.base_url.as_deref(),
+    )?;
+    opts.plugins
+        .apply_html_rendered(&output_dir, &opts.page_roots, &list)?;
+    write_sitemaps(
+        &output_dir,
+        &opts.page_roots,
+        &list,
+        &opts.i18n,
+        opts.url_style,
+        opts.base_url.as_deref(),
+    )?;
+    let stylesheets = write_stylesheets(
+        project_root_dir,
+        &output_dir,
+        &opts.page_roots,
+        &list,
+        &opts.css,
+    )?;
+    link_stylesheets(&output_dir, &opts.page_roots, &stylesheets, &opts.css)?;
+    link_module_preloads(
+        &output_dir,
+        &opts.page_roots,
+        &list,
+        &list,
+        &opts.preload,
+        cache,
+    )?;
+    write_fonts(
+        project_root_dir,
+        &output_dir,
+        &opts.npm_bin_dir,
+        &opts.page_roots,
+        &list,
+        &opts.fonts,
+        cache,
+    )?;
+    if opts.social_cards.enabled {
+        write_social_cards(
+            &output_dir,
+            &opts.npm_bin_dir,
+            &opts.page_roots,
+            &list,
+            opts.base_url.as_deref(),
+            &opts.social_cards,
+            cache,
+        )?;
+    }
+    if opts.minify_html {
+        minify_pages(&output_dir, &opts.page_roots, &list)?;
+    }
+    report.bytes_written += html_bytes(opts.fs.as_ref(), &output_dir, &opts.page_roots, &list);
+    progress.written(list.len() as u64);
+    apply_url_style(&output_dir, &opts.page_roots, &list, &list, opts.url_style)?;
+    record_page_outputs(
+        opts.fs.as_ref(),
+        cache,
+        &output_dir,
+        &opts.page_roots,
+        opts.url_style,
+        &list,
     );
-    render_pb.set_message("rendering html...");
-    render_pb.tick();
-    render_to_html(
-        tmp_dir.into_os_string().into_string().unwrap(),
-        output_dir.into_os_string().into_string().unwrap(),
-        list,
-        npm_bin_dir,
-        render_pb.clone(),
+
+    let feed_items: Vec<FeedItem> = set_data_events
+        .iter()
+        .filter_map(|Event::Set(set)| {
+            set.data
+                .as_ref()
+                .and_then(|data| FeedItem::from_page_data(&set.slug, data))
+        })
+        .chain(
+            frontmatter_index
+                .iter()
+                .filter_map(|(slug, frontmatter)| FeedItem::from_page_data(slug, frontmatter)),
+        )
+        .collect();
+    write_feeds(
+        &opts.feeds,
+        opts.base_url.as_deref(),
+        opts.url_style,
+        &feed_items,
+        &output_dir,
     )?;
-    render_pb.abandon_with_message("html rendered");
-
-    // # copy static dir to public dir
-    //
-    // * copy_inside seems to be for copying the whole `static` folder to
-    //   `public/static`.
-    // * `content_only` seems to be for copying `static/*` into `public/`
-    let options = CopyOptions {
-        copy_inside: false,
-        overwrite: true,
-        content_only: true,
-        ..CopyOptions::new()
-    };
+
+    copy_static_dir(opts.fs.as_ref(), project_root_dir, &output_dir)?;
+    write_redirects(
+        project_root_dir,
+        &output_dir,
+        &collect_redirects(&opts.redirects, &page_entries),
+    )?;
+    // after `static/` is copied, so a `static/robots.txt` can't replace a
+    // disallow-all one
+    write_robots(
+        project_root_dir,
+        &output_dir,
+        &opts.robots,
+        &opts.i18n,
+        opts.base_url.as_deref(),
+    )?;
+    process_images(
+        project_root_dir,
+        &output_dir,
+        &opts.npm_bin_dir,
+        &opts.images,
+        cache,
+    )?;
+    if opts.fingerprint {
+        fingerprint_assets(&output_dir, opts.base_path.as_deref())?;
+    }
+    if let Some(base_path) = &opts.base_path {
+        apply_base_path(&output_dir, base_path)?;
+    }
+    if opts.integrity {
+        apply_integrity(&output_dir, opts.base_path.as_deref())?;
+    }
+    write_headers(
+        project_root_dir,
+        &output_dir,
+        opts.base_path.as_deref(),
+        &opts.headers,
+    )?;
+    if opts.precompress {
+        precompress(&output_dir, opts.jobs, cache)?;
+    }
+    // pages left out by `--filter` or `--shard` didn't record their
+    // outputs
+    if opts.prune && opts.filter.is_none() && opts.shard.is_none() {
+        // everything a source produced is checked or recorded during a
+        // full build, so whatever wasn't belongs to files that are gone
+        cache.prune(opts.fs.as_ref())?;
+    }
+    cache.save()?;
+    if opts.reproducible {
+        normalize_timestamps(
+            &[&output_dir, &project_root_dir.join(CACHE_DIR)],
+            build_time(true, project_root_dir),
+        )?;
+    }
+
+    report.record_phase("write", start, write_start);
+    report.pages = list
+        .iter()
+        .filter(|page| {
+            !report
+                .render_failures
+                .iter()
+                .any(|failure| failure.page == **page)
+        })
+        .cloned()
+        .collect();
+    report.duration_ms = as_ms(start.elapsed());
+    info!(
+        pages = list.len(),
+        render_failures = report.render_failures.len(),
+        cache_hits = report.cache_hits,
+        cache_misses = report.cache_misses,
+        duration_ms = report.duration_ms,
+        "built site"
+    );
+    progress.finish();
+    opts.plugins.on_build_complete(report)?;
+    Ok(list)
+}
+
+/// Recompile and rerender only what `changed_paths` affect, reusing the
+/// incremental cache from a previous build. `pages` is the list returned
+/// by `build_with_cache` and is kept up to date as pages are added or
+/// removed.
+///
+/// The data file (`toast.js`) can create any number of pages, so changing
+/// it triggers a full rebuild.
+#[instrument(skip(cache))]
+pub async fn rebuild_files(
+    opts: IncrementalOpts<'_>,
+    cache: &mut Cache,
+    pages: &mut Vec<String>,
+    changed_paths: &[PathBuf],
+) -> Result<()> {
+    let project_root_dir = opts.project_root_dir;
+    let data_file = find_data_file(project_root_dir);
+    if changed_paths
+        .iter()
+        .any(|path| Some(path) == data_file.as_ref())
+    {
+        opts.renderer.invalidate();
+        *pages = build_with_cache(opts, cache, &mut BuildReport::default()).await?;
+        return Ok(());
+    }
+
+    let tmp_dir = project_root_dir.join(".tmp");
+    let source_dirs = source_dirs(&opts);
     let static_dir = project_root_dir.join("static");
-    let public_dir = project_root_dir.join("public");
-    if static_dir.exists() && public_dir.exists() {
-        copy(static_dir, public_dir, &options)?;
+    let mut pages_to_render: Vec<String> = vec![];
+    let mut render_all_pages = false;
+    let mut images_changed = false;
+    let mut robots_changed = false;
+    let mut pagination_changed = false;
+    let mut frontmatter_changed = false;
+    let mut css_changed = false;
+    for path in changed_paths {
+        if path.starts_with(&static_dir) {
+            copy_static_file(opts.fs.as_ref(), &static_dir, path, &opts.output_dir)?;
+            images_changed = images_changed || path.starts_with(static_dir.join("images"));
+            robots_changed = robots_changed || *path == static_dir.join("robots.txt");
+            continue;
+        }
+        let in_source_dir = source_dirs.iter().any(|dir| path.starts_with(dir));
+        if in_source_dir && is_stylesheet(path) {
+            css_changed = true;
+            continue;
+        }
+        if !is_compilable(path) || !in_source_dir {
+            continue;
+        }
+        let source_id = source_id(project_root_dir, path)
+            .wrap_err_with(|| format!("Failed to make `{}` relative", path.display()))?;
+        let dest = js_dest(&source_id);
+        let is_page = is_page(&opts.page_roots, &source_id);
+        if !opts.fs.exists(path) {
+            // deleted files can't be rendered anymore
+            pages.retain(|page| page != &dest);
+            continue;
+        }
+
+        let contents = if is_mdx(path) {
+            let compiled = compile_content(&opts, std::slice::from_ref(path))?
+                .remove(path)
+                .ok_or_else(|| eyre!("`{}` wasn't compiled", path.display()))?;
+            if is_page {
+                let visible = is_visible(
+                    &opts,
+                    &dest,
+                    compiled.frontmatter.as_ref(),
+                    build_time(opts.reproducible, project_root_dir),
+                );
+                update_page_frontmatter(
+                    opts.fs.as_ref(),
+                    &opts.output_dir,
+                    &opts.page_roots,
+                    &dest,
+                    Some(compiled.page_data()).filter(|_| visible),
+                    if visible { &compiled.toc } else { &[] },
+                )?;
+                if needs_page_text(&opts) {
+                    let source = opts
+                        .fs
+                        .read_to_string(path)
+                        .wrap_err_with(|| format!("Failed to read `{}`", path.display()))?;
+                    search::update_text(
+                        project_root_dir,
+                        &frontmatter::page_slug(&opts.page_roots, &dest),
+                        Some(page_text(&source)).filter(|_| visible),
+                    )?;
+                }
+                pagination_changed = true;
+                frontmatter_changed = true;
+                if !visible {
+                    // the page was just marked as a draft
+                    remove_unpublished_page(&opts, &dest)?;
+                    pages.retain(|page| page != &dest);
+                    continue;
+                }
+            }
+            compiled.jsx
+        } else {
+            opts.fs
+                .read_to_string(path)
+                .wrap_err_with(|| format!("Failed to read `{}`", path.display()))?
+        };
+        let mut sources = BTreeMap::new();
+        sources.insert(source_id.clone(), contents);
+        opts.plugins.transform_sources(&mut sources)?;
+        let contents = sources.remove(&source_id).unwrap_or_default();
+        let (contents, _) = split_css_imports(&contents);
+        cache.set_source(
+            &source_id,
+            Source {
+                source: contents,
+                kind: SourceKind::File {
+                    relative_path: path.clone(),
+                },
+            },
+        );
+        compile_js(
+            &source_id,
+            &OutputFile {
+                dest: dest.clone(),
+                frontmatter: None,
+                toc: vec![],
+            },
+            opts.clone(),
+            cache,
+            &tmp_dir,
+            &mut BuildReport::default(),
+        )?;
+
+        if opts
+            .paginate
+            .iter()
+            .any(|config| config.page_component(&opts.page_roots) == dest)
+        {
+            // generated pages re-export the page component, and the
+            // renderer would keep using the copy it already imported
+            opts.renderer.invalidate();
+            pagination_changed = true;
+        }
+        if is_page {
+            if !pages.contains(&dest) {
+                pages.push(dest.clone());
+            }
+            pages_to_render.push(dest);
+        } else {
+            // a shared component changed, so only the pages that import
+            // it, directly or through other components, are rerendered.
+            // Nothing imports the page-wrapper, and a module that no
+            // page is known to import could still affect any of them.
+            let dependents = cache.dependents(&source_id);
+            let affected: Vec<String> = pages
+                .iter()
+                .filter(|page| dependents.contains(page.as_str()))
+                .cloned()
+                .collect();
+            if affected.is_empty() {
+                render_all_pages = true;
+            } else {
+                // the renderer would keep using the copy of the component
+                // it already imported
+                opts.renderer.invalidate();
+                for page in affected {
+                    if !pages_to_render.contains(&page) {
+                        pages_to_render.push(page);
+                    }
+                }
+            }
+        }
+    }
+
+    if pagination_changed && !opts.paginate.is_empty() {
+        let entries = content_index::read_index(&opts.output_dir);
+        let frontmatter_index = frontmatter::read_index(&opts.output_dir);
+        for page in build_pagination(
+            opts.clone(),
+            cache,
+            &tmp_dir,
+            &entries,
+            &frontmatter_index,
+            &mut BuildReport::default(),
+        )? {
+            if !pages.contains(&page) {
+                pages.push(page.clone());
+            }
+            if !pages_to_render.contains(&page) {
+                pages_to_render.push(page);
+            }
+        }
+    }
+    if frontmatter_changed && !opts.taxonomies.is_empty() {
+        let entries = content_index::read_index(&opts.output_dir);
+        let previous: Vec<String> = pages
+            .iter()
+            .filter(|page| is_taxonomy_page(&opts.taxonomies, page))
+            .cloned()
+            .collect();
+        let TaxonomyOutput {
+            pages: taxonomy_pages,
+            changed,
+        } = build_taxonomies(
+            opts.clone(),
+            cache,
+            &tmp_dir,
+            &entries,
+            &mut BuildReport::default(),
+        )?;
+        // terms that no pages have anymore
+        for page in previous
+            .iter()
+            .filter(|page| !taxonomy_pages.contains(page))
+        {
+            remove_page_output(
+                opts.fs.as_ref(),
+                &opts.output_dir,
+                &opts.page_roots,
+                opts.url_style,
+                page,
+            )?;
+        }
+        pages.retain(|page| !previous.contains(page) || taxonomy_pages.contains(page));
+        for page in taxonomy_pages {
+            if !pages.contains(&page) {
+                pages.push(page);
+            }
+        }
+        for page in changed {
+            if !pages_to_render.contains(&page) {
+                pages_to_render.push(page);
+            }
+        }
+    }
+    if frontmatter_changed {
+        // other pages can be related to the changed page now, or not be
+        for page in write_related_props(
+            &opts,
+            pages,
+            &content_index::read_index(&opts.output_dir),
+            &search::read_text(project_root_dir),
+        )? {
+            if !pages_to_render.contains(&page) {
+                pages_to_render.push(page);
+            }
+        }
+    }
+    if css_changed && opts.css.inline && opts.url_style != UrlStyle::Bare {
+        // pages that aren't rerendered have already moved to where the
+        // url style puts them, so stylesheets can't be inlined into them
+        render_all_pages = true;
+    }
+    if render_all_pages {
+        opts.renderer.invalidate();
+        pages_to_render = pages.clone();
+    }
+    opts.plugins.on_pages_discovered(&mut pages_to_render)?;
+    if let Some(filter) = &opts.filter {
+        pages_to_render.retain(|page| matches_filter(&opts.page_roots, filter, page));
+    }
+    if let Some(shard) = &opts.shard {
+        pages_to_render.retain(|page| shard.contains(page));
+    }
+    cancel::check()?;
+    if !pages_to_render.is_empty() {
+        let rendered = opts
+            .renderer
+            .render(RenderJob {
+                dir_of_input_files: &tmp_dir,
+                output_dir: &opts.output_dir,
+                pages: &pages_to_render,
+                page_roots: &opts.page_roots,
+                progress: Progress::hidden(),
+            })
+            .wrap_err(Failure::Render)?;
+        log_page_timings(&rendered.timings);
+        report_render_failures(&opts, &rendered.failures)?;
+        apply_page_meta(
+            &opts.output_dir,
+            &opts.page_roots,
+            &pages_to_render,
+            opts.base_url.as_deref(),
+        )?;
+        apply_locales(
+            &opts.output_dir,
+            &opts.page_roots,
+            &pages_to_render,
+            pages,
+            &opts.i18n,
+            opts.url_style,
+            opts.base_url.as_deref(),
+        )?;
+        opts.plugins
+            .apply_html_rendered(&opts.output_dir, &opts.page_roots, &pages_to_render)?;
+        write_sitemaps(
+            &opts.output_dir,
+            &opts.page_roots,
+            pages,
+            &opts.i18n,
+            opts.url_style,
+            opts.base_url.as_deref(),
+        )?;
+    }
+    if css_changed || !pages_to_render.is_empty() {
+        // a page's stylesheets can come from any component it imports, so
+        // every bundle is rebuilt
+        let stylesheets = write_stylesheets(
+            project_root_dir,
+            &opts.output_dir,
+            &opts.page_roots,
+            pages,
+            &opts.css,
+        )?;
+        link_stylesheets(&opts.output_dir, &opts.page_roots, &stylesheets, &opts.css)?;
+    }
+    link_module_preloads(
+        &opts.output_dir,
+        &opts.page_roots,
+        &pages_to_render,
+        pages,
+        &opts.preload,
+        cache,
+    )?;
+    if !pages_to_render.is_empty() {
+        write_fonts(
+            project_root_dir,
+            &opts.output_dir,
+            &opts.npm_bin_dir,
+            &opts.page_roots,
+            &pages_to_render,
+            &opts.fonts,
+            cache,
+        )?;
+    }
+    if opts.social_cards.enabled && !pages_to_render.is_empty() {
+        write_social_cards(
+            &opts.output_dir,
+            &opts.npm_bin_dir,
+            &opts.page_roots,
+            &pages_to_render,
+            opts.base_url.as_deref(),
+            &opts.social_cards,
+            cache,
+        )?;
+    }
+    if opts.minify_html {
+        minify_pages(&opts.output_dir, &opts.page_roots, &pages_to_render)?;
     }
+    apply_url_style(
+        &opts.output_dir,
+        &opts.page_roots,
+        &pages_to_render,
+        pages,
+        opts.url_style,
+    )?;
+    record_page_outputs(
+        opts.fs.as_ref(),
+        cache,
+        &opts.output_dir,
+        &opts.page_roots,
+        opts.url_style,
+        &pages_to_render,
+    );
+    if frontmatter_changed {
+        search::write_search_indexes(
+            &opts.search,
+            &content_index::read_index(&opts.output_dir),
+            &search::read_text(project_root_dir),
+            opts.url_style,
+            opts.base_path.as_deref(),
+            &opts.output_dir,
+        )?;
+        write_redirects(
+            project_root_dir,
+            &opts.output_dir,
+            &collect_redirects(
+                &opts.redirects,
+                &content_index::read_index(&opts.output_dir),
+            ),
+        )?;
+    }
+    if robots_changed {
+        write_robots(
+            project_root_dir,
+            &opts.output_dir,
+            &opts.robots,
+            &opts.i18n,
+            opts.base_url.as_deref(),
+        )?;
+    }
+    if images_changed {
+        process_images(
+            project_root_dir,
+            &opts.output_dir,
+            &opts.npm_bin_dir,
+            &opts.images,
+            cache,
+        )?;
+    }
+    if let Some(base_path) = &opts.base_path {
+        apply_base_path(&opts.output_dir, base_path)?;
+    }
+    if opts.integrity {
+        // pages that weren't rerendered can link to a stylesheet bundle
+        // or module that changed
+        apply_integrity(&opts.output_dir, opts.base_path.as_deref())?;
+    }
+    // rerendered pages can have different inline scripts
+    write_headers(
+        project_root_dir,
+        &opts.output_dir,
+        opts.base_path.as_deref(),
+        &opts.headers,
+    )?;
+    if opts.precompress {
+        precompress(&opts.output_dir, opts.jobs, cache)?;
+    }
+    cache.save()?;
+    opts.plugins.on_build_complete(&BuildReport {
+        pages: pages_to_render,
+        ..BuildReport::default()
+    })?;
 
     Ok(())
 }
 
-#[instrument(skip(cache))]
-fn compile_src_files(
+/// `src`, and every page root that's somewhere else
+fn source_dirs(opts: &IncrementalOpts) -> Vec<PathBuf> {
+    let mut dirs = vec![opts.project_root_dir.join("src")];
+    for root in &opts.page_roots {
+        let dir = opts.project_root_dir.join(&root.dir);
+        if !dirs.iter().any(|existing| dir.starts_with(existing)) {
+            dirs.push(dir);
+        }
+    }
+    dirs
+}
+
+fn is_stylesheet(path: &Path) -> bool {
+    path.extension().and_then(|ext| ext.to_str()) == Some("css")
+}
+
+/// Files in `src` that get compiled into browser and node modules
+pub(crate) fn is_compilable(path: &Path) -> bool {
+    let is_declaration = path.to_str().is_some_and(|path| path.ends_with(".d.ts"));
+    !is_declaration
+        && matches!(
+            path.extension().and_then(|ext| ext.to_str()),
+            Some("js") | Some("ts") | Some("tsx") | Some("mdx") | Some("md")
+        )
+}
+
+/// `.mdx` and `.md` pages, which are compiled by `compile_content`
+fn is_mdx(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|ext| ext.to_str()),
+        Some("mdx") | Some("md")
+    )
+}
+
+/// Compile `.mdx` and `.md` files to page components. `.mdx` files are
+/// all compiled in a single node process, and `.md` files in Rust.
+fn compile_content(
+    opts: &IncrementalOpts<'_>,
+    files: &[PathBuf],
+) -> Result<HashMap<PathBuf, CompiledMdx>> {
+    let (markdown_files, mdx_files): (Vec<PathBuf>, Vec<PathBuf>) = files
+        .iter()
+        .cloned()
+        .partition(|path| path.extension().and_then(|ext| ext.to_str()) == Some("md"));
+    let mut compiled = compile_mdx(
+        &mdx_files,
+        &opts.npm_bin_dir,
+        &opts.highlight,
+        &opts.toc,
+        &opts.excerpt,
+    )?;
+    compiled.extend(compile_markdown(
+        &markdown_files,
+        opts.project_root_dir,
+        &opts.markdown,
+        &opts.toc,
+        &opts.excerpt,
+    )?);
+    Ok(compiled)
+}
+
+/// Every source compiles to a `.js` module at the same relative path
+fn js_dest(source_id: &str) -> String {
+    match [".mdx", ".md", ".tsx", ".ts"]
+        .iter()
+        .find_map(|extension| source_id.strip_suffix(extension))
+    {
+        Some(stem) => format!("{}.js", stem),
+        None => source_id.to_string(),
+    }
+}
+
+/// Whether page text is kept, for search indexes or related pages
+fn needs_page_text(opts: &IncrementalOpts<'_>) -> bool {
+    !opts.search.is_empty() || related::needs_text(&opts.related)
+}
+
+/// Add a `related` prop to each of `pages` with related pages, returning
+/// the pages whose related pages changed
+fn write_related_props(
+    opts: &IncrementalOpts<'_>,
+    pages: &[String],
+    entries: &[PageEntry],
+    text: &BTreeMap<String, String>,
+) -> Result<Vec<String>> {
+    if opts.related.is_empty() {
+        return Ok(vec![]);
+    }
+    let related = related_pages(&opts.related, entries, text);
+    let mut changed = vec![];
+    for page in pages {
+        let slugs = match related.get(&frontmatter::page_slug(&opts.page_roots, page)) {
+            Some(slugs) => Value::from(slugs.clone()),
+            None => continue,
+        };
+        let json_path = opts
+            .output_dir
+            .join(format!("{}on", page_path(&opts.page_roots, page)));
+        let mut props = opts
+            .fs
+            .read_to_string(&json_path)
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_else(|| Value::Object(Default::default()));
+        if let Value::Object(props) = &mut props {
+            if props.get("related") == Some(&slugs) {
+                continue;
+            }
+            props.insert(String::from("related"), slugs);
+        }
+        write_page_props(
+            opts.fs.as_ref(),
+            &opts.output_dir,
+            &opts.page_roots,
+            page,
+            &props,
+        )?;
+        changed.push(page.clone());
+    }
+    Ok(changed)
+}
+
+/// Write a page's frontmatter where `toast-render` looks for its props
+fn write_page_props(
+    fs: &dyn Filesystem,
+    output_dir: &Path,
+    page_roots: &[PageRoot],
+    dest: &str,
+    frontmatter: &Value,
+) -> Result<()> {
+    let json_path = output_dir.join(format!("{}on", page_path(page_roots, dest)));
+    if let Some(dir) = json_path.parent() {
+        fs.create_dir_all(dir)
+            .wrap_err_with(|| format!("Failed to create directories for `{}`", dir.display()))?;
+    }
+    fs.write(&json_path, frontmatter.to_string().as_bytes())
+        .wrap_err_with(|| format!("Failed to write props to `{}`", json_path.display()))
+}
+
+/// Rewrite a single page's props and its entries in the frontmatter and
+/// content indices after it changed in watch mode
+fn update_page_frontmatter(
+    fs: &dyn Filesystem,
+    output_dir: &Path,
+    page_roots: &[PageRoot],
+    dest: &str,
+    frontmatter: Option<Value>,
+    toc: &[TocEntry],
+) -> Result<()> {
+    let mut index = frontmatter::read_index(output_dir);
+    let slug = frontmatter::page_slug(page_roots, dest);
+    if let Some(props) = page_props(frontmatter.as_ref(), toc) {
+        write_page_props(fs, output_dir, page_roots, dest, &props)?;
+    }
+    match frontmatter {
+        Some(frontmatter) => {
+            index.insert(slug.clone(), frontmatter);
+        }
+        None => {
+            index.remove(&slug);
+        }
+    }
+    frontmatter::write_index(&index, output_dir)?;
+    content_index::update_entry(output_dir, &slug, index.get(&slug).cloned())
+}
+
+/// Write props for every page of each paginated collection and compile
+/// the modules for the generated pages, returning every page that needs
+/// to be rendered
+fn build_pagination(
     opts: IncrementalOpts,
     cache: &mut Cache,
     tmp_dir: &PathBuf,
-) -> Result<HashMap<String, OutputFile>> {
-    let IncrementalOpts {
-        debug,
-        project_root_dir,
-        output_dir,
-        npm_bin_dir,
-        import_map,
-    } = opts;
-    let files_by_source_id: HashMap<String, OutputFile> =
-        WalkDir::new(&project_root_dir.join("src"))
-            .into_iter()
-            // only scan .js files
-            .filter(|result| {
-                result.as_ref().map_or(false, |dir_entry| {
-                    dir_entry
-                        .file_name()
-                        .to_str()
-                        .map(|filename| filename.ends_with(".js"))
-                        .unwrap_or(false)
-                })
-            })
-            // insert source files into cache and return a
-            // HashMap so we can access the entries and such later
-            // by source_id
-            .fold(HashMap::new(), |mut map, entry| {
-                let e = entry.unwrap();
-                let path_buf = e.path().to_path_buf();
-                let file_stuff = cache.read(path_buf.clone());
-                let source_id = e
-                    .path()
-                    .strip_prefix(&project_root_dir)
-                    .unwrap()
-                    .to_str()
-                    .unwrap();
+    entries: &[PageEntry],
+    frontmatter_index: &FrontmatterIndex,
+    report: &mut BuildReport,
+) -> Result<Vec<String>> {
+    let mut pages = vec![];
+    for config in &opts.paginate {
+        let page_component = config.page_component(&opts.page_roots);
+        let base_props =
+            frontmatter_index.get(&frontmatter::page_slug(&opts.page_roots, &page_component));
+        for page in paginate(config, &opts.page_roots, opts.url_style, entries) {
+            write_page_props(
+                opts.fs.as_ref(),
+                &opts.output_dir,
+                &opts.page_roots,
+                &page.dest,
+                &page.props(base_props),
+            )?;
+            if page.dest != page_component {
                 cache.set_source(
-                    source_id,
+                    &page.dest,
                     Source {
-                        source: file_stuff,
-                        kind: SourceKind::File {
-                            relative_path: path_buf,
-                        },
+                        source: reexport_source(&page.dest, &page_component),
+                        kind: SourceKind::Raw,
                     },
                 );
+                compile_js(
+                    &page.dest,
+                    &OutputFile {
+                        dest: page.dest.clone(),
+                        frontmatter: None,
+                        toc: vec![],
+                    },
+                    opts.clone(),
+                    cache,
+                    tmp_dir,
+                    report,
+                )?;
+            }
+            pages.push(page.dest);
+        }
+    }
+    Ok(pages)
+}
+
+/// Pages generated for taxonomies, and which of them have different props
+/// than they did in the last build
+struct TaxonomyOutput {
+    pages: Vec<String>,
+    changed: Vec<String>,
+}
+
+/// Write props for every taxonomy page and compile their modules. Pages
+/// whose props haven't changed don't need to be rendered again in watch
+/// mode.
+fn build_taxonomies(
+    opts: IncrementalOpts,
+    cache: &mut Cache,
+    tmp_dir: &PathBuf,
+    entries: &[PageEntry],
+    report: &mut BuildReport,
+) -> Result<TaxonomyOutput> {
+    let mut output = TaxonomyOutput {
+        pages: vec![],
+        changed: vec![],
+    };
+    for config in &opts.taxonomies {
+        let (pages, terms) = taxonomy_pages(config, opts.url_style, entries);
+        write_terms(config, &terms, &opts.output_dir)?;
+        for page in pages {
+            let props_path = opts.output_dir.join(format!("{}on", page.dest));
+            let previous = opts
+                .fs
+                .read_to_string(&props_path)
+                .ok()
+                .and_then(|contents| serde_json::from_str::<Value>(&contents).ok());
+            if previous.as_ref() != Some(&page.props) {
+                write_page_props(
+                    opts.fs.as_ref(),
+                    &opts.output_dir,
+                    &opts.page_roots,
+                    &page.dest,
+                    &page.props,
+                )?;
+                output.changed.push(page.dest.clone());
+            }
+            cache.set_source(
+                &page.dest,
+                Source {
+                    source: reexport_source(&page.dest, &page.component),
+                    kind: SourceKind::Raw,
+                },
+            );
+            compile_js(
+                &page.dest,
+                &OutputFile {
+                    dest: page.dest.clone(),
+                    frontmatter: None,
+                    toc: vec![],
+                },
+                opts.clone(),
+                cache,
+                tmp_dir,
+                report,
+            )?;
+            output.pages.push(page.dest);
+        }
+    }
+    Ok(output)
+}
+
+fn is_taxonomy_page(taxonomies: &[TaxonomyConfig], page: &str) -> bool {
+    taxonomies
+        .iter()
+        .any(|config| page.starts_with(&format!("{}/", config.name)))
+}
+
+/// Remove the html and props for a page that isn't generated anymore
+fn remove_page_output(
+    fs: &dyn Filesystem,
+    output_dir: &Path,
+    page_roots: &[PageRoot],
+    url_style: UrlStyle,
+    page: &str,
+) -> Result<()> {
+    for path in page_outputs(fs, output_dir, page_roots, url_style, page) {
+        fs.remove_file(&path)
+            .wrap_err_with(|| format!("Failed to remove `{}`", path.display()))?;
+    }
+    Ok(())
+}
+
+/// The html, props, and `meta` export a page was rendered to, that exist.
+/// The html is wherever `url_style` moved it.
+fn page_outputs(
+    fs: &dyn Filesystem,
+    output_dir: &Path,
+    page_roots: &[PageRoot],
+    url_style: UrlStyle,
+    page: &str,
+) -> Vec<PathBuf> {
+    let path = page_path(page_roots, page);
+    let stem = path.trim_end_matches(".js");
+    let html = format!("{}.html", stem);
+    vec![
+        output_dir.join(url_style.html_path(&html)),
+        output_dir.join(&html),
+        output_dir.join(format!("{}.json", stem)),
+        output_dir.join(META_DIR).join(format!("{}.json", stem)),
+    ]
+    .into_iter()
+    .filter(|path| fs.exists(path))
+    .collect::<BTreeSet<PathBuf>>()
+    .into_iter()
+    .collect()
+}
+
+/// Record what rendering `pages` wrote, so it's pruned once the page is
+/// gone
+fn record_page_outputs(
+    fs: &dyn Filesystem,
+    cache: &mut Cache,
+    output_dir: &Path,
+    page_roots: &[PageRoot],
+    url_style: UrlStyle,
+    pages: &[String],
+) {
+    for page in pages {
+        cache.record_outputs(
+            &format!("page:{}", page),
+            String::new(),
+            page_outputs(fs, output_dir, page_roots, url_style, page),
+        );
+    }
+}
 
-                map.entry(String::from(source_id)).or_insert(OutputFile {
-                    dest: source_id.to_string(),
-                });
-                map
+/// Report pages that threw while they were rendered
+fn report_render_failures(opts: &IncrementalOpts, failures: &[RenderFailure]) -> Result<()> {
+    for failure in failures {
+        opts.diagnostics.report(Diagnostic {
+            kind: Some(Failure::Render),
+            ..Diagnostic::error(&failure.page, &failure.error)
+        })?;
+    }
+    Ok(())
+}
+
+/// Warn about modules that would overwrite each other on a
+/// case-insensitive filesystem, see `pages`
+fn report_case_collisions(
+    opts: &IncrementalOpts,
+    files_by_source_id: &HashMap<String, OutputFile>,
+) -> Result<()> {
+    let dests = files_by_source_id.values().map(|file| file.dest.as_str());
+    for group in case_collisions(dests) {
+        opts.diagnostics.report(Diagnostic::warning(
+            &group[0],
+            format!(
+                "{} only differ in case, so one overwrites the other on case-insensitive filesystems like macOS and Windows",
+                group
+                    .iter()
+                    .map(|dest| format!("`{}`", dest))
+                    .collect::<Vec<_>>()
+                    .join(" and ")
+            ),
+        ))?;
+    }
+    Ok(())
+}
+
+/// Report content pages without every `required_frontmatter` key
+fn report_missing_frontmatter(
+    opts: &IncrementalOpts,
+    frontmatter_index: &FrontmatterIndex,
+) -> Result<()> {
+    for (slug, frontmatter) in frontmatter_index {
+        for key in frontmatter::missing_keys(frontmatter, &opts.required_frontmatter) {
+            opts.diagnostics.report(Diagnostic::warning(
+                slug,
+                format!("missing required frontmatter `{}`", key),
+            ))?;
+        }
+    }
+    Ok(())
+}
+
+/// Drafts and future-dated pages are only built with `--drafts` or by
+/// `toast serve`
+fn is_visible(
+    opts: &IncrementalOpts,
+    dest: &str,
+    frontmatter: Option<&Value>,
+    now: DateTime<Utc>,
+) -> bool {
+    opts.drafts
+        || !is_page(&opts.page_roots, dest)
+        || frontmatter.is_none_or(|frontmatter| frontmatter::is_published(frontmatter, now))
+}
+
+/// Remove everything a page that isn't published anymore was built to
+fn remove_unpublished_page(opts: &IncrementalOpts, dest: &str) -> Result<()> {
+    remove_page_output(
+        opts.fs.as_ref(),
+        &opts.output_dir,
+        &opts.page_roots,
+        opts.url_style,
+        dest,
+    )?;
+    let module = opts.output_dir.join(dest);
+    if opts.fs.exists(&module) {
+        opts.fs
+            .remove_file(&module)
+            .wrap_err_with(|| format!("Failed to remove `{}`", module.display()))?;
+    }
+    Ok(())
+}
+
+/// Copy everything in `static/` into the output directory verbatim,
+/// keeping subdirectories, so `static/images/logo.png` is served from
+/// `/images/logo.png`.
+fn copy_static_dir(fs: &dyn Filesystem, project_root_dir: &Path, output_dir: &Path) -> Result<()> {
+    let static_dir = project_root_dir.join("static");
+    if fs.exists(&static_dir) {
+        fs.create_dir_all(output_dir).wrap_err_with(|| {
+            format!(
+                "Failed to create output directory `{}`",
+                output_dir.display()
+            )
+        })?;
+        for path in fs.walk(&static_dir) {
+            copy_static_file(fs, &static_dir, &path, output_dir)?;
+        }
+    }
+    Ok(())
+}
+
+/// Re-copy a single file that changed in `static/`. Files that were
+/// deleted are left in the output directory until the next clean build.
+fn copy_static_file(
+    fs: &dyn Filesystem,
+    static_dir: &Path,
+    path: &Path,
+    output_dir: &Path,
+) -> Result<()> {
+    if !fs.is_file(path) {
+        return Ok(());
+    }
+    let relative = path
+        .strip_prefix(static_dir)
+        .wrap_err_with(|| format!("Failed to make `{}` relative", path.display()))?;
+    let dest = output_dir.join(relative);
+    if let Some(parent) = dest.parent() {
+        fs.create_dir_all(parent)
+            .wrap_err_with(|| format!("Failed to create directories for `{}`", dest.display()))?;
+    }
+    fs.copy(path, &dest).wrap_err_with(|| {
+        format!(
+            "Failed to copy `{}` to `{}`",
+            path.display(),
+            dest.display()
+        )
+    })?;
+    Ok(())
+}
+
+/// Find every source, compile content to JS, and put them in the cache,
+/// returning them by source id along with the pages that are drafts now
+#[instrument(skip(cache, progress))]
+fn discover_sources(
+    opts: &IncrementalOpts,
+    cache: &mut Cache,
+    progress: &Progress,
+) -> Result<(HashMap<String, OutputFile>, Vec<String>)> {
+    let project_root_dir = opts.project_root_dir;
+    let source_files: Vec<PathBuf> = source_dirs(opts)
+        .iter()
+        .flat_map(|dir| opts.fs.walk(dir))
+        // only scan files we know how to compile
+        .filter(|path| is_compilable(path))
+        .collect();
+    progress.discovered(source_files.len() as u64);
+    let content_files: Vec<PathBuf> = source_files
+        .iter()
+        .filter(|path| is_mdx(path))
+        .cloned()
+        .collect();
+    let mut compiled_mdx = compile_content(opts, &content_files)?;
+    let now = build_time(opts.reproducible, project_root_dir);
+    let mut unpublished: Vec<String> = vec![];
+    // by source id, so plugins can transform them all at once
+    let mut sources: BTreeMap<String, String> = BTreeMap::new();
+    let mut paths: BTreeMap<String, PathBuf> = BTreeMap::new();
+    let files_by_source_id: HashMap<String, OutputFile> = source_files
+        .into_iter()
+        // insert source files into cache and return a
+        // HashMap so we can access the entries and such later
+        // by source_id
+        .fold(HashMap::new(), |mut map, path_buf| {
+            let (file_stuff, frontmatter, toc) = match compiled_mdx.remove(&path_buf) {
+                Some(compiled) => {
+                    let data = compiled.page_data();
+                    (compiled.jsx, Some(data), compiled.toc)
+                }
+                None => (
+                    opts.fs.read_to_string(&path_buf).unwrap_or_default(),
+                    None,
+                    vec![],
+                ),
+            };
+            let source_id = match source_id(project_root_dir, &path_buf) {
+                Ok(source_id) => source_id,
+                Err(_) => return map,
+            };
+            let dest = js_dest(&source_id);
+            if !is_visible(opts, &dest, frontmatter.as_ref(), now) {
+                unpublished.push(dest);
+                return map;
+            }
+            sources.insert(source_id.clone(), file_stuff);
+            paths.insert(source_id.clone(), path_buf);
+            map.entry(source_id).or_insert(OutputFile {
+                dest,
+                frontmatter,
+                toc,
             });
-    for (source_id, output_file) in files_by_source_id.iter() {
-        compile_js(
-            source_id,
-            output_file,
-            IncrementalOpts {
-                debug,
-                project_root_dir: &project_root_dir,
-                output_dir: output_dir.clone(),
-                npm_bin_dir: npm_bin_dir.clone(),
-                import_map: import_map.clone(),
+            map
+        });
+    report_case_collisions(opts, &files_by_source_id)?;
+    opts.plugins.transform_sources(&mut sources)?;
+    for (source_id, file_stuff) in sources {
+        let path_buf = paths.remove(&source_id).unwrap_or_default();
+        // stylesheets are bundled separately, see `css::write_stylesheets`
+        let (file_stuff, _) = split_css_imports(&file_stuff);
+        cache.set_source(
+            &source_id,
+            Source {
+                source: file_stuff,
+                kind: SourceKind::File {
+                    relative_path: path_buf,
+                },
             },
-            cache,
-            &tmp_dir,
-        )?;
+        );
+    }
+    Ok((files_by_source_id, unpublished))
+}
+
+#[instrument(skip(cache, report))]
+fn compile_src_files(
+    opts: IncrementalOpts,
+    cache: &mut Cache,
+    tmp_dir: &PathBuf,
+    report: &mut BuildReport,
+    progress: &Progress,
+) -> Result<HashMap<String, OutputFile>> {
+    let (files_by_source_id, unpublished) = discover_sources(&opts, cache, progress)?;
+    // drafts that were built by an earlier `toast serve`
+    for dest in unpublished {
+        remove_unpublished_page(&opts, &dest)?;
     }
+    compile_js_parallel(
+        &files_by_source_id,
+        opts.clone(),
+        cache,
+        tmp_dir,
+        report,
+        progress,
+    )?;
     Ok(files_by_source_id)
 }
 
+/// What a full build with `opts` would compile, render, copy, and delete,
+/// checked against the cache without writing anything, see `dry_run`
 #[instrument(skip(cache))]
+pub fn plan_build(opts: IncrementalOpts, cache: &mut Cache) -> Result<BuildPlan> {
+    let (files_by_source_id, unpublish) = discover_sources(&opts, cache, &Progress::hidden())?;
+    let mut plan = BuildPlan {
+        unpublish,
+        ..BuildPlan::default()
+    };
+    let source_ids: BTreeSet<String> = files_by_source_id.keys().cloned().collect();
+    for source_id in &source_ids {
+        let hash = cache.input_hash(source_id, &opts.import_map, opts.browser_js());
+        if cache.is_fresh(source_id, &hash) {
+            plan.fresh.push(source_id.clone());
+        } else {
+            plan.compile.push(source_id.clone());
+        }
+        let dest = &files_by_source_id[source_id].dest;
+        if is_page(&opts.page_roots, dest) {
+            plan.render.push(dest.clone());
+        }
+    }
+    if let Some(filter) = &opts.filter {
+        plan.render
+            .retain(|page| matches_filter(&opts.page_roots, filter, page));
+    }
+    if let Some(shard) = &opts.shard {
+        plan.render.retain(|page| shard.contains(page));
+    }
+    let static_dir = opts.project_root_dir.join("static");
+    if opts.fs.exists(&static_dir) {
+        plan.copy = opts
+            .fs
+            .walk(&static_dir)
+            .into_iter()
+            .filter(|path| opts.fs.is_file(path))
+            .filter_map(|path| path.strip_prefix(&static_dir).ok().map(Path::to_path_buf))
+            .collect();
+        plan.copy.sort();
+    }
+    // the same rule as the prune at the end of `build_with_cache`
+    if opts.prune && opts.filter.is_none() && opts.shard.is_none() {
+        plan.delete = cache.orphaned_source_outputs(&source_ids);
+    }
+    Ok(plan)
+}
+
+#[instrument(skip(cache, report))]
 fn compile_js(
     source_id: &str,
     output_file: &OutputFile,
     opts: IncrementalOpts,
     cache: &mut Cache,
     tmp_dir: &PathBuf,
+    report: &mut BuildReport,
 ) -> Result<()> {
+    let browser_js = opts.browser_js();
     let IncrementalOpts {
-        debug: _,
-        project_root_dir: _,
         output_dir,
-        npm_bin_dir: _,
         import_map,
+        ..
     } = opts;
-    let browser_output_file = output_dir.join(Path::new(&output_file.dest));
-    let js_browser = cache.get_js_for_browser(source_id, import_map);
-    let file_dir = browser_output_file.parent().ok_or(eyre!(format!(
-        "could not get .parent() directory for `{}`",
-        &browser_output_file.display()
-    )))?;
-    std::fs::create_dir_all(&file_dir).wrap_err_with(|| {
-        format!(
-            "Failed to create parent directories for `{}`. ",
-            &browser_output_file.display()
-        )
-    })?;
-    let _res = std::fs::write(&browser_output_file, js_browser).wrap_err_with(|| {
-        format!(
-            "Failed to write browser JS file for `{}`. ",
-            &browser_output_file.display()
-        )
-    })?;
+    let (browser_output_file, node_output_file) = output_paths(output_file, &output_dir, tmp_dir);
+
+    // skip files that a previous run of toast already compiled
+    let start = Instant::now();
+    let hash = cache.input_hash(source_id, &import_map, browser_js);
+    if cache.is_fresh(source_id, &hash) {
+        report.record_file(FileReport {
+            source_id: source_id.to_string(),
+            output: output_file.dest.clone(),
+            cache_hit: true,
+            duration_ms: as_ms(start.elapsed()),
+            bytes_written: 0,
+        });
+        return Ok(());
+    }
+
+    let js_browser = if ships_browser_js(
+        &opts.page_roots,
+        output_file,
+        cache.get_hydrate_mode(source_id),
+    ) {
+        Some(cache.get_js_for_browser(source_id, import_map, browser_js)?)
+    } else {
+        None
+    };
+    let js_node = cache.get_js_for_server(source_id)?;
+    let outputs = browser_outputs(&browser_output_file, &js_browser, &node_output_file);
+    let bytes_written = write_js_outputs(
+        opts.fs.as_ref(),
+        &browser_output_file,
+        js_browser,
+        &node_output_file,
+        js_node,
+    )?;
+    cache.record_outputs(source_id, hash, outputs);
+    report.record_file(FileReport {
+        source_id: source_id.to_string(),
+        output: output_file.dest.clone(),
+        cache_hit: false,
+        duration_ms: as_ms(start.elapsed()),
+        bytes_written,
+    });
+    Ok(())
+}
+
+/// A file waiting to be compiled by a worker thread
+struct CompileJob {
+    source_id: String,
+    dest: String,
+    hash: String,
+    browser_output_file: PathBuf,
+    node_output_file: PathBuf,
+    /// pages with `hydrate = "none"` only get a node build
+    ships_browser_js: bool,
+}
+
+/// A file compiled on a worker thread, to be recorded in the cache and
+/// the build report once every thread is done
+struct CompiledFile {
+    source_id: String,
+    hash: String,
+    outputs: Vec<PathBuf>,
+    report: FileReport,
+}
+
+/// Compile many source files at once. Files that aren't already fresh
+/// are spread across `opts.jobs` threads, each of which queries its own
+/// read-only snapshot of the cache.
+#[instrument(skip(cache, files, report, progress))]
+fn compile_js_parallel(
+    files: &HashMap<String, OutputFile>,
+    opts: IncrementalOpts,
+    cache: &mut Cache,
+    tmp_dir: &PathBuf,
+    report: &mut BuildReport,
+    progress: &Progress,
+) -> Result<()> {
+    let browser_js = opts.browser_js();
+    let (work_tx, work_rx) = unbounded();
+    for (source_id, output_file) in files.iter() {
+        let hash = cache.input_hash(source_id, &opts.import_map, browser_js);
+        if cache.is_fresh(source_id, &hash) {
+            progress.compiled(source_id);
+            report.record_file(FileReport {
+                source_id: source_id.clone(),
+                output: output_file.dest.clone(),
+                cache_hit: true,
+                duration_ms: 0.0,
+                bytes_written: 0,
+            });
+        } else {
+            let (browser_output_file, node_output_file) =
+                output_paths(output_file, &opts.output_dir, tmp_dir);
+            work_tx.send(CompileJob {
+                source_id: source_id.clone(),
+                dest: output_file.dest.clone(),
+                hash,
+                browser_output_file,
+                node_output_file,
+                ships_browser_js: ships_browser_js(
+                    &opts.page_roots,
+                    output_file,
+                    cache.get_hydrate_mode(source_id),
+                ),
+            })?;
+        }
+    }
+    drop(work_tx);
+
+    let shared_cache: &Cache = cache;
+    let import_map = &opts.import_map;
+    let fs = opts.fs.as_ref();
+    let results = crossbeam::scope(|scope| {
+        let workers: Vec<_> = (0..opts.jobs.max(1))
+            .map(|_| {
+                let work_rx = work_rx.clone();
+                let snapshot = shared_cache.snapshot();
+                scope.spawn(move |_| -> Result<Vec<CompiledFile>> {
+                    let mut compiled = vec![];
+                    for job in work_rx.iter() {
+                        cancel::check()?;
+                        let start = Instant::now();
+                        let js_browser = if job.ships_browser_js {
+                            Some(snapshot.get_js_for_browser(
+                                &job.source_id,
+                                import_map.clone(),
+                                browser_js,
+                            )?)
+                        } else {
+                            None
+                        };
+                        let js_node = snapshot.get_js_for_server(&job.source_id)?;
+                        let outputs = browser_outputs(
+                            &job.browser_output_file,
+                            &js_browser,
+                            &job.node_output_file,
+                        );
+                        let bytes_written = write_js_outputs(
+                            fs,
+                            &job.browser_output_file,
+                            js_browser,
+                            &job.node_output_file,
+                            js_node,
+                        )?;
+                        progress.compiled(&job.source_id);
+                        progress.written(outputs.len() as u64);
+                        compiled.push(CompiledFile {
+                            report: FileReport {
+                                source_id: job.source_id.clone(),
+                                output: job.dest,
+                                cache_hit: false,
+                                duration_ms: as_ms(start.elapsed()),
+                                bytes_written,
+                            },
+                            source_id: job.source_id,
+                            hash: job.hash,
+                            outputs,
+                        });
+                    }
+                    Ok(compiled)
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| worker.join())
+            .collect::<Vec<_>>()
+    })
+    .map_err(|_| eyre!("Failed to run compile threads"))?;
+
+    for result in results {
+        let compiled = result.map_err(|_| eyre!("A compile thread panicked"))??;
+        for file in compiled {
+            cache.record_outputs(&file.source_id, file.hash, file.outputs);
+            report.record_file(file.report);
+        }
+    }
+    Ok(())
+}
 
-    let js_node = cache.get_js_for_server(source_id);
-    let mut node_output_file = tmp_dir.clone();
-    node_output_file.push(&output_file.dest);
+/// Where the browser and node versions of a compiled file get written
+fn output_paths(output_file: &OutputFile, output_dir: &Path, tmp_dir: &Path) -> (PathBuf, PathBuf) {
+    let browser_output_file = output_dir.join(Path::new(&output_file.dest));
+    let node_output_file = tmp_dir.join(&output_file.dest);
     // node_output_file.set_extension("mjs");
+    (browser_output_file, node_output_file)
+}
+
+/// Only pages can opt out of hydration. Components are still imported by
+/// the pages that do hydrate.
+fn ships_browser_js(page_roots: &[PageRoot], output_file: &OutputFile, mode: HydrateMode) -> bool {
+    !is_page(page_roots, &output_file.dest) || mode.ships_browser_js()
+}
+
+/// The files a compile writes, which the cache checks for to know the
+/// compile is still fresh
+fn browser_outputs(
+    browser_output_file: &Path,
+    js_browser: &Option<CompiledJs>,
+    node_output_file: &Path,
+) -> Vec<PathBuf> {
+    match js_browser {
+        Some(js_browser) => {
+            let mut outputs = vec![
+                browser_output_file.to_path_buf(),
+                node_output_file.to_path_buf(),
+            ];
+            if js_browser.map.is_some() {
+                outputs.push(map_path(browser_output_file));
+            }
+            outputs
+        }
+        None => vec![node_output_file.to_path_buf()],
+    }
+}
+
+/// Write both builds of a module. Without a browser build, any browser
+/// build left over from before the page stopped hydrating is removed.
+fn write_js_outputs(
+    fs: &dyn Filesystem,
+    browser_output_file: &Path,
+    js_browser: Option<CompiledJs>,
+    node_output_file: &Path,
+    js_node: String,
+) -> Result<u64> {
+    let mut bytes_written = js_node.len() as u64;
+    let map_file = map_path(browser_output_file);
+    let mut wrote_map = false;
+    match js_browser {
+        Some(CompiledJs { code, map }) => {
+            let js_browser = match &map {
+                Some(_) => with_mapping_url(&code, browser_output_file),
+                None => code,
+            };
+            bytes_written += js_browser.len() as u64;
+            let file_dir = browser_output_file.parent().ok_or(eyre!(format!(
+                "could not get .parent() directory for `{}`",
+                &browser_output_file.display()
+            )))?;
+            fs.create_dir_all(file_dir).wrap_err_with(|| {
+                format!(
+                    "Failed to create parent directories for `{}`. ",
+                    &browser_output_file.display()
+                )
+            })?;
+            fs.write(browser_output_file, js_browser.as_bytes())
+                .wrap_err_with(|| {
+                    format!(
+                        "Failed to write browser JS file for `{}`. ",
+                        &browser_output_file.display()
+                    )
+                })?;
+            if let Some(map) = &map {
+                bytes_written += map.len() as u64;
+                fs.write(&map_file, map.as_bytes()).wrap_err_with(|| {
+                    format!("Failed to write source map `{}`. ", map_file.display())
+                })?;
+            }
+            wrote_map = map.is_some();
+        }
+        None => {
+            if fs.exists(browser_output_file) {
+                fs.remove_file(browser_output_file).wrap_err_with(|| {
+                    format!(
+                        "Failed to remove browser JS file `{}`. ",
+                        &browser_output_file.display()
+                    )
+                })?;
+            }
+        }
+    }
+    // written when source maps were last turned on
+    if !wrote_map && fs.exists(&map_file) {
+        fs.remove_file(&map_file)
+            .wrap_err_with(|| format!("Failed to remove source map `{}`. ", map_file.display()))?;
+    }
+
     let file_dir = node_output_file.parent().ok_or(eyre!(format!(
         "could not get .parent() directory for `{}`",
         &node_output_file.display()
     )))?;
-    std::fs::create_dir_all(&file_dir).wrap_err_with(|| {
+    fs.create_dir_all(file_dir).wrap_err_with(|| {
         format!(
             "Failed to create parent directories for `{}`. ",
-            &browser_output_file.display()
-        )
-    })?;
-    let _node_res = std::fs::write(&node_output_file, js_node).wrap_err_with(|| {
-        format!(
-            "Failed to write node JS file for `{}`. ",
             &node_output_file.display()
         )
     })?;
-    Ok(())
+    fs.write(node_output_file, js_node.as_bytes())
+        .wrap_err_with(|| {
+            format!(
+                "Failed to write node JS file for `{}`. ",
+                &node_output_file.display()
+            )
+        })?;
+    Ok(bytes_written)
+}
+
+/// Total size of the html rendered for `pages`. Mirrors where
+/// `toast-render` writes each page.
+fn html_bytes(
+    fs: &dyn Filesystem,
+    output_dir: &Path,
+    page_roots: &[PageRoot],
+    pages: &[String],
+) -> u64 {
+    pages
+        .iter()
+        .map(|page| html_output_path(output_dir, page_roots, page))
+        .filter_map(|path| fs.size(&path).ok())
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        build::incremental_opts, cli_args::BuildArgs, config::ToastConfig, filesystem::MemoryFs,
+    };
+
+    #[test]
+    fn test_outputs_in_memory() {
+        let fs = MemoryFs::with_files(vec![
+            ("site/static/images/logo.svg", "<svg></svg>"),
+            ("site/public/index.js", "export default () => null;"),
+            ("site/public/index.js.map", "{}"),
+        ]);
+        let output_dir = Path::new("site/public");
+        copy_static_dir(&fs, Path::new("site"), output_dir).unwrap();
+        assert!(fs.is_file(Path::new("site/public/images/logo.svg")));
+
+        // the page stopped hydrating, so its browser build is stale
+        let bytes = write_js_outputs(
+            &fs,
+            &output_dir.join("index.js"),
+            None,
+            Path::new("site/.tmp/index.js"),
+            String::from("export default () => null;"),
+        )
+        .unwrap();
+        assert_eq!(bytes, 26);
+        assert!(!fs.exists(Path::new("site/public/index.js")));
+        assert!(!fs.exists(Path::new("site/public/index.js.map")));
+        assert!(fs.is_file(Path::new("site/.tmp/index.js")));
+    }
+
+    #[test]
+    fn test_compile_in_memory() {
+        // only the cache manifest is written to disk
+        let root = std::env::temp_dir().join(format!("toast-memory-{}", std::process::id()));
+        let page = root.join("src/pages/index.js");
+        let old = root.join("src/pages/old.js");
+        let fs = Arc::new(MemoryFs::with_files(vec![
+            (page, "export default () => <h1>hi</h1>;"),
+            (old.clone(), "export default () => <h1>old</h1>;"),
+        ]));
+        let args = BuildArgs::default();
+        let settings = ToastConfig::default().merge(&root, &args);
+        let compile = |cache: &mut Cache| {
+            let opts = IncrementalOpts {
+                fs: fs.clone(),
+                ..incremental_opts(
+                    &args,
+                    &settings,
+                    root.join("node_modules/.bin"),
+                    root.join("public"),
+                    ImportMap::default(),
+                    Diagnostics::default(),
+                    vec![],
+                )
+            };
+            compile_src_files(
+                opts,
+                cache,
+                &root.join(".tmp"),
+                &mut BuildReport::default(),
+                &Progress::hidden(),
+            )
+            .unwrap();
+            cache.save().unwrap();
+        };
+        compile(&mut init(root.join("node_modules/.bin"), &root));
+        let old_page = root.join("public/src/pages/old.js");
+        assert!(fs.is_file(&root.join("public/src/pages/index.js")));
+        assert!(fs.is_file(&old_page));
+
+        // the next build's prune deletes the outputs of the removed page
+        fs.remove_file(&old).unwrap();
+        let mut cache = init(root.join("node_modules/.bin"), &root);
+        compile(&mut cache);
+        let pruned = cache.prune(fs.as_ref());
+        std::fs::remove_dir_all(&root).unwrap();
+        assert!(pruned.unwrap().contains(&old_page));
+        assert!(!fs.exists(&old_page));
+        assert!(!fs.exists(&root.join(".tmp/src/pages/old.js")));
+        assert!(fs.is_file(&root.join("public/src/pages/index.js")));
+    }
 }
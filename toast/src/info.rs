@@ -0,0 +1,260 @@
+//! `toast info`, which prints what toast knows about a project without
+//! building it: the settings a build would run with, how many page modules
+//! there are, the import map, the cache, and the versions of everything
+//! toast runs. It's what to paste into a bug report, and `--json` prints
+//! the same thing for build scripts.
+//!
+//! Unlike `toast doctor`, nothing here fails because node or the npm
+//! package is missing, they're just reported as missing.
+use color_eyre::eyre::{Result, WrapErr};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+use tracing::instrument;
+
+use crate::{
+    cache::{cache_stats, CacheStats},
+    cli_args::BuildArgs,
+    config::{self, BuildSettings},
+    esinstall::parse_import_map,
+    exit_code::Failure,
+    filesystem::{Filesystem, RealFs},
+    incremental::is_compilable,
+    node::{node_bin, node_version},
+    npm_bin::find_npm_bin_dir,
+    pages::PageRoot,
+    protocol::{package_version, PROTOCOL_VERSION},
+    renderer::RendererKind,
+    url_style::UrlStyle,
+};
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The versions of toast, its npm package, and node
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Toolchain {
+    pub toast: String,
+    pub protocol: u32,
+    /// `None` when the npm package isn't installed
+    pub npm_package: Option<String>,
+    pub node_path: PathBuf,
+    /// `None` when node can't be run
+    pub node: Option<String>,
+}
+
+/// The parts of `BuildSettings` that say where a build reads and writes,
+/// and how it renders
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct SettingsInfo {
+    /// the config file the settings were merged from, if there is one
+    pub config_file: Option<PathBuf>,
+    pub input_dir: PathBuf,
+    pub output_dir: PathBuf,
+    pub import_map: PathBuf,
+    pub page_roots: Vec<PageRoot>,
+    pub locales: Vec<String>,
+    pub base_url: Option<String>,
+    pub base_path: Option<String>,
+    pub url_style: UrlStyle,
+    pub renderer: RendererKind,
+    pub plugins: Vec<String>,
+    pub drafts: bool,
+    pub offline: bool,
+    pub follow_symlinks: bool,
+    pub min_node_version: String,
+}
+
+impl SettingsInfo {
+    fn new(settings: &BuildSettings, config_file: Option<PathBuf>) -> SettingsInfo {
+        SettingsInfo {
+            config_file,
+            input_dir: settings.input_dir.clone(),
+            output_dir: settings.output_dir.clone(),
+            import_map: settings.import_map.clone(),
+            page_roots: settings.page_roots.clone(),
+            locales: settings.i18n.locales.clone(),
+            base_url: settings.base_url.clone(),
+            base_path: settings.base_path.clone(),
+            url_style: settings.url_style,
+            renderer: settings.renderer,
+            plugins: settings.plugins.clone(),
+            drafts: settings.drafts,
+            offline: settings.offline,
+            follow_symlinks: settings.follow_symlinks,
+            min_node_version: settings.min_node_version.clone(),
+        }
+    }
+}
+
+/// What `toast info` prints
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Info {
+    pub toolchain: Toolchain,
+    pub settings: SettingsInfo,
+    /// page modules in the page roots, without the pages `toast.js`
+    /// creates, which only a build knows about
+    pub pages: usize,
+    /// specifier to url, empty when there's no import map
+    pub imports: BTreeMap<String, String>,
+    /// `None` when the cache can't be read
+    pub cache: Option<CacheStats>,
+}
+
+/// Which config file `config::load` reads from `dir`
+fn config_file(dir: &Path) -> Option<PathBuf> {
+    [config::TOML_CONFIG_FILE, config::JS_CONFIG_FILE]
+        .iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.exists())
+}
+
+/// How many page modules are in `page_roots`, counting the ones in nested
+/// roots once
+fn count_pages(fs: &dyn Filesystem, input_dir: &Path, page_roots: &[PageRoot]) -> usize {
+    page_roots
+        .iter()
+        .flat_map(|root| fs.walk(&input_dir.join(&root.dir)))
+        .filter(|path| is_compilable(path))
+        .collect::<BTreeSet<PathBuf>>()
+        .len()
+}
+
+fn read_imports(import_map: &Path) -> Result<BTreeMap<String, String>> {
+    if !import_map.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let contents = fs::read_to_string(import_map)
+        .wrap_err_with(|| format!("Failed to read `{}`", import_map.display()))?;
+    let map = parse_import_map(&contents)
+        .wrap_err_with(|| format!("Failed to parse `{}`", import_map.display()))?;
+    Ok(map
+        .imports
+        .iter()
+        .map(|(specifier, url)| (specifier.to_string(), url.to_string()))
+        .collect())
+}
+
+/// Everything `toast info` prints about the project in `args`
+#[instrument]
+pub fn info(args: &BuildArgs) -> Result<Info> {
+    let dir = match &args.input_dir {
+        Some(dir) => dir.clone(),
+        None => std::env::current_dir().wrap_err("Failed to get the current directory")?,
+    };
+    let settings = config::load(&dir)
+        .wrap_err(Failure::Config)?
+        .merge(&dir, args);
+    let fs = RealFs {
+        follow_symlinks: settings.follow_symlinks,
+    };
+    let npm_package = find_npm_bin_dir(&settings.input_dir)
+        .ok()
+        .and_then(|bin_dir| package_version(&bin_dir));
+    Ok(Info {
+        toolchain: Toolchain {
+            toast: VERSION.to_string(),
+            protocol: PROTOCOL_VERSION,
+            npm_package,
+            node_path: node_bin(),
+            node: node_version().ok().map(|version| version.to_string()),
+        },
+        pages: count_pages(&fs, &settings.input_dir, &settings.page_roots),
+        imports: read_imports(&settings.import_map)?,
+        cache: cache_stats(&settings.input_dir).ok(),
+        settings: SettingsInfo::new(&settings, config_file(&dir)),
+    })
+}
+
+/// `None` as `none`, for the human readable output
+fn or_none(value: Option<impl fmt::Display>) -> String {
+    value.map_or_else(|| String::from("none"), |value| value.to_string())
+}
+
+impl fmt::Display for Info {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let toolchain = &self.toolchain;
+        let settings = &self.settings;
+        writeln!(
+            f,
+            "toast {} (protocol {})",
+            toolchain.toast, toolchain.protocol
+        )?;
+        writeln!(
+            f,
+            "toast npm package: {}",
+            or_none(toolchain.npm_package.as_ref())
+        )?;
+        writeln!(
+            f,
+            "node: {} (`{}`)",
+            toolchain.node.as_deref().unwrap_or("not found"),
+            toolchain.node_path.display()
+        )?;
+        writeln!(
+            f,
+            "config file: {}",
+            or_none(settings.config_file.as_ref().map(|path| path.display()))
+        )?;
+        writeln!(f, "input dir: `{}`", settings.input_dir.display())?;
+        writeln!(f, "output dir: `{}`", settings.output_dir.display())?;
+        writeln!(f, "page roots:")?;
+        for root in &settings.page_roots {
+            writeln!(f, "    {} -> /{}", root.dir, root.prefix)?;
+        }
+        if !settings.locales.is_empty() {
+            writeln!(f, "locales: {}", settings.locales.join(", "))?;
+        }
+        writeln!(f, "base url: {}", or_none(settings.base_url.as_ref()))?;
+        writeln!(f, "base path: {}", or_none(settings.base_path.as_ref()))?;
+        writeln!(f, "url style: {:?}", settings.url_style)?;
+        writeln!(f, "renderer: {:?}", settings.renderer)?;
+        if !settings.plugins.is_empty() {
+            writeln!(f, "plugins: {}", settings.plugins.join(", "))?;
+        }
+        writeln!(
+            f,
+            "drafts: {}, offline: {}, follow symlinks: {}",
+            settings.drafts, settings.offline, settings.follow_symlinks
+        )?;
+        writeln!(f, "minimum node version: {}", settings.min_node_version)?;
+        writeln!(f, "pages: {}", self.pages)?;
+        writeln!(
+            f,
+            "import map `{}`: {} imports",
+            settings.import_map.display(),
+            self.imports.len()
+        )?;
+        for (specifier, url) in &self.imports {
+            writeln!(f, "    {} -> {}", specifier, url)?;
+        }
+        match &self.cache {
+            Some(cache) => write!(f, "cache: {}", cache),
+            None => writeln!(f, "cache: couldn't be read"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::MemoryFs;
+
+    #[test]
+    fn test_count_pages() {
+        let fs = MemoryFs::with_files(vec![
+            ("site/src/pages/index.js", ""),
+            ("site/src/pages/blog/post.mdx", ""),
+            ("site/src/pages/blog/types.d.ts", ""),
+            ("site/src/pages/styles.css", ""),
+            ("site/src/nav.js", ""),
+        ]);
+        let roots = vec![
+            PageRoot::new("src/pages", ""),
+            PageRoot::new("src/pages/blog", "blog"),
+        ];
+        assert_eq!(count_pages(&fs, Path::new("site"), &roots), 2);
+    }
+}
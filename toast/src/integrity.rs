@@ -0,0 +1,171 @@
+//! Subresource Integrity for the stylesheets, module preloads, and
+//! scripts pages load from the site itself, turned on with
+//! `integrity = true` in `toast.config.toml`. Each of their tags in page
+//! html gets an `integrity="sha384-..."` attribute with the hash of the
+//! file it points at, so a CDN that serves a modified copy is refused.
+//!
+//! Hashes are taken after everything else that changes those files, like
+//! `fingerprint` and `base_path`, and every page's hashes are recomputed
+//! after incremental rebuilds, since a changed stylesheet bundle or
+//! component is linked from pages that weren't rerendered. Modules the
+//! hydration script loads with `import()` can't carry an `integrity`
+//! attribute, but with it on their preload hints, the browser checks the
+//! copy it preloads.
+use color_eyre::eyre::{Result, WrapErr};
+use sha2::{Digest, Sha384};
+use std::{collections::HashMap, fs, path::Path};
+use tracing::instrument;
+use walkdir::WalkDir;
+
+use crate::base_path::strip_base_path;
+
+/// The `integrity` value for `contents`
+pub fn integrity(contents: &[u8]) -> String {
+    format!("sha384-{}", base64::encode(Sha384::digest(contents)))
+}
+
+/// Where the value of attribute `name` is in `tag`, without its quotes
+fn attribute(tag: &str, name: &str) -> Option<(usize, usize)> {
+    let lower = tag.to_ascii_lowercase();
+    for quote in &['"', '\''] {
+        let prefix = format!(" {}={}", name, quote);
+        if let Some(index) = lower.find(&prefix) {
+            let start = index + prefix.len();
+            let end = start + tag[start..].find(*quote)?;
+            return Some((start, end));
+        }
+    }
+    None
+}
+
+/// The url a tag loads that should be checked, if it's one of the tags
+/// integrity applies to
+fn subresource_url(tag: &str) -> Option<String> {
+    let lower = tag.to_ascii_lowercase();
+    let name = if lower.starts_with("<link") {
+        let (start, end) = attribute(tag, "rel")?;
+        let rel = &lower[start..end];
+        if !rel
+            .split_whitespace()
+            .any(|rel| rel == "stylesheet" || rel == "modulepreload")
+        {
+            return None;
+        }
+        "href"
+    } else {
+        "src"
+    };
+    let (start, end) = attribute(tag, name)?;
+    Some(tag[start..end].to_string())
+}
+
+/// `tag` with its `integrity` attribute set to `value`, replacing one from
+/// an earlier build
+fn with_attribute(tag: &str, value: &str) -> String {
+    let mut tag = tag.to_string();
+    if let Some((start, end)) = attribute(&tag, "integrity") {
+        tag.replace_range(start..end, value);
+        return tag;
+    }
+    let close = if tag.ends_with("/>") {
+        tag.len() - 2
+    } else {
+        tag.len() - 1
+    };
+    tag.insert_str(close, &format!(" integrity=\"{}\"", value));
+    tag
+}
+
+/// Add `integrity` to the `<link>` and `<script>` tags in `html`, with the
+/// hashes from `lookup`, which gets each tag's url and returns `None` for
+/// files it can't hash
+pub fn add_integrity(html: &str, mut lookup: impl FnMut(&str) -> Option<String>) -> String {
+    let lower = html.to_ascii_lowercase();
+    let mut out = String::with_capacity(html.len());
+    let mut rest = 0;
+    while let Some(start) = ["<link", "<script"]
+        .iter()
+        .filter_map(|name| lower[rest..].find(name))
+        .min()
+        .map(|index| rest + index)
+    {
+        let end = match html[start..].find('>') {
+            Some(index) => start + index + 1,
+            None => break,
+        };
+        let tag = &html[start..end];
+        out.push_str(&html[rest..start]);
+        match subresource_url(tag).and_then(|url| lookup(&url)) {
+            Some(value) => out.push_str(&with_attribute(tag, &value)),
+            None => out.push_str(tag),
+        }
+        rest = end;
+    }
+    out.push_str(&html[rest..]);
+    out
+}
+
+/// Where the file a root-relative `url` is served from is in the output
+/// directory
+fn local_file(base_path: Option<&str>, url: &str) -> Option<String> {
+    if !url.starts_with('/') || url.starts_with("//") {
+        return None;
+    }
+    let path = strip_base_path(base_path, url).split(['?', '#']).next()?;
+    Some(path.trim_start_matches('/').to_string())
+}
+
+/// Add `integrity` attributes to every html file in `output_dir`
+#[instrument]
+pub fn apply_integrity(output_dir: &Path, base_path: Option<&str>) -> Result<()> {
+    let mut hashes: HashMap<String, Option<String>> = HashMap::new();
+    for entry in WalkDir::new(output_dir)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension().and_then(|ext| ext.to_str()) == Some("html"))
+    {
+        let html_path = entry.path();
+        let html = fs::read_to_string(html_path)
+            .wrap_err_with(|| format!("Failed to read `{}`", html_path.display()))?;
+        let with_integrity = add_integrity(&html, |url| {
+            let file = local_file(base_path, url)?;
+            hashes
+                .entry(file.clone())
+                .or_insert_with(|| fs::read(output_dir.join(&file)).ok().map(|c| integrity(&c)))
+                .clone()
+        });
+        if with_integrity != html {
+            fs::write(html_path, with_integrity)
+                .wrap_err_with(|| format!("Failed to write `{}`", html_path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_integrity() {
+        let html = r#"<head><link rel="stylesheet" href="/toast-css/index.css"><link rel="canonical" href="/"><link rel="modulepreload" href="/src/pages/index.js" integrity="sha384-old"><script src="https://cdn.example.com/a.js"></script></head>"#;
+        let with_integrity = add_integrity(html, |url| {
+            url.starts_with("/src")
+                .then(|| String::from("sha384-js"))
+                .or_else(|| url.ends_with(".css").then(|| String::from("sha384-css")))
+        });
+        assert_eq!(
+            with_integrity,
+            r#"<head><link rel="stylesheet" href="/toast-css/index.css" integrity="sha384-css"><link rel="canonical" href="/"><link rel="modulepreload" href="/src/pages/index.js" integrity="sha384-js"><script src="https://cdn.example.com/a.js"></script></head>"#
+        );
+    }
+
+    #[test]
+    fn test_local_file() {
+        assert_eq!(
+            local_file(Some("/docs"), "/docs/src/a.js?v=1"),
+            Some(String::from("src/a.js"))
+        );
+        assert_eq!(local_file(None, "//cdn.example.com/a.js"), None);
+    }
+}
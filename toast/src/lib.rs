@@ -1,10 +1,76 @@
+pub mod base_path;
+pub mod bench;
+pub mod build;
 pub mod cache;
+pub mod cancel;
 pub mod cli_args;
+pub mod config;
+pub mod content_index;
+pub mod css;
+pub mod deploy;
+pub mod diagnostics;
+pub mod diff;
+pub mod doctor;
+pub mod dry_run;
 pub mod esinstall;
+pub mod excerpt;
+pub mod exit_code;
+pub mod feeds;
+pub mod filesystem;
+pub mod fingerprint;
+pub mod fonts;
+pub mod frontmatter;
+pub mod head;
+pub mod headers;
+pub mod html_minify;
+pub mod hydration;
+pub mod i18n;
+pub mod images;
 pub mod incremental;
+pub mod info;
+pub mod integrity;
 pub mod internal_api;
+pub mod links;
+pub mod listen;
+pub mod logging;
+pub mod markdown;
+pub mod mdx;
+pub mod module_graph;
+pub mod new_project;
 pub mod node;
+pub mod npm_bin;
+pub mod pages;
+pub mod pagination;
+pub mod plugins;
+pub mod precompress;
+pub mod preload;
+pub mod progress;
+pub mod protocol;
+pub mod public_env;
+pub mod reading_time;
+pub mod redirects;
+pub mod related;
+pub mod remote_data;
+pub mod renderer;
+pub mod report;
+pub mod reproducible;
+pub mod robots;
+pub mod s3;
+pub mod scripts;
+pub mod search;
+pub mod serve;
+pub mod social_cards;
+pub mod source_data;
+pub mod source_maps;
 pub mod sources;
+pub mod staging;
+pub mod supervisor;
 pub mod svg;
 pub mod swc_import_map_rewrite;
 pub mod swc_ops;
+pub mod taxonomy;
+pub mod telemetry;
+pub mod toc;
+pub mod url_style;
+pub mod watch;
+pub mod web_modules;
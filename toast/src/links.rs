@@ -0,0 +1,422 @@
+//! Checking that the links in the built site go somewhere, turned on with
+//! `--check-links`, which makes a broken link an error, see `diagnostics`.
+//! `--strict` checks internal links too.
+//!
+//! Every `href`, `src`, and `srcset` url in the html in the output
+//! directory that points at the site itself has to resolve to a file the
+//! way a static host would serve it: `/blog/post` can be `blog/post`,
+//! `blog/post.html`, or `blog/post/index.html`. Links to other sites,
+//! fragments on the same page, and `mailto:` style links are skipped.
+//!
+//! With `--check-external-links`, or `external = true` in the
+//! `[link_check]` table of `toast.config.toml`, `http` and `https` links
+//! are requested too, a few at a time:
+//!
+//! ```toml
+//! [link_check]
+//! external = true
+//! # urls to skip, as globs
+//! ignore = ["https://twitter.com/*", "/drafts/*"]
+//! # how long a checked url is trusted for
+//! cache_ttl_hours = 24
+//! concurrency = 8
+//! ```
+//!
+//! Results for external urls are kept in `.toast/linkcheck.json`, so
+//! later builds only request urls that haven't been checked recently.
+//! `--offline` builds don't check external links at all.
+use async_std::future::timeout;
+use color_eyre::eyre::{Result, WrapErr};
+use futures::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::instrument;
+use walkdir::WalkDir;
+
+use crate::{
+    base_path::strip_base_path,
+    cache::CACHE_DIR,
+    diagnostics::{Diagnostic, Diagnostics, Severity},
+    exit_code::Failure,
+};
+
+const URL_ATTRIBUTES: [&str; 3] = ["href", "src", "srcset"];
+
+/// Where external link results are kept between builds, in `CACHE_DIR`
+const EXTERNAL_CACHE_FILE: &str = "linkcheck.json";
+
+/// How long to wait for an external site before calling the link broken
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// `[link_check]` in `toast.config.toml`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LinkCheckConfig {
+    /// request `http` and `https` links as well
+    pub external: bool,
+    /// glob patterns for urls that aren't checked
+    pub ignore: Vec<String>,
+    pub cache_ttl_hours: u64,
+    /// how many external urls are requested at the same time
+    pub concurrency: usize,
+}
+
+impl Default for LinkCheckConfig {
+    fn default() -> Self {
+        LinkCheckConfig {
+            external: false,
+            ignore: vec![],
+            cache_ttl_hours: 24,
+            concurrency: 8,
+        }
+    }
+}
+
+impl LinkCheckConfig {
+    fn is_ignored(&self, url: &str) -> bool {
+        self.ignore.iter().any(|pattern| {
+            glob::Pattern::new(pattern)
+                .map(|pattern| pattern.matches(url))
+                .unwrap_or(false)
+        })
+    }
+}
+
+/// The last time an external url was checked, and whether it worked
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+struct CheckedUrl {
+    /// seconds since the unix epoch
+    checked_at: u64,
+    ok: bool,
+}
+
+type ExternalCache = BTreeMap<String, CheckedUrl>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BrokenLink {
+    /// the html file the link is in, relative to the output directory
+    pub page: PathBuf,
+    pub url: String,
+}
+
+impl fmt::Display for BrokenLink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.page.display(), self.url)
+    }
+}
+
+/// The values of url attributes in `html`. `srcset` lists are split into
+/// their urls.
+pub fn attribute_urls(html: &str) -> Vec<String> {
+    let mut urls = vec![];
+    let lower = html.to_ascii_lowercase();
+    for attribute in &URL_ATTRIBUTES {
+        for (index, _) in lower.match_indices(attribute) {
+            // `data-src` and `xhref` aren't the attributes we want
+            let preceded_by_space = html[..index]
+                .chars()
+                .last()
+                .is_some_and(char::is_whitespace);
+            let rest = html[index + attribute.len()..].trim_start();
+            let value = match rest.strip_prefix('=') {
+                Some(value) if preceded_by_space => value.trim_start(),
+                _ => continue,
+            };
+            let value = match value.chars().next() {
+                Some(quote @ '"') | Some(quote @ '\'') => {
+                    let value = &value[1..];
+                    &value[..value.find(quote).unwrap_or(value.len())]
+                }
+                _ => {
+                    let end = value
+                        .find(|c: char| c.is_whitespace() || c == '>')
+                        .unwrap_or(value.len());
+                    &value[..end]
+                }
+            };
+            if *attribute == "srcset" {
+                urls.extend(
+                    value
+                        .split(',')
+                        .filter_map(|candidate| candidate.split_whitespace().next())
+                        .map(String::from),
+                );
+            } else {
+                urls.push(value.to_string());
+            }
+        }
+    }
+    urls
+}
+
+/// Urls with a scheme (`https:`, `mailto:`, `data:`), protocol-relative
+/// urls, and fragments on the same page aren't files in the output
+/// directory
+fn is_internal(url: &str) -> bool {
+    let has_scheme = url
+        .find(':')
+        .map(|colon| &url[..colon])
+        .is_some_and(|scheme| {
+            !scheme.is_empty()
+                && scheme
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+        });
+    !url.is_empty() && !url.starts_with('#') && !url.starts_with("//") && !has_scheme
+}
+
+/// The path in the output directory `url` points at from a page in
+/// `from_dir`. Urls that climb out of the output directory can't resolve.
+fn resolve(url: &str, from_dir: &Path) -> Option<PathBuf> {
+    let path = url.split(&['?', '#'][..]).next().unwrap_or("");
+    let joined = match path.strip_prefix('/') {
+        Some(absolute) => PathBuf::from(absolute),
+        None => from_dir.join(path),
+    };
+    let mut normalized = PathBuf::new();
+    for component in joined.components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    return None;
+                }
+            }
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    Some(normalized)
+}
+
+/// Whether a static host would serve something for `path`
+fn is_served(output_dir: &Path, path: &Path) -> bool {
+    let file = output_dir.join(path);
+    file.is_file()
+        || file.join("index.html").is_file()
+        || (path.extension().is_none() && file.with_extension("html").is_file())
+}
+
+/// Every url in the html in `output_dir`, with the page it's on
+fn page_urls(output_dir: &Path) -> Result<Vec<(PathBuf, String)>> {
+    let mut urls = vec![];
+    for entry in WalkDir::new(output_dir)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+        .into_iter()
+        .filter_map(|result| result.ok())
+        .filter(|entry| {
+            entry.file_type().is_file()
+                && entry.path().extension().and_then(|ext| ext.to_str()) == Some("html")
+        })
+    {
+        let path = entry.path();
+        let html = fs::read_to_string(path)
+            .wrap_err_with(|| format!("Failed to read `{}`", path.display()))?;
+        let page = path.strip_prefix(output_dir).unwrap_or(path).to_path_buf();
+        for url in attribute_urls(&html) {
+            urls.push((page.clone(), url));
+        }
+    }
+    Ok(urls)
+}
+
+fn is_external(url: &str) -> bool {
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+/// Every internal link in `urls` that doesn't resolve in `output_dir`.
+/// Root-relative links include the `base_path` the site is served under.
+fn check_internal(
+    output_dir: &Path,
+    base_path: Option<&str>,
+    urls: &[(PathBuf, String)],
+) -> Vec<BrokenLink> {
+    urls.iter()
+        .filter(|(_, url)| is_internal(url))
+        .filter(|(page, url)| {
+            let from_dir = page.parent().unwrap_or_else(|| Path::new(""));
+            match resolve(strip_base_path(base_path, url), from_dir) {
+                Some(target) => !is_served(output_dir, &target),
+                None => true,
+            }
+        })
+        .map(|(page, url)| BrokenLink {
+            page: page.clone(),
+            url: url.clone(),
+        })
+        .collect()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// Whether a site answers for `url`. Some servers don't allow `HEAD`, so
+/// those get a `GET`. Redirects count as working.
+async fn url_works(client: &surf::Client, url: &str) -> bool {
+    let works = |status: u16| (200..400).contains(&status);
+    let head = timeout(REQUEST_TIMEOUT, client.head(url)).await;
+    match head {
+        Ok(Ok(response)) if works(u16::from(response.status())) => true,
+        // the site exists but didn't like the request
+        Ok(Ok(_)) => match timeout(REQUEST_TIMEOUT, client.get(url)).await {
+            Ok(Ok(response)) => works(u16::from(response.status())),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Request every external url in `urls` that isn't in the cache or was
+/// checked longer ago than the configured ttl
+async fn check_external(
+    project_root_dir: &Path,
+    config: &LinkCheckConfig,
+    urls: &[(PathBuf, String)],
+) -> Result<Vec<BrokenLink>> {
+    let cache_path = project_root_dir.join(CACHE_DIR).join(EXTERNAL_CACHE_FILE);
+    let mut cache: ExternalCache = fs::read_to_string(&cache_path)
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .unwrap_or_default();
+    let now = now();
+    let ttl = config.cache_ttl_hours * 60 * 60;
+
+    let mut unchecked: Vec<&str> = urls
+        .iter()
+        .map(|(_, url)| url.as_str())
+        .filter(|url| is_external(url) && !config.is_ignored(url))
+        .filter(|url| match cache.get(*url) {
+            Some(checked) => now.saturating_sub(checked.checked_at) > ttl,
+            None => true,
+        })
+        .collect();
+    unchecked.sort_unstable();
+    unchecked.dedup();
+
+    let client = surf::Client::new();
+    let results: Vec<(String, bool)> = stream::iter(unchecked)
+        .map(|url| {
+            let client = &client;
+            async move { (url.to_string(), url_works(client, url).await) }
+        })
+        .buffer_unordered(config.concurrency.max(1))
+        .collect()
+        .await;
+    for (url, ok) in results {
+        cache.insert(
+            url,
+            CheckedUrl {
+                checked_at: now,
+                ok,
+            },
+        );
+    }
+
+    if let Some(dir) = cache_path.parent() {
+        fs::create_dir_all(dir)
+            .wrap_err_with(|| format!("Failed to create cache directory `{}`", dir.display()))?;
+    }
+    fs::write(&cache_path, serde_json::to_string_pretty(&cache)?)
+        .wrap_err_with(|| format!("Failed to write `{}`", cache_path.display()))?;
+
+    Ok(urls
+        .iter()
+        .filter(|(_, url)| is_external(url) && !config.is_ignored(url))
+        .filter(|(_, url)| cache.get(url).is_some_and(|checked| !checked.ok))
+        .map(|(page, url)| BrokenLink {
+            page: page.clone(),
+            url: url.clone(),
+        })
+        .collect())
+}
+
+/// Every link in the html in `output_dir` that doesn't resolve, including
+/// external links if `config.external` is set
+#[instrument]
+pub async fn check_links(
+    output_dir: &Path,
+    project_root_dir: &Path,
+    base_path: Option<&str>,
+    config: &LinkCheckConfig,
+) -> Result<Vec<BrokenLink>> {
+    let urls: Vec<(PathBuf, String)> = page_urls(output_dir)?
+        .into_iter()
+        .filter(|(_, url)| !config.is_ignored(url))
+        .collect();
+    let mut broken = check_internal(output_dir, base_path, &urls);
+    if config.external {
+        broken.extend(check_external(project_root_dir, config, &urls).await?);
+        broken.sort_by(|a, b| (&a.page, &a.url).cmp(&(&b.page, &b.url)));
+    }
+    Ok(broken)
+}
+
+/// Report every broken link to `diagnostics`, as errors if links were
+/// asked to be checked and warnings if `--strict` is checking them
+pub async fn report_broken_links(
+    output_dir: &Path,
+    project_root_dir: &Path,
+    base_path: Option<&str>,
+    config: &LinkCheckConfig,
+    severity: Severity,
+    diagnostics: &Diagnostics,
+) -> Result<()> {
+    for link in check_links(output_dir, project_root_dir, base_path, config).await? {
+        diagnostics.report(Diagnostic {
+            severity,
+            source: link.page.display().to_string(),
+            message: format!("broken link `{}`", link.url),
+            kind: Some(Failure::LinkCheck),
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attribute_urls() {
+        let html = r#"<a href="/about">a</a><img data-src="/lazy.png" src='/logo.png' srcset="/a-480.webp 480w, /a-960.webp 960w"><a href=post/>b</a>"#;
+        assert_eq!(
+            attribute_urls(html),
+            vec!["/about", "post/", "/logo.png", "/a-480.webp", "/a-960.webp"]
+        );
+    }
+
+    #[test]
+    fn test_internal_urls() {
+        assert!(is_internal("/blog/"));
+        assert!(is_internal("../post?page=2"));
+        assert!(!is_internal("https://toast.dev"));
+        assert!(!is_internal("mailto:hi@toast.dev"));
+        assert!(!is_internal("//cdn.example.com/a.js"));
+        assert!(!is_internal("#top"));
+        assert_eq!(
+            resolve("../post#comments", Path::new("blog/2020")),
+            Some(PathBuf::from("blog/post"))
+        );
+        assert_eq!(resolve("../../..", Path::new("blog")), None);
+    }
+
+    #[test]
+    fn test_ignore_patterns() {
+        let config = LinkCheckConfig {
+            ignore: vec![String::from("https://twitter.com/*")],
+            ..LinkCheckConfig::default()
+        };
+        assert!(config.is_ignored("https://twitter.com/toast"));
+        assert!(!config.is_ignored("https://toast.dev/"));
+        assert!(is_external("https://toast.dev/") && !is_external("/about"));
+    }
+}
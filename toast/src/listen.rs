@@ -0,0 +1,127 @@
+//! `toast listen`, which keeps running after the first build and builds
+//! again whenever a webhook, like a CMS's publish hook, sends a `POST`.
+//!
+//! Requests have to carry the secret from `--secret` or
+//! `TOAST_WEBHOOK_SECRET`, either as `Authorization: Bearer <secret>` or
+//! as a `?token=<secret>` query parameter for services that can't set
+//! headers. They're answered with `202 Accepted` right away, and requests
+//! that arrive while a build is running are rolled into one more build
+//! after it.
+//!
+//! Each build reruns the data file and reuses the incremental cache, and
+//! like every build it's staged and swapped in, see `staging`.
+use async_std::task;
+use color_eyre::eyre::{Result, WrapErr};
+use crossbeam::{unbounded, Sender};
+use tide::{Request, Response, StatusCode};
+use tracing::instrument;
+
+use crate::{
+    cache::Cache,
+    cancel,
+    incremental::{build_with_cache, IncrementalOpts},
+    report::BuildReport,
+    staging::build_staged,
+};
+
+/// Where the secret comes from if `--secret` isn't passed
+pub const SECRET_ENV_VAR: &str = "TOAST_WEBHOOK_SECRET";
+
+#[derive(Clone)]
+struct ListenState {
+    secret: String,
+    rebuild: Sender<()>,
+}
+
+/// Compare every byte so how long this takes doesn't say how much of a
+/// guess was right
+fn secrets_match(given: &str, secret: &str) -> bool {
+    given.len() == secret.len()
+        && given
+            .bytes()
+            .zip(secret.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn is_authorized(authorization: Option<&str>, token: Option<&str>, secret: &str) -> bool {
+    let bearer = authorization.and_then(|value| value.strip_prefix("Bearer "));
+    bearer
+        .into_iter()
+        .chain(token)
+        .any(|given| secrets_match(given.trim(), secret))
+}
+
+async fn webhook(req: Request<ListenState>) -> tide::Result {
+    let state = req.state();
+    let authorization = req
+        .header("Authorization")
+        .map(|values| values.last().as_str().to_string());
+    let token = req
+        .url()
+        .query_pairs()
+        .find(|(name, _)| name == "token")
+        .map(|(_, value)| value.to_string());
+    if !is_authorized(authorization.as_deref(), token.as_deref(), &state.secret) {
+        return Ok(Response::new(StatusCode::Unauthorized));
+    }
+    // the build loop only stops when the process does
+    let _ = state.rebuild.send(());
+    Ok(Response::builder(StatusCode::Accepted)
+        .body("rebuild queued\n")
+        .build())
+}
+
+/// Listen for webhooks on `port` and rebuild for each one, until the
+/// process is killed
+#[instrument(skip(cache, secret))]
+pub fn listen(
+    opts: IncrementalOpts<'_>,
+    cache: &mut Cache,
+    port: u16,
+    secret: String,
+) -> Result<()> {
+    let (tx, rx) = unbounded();
+    let mut app = tide::with_state(ListenState {
+        secret,
+        rebuild: tx,
+    });
+    app.at("/").post(webhook);
+    app.at("/*path").post(webhook);
+    // webhooks come from other machines, unlike `toast serve` requests
+    let addr = format!("0.0.0.0:{}", port);
+    eprintln!("listening for webhooks on port {}", port);
+    let _server = task::spawn(app.listen(addr));
+
+    loop {
+        // `recv` fails once the server has stopped and dropped its state
+        rx.recv().wrap_err("Webhook server stopped")?;
+        while rx.try_recv().is_ok() {}
+        let built = build_staged(&opts, cache, |opts, cache| {
+            task::block_on(build_with_cache(opts, cache, &mut BuildReport::default()))
+        });
+        match built {
+            Ok(_) => match opts.diagnostics.finish() {
+                Ok(()) => eprintln!("rebuilt `{}`", opts.output_dir.display()),
+                Err(e) => eprintln!("{:?}", e),
+            },
+            Err(e) if cancel::is_cancelled() => return Err(e),
+            // the last good build is still being served
+            Err(e) => eprintln!("{:?}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_authorized() {
+        assert!(is_authorized(Some("Bearer hunter2"), None, "hunter2"));
+        assert!(is_authorized(None, Some("hunter2"), "hunter2"));
+        assert!(!is_authorized(Some("hunter2"), None, "hunter2"));
+        assert!(!is_authorized(None, Some("hunter"), "hunter2"));
+        assert!(!is_authorized(None, None, "hunter2"));
+    }
+}
@@ -0,0 +1,105 @@
+//! Log output, which goes to stderr so it never mixes with a `--report`
+//! on stdout. Human-readable text is the default, and only shows warnings
+//! unless `-v` asks for more, since the progress bars already show how a
+//! build is going. `--log-format json` writes one JSON object per line for
+//! CI log processors, and starts at `info`, which includes an event for
+//! every page rendered with how long it took. Each `-v` logs one level
+//! more, and at `trace` every instrumented span is logged as it closes,
+//! with its duration.
+//!
+//! `RUST_LOG` overrides the level flags, like `RUST_LOG=toast::cache=trace`.
+use color_eyre::eyre::{eyre, Result};
+use std::str::FromStr;
+use tracing_error::ErrorLayer;
+use tracing_subscriber::{fmt, fmt::format::FmtSpan, prelude::*, EnvFilter};
+
+/// Formats for `--log-format`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = color_eyre::Report;
+    fn from_str(format: &str) -> Result<Self> {
+        match format {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(eyre!(
+                "Unknown log format `{}`, the supported formats are `text` and `json`",
+                format
+            )),
+        }
+    }
+}
+
+/// The most verbose level to log, from `-v` and `-q`
+pub fn level(format: LogFormat, verbose: u8, quiet: bool) -> &'static str {
+    if quiet {
+        return "error";
+    }
+    let base = match format {
+        LogFormat::Text => 1,
+        LogFormat::Json => 2,
+    };
+    match base + verbose as usize {
+        1 => "warn",
+        2 => "info",
+        3 => "debug",
+        _ => "trace",
+    }
+}
+
+/// Install the tracing subscriber for the rest of the process. Span traces
+/// for error reports are captured whatever the level is.
+pub fn install(format: LogFormat, verbose: u8, quiet: bool) {
+    let level = level(format, verbose, quiet);
+    // dependencies only get to log warnings unless `RUST_LOG` says so
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        EnvFilter::new(match level {
+            "error" => String::from("error"),
+            level => format!("warn,toast={}", level),
+        })
+    });
+    let span_events = if level == "trace" {
+        FmtSpan::CLOSE
+    } else {
+        FmtSpan::NONE
+    };
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with(ErrorLayer::default());
+    match format {
+        LogFormat::Text => registry
+            .with(
+                fmt::layer()
+                    .with_target(false)
+                    .with_span_events(span_events)
+                    .with_writer(std::io::stderr),
+            )
+            .init(),
+        LogFormat::Json => registry
+            .with(
+                fmt::layer()
+                    .json()
+                    .with_span_events(span_events)
+                    .with_writer(std::io::stderr),
+            )
+            .init(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_level() {
+        assert_eq!(level(LogFormat::Text, 0, false), "warn");
+        assert_eq!(level(LogFormat::Text, 2, false), "debug");
+        assert_eq!(level(LogFormat::Json, 0, false), "info");
+        assert_eq!(level(LogFormat::Json, 5, false), "trace");
+        assert_eq!(level(LogFormat::Json, 1, true), "error");
+    }
+}
@@ -1,74 +1,145 @@
 use async_std::task;
 use color_eyre::eyre::{eyre, Result, WrapErr};
-use semver::Version;
+use std::env;
 use std::path::PathBuf;
-use std::process::Command;
-use std::{fs, time::Instant};
+use std::time::Instant;
 use structopt::StructOpt;
 use sys_info::{os_release, os_type};
 use tracing::instrument;
 
 use toast::{
-    cli_args::Toast,
-    esinstall::parse_import_map,
-    incremental::{incremental_compile, IncrementalOpts},
+    bench::bench,
+    build::{self, load_settings, Build},
+    cache::{cache_entries, cache_stats, collect_garbage, merge_manifests, Cache},
+    cancel,
+    cli_args::{BuildArgs, CacheCommand, Cli, Toast},
+    config::BuildSettings,
+    deploy::deploy,
+    diff::diff_builds,
+    doctor::{diagnose, Status},
+    exit_code,
+    incremental::IncrementalOpts,
+    info::info,
+    listen::{listen, SECRET_ENV_VAR},
+    logging,
+    new_project::new_project,
+    node,
+    npm_bin::find_npm_bin_dir,
+    progress,
+    protocol::handshake,
+    serve::{serve, LiveReload},
+    telemetry::export,
+    watch::watch,
+    web_modules::install_web_modules,
 };
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
+/// Build the site described by `settings`, then print the profile, write
+/// the report, and send telemetry if they were asked for. What the
+/// build's `diagnostics` found is listed last, and errors fail the build
+/// unless it's watching for a fix.
 #[instrument]
-fn get_npm_bin_dir() -> Result<PathBuf> {
-    let npm_path = which::which("npm").expect("failed to get npm path");
-    let output = Command::new(npm_path)
-        .arg("bin")
-        .output()
-        .expect("failed to get npm bin dir");
-    let possible_path = std::str::from_utf8(&output.stdout)?;
-    Ok(PathBuf::from(possible_path.trim()))
+fn build_site<'a>(
+    args: &BuildArgs,
+    settings: &'a BuildSettings,
+    npm_bin_dir: PathBuf,
+) -> Result<(IncrementalOpts<'a>, Cache, Vec<String>)> {
+    let Build {
+        opts,
+        cache,
+        pages,
+        report,
+    } = build::build_site(args, settings, npm_bin_dir, vec![])?;
+    if args.profile {
+        eprintln!("{}", report.profile_table(10));
+    }
+    if let Some(format) = args.report {
+        report.write(format, args.report_file.as_deref())?;
+    }
+    if let Some(kind) = args.telemetry {
+        // telemetry is for watching trends, it shouldn't fail a build
+        if let Err(e) = task::block_on(export(kind, &report)) {
+            eprintln!("Failed to send build telemetry: {:?}", e);
+        }
+    }
+    let finished = opts.diagnostics.finish();
+    if !args.watch {
+        finished?;
+    }
+    Ok((opts, cache, pages))
+}
+
+/// `--dry-run` is shared with the commands that can plan a build, but
+/// `command` has to really build to serve what it built
+fn reject_dry_run(build: &BuildArgs, command: &str) -> Result<()> {
+    if build.dry_run {
+        return Err(eyre!(
+            "`toast {}` can't `--dry-run`, try `toast incremental --dry-run`",
+            command
+        ));
+    }
+    Ok(())
 }
 
+/// Print every diagnostic, failing if any of them would stop a build
 #[instrument]
-fn check_node_version() -> Result<()> {
-    let minimum_required_node_major_version = Version {
-        major: 14,
-        minor: 0,
-        patch: 0,
-        pre: vec![],
-        build: vec![],
+fn doctor(args: &BuildArgs) -> Result<()> {
+    let dir = match &args.input_dir {
+        Some(dir) => dir.clone(),
+        None => env::current_dir().wrap_err("Failed to get the current directory")?,
     };
+    let checks = diagnose(&dir, args);
+    for check in &checks {
+        println!("{}", check);
+    }
+    let failures = checks
+        .iter()
+        .filter(|check| check.status == Status::Fail)
+        .count();
+    if failures > 0 {
+        Err(eyre!(
+            "{} problems found that will stop toast from building",
+            failures
+        ))
+    } else {
+        println!("Everything looks ready to build");
+        Ok(())
+    }
+}
 
-    let mut cmd = Command::new("node");
-    cmd.arg("-v");
-    let output = cmd
-        .output()
-        .wrap_err_with(|| "Failed to execute `node -v` Command and collect output")?;
-    let version_string = std::str::from_utf8(&output.stdout)
-        .wrap_err_with(|| "Failed to create utf8 string from node -v Command output")?;
-    let version_string_trimmed = version_string.trim_start_matches('v');
-    let current_node_version_result = Version::parse(version_string_trimmed);
-    match current_node_version_result {
-        Ok(current_node_version) => {
-            if current_node_version < minimum_required_node_major_version {
-                Err(eyre!(format!(
-                    "node version {} doesn't meet the minimum required version {}",
-                    current_node_version, minimum_required_node_major_version
-                )))
-            } else {
-                Ok(())
+/// Run a `toast cache` subcommand
+#[instrument]
+fn cache_command(command: &CacheCommand) -> Result<()> {
+    let input_dir = match command {
+        CacheCommand::Stats { input_dir }
+        | CacheCommand::Ls { input_dir }
+        | CacheCommand::Gc { input_dir, .. } => input_dir,
+    };
+    let dir = match input_dir {
+        Some(dir) => dir.clone(),
+        None => env::current_dir().wrap_err("Failed to get the current directory")?,
+    };
+    match command {
+        CacheCommand::Stats { .. } => println!("{}", cache_stats(&dir)?),
+        CacheCommand::Ls { .. } => {
+            for entry in cache_entries(&dir)? {
+                println!("{}", entry);
             }
         }
-        Err(_e) => Err(eyre!(format!(
-            "Couldn't parse node version from trimmed version `{}`, original string is `{}`",
-            version_string_trimmed, version_string
-        ))),
+        CacheCommand::Gc {
+            max_size, dry_run, ..
+        } => println!("{}", collect_garbage(&dir, *max_size, *dry_run)?),
     }
+    Ok(())
 }
 
 #[instrument]
 fn main() -> Result<()> {
-    #[cfg(feature = "capture-spantrace")]
-    install_tracing();
     let start = Instant::now();
+    let cli = Cli::from_args();
+    logging::install(cli.log.log_format, cli.log.verbose, cli.log.quiet);
+    progress::install(cli.log.log_format);
 
     color_eyre::config::HookBuilder::default()
         // .panic_message(MyPanicMessage)
@@ -83,94 +154,209 @@ fn main() -> Result<()> {
             os_release().unwrap_or_else(|_| "unavailable".to_string()),
         )
         .install()?;
+    cancel::install()?;
+    if let Some(path) = cli.node_path {
+        node::use_node(path)?;
+    }
+
+    let result = run(cli.command, start);
+    if cancel::is_cancelled() {
+        eprintln!("Toast was cancelled, the last build was left in place");
+        std::process::exit(cancel::exit_code().unwrap_or(130));
+    }
+    match result {
+        // printed the way returning it from `main` would, but with the
+        // code for the kind of failure it is
+        Err(report) if exit_code::classify(&report).is_some() => {
+            eprintln!("Error: {:?}", report);
+            std::process::exit(exit_code::exit_code(&report));
+        }
+        result => result,
+    }
+}
+
+/// Run the `opt` subcommand
+#[instrument]
+fn run(opt: Toast, start: Instant) -> Result<()> {
+    if let Toast::Completions { shell } = &opt {
+        Cli::clap().gen_completions_to("toast", *shell, &mut std::io::stdout());
+        return Ok(());
+    }
+
+    // scaffolding a project doesn't need node or npm installed yet
+    if let Toast::New { dir } = &opt {
+        new_project(dir)?;
+        eprintln!(
+            "Created a new Toast project in `{}`. Run `npm install` there, then `npm run dev`.",
+            dir.display()
+        );
+        return Ok(());
+    }
+
+    if let Toast::Doctor { build } = &opt {
+        return doctor(build);
+    }
+
+    if let Toast::Info { build, json } = &opt {
+        let info = info(build)?;
+        if *json {
+            println!("{}", serde_json::to_string_pretty(&info)?);
+        } else {
+            print!("{}", info);
+        }
+        return Ok(());
+    }
+
+    if let Toast::Cache { command } = &opt {
+        return cache_command(command);
+    }
+
+    if let Toast::Bench { bench: args } = &opt {
+        return bench(args);
+    }
+
+    if let Toast::ImportMap { build } = &opt {
+        let settings = load_settings(build)?;
+        let npm_bin_dir = find_npm_bin_dir(&settings.input_dir)?;
+        handshake(&npm_bin_dir)?;
+        let import_map = install_web_modules(
+            &settings.input_dir,
+            &settings.output_dir,
+            &settings.import_map,
+            &npm_bin_dir,
+        )?;
+        eprintln!(
+            "Wrote {} imports to `{}`",
+            import_map.imports.len(),
+            settings.import_map.display()
+        );
+        return Ok(());
+    }
+
+    if let Toast::Deploy { build, all } = &opt {
+        let settings = load_settings(build)?;
+        println!("{}", deploy(&settings, *all, build.dry_run)?);
+        return Ok(());
+    }
+
+    if let Toast::Diff {
+        input_dir,
+        from,
+        to,
+        json,
+    } = &opt
+    {
+        let dir = match input_dir {
+            Some(dir) => dir.clone(),
+            None => env::current_dir().wrap_err("Failed to get the current directory")?,
+        };
+        let diff = diff_builds(&dir, from.as_deref(), to.as_deref())?;
+        if *json {
+            println!("{}", serde_json::to_string_pretty(&diff)?);
+        } else {
+            println!("{}", diff);
+        }
+        return Ok(());
+    }
 
-    check_node_version()?;
-    let _ = fs::remove_file("/var/tmp/toaster.sock");
-    // let client = libhoney::init(libhoney::Config {
-    //     options: libhoney::client::Options {
-    //         api_key: "YOUR_API_KEY".to_string(),
-    //         dataset: "honeycomb-rust-example".to_string(),
-    //         ..libhoney::client::Options::default()
-    //     },
-    //     transmission_options: libhoney::transmission::Options::default(),
-    // });
-    // event := builder.new_event()
-    // event.add_field("key", Value::String("val".to_string())), event.add(data)
-    let npm_bin_dir = get_npm_bin_dir()?;
-    let opt = Toast::from_args();
+    if let Toast::MergeManifests { manifests, output } = &opt {
+        let entries = merge_manifests(manifests, output)?;
+        eprintln!(
+            "Merged {} manifests into `{}` with {} entries",
+            manifests.len(),
+            output.display(),
+            entries
+        );
+        return Ok(());
+    }
 
     let result = match opt {
-        Toast::Incremental {
-            debug,
-            input_dir,
-            output_dir,
+        Toast::Incremental { build } => {
+            let settings = load_settings(&build)?;
+            let npm_bin_dir = find_npm_bin_dir(&settings.input_dir)?;
+            if build.dry_run {
+                println!("{}", build::dry_run(&build, &settings, npm_bin_dir)?);
+                return Ok(());
+            }
+            let (opts, mut cache, pages) = build_site(&build, &settings, npm_bin_dir)?;
+            if build.watch {
+                watch(opts, &mut cache, pages, || {})
+            } else {
+                Ok(())
+            }
+        }
+        Toast::Serve { build, port } => {
+            reject_dry_run(&build, "serve")?;
+            // serving is for development, so drafts and source maps are
+            // always built, and JS is only minified when it's asked for
+            let settings = BuildSettings {
+                drafts: true,
+                source_maps: true,
+                minify_js: build.minify,
+                // drafts shouldn't end up in an `s3://` bucket, the copy
+                // of it is served instead, or be compared with real builds
+                // by `toast diff`
+                output_bucket: None,
+                record_output: false,
+                ..load_settings(&build)?
+            };
+            let npm_bin_dir = find_npm_bin_dir(&settings.input_dir)?;
+            let (opts, mut cache, pages) = build_site(&build, &settings, npm_bin_dir)?;
+            eprintln!("Toast built in {:?}", start.elapsed());
+            if build.watch {
+                let live_reload = LiveReload::default();
+                let _server = task::spawn(serve(
+                    opts.output_dir.clone(),
+                    port,
+                    settings.url_style,
+                    settings.base_path.clone(),
+                    Some(live_reload.clone()),
+                ));
+                watch(opts, &mut cache, pages, || live_reload.reload())
+            } else {
+                task::block_on(serve(
+                    opts.output_dir,
+                    port,
+                    settings.url_style,
+                    settings.base_path.clone(),
+                    None,
+                ))
+            }
+        }
+        Toast::Listen {
+            build,
+            port,
+            secret,
         } => {
-            let import_map = {
-                let import_map_filepath = output_dir
-                    .clone()
-                    .unwrap_or(input_dir.join("public"))
-                    .join("web_modules")
-                    .join("import-map.json");
-                let contents = fs::read_to_string(&import_map_filepath).wrap_err_with(|| {
-                    format!(
-                        "Failed to read `import-map.json` from `{}`",
-                        &import_map_filepath.display()
-                    )
-                })?;
-                parse_import_map(&contents).wrap_err_with(|| {
-                    format!(
-                        "Failed to parse import map from content `{}` at `{}`",
-                        contents,
-                        &import_map_filepath.display()
-                    )
-                })?
+            reject_dry_run(&build, "listen")?;
+            let secret = match secret.or_else(|| env::var(SECRET_ENV_VAR).ok()) {
+                Some(secret) if !secret.is_empty() => secret,
+                _ => {
+                    return Err(eyre!(
+                        "`toast listen` needs a secret for webhooks to send, pass `--secret` or set `{}`",
+                        SECRET_ENV_VAR
+                    ))
+                }
             };
-
-            task::block_on(incremental_compile(IncrementalOpts {
-                debug,
-                project_root_dir: &input_dir,
-                output_dir: match output_dir {
-                    Some(v) => v,
-                    None => {
-                        let full_output_dir = input_dir.join("public");
-                        std::fs::create_dir_all(&full_output_dir).wrap_err_with(|| {
-                            format!(
-                                "Failed create directories for path `{}`",
-                                &full_output_dir.display()
-                            )
-                        })?;
-                        full_output_dir
-                            .canonicalize()
-                            .wrap_err_with(|| "Failed canonicalize the output directory path")?
-                    }
-                },
-                npm_bin_dir,
-                import_map,
-            }))
+            let settings = load_settings(&build)?;
+            let npm_bin_dir = find_npm_bin_dir(&settings.input_dir)?;
+            let (opts, mut cache, _pages) = build_site(&build, &settings, npm_bin_dir)?;
+            eprintln!("Toast built in {:?}", start.elapsed());
+            listen(opts, &mut cache, port, secret)
         }
+        Toast::New { .. }
+        | Toast::Completions { .. }
+        | Toast::Doctor { .. }
+        | Toast::Info { .. }
+        | Toast::Cache { .. }
+        | Toast::Bench { .. }
+        | Toast::ImportMap { .. }
+        | Toast::Deploy { .. }
+        | Toast::Diff { .. }
+        | Toast::MergeManifests { .. } => unreachable!(
+            "`new`, `completions`, `doctor`, `info`, `cache`, `bench`, `import-map`, `deploy`, `diff`, and `merge-manifests` are handled before building"
+        ),
     };
     eprintln!("Toast executed in {:?}", start.elapsed());
     result
-    // .expect("failed to process file");
-    // event.send(&mut client)
-    // client.close();
-}
-
-#[cfg(feature = "capture-spantrace")]
-fn install_tracing() {
-    println!("capturing spantraces");
-    use tracing_error::ErrorLayer;
-    use tracing_subscriber::prelude::*;
-    use tracing_subscriber::{fmt, EnvFilter};
-
-    let fmt_layer = fmt::layer().with_target(false);
-    let filter_layer = EnvFilter::try_from_default_env()
-        .or_else(|_| EnvFilter::try_new("info"))
-        .unwrap();
-
-    tracing_subscriber::registry()
-        .with(filter_layer)
-        .with(fmt_layer)
-        .with(ErrorLayer::default())
-        .init();
 }
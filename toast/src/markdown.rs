@@ -0,0 +1,474 @@
+//! `.md` pages, compiled to html in Rust rather than by the mdx compiler
+//! in node, since plain markdown can't import components or use JSX.
+//!
+//! Each page becomes a small module that renders its html. To put the
+//! html inside the rest of the site's markup, set a layout component in
+//! `toast.config.toml`:
+//!
+//! ```toml
+//! [markdown]
+//! layout = "src/layouts/markdown.js"
+//! ```
+//!
+//! ```js
+//! // gets the page's frontmatter as props, and its html as `children`
+//! export default ({ title, children }) => (
+//!   <main>
+//!     <h1>{title}</h1>
+//!     {children}
+//!   </main>
+//! );
+//! ```
+//!
+//! Tables, footnotes, strikethrough, and task lists are supported. Code
+//! blocks get a `language-*` class but aren't highlighted, see
+//! `HighlightConfig` for `.mdx` pages.
+//!
+//! Pages can embed components with shortcodes once there's a directory
+//! for them:
+//!
+//! ```toml
+//! [markdown]
+//! shortcodes = "src/shortcodes"
+//! ```
+//!
+//! ```md
+//! Here's the talk:
+//!
+//! {{ youtube id="dQw4w9WgXcQ" title="The talk" }}
+//! ```
+//!
+//! renders the default export of `src/shortcodes/youtube.js` (or `.tsx`,
+//! or any other source extension) with `id` and `title` as string props.
+//! A shortcode has to be on a line of its own, and isn't replaced inside
+//! code blocks.
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use pulldown_cmark::{html, Event, Options, Parser, Tag};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Component, Path, PathBuf},
+};
+use tracing::instrument;
+
+use crate::{
+    excerpt::{excerpt, ExcerptConfig},
+    frontmatter,
+    mdx::{module_exports, CompiledMdx},
+    reading_time::reading_stats,
+    toc::{closing_tag, table_of_contents, Heading, Slugger, TocConfig},
+};
+
+/// `[markdown]` in `toast.config.toml`
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MarkdownConfig {
+    /// component every `.md` page is rendered in, relative to the project
+    /// root
+    pub layout: Option<String>,
+    /// directory of components `{{ name }}` shortcodes render, relative
+    /// to the project root
+    pub shortcodes: Option<String>,
+}
+
+/// A `{{ name key="value" }}` line in a `.md` page
+#[derive(Debug, Clone, PartialEq)]
+struct Shortcode {
+    name: String,
+    props: BTreeMap<String, String>,
+}
+
+/// What a shortcode is replaced with before the markdown is rendered. It's
+/// an html block, so it ends up in the html exactly like this, between
+/// top level elements.
+const PLACEHOLDER: &str = "<div data-toast-shortcode></div>\n";
+
+/// The extensions a shortcode's component can be written with
+const COMPONENT_EXTENSIONS: [&str; 4] = ["js", "jsx", "ts", "tsx"];
+
+/// The shortcode on `line`, if it is one
+fn parse_shortcode(line: &str) -> Result<Option<Shortcode>> {
+    let inner = match line
+        .trim_end()
+        .strip_prefix("{{")
+        .and_then(|line| line.strip_suffix("}}"))
+    {
+        Some(inner) => inner.trim(),
+        None => return Ok(None),
+    };
+    let name_end = inner.find(char::is_whitespace).unwrap_or(inner.len());
+    let (name, mut rest) = inner.split_at(name_end);
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(eyre!("Invalid shortcode name `{}` in `{}`", name, line));
+    }
+    let mut props = BTreeMap::new();
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            break;
+        }
+        let (key, value) = rest
+            .split_once("=\"")
+            .ok_or_else(|| eyre!("Expected `key=\"value\"` in shortcode `{}`", line))?;
+        let mut chars = value.char_indices();
+        let mut parsed = String::new();
+        let end = loop {
+            match chars.next() {
+                Some((i, '"')) => break i,
+                Some((_, '\\')) => {
+                    if let Some((_, c)) = chars.next() {
+                        parsed.push(c);
+                    }
+                }
+                Some((_, c)) => parsed.push(c),
+                None => {
+                    return Err(eyre!("Unclosed string in shortcode `{}`", line));
+                }
+            }
+        };
+        props.insert(key.trim().to_string(), parsed);
+        rest = &value[end + 1..];
+    }
+    Ok(Some(Shortcode {
+        name: name.to_string(),
+        props,
+    }))
+}
+
+/// Replace the shortcodes in `markdown` with `PLACEHOLDER`, skipping
+/// fenced code blocks
+fn substitute_shortcodes(markdown: &str) -> Result<(String, Vec<Shortcode>)> {
+    let mut substituted = String::with_capacity(markdown.len());
+    let mut shortcodes = vec![];
+    let mut fence: Option<&str> = None;
+    for line in markdown.lines() {
+        let trimmed = line.trim_start();
+        match fence {
+            Some(open) if trimmed.starts_with(open) => fence = None,
+            Some(_) => {}
+            None if trimmed.starts_with("```") => fence = Some("```"),
+            None if trimmed.starts_with("~~~") => fence = Some("~~~"),
+            None => {
+                if let Some(shortcode) = parse_shortcode(line)? {
+                    shortcodes.push(shortcode);
+                    // the blank lines end whatever block came before
+                    substituted.push('\n');
+                    substituted.push_str(PLACEHOLDER);
+                    substituted.push('\n');
+                    continue;
+                }
+            }
+        }
+        substituted.push_str(line);
+        substituted.push('\n');
+    }
+    Ok((substituted, shortcodes))
+}
+
+/// The component `name` renders, checking that it exists, as the path of
+/// the module it compiles to relative to the project root
+fn shortcode_module(project_root_dir: &Path, dir: &str, name: &str) -> Result<PathBuf> {
+    let dir = Path::new(dir);
+    if COMPONENT_EXTENSIONS.iter().any(|ext| {
+        project_root_dir
+            .join(dir)
+            .join(name)
+            .with_extension(ext)
+            .exists()
+    }) {
+        Ok(dir.join(format!("{}.js", name)))
+    } else {
+        Err(eyre!(
+            "Unknown shortcode `{}`, there's no component for it in `{}`",
+            name,
+            dir.display()
+        ))
+    }
+}
+
+/// Render `markdown` to html with an id on every heading, returning the
+/// headings too
+pub fn render_markdown(markdown: &str, anchors: bool) -> (String, Vec<Heading>) {
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_FOOTNOTES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS;
+    let mut slugger = Slugger::default();
+    let mut headings = vec![];
+    let mut events = vec![];
+    let mut parser = Parser::new_ext(markdown, options);
+    while let Some(event) = parser.next() {
+        match event {
+            Event::Start(Tag::Heading(depth)) => {
+                let inner: Vec<Event> = parser
+                    .by_ref()
+                    .take_while(|event| !matches!(event, Event::End(Tag::Heading(_))))
+                    .collect();
+                let text: String = inner
+                    .iter()
+                    .filter_map(|event| match event {
+                        Event::Text(text) | Event::Code(text) => Some(&**text),
+                        _ => None,
+                    })
+                    .collect();
+                let id = slugger.slug(&text);
+                events.push(Event::Html(format!("<h{} id=\"{}\">", depth, id).into()));
+                events.extend(inner);
+                events.push(Event::Html(closing_tag(depth, &id, anchors).into()));
+                headings.push(Heading { depth, text, id });
+            }
+            event => events.push(event),
+        }
+    }
+    let mut html = String::new();
+    html::push_html(&mut html, events.into_iter());
+    (html, headings)
+}
+
+/// How the module compiled from `from` imports `to`, both relative to the
+/// project root, like `../layouts/markdown.js`
+fn relative_import(from: &Path, to: &Path) -> String {
+    let from_dir: Vec<Component> = from
+        .parent()
+        .map(|dir| dir.components().collect())
+        .unwrap_or_default();
+    let to: Vec<Component> = to.components().collect();
+    let common = from_dir.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    let mut path = PathBuf::new();
+    for _ in common..from_dir.len() {
+        path.push("..");
+    }
+    for component in &to[common..] {
+        path.push(component);
+    }
+    let path = path.to_string_lossy().replace('\\', "/");
+    if path.starts_with("../") {
+        path
+    } else {
+        format!("./{}", path)
+    }
+}
+
+/// The page module for `html`, inside `layout` if there is one. Each
+/// `PLACEHOLDER` in `html` renders the next of `shortcodes`, which are
+/// imported from `modules` by name.
+fn page_module(
+    source_id: &Path,
+    html: &str,
+    shortcodes: &[Shortcode],
+    modules: &BTreeMap<String, PathBuf>,
+    layout: Option<&str>,
+) -> String {
+    let mut imports = String::from("import { h } from \"preact\";\n");
+    if let Some(layout) = layout {
+        imports.push_str(&format!(
+            "import Layout from \"{}\";\n",
+            relative_import(source_id, Path::new(layout))
+        ));
+    }
+    let (html, content) = if shortcodes.is_empty() {
+        // JSON strings are JS strings
+        (
+            Value::String(html.to_string()).to_string(),
+            String::from("h(\"div\", { dangerouslySetInnerHTML: { __html: html } })"),
+        )
+    } else {
+        let components: BTreeMap<&str, String> = modules
+            .iter()
+            .enumerate()
+            .map(|(i, (name, module))| {
+                let component = format!("Shortcode{}", i);
+                imports.push_str(&format!(
+                    "import {} from \"{}\";\n",
+                    component,
+                    relative_import(source_id, module)
+                ));
+                (name.as_str(), component)
+            })
+            .collect();
+        let chunks: Vec<&str> = html.split(PLACEHOLDER).collect();
+        let mut children = vec![];
+        for (i, chunk) in chunks.iter().enumerate() {
+            if !chunk.trim().is_empty() {
+                children.push(format!(
+                    "h(\"div\", {{ dangerouslySetInnerHTML: {{ __html: html[{}] }} }})",
+                    i
+                ));
+            }
+            if let Some(shortcode) = shortcodes.get(i) {
+                children.push(format!(
+                    "h({}, {})",
+                    components[shortcode.name.as_str()],
+                    serde_json::to_string(&shortcode.props).unwrap_or_default()
+                ));
+            }
+        }
+        (
+            serde_json::to_string(&chunks).unwrap_or_default(),
+            format!("h(\"div\", null, {})", children.join(", ")),
+        )
+    };
+    match layout {
+        Some(_) => format!(
+            "{}\nconst html = {};\n\nexport default (props) => h(Layout, props, {});\n",
+            imports, html, content
+        ),
+        None => format!(
+            "{}\nconst html = {};\n\nexport default () => {};\n",
+            imports, html, content
+        ),
+    }
+}
+
+/// Compile `.md` files into page components. Like `compile_mdx`,
+/// frontmatter is exported from the component as `frontmatter`, and the
+/// table of contents as `toc`.
+#[instrument]
+pub fn compile_markdown(
+    files: &[PathBuf],
+    project_root_dir: &Path,
+    config: &MarkdownConfig,
+    toc_config: &TocConfig,
+    excerpt_config: &ExcerptConfig,
+) -> Result<HashMap<PathBuf, CompiledMdx>> {
+    files
+        .iter()
+        .map(|file| {
+            let contents = fs::read_to_string(file)
+                .wrap_err_with(|| format!("Failed to read `{}`", file.display()))?;
+            let (frontmatter, body) = frontmatter::extract(&contents)
+                .wrap_err_with(|| format!("Invalid frontmatter in `{}`", file.display()))?;
+            let source_id = file.strip_prefix(project_root_dir).unwrap_or(file);
+            let reading = reading_stats(body);
+            let excerpt = excerpt(frontmatter.as_ref(), body, excerpt_config);
+            let mut modules = BTreeMap::new();
+            let (body, shortcodes) = match &config.shortcodes {
+                Some(dir) => {
+                    let (body, shortcodes) = substitute_shortcodes(body)
+                        .wrap_err_with(|| format!("Invalid shortcode in `{}`", file.display()))?;
+                    for shortcode in &shortcodes {
+                        if !modules.contains_key(&shortcode.name) {
+                            let module = shortcode_module(project_root_dir, dir, &shortcode.name)
+                                .wrap_err_with(|| {
+                                format!("Failed to compile `{}`", file.display())
+                            })?;
+                            modules.insert(shortcode.name.clone(), module);
+                        }
+                    }
+                    (body, shortcodes)
+                }
+                None => (body.to_string(), vec![]),
+            };
+            let (html, headings) = render_markdown(&body, toc_config.anchors);
+            let toc = table_of_contents(&headings, toc_config);
+            let jsx = format!(
+                "{}\n{}",
+                page_module(
+                    source_id,
+                    &html,
+                    &shortcodes,
+                    &modules,
+                    config.layout.as_deref()
+                ),
+                module_exports(frontmatter.as_ref(), &toc)
+            );
+            Ok((
+                file.clone(),
+                CompiledMdx {
+                    jsx,
+                    frontmatter,
+                    toc,
+                    reading,
+                    excerpt,
+                },
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relative_import() {
+        assert_eq!(
+            relative_import(
+                Path::new("src/pages/blog/post.md"),
+                Path::new("src/layouts/markdown.js")
+            ),
+            "../../layouts/markdown.js"
+        );
+        assert_eq!(
+            relative_import(
+                Path::new("src/pages/about.md"),
+                Path::new("src/pages/layout.js")
+            ),
+            "./layout.js"
+        );
+    }
+
+    #[test]
+    fn test_page_module_escapes_html() {
+        let module = page_module(
+            Path::new("src/pages/about.md"),
+            "<p>\"quoted\"</p>\n",
+            &[],
+            &BTreeMap::new(),
+            None,
+        );
+        assert!(module.contains(r#"const html = "<p>\"quoted\"</p>\n";"#));
+        assert!(!module.contains("Layout"));
+    }
+
+    #[test]
+    fn test_substitute_shortcodes() {
+        let (markdown, shortcodes) = substitute_shortcodes(
+            "Intro\n{{ youtube id=\"abc\" title=\"Say \\\"hi\\\"\" }}\n```\n{{ youtube }}\n```\n",
+        )
+        .unwrap();
+        assert_eq!(
+            markdown,
+            "Intro\n\n<div data-toast-shortcode></div>\n\n```\n{{ youtube }}\n```\n"
+        );
+        assert_eq!(shortcodes.len(), 1);
+        assert_eq!(shortcodes[0].name, "youtube");
+        assert_eq!(shortcodes[0].props["title"], "Say \"hi\"");
+        assert!(parse_shortcode("{{ youtube id=abc }}").is_err());
+    }
+
+    #[test]
+    fn test_page_module_renders_shortcodes() {
+        let (markdown, shortcodes) = substitute_shortcodes("# Hi\n{{ note }}\n").unwrap();
+        let mut modules = BTreeMap::new();
+        modules.insert(
+            String::from("note"),
+            PathBuf::from("src/shortcodes/note.js"),
+        );
+        let module = page_module(
+            Path::new("src/pages/about.md"),
+            &render_markdown(&markdown, false).0,
+            &shortcodes,
+            &modules,
+            None,
+        );
+        assert!(module.contains("import Shortcode0 from \"../shortcodes/note.js\";"));
+        assert!(module.contains(
+            "h(\"div\", { dangerouslySetInnerHTML: { __html: html[0] } }), h(Shortcode0, {})"
+        ));
+    }
+
+    #[test]
+    fn test_render_markdown_heading_ids() {
+        let (html, headings) = render_markdown("# Hello `world`\n\n## Hello `world`\n", true);
+        assert!(html.starts_with("<h1 id=\"hello-world\">Hello <code>world</code><a class=\"heading-anchor\" href=\"#hello-world\""));
+        assert_eq!(headings[1].id, "hello-world-1");
+        assert_eq!(headings[1].text, "Hello world");
+    }
+}
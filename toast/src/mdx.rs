@@ -0,0 +1,198 @@
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use duct::cmd;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+use tracing::instrument;
+
+use crate::{
+    diagnostics::{summary, CompileError},
+    excerpt::{excerpt, ExcerptConfig},
+    frontmatter,
+    node::node_bin,
+    npm_bin::node_script,
+    reading_time::{reading_stats, with_reading_stats, ReadingStats},
+    supervisor::{batch_timeout, run_node},
+    toc::{table_of_contents, Heading, TocConfig, TocEntry},
+};
+
+/// `[highlight]` in `toast.config.toml`. Setting a `theme` highlights
+/// fenced code blocks with a language, like ` ```js `, when they're
+/// compiled, using any theme shiki ships with (`github-dark`, `nord`,
+/// `solarized-light`, and so on). Colors are inline styles on the spans in
+/// the html, so pages don't need a highlighter or a stylesheet in the
+/// browser.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct HighlightConfig {
+    pub theme: Option<String>,
+}
+
+/// An `.mdx` or `.md` file compiled to a JSX page component
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompiledMdx {
+    pub jsx: String,
+    pub frontmatter: Option<Value>,
+    pub toc: Vec<TocEntry>,
+    pub reading: ReadingStats,
+    pub excerpt: Option<String>,
+}
+
+impl CompiledMdx {
+    /// What's in the page's entry in the frontmatter and content indices,
+    /// its frontmatter with its reading time and excerpt
+    pub fn page_data(&self) -> Value {
+        let mut data = with_reading_stats(self.frontmatter.clone(), self.reading);
+        if let (Some(excerpt), Value::Object(data)) = (&self.excerpt, &mut data) {
+            data.entry("excerpt")
+                .or_insert_with(|| Value::String(excerpt.clone()));
+        }
+        data
+    }
+}
+
+/// What `toast-mdx` compiles each file to
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum MdxOutput {
+    Compiled { jsx: String, headings: Vec<Heading> },
+    Failed { error: MdxError },
+}
+
+/// Where `toast-mdx` says a file failed to compile, in its body after the
+/// frontmatter
+#[derive(Debug, Deserialize)]
+struct MdxError {
+    message: String,
+    line: Option<usize>,
+    column: Option<usize>,
+    length: Option<usize>,
+}
+
+/// The `frontmatter` and `toc` exports every compiled page has
+pub fn module_exports(frontmatter: Option<&Value>, toc: &[TocEntry]) -> String {
+    format!(
+        "export const frontmatter = {};\nexport const toc = {};\n",
+        frontmatter.unwrap_or(&Value::Null),
+        serde_json::to_string(toc).unwrap_or_else(|_| String::from("[]"))
+    )
+}
+
+/// Compile `.mdx` files into JSX page components using the mdx
+/// compiler bundled with the toast npm package. Frontmatter is split off
+/// before compiling and exported from the component as `frontmatter`, and
+/// the table of contents is exported as `toc`.
+/// All of the files are compiled in one node process, and the result maps
+/// each input path to its JSX. Files that don't compile fail with a code
+/// frame for each of them.
+#[instrument]
+pub fn compile_mdx(
+    files: &[PathBuf],
+    npm_bin_dir: &Path,
+    highlight: &HighlightConfig,
+    toc_config: &TocConfig,
+    excerpt_config: &ExcerptConfig,
+) -> Result<HashMap<PathBuf, CompiledMdx>> {
+    if files.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let mut bodies: HashMap<&Path, String> = HashMap::new();
+    let mut frontmatters: HashMap<&Path, Option<Value>> = HashMap::new();
+    let mut reading: HashMap<&Path, ReadingStats> = HashMap::new();
+    let mut excerpts: HashMap<&Path, Option<String>> = HashMap::new();
+    // each file, and how many lines of frontmatter come before its body
+    let mut sources: HashMap<&Path, (String, usize)> = HashMap::new();
+    for file in files {
+        let contents = fs::read_to_string(file)
+            .wrap_err_with(|| format!("Failed to read `{}`", file.display()))?;
+        let (frontmatter, body) = frontmatter::extract(&contents)
+            .wrap_err_with(|| format!("Invalid frontmatter in `{}`", file.display()))?;
+        reading.insert(file, reading_stats(body));
+        excerpts.insert(file, excerpt(frontmatter.as_ref(), body, excerpt_config));
+        bodies.insert(file, body.to_string());
+        frontmatters.insert(file, frontmatter);
+        let frontmatter_lines = contents[..contents.len() - body.len()]
+            .matches('\n')
+            .count();
+        sources.insert(file, (contents, frontmatter_lines));
+    }
+
+    let bin = node_script(npm_bin_dir, "toast-mdx");
+    let bin_str = bin
+        .to_str()
+        .ok_or_else(|| eyre!("failed to make npm bin into str"))?;
+    let mut args = vec!["--unhandled-rejections", "strict", bin_str];
+    let theme_arg = highlight
+        .theme
+        .as_ref()
+        .map(|theme| format!("--highlight-theme={}", theme));
+    args.extend(theme_arg.as_deref());
+    if toc_config.anchors {
+        args.push("--heading-anchors");
+    }
+    let output = run_node(
+        "toast-mdx",
+        cmd(node_bin(), &args).stdin_bytes(serde_json::to_vec(&bodies)?),
+        batch_timeout(bodies.len()),
+    )
+    .wrap_err("Failed to compile mdx files with `toast-mdx`")?;
+    let compiled: HashMap<PathBuf, MdxOutput> = serde_json::from_str(&output)
+        .wrap_err_with(|| format!("Failed to parse output from `toast-mdx`: {}", output))?;
+    let mut pages = HashMap::new();
+    let mut errors = vec![];
+    for (path, output) in compiled {
+        match output {
+            MdxOutput::Compiled { jsx, headings } => {
+                let frontmatter = frontmatters.remove(path.as_path()).flatten();
+                let toc = table_of_contents(&headings, toc_config);
+                let reading = reading.remove(path.as_path()).unwrap_or_default();
+                let excerpt = excerpts.remove(path.as_path()).flatten();
+                let jsx = format!("{}\n{}", jsx, module_exports(frontmatter.as_ref(), &toc));
+                pages.insert(
+                    path,
+                    CompiledMdx {
+                        jsx,
+                        frontmatter,
+                        toc,
+                        reading,
+                        excerpt,
+                    },
+                );
+            }
+            MdxOutput::Failed { error } => errors.push(compile_error(&path, error, &sources)),
+        }
+    }
+    if !errors.is_empty() {
+        errors.sort_by(|a, b| a.file.cmp(&b.file));
+        let diagnostics: Vec<_> = errors.iter().map(CompileError::diagnostic).collect();
+        return Err(eyre!("{}", summary(&diagnostics)));
+    }
+    Ok(pages)
+}
+
+/// A `toast-mdx` error with its line moved past the frontmatter, so the
+/// code frame is of the file as it was written
+fn compile_error(
+    path: &Path,
+    error: MdxError,
+    sources: &HashMap<&Path, (String, usize)>,
+) -> CompileError {
+    let (source, frontmatter_lines) = sources
+        .get(path)
+        .map(|(source, lines)| (source.as_str(), *lines))
+        .unwrap_or(("", 0));
+    let position = error
+        .line
+        .map(|line| (line + frontmatter_lines, error.column.unwrap_or(1)));
+    CompileError::new(
+        path.display().to_string(),
+        error.message,
+        source,
+        position,
+        error.length.unwrap_or(1),
+    )
+}
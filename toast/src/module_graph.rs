@@ -0,0 +1,170 @@
+//! Which source modules import which, so that changing a shared component
+//! only rerenders the pages that depend on it.
+//!
+//! Modules are identified by where they're compiled to, relative to the
+//! output directory: `src/components/nav.js` for `src/components/nav.js`
+//! or `src/components/nav.tsx`, and `src/pages/post.js` for
+//! `src/pages/post.mdx`. Relative and
+//! root-relative imports are followed, but bare specifiers resolve through
+//! the import map to `web_modules`, which isn't a source.
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::fingerprint::js_specifiers;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ModuleGraph {
+    /// module to the modules it imports
+    imports: BTreeMap<String, BTreeSet<String>>,
+}
+
+/// The module id for a source id or output path
+pub fn module_id(path: &str) -> String {
+    let path = path.replace('\\', "/");
+    let path = path.trim_start_matches('/');
+    match [".mdx", ".md", ".tsx", ".ts"]
+        .iter()
+        .find_map(|extension| path.strip_suffix(extension))
+    {
+        Some(stem) => format!("{}.js", stem),
+        None => path.to_string(),
+    }
+}
+
+/// Resolve `specifier` against the module it's imported from, if it's a
+/// path to another module
+fn resolve(specifier: &str, from: &str) -> Option<String> {
+    let mut segments: Vec<&str> = if specifier.starts_with("./") || specifier.starts_with("../") {
+        let mut dir: Vec<&str> = from.split('/').collect();
+        dir.pop();
+        dir
+    } else if specifier.starts_with('/') {
+        vec![]
+    } else {
+        return None;
+    };
+    for segment in specifier.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop()?;
+            }
+            segment => segments.push(segment),
+        }
+    }
+    let path = segments.join("/");
+    let has_extension = segments.last().is_some_and(|name| name.contains('.'));
+    Some(if has_extension {
+        module_id(&path)
+    } else {
+        format!("{}.js", path)
+    })
+}
+
+impl ModuleGraph {
+    /// Replace what `module` imports with the imports in `source`
+    pub fn set_imports(&mut self, module: &str, source: &str) {
+        let module = module_id(module);
+        let imports = js_specifiers(source)
+            .iter()
+            .filter_map(|specifier| resolve(&specifier.url, &module))
+            .collect();
+        self.imports.insert(module, imports);
+    }
+
+    /// Every module that imports `module`, directly or through other
+    /// modules
+    pub fn dependents(&self, module: &str) -> BTreeSet<String> {
+        let mut dependents = BTreeSet::new();
+        let mut queue = vec![module_id(module)];
+        while let Some(current) = queue.pop() {
+            for (importer, imports) in &self.imports {
+                if imports.contains(&current) && dependents.insert(importer.clone()) {
+                    queue.push(importer.clone());
+                }
+            }
+        }
+        dependents
+    }
+
+    /// Every module `module` imports, directly or through other modules
+    pub fn imports(&self, module: &str) -> BTreeSet<String> {
+        let mut imports = BTreeSet::new();
+        let mut queue = vec![module_id(module)];
+        while let Some(current) = queue.pop() {
+            for import in self.imports.get(&current).into_iter().flatten() {
+                if imports.insert(import.clone()) {
+                    queue.push(import.clone());
+                }
+            }
+        }
+        imports
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transitive_dependents() {
+        let mut graph = ModuleGraph::default();
+        graph.set_imports(
+            "src/pages/index.mdx",
+            "import Layout from \"../components/layout.js\";",
+        );
+        graph.set_imports(
+            "src/components/layout.js",
+            "import { h } from \"preact\";\nimport Nav from \"./nav\";",
+        );
+        graph.set_imports(
+            "src/pages/about.js",
+            "import Nav from \"/src/components/nav.js\";",
+        );
+        graph.set_imports("src/pages/other.js", "export default () => null;");
+        assert_eq!(
+            graph.dependents("src/components/nav.js"),
+            vec![
+                "src/components/layout.js",
+                "src/pages/about.js",
+                "src/pages/index.js"
+            ]
+            .into_iter()
+            .map(String::from)
+            .collect()
+        );
+    }
+
+    #[test]
+    fn test_transitive_imports() {
+        let mut graph = ModuleGraph::default();
+        graph.set_imports(
+            "src/pages/index.mdx",
+            "import Layout from \"../components/layout.js\";\nimport \"preact\";",
+        );
+        graph.set_imports(
+            "src/components/layout.js",
+            "import Nav from \"./nav\";\nimport Layout from \"./layout.js\";",
+        );
+        assert_eq!(
+            graph.imports("src/pages/index.mdx"),
+            vec!["src/components/layout.js", "src/components/nav.js"]
+                .into_iter()
+                .map(String::from)
+                .collect()
+        );
+    }
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(
+            resolve("../../src/pages/blog.js", "blog/page/2.js"),
+            Some(String::from("src/pages/blog.js"))
+        );
+        assert_eq!(
+            resolve("./button.tsx", "src/pages/index.js"),
+            Some(String::from("src/pages/button.js"))
+        );
+        assert_eq!(resolve("preact", "src/pages/index.js"), None);
+        assert_eq!(resolve("../../../x.js", "src/index.js"), None);
+    }
+}
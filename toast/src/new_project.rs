@@ -0,0 +1,127 @@
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use std::{fs, path::Path};
+use tracing::instrument;
+
+use crate::protocol::NPM_PACKAGE_VERSION;
+
+const PAGE_TEMPLATE: &str = r#"import { h } from "preact";
+
+export default () => (
+  <div>
+    <h1>Welcome to Toast</h1>
+    <p>
+      Edit <code>src/pages/index.js</code> and rebuild to see your changes.
+    </p>
+  </div>
+);
+"#;
+
+// rendered to `404.html` for unknown routes
+const NOT_FOUND_TEMPLATE: &str = r#"import { h } from "preact";
+
+export default () => (
+  <div>
+    <h1>Page not found</h1>
+    <a href="/">Go home</a>
+  </div>
+);
+"#;
+
+// the browser loads preact from a CDN until the project installs its own
+// web_modules, so a fresh project builds without any extra steps.
+const IMPORT_MAP_TEMPLATE: &str = r#"{
+  "imports": {
+    "preact": "https://cdn.skypack.dev/preact@^10.4.8",
+    "preact/hooks": "https://cdn.skypack.dev/preact@^10.4.8/hooks"
+  }
+}
+"#;
+
+const GITIGNORE_TEMPLATE: &str = "node_modules\n.tmp\n.toast\n";
+
+/// The files a new project starts with, relative to the project directory
+pub fn template_files(name: &str) -> Vec<(&'static str, String)> {
+    vec![
+        ("package.json", package_json(name)),
+        ("src/pages/index.js", PAGE_TEMPLATE.to_string()),
+        ("src/pages/404.js", NOT_FOUND_TEMPLATE.to_string()),
+        (
+            "public/web_modules/import-map.json",
+            IMPORT_MAP_TEMPLATE.to_string(),
+        ),
+        (".gitignore", GITIGNORE_TEMPLATE.to_string()),
+    ]
+}
+
+fn package_json(name: &str) -> String {
+    let package = serde_json::json!({
+        "name": name,
+        "version": "0.1.0",
+        "private": true,
+        "type": "module",
+        "scripts": {
+            "build": "toast incremental",
+            "dev": "toast serve --watch"
+        },
+        "dependencies": {
+            "preact": "^10.4.8",
+            "toast": format!("^{}", NPM_PACKAGE_VERSION)
+        }
+    });
+    // serde_json can't fail to serialize a `Value`
+    format!("{}\n", serde_json::to_string_pretty(&package).unwrap())
+}
+
+/// Scaffold a new Toast project in `dir`. The directory may already exist
+/// as long as it's empty, so we never overwrite someone's files.
+#[instrument]
+pub fn new_project(dir: &Path) -> Result<()> {
+    if dir.exists() {
+        let mut entries = fs::read_dir(dir)
+            .wrap_err_with(|| format!("Failed to read directory `{}`", dir.display()))?;
+        if entries.next().is_some() {
+            return Err(eyre!(
+                "Can't create a new project in `{}` because it isn't empty",
+                dir.display()
+            ));
+        }
+    }
+    let name = dir
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("toast-site");
+    for (path, contents) in template_files(name) {
+        let file = dir.join(path);
+        if let Some(parent) = file.parent() {
+            fs::create_dir_all(parent).wrap_err_with(|| {
+                format!("Failed to create directories for `{}`", file.display())
+            })?;
+        }
+        fs::write(&file, contents)
+            .wrap_err_with(|| format!("Failed to write `{}`", file.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::esinstall::parse_import_map;
+
+    #[test]
+    fn test_templates_are_valid() {
+        let files = template_files("my-site");
+        let (_, package) = files
+            .iter()
+            .find(|(path, _)| *path == "package.json")
+            .unwrap();
+        let package: serde_json::Value = serde_json::from_str(package).unwrap();
+        assert_eq!(package["name"], "my-site");
+        assert_eq!(package["dependencies"]["toast"], "^0.3.3");
+        let (_, import_map) = files
+            .iter()
+            .find(|(path, _)| path.ends_with("import-map.json"))
+            .unwrap();
+        assert!(parse_import_map(import_map).is_ok());
+    }
+}
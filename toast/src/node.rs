@@ -1,101 +1,193 @@
-use color_eyre::eyre::{eyre, Result};
-use duct::cmd;
-use indicatif::ProgressBar;
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use semver::Version;
 use std::{
+    env,
     io::{prelude::*, BufReader},
     path::PathBuf,
-    sync::Arc,
+    process::Command,
+    sync::{Arc, OnceLock},
+    time::Duration,
 };
 use tracing::instrument;
 
-#[instrument]
-pub fn render_to_html(
-    dir_of_input_files: String,
-    output_dir: String,
-    filepaths: Vec<String>,
-    npm_bin_dir: PathBuf,
-    active_pb: Arc<ProgressBar>,
-) -> Result<()> {
-    let bin = npm_bin_dir.join("toast-render");
-    let bin_str = bin
-        .to_str()
-        .ok_or_else(|| eyre!("failed to make npm bin into str"))?;
-    let mut args: Vec<String> = vec![
-        "--unhandled-rejections".to_owned(),
-        "strict".to_owned(),
-        "--loader".to_owned(),
-        "toast/src/loader.mjs".to_owned(),
-        bin_str.to_owned(),
-        dir_of_input_files,
-        output_dir,
-    ];
-    args.extend(filepaths.iter().cloned());
-    let output = cmd("node", args).stderr_to_stdout();
-    run_cmd("sourceData", output, active_pb)?;
+use crate::{
+    cancel,
+    progress::Progress,
+    renderer::Rendered,
+    report::{PageTiming, RenderFailure},
+    supervisor::{StderrTail, Watchdog},
+};
+
+/// Marks lines of `toast-render` output that are page timings rather
+/// than user output. Must match the prefix in `toast-render.mjs`.
+pub const TIMING_PREFIX: &str = "toast-timing:";
+
+/// Marks lines of `toast-render` output that are pages that threw while
+/// rendering. Must match the prefix in `toast-render.mjs`.
+pub const FAILURE_PREFIX: &str = "toast-render-failure:";
 
+/// The node set with `--node-path` or `TOAST_NODE`
+static NODE_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Run every node process with the binary at `path`, or `node` in the
+/// directory at `path`, instead of the `node` on the `PATH`, like one
+/// installed by volta or nvm or a CI toolchain. It's put first on the
+/// `PATH` too, so scripts that start node themselves get the same one.
+pub fn use_node(path: PathBuf) -> Result<()> {
+    let path = if path.is_dir() {
+        path.join(format!("node{}", env::consts::EXE_SUFFIX))
+    } else {
+        path
+    };
+    if !path.is_file() {
+        return Err(eyre!(
+            "Couldn't find node at `{}` from `--node-path` or `TOAST_NODE`",
+            path.display()
+        ));
+    }
+    let path = dunce::canonicalize(&path)
+        .wrap_err_with(|| format!("Failed to resolve `{}`", path.display()))?;
+    if let Some(dir) = path.parent() {
+        let paths = env::var_os("PATH").unwrap_or_default();
+        let paths = std::iter::once(dir.to_path_buf()).chain(env::split_paths(&paths));
+        env::set_var(
+            "PATH",
+            env::join_paths(paths).wrap_err("Failed to add node's directory to `PATH`")?,
+        );
+    }
+    let _ = NODE_PATH.set(path);
     Ok(())
 }
 
+/// The node to run, see `use_node`. Otherwise it's the `node` on the
+/// `PATH`, resolved here because duct runs a bare relative path like
+/// `node` from the current directory as `./node`.
+pub fn node_bin() -> PathBuf {
+    NODE_PATH
+        .get()
+        .cloned()
+        .unwrap_or_else(|| which::which("node").unwrap_or_else(|_| PathBuf::from("node")))
+}
+
+/// Node 14 is the first version with the ES module support toast needs.
+/// Set `min_node_version` in `toast.config.toml`, `--min-node-version`,
+/// or `TOAST_MIN_NODE_VERSION` to require a newer one.
+pub const DEFAULT_MIN_NODE_VERSION: &str = "14";
+
+/// A version like `node -v` prints it, or a partial one like `16` or
+/// `16.14`, which are `16.0.0` and `16.14.0`
+pub fn parse_node_version(version: &str) -> Result<Version> {
+    let version = version.trim().trim_start_matches('v');
+    let (release, rest) = match version.find(['-', '+']) {
+        Some(index) => version.split_at(index),
+        None => (version, ""),
+    };
+    let parts = release.split('.').count();
+    let padded = format!(
+        "{}{}{}",
+        release,
+        ".0".repeat(3usize.saturating_sub(parts)),
+        rest
+    );
+    Version::parse(&padded).wrap_err_with(|| format!("`{}` isn't a node version", version))
+}
+
+/// Whether `current` is at least `minimum`. Prereleases and nightlies,
+/// like `18.0.0-nightly20220301`, count as the release they lead up to,
+/// since they already have its features.
+pub fn meets_minimum(current: &Version, minimum: &Version) -> bool {
+    (current.major, current.minor, current.patch) >= (minimum.major, minimum.minor, minimum.patch)
+}
+
+/// The version of the node `node_bin` runs
 #[instrument]
-pub async fn source_data(
-    toast_js_file: &PathBuf,
-    npm_bin_dir: PathBuf,
-    active_pb: Arc<ProgressBar>,
-) -> Result<()> {
-    // not a guarantee that toast.js will exist when node
-    // goes to look for it: just a sanity check to not
-    // execute Command if we don't need to
-    if toast_js_file.exists() {
-        let bin = npm_bin_dir.join("toast-source-data");
-        let bin_str = bin
-            .to_str()
-            .ok_or_else(|| eyre!("failed to make npm bin into str"))?;
-        let output = cmd!(
-            "node",
-            "--unhandled-rejections",
-            "strict",
-            "--loader",
-            "toast/src/loader.mjs",
-            bin_str,
-            "/var/tmp/toaster.sock",
-            &toast_js_file
-                .to_str()
-                .ok_or_else(|| eyre!("failed to make toast_js_file into str"))?
+pub fn node_version() -> Result<Version> {
+    let mut cmd = Command::new(node_bin());
+    cmd.arg("-v");
+    let output = cmd.output().wrap_err_with(|| {
+        format!(
+            "Failed to execute `{} -v` Command and collect output",
+            node_bin().display()
         )
-        .stderr_to_stdout();
+    })?;
+    let version_string = std::str::from_utf8(&output.stdout)
+        .wrap_err_with(|| "Failed to create utf8 string from node -v Command output")?;
+    // node ends the line with `\r\n` on Windows
+    let version_string_trimmed = version_string.trim().trim_start_matches('v');
+    parse_node_version(version_string_trimmed).map_err(|_e| {
+        eyre!(format!(
+            "Couldn't parse node version from trimmed version `{}`, original string is `{}`",
+            version_string_trimmed, version_string
+        ))
+    })
+}
 
-        run_cmd("sourceData", output, active_pb)?;
-        Ok(())
+/// Check that the node `node_bin` runs is at least `minimum`
+#[instrument]
+pub fn check_node_version(minimum: &str) -> Result<()> {
+    let minimum =
+        parse_node_version(minimum).wrap_err("Failed to parse the minimum node version")?;
+    let current_node_version = node_version()?;
+    if !meets_minimum(&current_node_version, &minimum) {
+        Err(eyre!(format!(
+            "node version {} doesn't meet the minimum required version {}",
+            current_node_version, minimum
+        )))
     } else {
-        // toast file doesn't exist
-        // skip running sourceData
         Ok(())
     }
 }
 
-fn run_cmd(
+/// Run a `toast-render` command, passing its output through to the
+/// terminal and collecting the page timings and failures it reports. The first
+/// `skip_lines` lines are runtime warnings and are dropped. The command
+/// is killed if it goes `timeout` without reporting a page.
+pub(crate) fn run_cmd(
     subcommand_name: &str,
     command: duct::Expression,
-    active_pb: Arc<ProgressBar>,
-) -> Result<()> {
+    progress: Progress,
+    skip_lines: usize,
+    timeout: Option<Duration>,
+) -> Result<Rendered> {
     if let Ok(reader) = command.reader() {
+        let pids = reader.pids();
+        for pid in &pids {
+            cancel::track_child(*pid);
+        }
         let reader = Arc::new(reader);
         let thread_reader = reader.clone();
-        let child = std::thread::spawn(move || -> std::io::Result<()> {
+        let watchdog_reader = reader.clone();
+        let watchdog = Watchdog::start(timeout, move || {
+            let _ = watchdog_reader.kill();
+        });
+        let thread_watchdog = watchdog.clone();
+        let output = StderrTail::default();
+        let thread_output = output.clone();
+        let child = std::thread::spawn(move || -> Rendered {
+            let mut rendered = Rendered::default();
             let lines = BufReader::new(&*thread_reader).lines();
             for (i, line_result) in lines.enumerate() {
                 match line_result {
                     Ok(line) => {
-                        // this magic number pulls off the warning
-                        if i > 1 {
-                            // if the progress bars are hidden, so is the
-                            // output from the pb.println function
-                            // so we use the println macro instead
-                            if active_pb.is_hidden() {
-                                println!("{}", line)
-                            } else {
-                                active_pb.println(line);
+                        if let Some(timing) = line.strip_prefix(TIMING_PREFIX) {
+                            if let Ok(timing) = serde_json::from_str::<PageTiming>(timing) {
+                                progress.rendered(&timing.page);
+                                rendered.timings.push(timing);
+                            }
+                            thread_watchdog.progress();
+                            continue;
+                        }
+                        if let Some(failure) = line.strip_prefix(FAILURE_PREFIX) {
+                            if let Ok(failure) = serde_json::from_str::<RenderFailure>(failure) {
+                                progress.rendered(&failure.page);
+                                rendered.failures.push(failure);
                             }
+                            thread_watchdog.progress();
+                            continue;
+                        }
+                        if i >= skip_lines {
+                            thread_output.push(line.clone());
+                            progress.println(line);
                         }
                     }
                     Err(_) => {
@@ -103,29 +195,44 @@ fn run_cmd(
                     }
                 }
             }
-            Ok(())
+            rendered
         });
         // wait for the process to stop running
         while let Ok(None) = &reader.try_wait() {}
         // wait for thread with stderr/stdout logging from the node
         // process to complete
-        let _ = child.join();
+        let rendered = child.join().unwrap_or_default();
+        for pid in &pids {
+            cancel::untrack_child(*pid);
+        }
+        if watchdog.timed_out() {
+            return Err(output.attach(eyre!(
+                "`{}` went {}s without finishing a page, so it was stopped. Pass `--render-timeout` to wait longer",
+                subcommand_name,
+                timeout.unwrap_or_default().as_secs()
+            )));
+        }
         // if the process ended in error, this will return
-        match &reader.try_wait()? {
-            None => {
+        match &reader.try_wait() {
+            Err(err) => {
+                Err(output.attach(eyre!("{} node process failed: {}", subcommand_name, err)))
+            }
+            Ok(None) => {
                 // should never happen because we're while-let'ing above
                 panic!("{} reader returned None while still running. This is an unexpected error please report it on github.", subcommand_name)
             }
-            Some(output_status) => {
+            Ok(Some(output_status)) => {
                 if output_status.status.success() {
-                    Ok(())
+                    Ok(rendered)
                 } else if let Some(code) = output_status.status.code() {
-                    Err(eyre!(
+                    Err(output.attach(eyre!(
                         "{} node process exited with code {}",
                         subcommand_name,
                         code
-                    ))
+                    )))
                 } else {
+                    // killed by a signal, which a cancelled build does
+                    cancel::check()?;
                     panic!("Should never reach here: 155");
                 }
             }
@@ -134,3 +241,25 @@ fn run_cmd(
         Err(eyre!("{} node process didn't start", subcommand_name))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_node_versions() {
+        let minimum = parse_node_version(DEFAULT_MIN_NODE_VERSION).unwrap();
+        assert_eq!(minimum, Version::parse("14.0.0").unwrap());
+        assert_eq!(
+            parse_node_version("16.14").unwrap(),
+            Version::parse("16.14.0").unwrap()
+        );
+        let nightly = parse_node_version("v14.0.0-nightly20200301abc\r\n").unwrap();
+        assert!(meets_minimum(&nightly, &minimum));
+        let canary = parse_node_version("v18.0.0-v8-canary202201").unwrap();
+        assert!(meets_minimum(&canary, &minimum));
+        let old = parse_node_version("v12.22.1").unwrap();
+        assert!(!meets_minimum(&old, &minimum));
+        assert!(parse_node_version("lts").is_err());
+    }
+}
@@ -0,0 +1,203 @@
+//! Where the toast npm package's bins are, and what to run them with.
+//!
+//! On Unix a bin in `node_modules/.bin` is a symlink to the script, which
+//! node can run. On Windows, and in Git Bash, npm writes shims instead: a
+//! shell script with the bin's name and a `.cmd` next to it. Node can't
+//! run either, so `node_script` reads the script out of the shim. Package
+//! managers are found through `PATHEXT` (`npm.cmd`, `pnpm.cmd`), and the
+//! directories they print lose the `\r\n` Windows ends lines with and
+//! Git Bash's `/c/...` form.
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+use thiserror::Error;
+use tracing::instrument;
+
+use crate::scripts::embedded_script;
+
+/// A bin that the toast npm package installs, used to tell whether a
+/// directory is one that has toast's node scripts in it.
+const TOAST_BIN: &str = "toast-render";
+
+#[derive(Error, Debug)]
+pub enum NpmBinError {
+    #[error("couldn't find the toast npm package's bin directory. Looked in: {}. Is `toast` installed in `{project_root_dir}`?", display_paths(.searched))]
+    NotFound {
+        project_root_dir: PathBuf,
+        searched: Vec<PathBuf>,
+    },
+}
+
+fn display_paths(paths: &[PathBuf]) -> String {
+    paths
+        .iter()
+        .map(|path| format!("`{}`", path.display()))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Find the directory the toast npm package's bins (`toast-render`,
+/// `toast-source-data`, ...) are installed in. Package managers are
+/// asked first, then `node_modules/.bin` is searched for from
+/// `project_root_dir` upward, so builds work without a global npm.
+#[instrument]
+pub fn find_npm_bin_dir(project_root_dir: &Path) -> Result<PathBuf, NpmBinError> {
+    let mut searched = vec![];
+    for (program, args) in &[
+        ("npm", &["bin"][..]),
+        ("yarn", &["bin"][..]),
+        ("pnpm", &["bin"][..]),
+    ] {
+        if let Some(dir) = package_manager_bin_dir(program, args, project_root_dir) {
+            if has_toast_bin(&dir) {
+                return Ok(dir);
+            }
+            searched.push(dir);
+        }
+    }
+    for ancestor in project_root_dir.ancestors() {
+        let dir = ancestor.join("node_modules").join(".bin");
+        if has_toast_bin(&dir) {
+            return Ok(dir);
+        }
+        searched.push(dir);
+    }
+    Err(NpmBinError::NotFound {
+        project_root_dir: project_root_dir.to_path_buf(),
+        searched,
+    })
+}
+
+/// Keep npm, yarn, and the package managers corepack would download off
+/// the registry, in every process toast starts, for `--offline`
+pub fn disable_registry() {
+    std::env::set_var("npm_config_offline", "true");
+    std::env::set_var("YARN_ENABLE_NETWORK", "0");
+    std::env::set_var("COREPACK_ENABLE_NETWORK", "0");
+}
+
+/// Ask a package manager where its bin directory is, if it is installed
+fn package_manager_bin_dir(program: &str, args: &[&str], cwd: &Path) -> Option<PathBuf> {
+    let path = which::which(program).ok()?;
+    let output = Command::new(path)
+        .args(args)
+        .current_dir(cwd)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let dir = std::str::from_utf8(&output.stdout).ok()?.trim();
+    if dir.is_empty() {
+        None
+    } else if cfg!(windows) {
+        Some(PathBuf::from(
+            from_msys_path(dir).unwrap_or_else(|| dir.to_string()),
+        ))
+    } else {
+        Some(PathBuf::from(dir))
+    }
+}
+
+/// `C:\Users\toast` for Git Bash's `/c/Users/toast`
+fn from_msys_path(path: &str) -> Option<String> {
+    let rest = path.strip_prefix('/')?;
+    let mut chars = rest.chars();
+    let drive = chars.next().filter(char::is_ascii_alphabetic)?;
+    let rest = chars.as_str();
+    if !rest.is_empty() && !rest.starts_with('/') {
+        return None;
+    }
+    Some(format!(
+        "{}:\\{}",
+        drive.to_ascii_uppercase(),
+        rest.trim_start_matches('/').replace('/', "\\")
+    ))
+}
+
+/// The script to run for the bin `name`, to pass to node or deno: the
+/// one built into toast, see `scripts`, or the one the npm package in
+/// `npm_bin_dir` installed
+pub fn node_script(npm_bin_dir: &Path, name: &str) -> PathBuf {
+    embedded_script(npm_bin_dir, &format!("{}.mjs", name))
+        .unwrap_or_else(|| bin_script(npm_bin_dir, name))
+}
+
+/// The `--loader` that aliases `react` imports for node, see
+/// `node_script`
+pub fn node_loader(npm_bin_dir: &Path) -> String {
+    embedded_script(npm_bin_dir, "src/loader.mjs")
+        .and_then(|path| url::Url::from_file_path(path).ok())
+        .map(|url| url.to_string())
+        .unwrap_or_else(|| String::from("toast/src/loader.mjs"))
+}
+
+/// The script the bin `name` in `npm_bin_dir` runs, see the module docs
+pub(crate) fn bin_script(npm_bin_dir: &Path, name: &str) -> PathBuf {
+    let bin = npm_bin_dir.join(name);
+    let shims = [bin.clone(), npm_bin_dir.join(format!("{}.cmd", name))];
+    let script = shims
+        .iter()
+        .filter_map(|shim| fs::read_to_string(shim).ok())
+        .find_map(|contents| shim_target(&contents))
+        .map(|target| npm_bin_dir.join(target))
+        .unwrap_or(bin);
+    dunce::canonicalize(&script).unwrap_or(script)
+}
+
+/// The script a shell or `.cmd` shim written by npm runs, relative to
+/// the shim. Scripts themselves have no target.
+fn shim_target(shim: &str) -> Option<String> {
+    shim.split('"')
+        .skip(1)
+        .step_by(2)
+        .filter_map(|quoted| {
+            ["$basedir/", "%dp0%\\", "%~dp0\\"]
+                .iter()
+                .find_map(|prefix| quoted.strip_prefix(prefix))
+        })
+        .find(|target| !matches!(*target, "node" | "node.exe"))
+        .map(|target| target.replace('\\', "/"))
+}
+
+fn has_toast_bin(dir: &Path) -> bool {
+    dir.join(TOAST_BIN).exists() || dir.join(format!("{}.cmd", TOAST_BIN)).exists()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shim_target() {
+        let sh = r#"#!/bin/sh
+basedir=$(dirname "$(echo "$0" | sed -e 's,\\,/,g')")
+
+if [ -x "$basedir/node" ]; then
+  exec "$basedir/node"  "$basedir/../toast/bin/toast-render.mjs" "$@"
+else
+  exec node  "$basedir/../toast/bin/toast-render.mjs" "$@"
+fi
+"#;
+        assert_eq!(
+            shim_target(sh).as_deref(),
+            Some("../toast/bin/toast-render.mjs")
+        );
+        let cmd = "@ECHO off\r\nGOTO start\r\n:find_dp0\r\nSET dp0=%~dp0\r\nEXIT /b\r\n:start\r\nendLocal & goto #_undefined_# 2>NUL || title %COMSPEC% & \"%_prog%\"  \"%dp0%\\..\\toast\\bin\\toast-render.mjs\" %*\r\n";
+        assert_eq!(
+            shim_target(cmd).as_deref(),
+            Some("../toast/bin/toast-render.mjs")
+        );
+        assert_eq!(
+            shim_target("#!/usr/bin/env node\nimport \"./render.mjs\";"),
+            None
+        );
+        assert_eq!(
+            from_msys_path("/c/Users/toast/node_modules/.bin").as_deref(),
+            Some("C:\\Users\\toast\\node_modules\\.bin")
+        );
+        assert_eq!(from_msys_path("/home/toast"), None);
+    }
+}
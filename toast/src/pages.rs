@@ -0,0 +1,313 @@
+//! Where page modules come from.
+//!
+//! Pages are found in the pages directory (`src/pages`, or `pages_dir` in
+//! `toast.config.toml`), which is served from the root of the site, and in
+//! any number of content roots that are each served under a url prefix:
+//!
+//! ```toml
+//! [[content]]
+//! dir = "content/blog"
+//! prefix = "/blog"
+//!
+//! [[content]]
+//! dir = "docs"
+//! prefix = "/docs"
+//! ```
+//!
+//! so `content/blog/hello.mdx` is rendered to `/blog/hello`. Compiled
+//! modules keep their place in the project, so relative imports between
+//! roots and `src` still work.
+//!
+//! Symlinks in the roots are skipped unless `--follow-symlinks` (or
+//! `follow_symlinks = true`) is set. Followed links are named by where
+//! the link is, not where it points, and a link back up to a directory
+//! that contains it is only walked once. Routes are always
+//! `/`-separated, and two sources whose routes only differ in case are
+//! warned about, since one overwrites the other on the case-insensitive
+//! filesystems macOS and Windows use by default.
+use color_eyre::eyre::{eyre, Report};
+use glob::Pattern;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::{Component, Path},
+    str::FromStr,
+};
+
+/// Where page modules are, relative to the project root, unless
+/// `pages_dir` is set in `toast.config.toml` or with `--pages-dir`
+pub const DEFAULT_PAGES_DIR: &str = "src/pages";
+
+/// A directory of page modules, and the url prefix its pages are served
+/// under. Both are stored without leading or trailing slashes, in the
+/// same `/`-separated form as source ids.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PageRoot {
+    /// relative to the project root
+    pub dir: String,
+    #[serde(default)]
+    pub prefix: String,
+}
+
+impl PageRoot {
+    pub fn new(dir: &str, prefix: &str) -> PageRoot {
+        let dir = dir.replace('\\', "/");
+        let dir = dir.trim_start_matches("./").trim_matches('/');
+        PageRoot {
+            // pages can't be the whole project, which has `node_modules`
+            // in it
+            dir: if dir.is_empty() {
+                DEFAULT_PAGES_DIR.to_string()
+            } else {
+                dir.to_string()
+            },
+            prefix: prefix.trim_matches('/').to_string(),
+        }
+    }
+
+    /// `dest` inside of this root, if it's in it
+    fn strip<'a>(&self, dest: &'a str) -> Option<&'a str> {
+        dest.strip_prefix(&self.dir)
+            .and_then(|rest| rest.strip_prefix('/'))
+    }
+}
+
+/// The root `dest` is in. Roots can be nested, so the deepest one wins.
+fn root_for<'a>(roots: &'a [PageRoot], dest: &str) -> Option<&'a PageRoot> {
+    roots
+        .iter()
+        .filter(|root| root.strip(dest).is_some())
+        .max_by_key(|root| root.dir.len())
+}
+
+/// Whether `dest` is a module in one of the page roots
+pub fn is_page(roots: &[PageRoot], dest: &str) -> bool {
+    root_for(roots, dest).is_some()
+}
+
+/// Where a page module is in the output directory, `blog/post.js` for
+/// `src/pages/blog/post.js` or for `content/post.js` in a root with the
+/// `/blog` prefix. Pages created by `toast.js` and generated pages aren't
+/// in a page root, so they're already relative to the output directory.
+pub fn page_path(roots: &[PageRoot], dest: &str) -> String {
+    match root_for(roots, dest) {
+        Some(root) => {
+            let rest = root.strip(dest).unwrap_or(dest);
+            if root.prefix.is_empty() {
+                rest.to_string()
+            } else {
+                format!("{}/{}", root.prefix, rest)
+            }
+        }
+        None => dest.to_string(),
+    }
+}
+
+/// Whether `dest` is picked by `--filter`, matched against both where the
+/// page is in the output directory, like `blog/post.js`, and its module,
+/// like `src/pages/blog/post.js`
+pub fn matches_filter(roots: &[PageRoot], filter: &Pattern, dest: &str) -> bool {
+    filter.matches(&page_path(roots, dest)) || filter.matches(dest)
+}
+
+/// One of `count` parts of the pages, picked with `--shard 2/4`, so that
+/// many machines can each render part of a site into the same output
+/// layout. Pages are split by a hash of their module, so each page is in
+/// the same shard on every machine however many pages there are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shard {
+    /// from 1 to `count`
+    pub index: u64,
+    pub count: u64,
+}
+
+impl Shard {
+    pub fn contains(&self, dest: &str) -> bool {
+        // FNV-1a, which unlike `DefaultHasher` is the same everywhere
+        let hash = dest.bytes().fold(0xcbf29ce484222325_u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+        });
+        hash % self.count == self.index - 1
+    }
+}
+
+impl FromStr for Shard {
+    type Err = Report;
+
+    fn from_str(shard: &str) -> Result<Shard, Report> {
+        let invalid = || eyre!("`{}` isn't a shard like `1/4`", shard);
+        let (index, count) = shard.split_once('/').ok_or_else(invalid)?;
+        let index: u64 = index.trim().parse().map_err(|_| invalid())?;
+        let count: u64 = count.trim().parse().map_err(|_| invalid())?;
+        if index == 0 || index > count {
+            return Err(eyre!("shard `{}` has to be from 1 to {}", shard, count));
+        }
+        Ok(Shard { index, count })
+    }
+}
+
+/// The page module that would be served from `path`, like
+/// `src/pages/blog/index.js` for `blog/index.js`. The opposite of
+/// `page_path`.
+pub fn page_module(roots: &[PageRoot], path: &str) -> String {
+    let root = roots
+        .iter()
+        .filter(|root| {
+            root.prefix.is_empty()
+                || path
+                    .strip_prefix(&root.prefix)
+                    .is_some_and(|rest| rest.starts_with('/'))
+        })
+        .max_by_key(|root| root.prefix.len());
+    match root {
+        Some(root) if root.prefix.is_empty() => format!("{}/{}", root.dir, path),
+        Some(root) => format!("{}{}", root.dir, &path[root.prefix.len()..]),
+        None => format!("{}/{}", DEFAULT_PAGES_DIR, path),
+    }
+}
+
+/// The source id of `path`, relative to `project_root_dir` and
+/// `/`-separated on every platform. Paths that only reach the project
+/// through a symlink, like the ones file watchers report, are compared
+/// once both are canonical.
+pub fn source_id(project_root_dir: &Path, path: &Path) -> Result<String, Report> {
+    let relative = match path.strip_prefix(project_root_dir) {
+        Ok(relative) => relative.to_path_buf(),
+        Err(_) => {
+            let root = dunce::canonicalize(project_root_dir)?;
+            dunce::canonicalize(path)?
+                .strip_prefix(&root)
+                .map_err(|_| eyre!("`{}` is outside of the project", path.display()))?
+                .to_path_buf()
+        }
+    };
+    let parts = relative
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(
+                part.to_str()
+                    .ok_or_else(|| eyre!("`{}` is not a valid utf8 path", path.display())),
+            ),
+            _ => None,
+        })
+        .collect::<Result<Vec<&str>, Report>>()?;
+    Ok(parts.join("/"))
+}
+
+/// Groups of `dests` that are the same except for case, each sorted
+pub fn case_collisions<'a>(dests: impl IntoIterator<Item = &'a str>) -> Vec<Vec<String>> {
+    let mut by_lowercase: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for dest in dests {
+        let group = by_lowercase.entry(dest.to_lowercase()).or_default();
+        if !group.iter().any(|existing| existing == dest) {
+            group.push(dest.to_string());
+        }
+    }
+    by_lowercase
+        .into_values()
+        .filter(|group| group.len() > 1)
+        .map(|mut group| {
+            group.sort();
+            group
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roots() -> Vec<PageRoot> {
+        vec![
+            PageRoot::new(DEFAULT_PAGES_DIR, ""),
+            PageRoot::new("./content/blog/", "/blog/"),
+        ]
+    }
+
+    #[test]
+    fn test_page_path() {
+        let roots = roots();
+        assert_eq!(roots[1].dir, "content/blog");
+        assert_eq!(roots[1].prefix, "blog");
+        assert_eq!(page_path(&roots, "src/pages/about.js"), "about.js");
+        assert_eq!(page_path(&roots, "content/blog/hello.js"), "blog/hello.js");
+        // created by toast.js
+        assert_eq!(
+            page_path(&roots, "tags/rust/index.js"),
+            "tags/rust/index.js"
+        );
+        assert!(!is_page(&roots, "src/components/nav.js"));
+    }
+
+    #[test]
+    fn test_matches_filter() {
+        let roots = roots();
+        let filter = Pattern::new("blog/**").unwrap();
+        assert!(matches_filter(&roots, &filter, "content/blog/hello.js"));
+        assert!(matches_filter(&roots, &filter, "src/pages/blog/index.js"));
+        assert!(!matches_filter(&roots, &filter, "src/pages/about.js"));
+        let filter = Pattern::new("src/pages/*.js").unwrap();
+        assert!(matches_filter(&roots, &filter, "src/pages/about.js"));
+    }
+
+    #[test]
+    fn test_shards_split_every_page_once() {
+        let shards: Vec<Shard> = (1..=3)
+            .map(|index| format!("{}/3", index).parse().unwrap())
+            .collect();
+        for page in &[
+            "src/pages/index.js",
+            "src/pages/about.js",
+            "content/blog/a.js",
+        ] {
+            assert_eq!(
+                shards.iter().filter(|shard| shard.contains(page)).count(),
+                1
+            );
+        }
+        assert!("0/3".parse::<Shard>().is_err());
+        assert!("4/3".parse::<Shard>().is_err());
+        assert!("two".parse::<Shard>().is_err());
+    }
+
+    #[test]
+    fn test_page_module() {
+        let roots = roots();
+        assert_eq!(
+            page_module(&roots, "blog/index.js"),
+            "content/blog/index.js"
+        );
+        assert_eq!(
+            page_module(&roots, "docs/index.js"),
+            "src/pages/docs/index.js"
+        );
+    }
+
+    #[test]
+    fn test_routes() {
+        assert_eq!(
+            source_id(
+                Path::new("/site"),
+                &Path::new("/site")
+                    .join("src")
+                    .join("pages")
+                    .join("index.js")
+            )
+            .unwrap(),
+            "src/pages/index.js"
+        );
+        assert_eq!(
+            case_collisions(vec![
+                "src/pages/About.js",
+                "src/pages/index.js",
+                "src/pages/about.js",
+                "src/pages/about.js",
+            ]),
+            vec![vec![
+                String::from("src/pages/About.js"),
+                String::from("src/pages/about.js")
+            ]]
+        );
+    }
+}
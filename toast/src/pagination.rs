@@ -0,0 +1,223 @@
+//! Paginated list pages for a collection, configured with `[[paginate]]`
+//! in `toast.config.toml`
+//!
+//! ```toml
+//! [[paginate]]
+//! collection = "blog"
+//! per_page = 10
+//! ```
+//!
+//! The page component (`blog/index.js` in the pages directory by default) is rendered
+//! at `/blog/`, `/blog/2/`, `/blog/3/` and so on. Each one gets its slice
+//! of the collection as the `pages` prop and a `pagination` prop with the
+//! page number and links to the previous and next pages.
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::content_index::{sort_entries, PageEntry};
+use crate::frontmatter::page_slug;
+use crate::pages::{page_module, PageRoot};
+use crate::url_style::UrlStyle;
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PaginateConfig {
+    /// a collection from the content index, like `blog`
+    pub collection: String,
+    #[serde(default = "default_per_page")]
+    pub per_page: usize,
+    /// page component rendered for every page of the collection, relative
+    /// to the project root. Defaults to `<collection>/index.js` in the
+    /// pages directory.
+    #[serde(default)]
+    pub page: Option<String>,
+}
+
+fn default_per_page() -> usize {
+    10
+}
+
+impl PaginateConfig {
+    pub fn page_component(&self, page_roots: &[PageRoot]) -> String {
+        match &self.page {
+            Some(page) => page.clone(),
+            None => page_module(page_roots, &format!("{}/index.js", self.collection)),
+        }
+    }
+
+    /// URL of the first page, with a trailing slash, like `/blog/`
+    fn base_url(&self, page_roots: &[PageRoot]) -> String {
+        let slug = page_slug(page_roots, &self.page_component(page_roots));
+        format!("{}/", slug.trim_end_matches('/'))
+    }
+
+    fn url_for(&self, page_roots: &[PageRoot], url_style: UrlStyle, page: usize) -> String {
+        if page == 1 {
+            url_style.url(&self.base_url(page_roots))
+        } else {
+            url_style.url(&format!("{}{}/", self.base_url(page_roots), page))
+        }
+    }
+}
+
+/// Passed to each list page as the `pagination` prop
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Pagination {
+    /// starts at 1
+    pub page: usize,
+    pub total_pages: usize,
+    pub per_page: usize,
+    pub total_items: usize,
+    pub prev: Option<String>,
+    pub next: Option<String>,
+}
+
+/// One page of a paginated collection
+#[derive(Debug, Clone, PartialEq)]
+pub struct PaginatedPage {
+    /// the page component's own module for the first page, and a
+    /// generated module like `blog/2/index.js` for the rest
+    pub dest: String,
+    pub pagination: Pagination,
+    pub pages: Vec<PageEntry>,
+}
+
+impl PaginatedPage {
+    /// Props for the page, on top of any the page component already has
+    /// from its frontmatter
+    pub fn props(&self, base: Option<&Value>) -> Value {
+        let mut props = match base {
+            Some(Value::Object(map)) => map.clone(),
+            _ => Default::default(),
+        };
+        props.insert("pages".to_string(), json!(self.pages));
+        props.insert("pagination".to_string(), json!(self.pagination));
+        Value::Object(props)
+    }
+}
+
+/// Source for a generated page at `dest` that re-exports everything from
+/// `page_component`, so the node and browser builds both share it. Both
+/// paths are relative to the project root.
+pub fn reexport_source(dest: &str, page_component: &str) -> String {
+    let depth = dest.matches('/').count();
+    let path = format!("{}{}", "../".repeat(depth), page_component);
+    format!(
+        "export {{ default }} from \"{path}\";\nexport * from \"{path}\";\n",
+        path = path
+    )
+}
+
+/// Split a collection into pages of `per_page` entries, newest first. An
+/// empty collection still gets a first page.
+pub fn paginate(
+    config: &PaginateConfig,
+    page_roots: &[PageRoot],
+    url_style: UrlStyle,
+    entries: &[PageEntry],
+) -> Vec<PaginatedPage> {
+    let mut items: Vec<PageEntry> = entries
+        .iter()
+        .filter(|entry| entry.collection() == Some(config.collection.as_str()))
+        .cloned()
+        .collect();
+    sort_entries(&mut items);
+    let per_page = config.per_page.max(1);
+    let total_pages = items.len().div_ceil(per_page).max(1);
+    (1..=total_pages)
+        .map(|page| {
+            let dest = if page == 1 {
+                config.page_component(page_roots)
+            } else {
+                format!(
+                    "{}{}/index.js",
+                    config.base_url(page_roots).trim_start_matches('/'),
+                    page
+                )
+            };
+            PaginatedPage {
+                dest,
+                pagination: Pagination {
+                    page,
+                    total_pages,
+                    per_page,
+                    total_items: items.len(),
+                    prev: if page > 1 {
+                        Some(config.url_for(page_roots, url_style, page - 1))
+                    } else {
+                        None
+                    },
+                    next: if page < total_pages {
+                        Some(config.url_for(page_roots, url_style, page + 1))
+                    } else {
+                        None
+                    },
+                },
+                pages: items
+                    .iter()
+                    .skip((page - 1) * per_page)
+                    .take(per_page)
+                    .cloned()
+                    .collect(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(per_page: usize) -> PaginateConfig {
+        PaginateConfig {
+            collection: String::from("blog"),
+            per_page,
+            page: None,
+        }
+    }
+
+    fn roots() -> Vec<PageRoot> {
+        vec![PageRoot::new(crate::pages::DEFAULT_PAGES_DIR, "")]
+    }
+
+    fn posts(count: usize) -> Vec<PageEntry> {
+        (0..count)
+            .map(|i| PageEntry {
+                slug: format!("/blog/post-{}", i),
+                data: json!({ "date": format!("2020-01-{:02}", i + 1) }),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_paginate_links() {
+        let pages = paginate(&config(2), &roots(), UrlStyle::Bare, &posts(5));
+        assert_eq!(pages.len(), 3);
+        assert_eq!(pages[0].dest, "src/pages/blog/index.js");
+        assert_eq!(pages[1].dest, "blog/2/index.js");
+        assert_eq!(pages[0].pagination.prev, None);
+        assert_eq!(pages[0].pagination.next.as_deref(), Some("/blog/2/"));
+        assert_eq!(pages[1].pagination.prev.as_deref(), Some("/blog/"));
+        assert_eq!(pages[2].pagination.next, None);
+        assert_eq!(pages[2].pages.len(), 1);
+        // newest first
+        assert_eq!(pages[0].pages[0].slug, "/blog/post-4");
+    }
+
+    #[test]
+    fn test_empty_collection_has_one_page() {
+        let pages = paginate(&config(10), &roots(), UrlStyle::Bare, &[]);
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[0].pagination.total_pages, 1);
+    }
+
+    #[test]
+    fn test_module_source() {
+        let page = &paginate(&config(1), &roots(), UrlStyle::Bare, &posts(2))[1];
+        assert_eq!(
+            reexport_source(&page.dest, "src/pages/blog/index.js"),
+            "export { default } from \"../../src/pages/blog/index.js\";\nexport * from \"../../src/pages/blog/index.js\";\n"
+        );
+    }
+}
@@ -0,0 +1,297 @@
+//! Plugins that hook into the build. JS plugins are modules listed in
+//! `toast.config.toml`, relative to the project:
+//!
+//! ```toml
+//! plugins = ["./plugins/analytics.mjs"]
+//! ```
+//!
+//! Each one exports any of these, which are called in the order the
+//! plugins are listed:
+//!
+//! ```js
+//! // return false to leave a page out of the build
+//! export function onPageDiscovered({ page }) {}
+//! // return new source for a module before it's compiled
+//! export function transformSource({ id, source }) {}
+//! // return new html for a page after it's rendered
+//! export function onHtmlRendered({ page, html }) {}
+//! // the same summary `--report json` prints
+//! export function onBuildComplete(report) {}
+//! ```
+//!
+//! Hooks can be async, and returning `undefined` keeps what they were
+//! given. Programs that build with `toast::build` can also implement
+//! `Plugin` in Rust and pass it in `BuildOptions`.
+//!
+//! Hooks are given everything they apply to at once, so node starts once
+//! per hook rather than once per page. `transformSource` gets the JSX that
+//! `.mdx` and `.md` files compile to, and runs on every build, before the
+//! incremental cache decides what needs compiling.
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use duct::cmd;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tracing::instrument;
+
+use crate::{
+    node::node_bin,
+    npm_bin::node_script,
+    pages::PageRoot,
+    renderer::html_output_path,
+    report::BuildReport,
+    supervisor::{batch_timeout, run_node},
+};
+
+/// The build lifecycle, every hook does nothing unless it's implemented
+pub trait Plugin: Debug + Send + Sync {
+    /// Remove pages that shouldn't be rendered from `pages`
+    fn on_pages_discovered(&self, _pages: &mut Vec<String>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Change the source of modules before they're compiled, by source id
+    fn transform_sources(&self, _sources: &mut BTreeMap<String, String>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Change the html of rendered pages, by page module
+    fn on_html_rendered(&self, _pages: &mut BTreeMap<String, String>) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_build_complete(&self, _report: &BuildReport) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Every plugin in a build, run in order
+#[derive(Debug, Clone, Default)]
+pub struct Plugins(Vec<Arc<dyn Plugin>>);
+
+impl Plugins {
+    pub fn new(plugins: Vec<Arc<dyn Plugin>>) -> Plugins {
+        Plugins(plugins)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn on_pages_discovered(&self, pages: &mut Vec<String>) -> Result<()> {
+        self.0
+            .iter()
+            .try_for_each(|plugin| plugin.on_pages_discovered(pages))
+    }
+
+    pub fn transform_sources(&self, sources: &mut BTreeMap<String, String>) -> Result<()> {
+        self.0
+            .iter()
+            .try_for_each(|plugin| plugin.transform_sources(sources))
+    }
+
+    /// Run `on_html_rendered` over the html files of `pages`
+    #[instrument]
+    pub fn apply_html_rendered(
+        &self,
+        output_dir: &Path,
+        page_roots: &[PageRoot],
+        pages: &[String],
+    ) -> Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        let html: BTreeMap<String, String> = pages
+            .iter()
+            .filter_map(|page| {
+                // pages that failed to render
+                let html =
+                    fs::read_to_string(html_output_path(output_dir, page_roots, page)).ok()?;
+                Some((page.clone(), html))
+            })
+            .collect();
+        let mut rendered = html.clone();
+        for plugin in &self.0 {
+            plugin.on_html_rendered(&mut rendered)?;
+        }
+        for (page, contents) in rendered {
+            if html.get(&page) != Some(&contents) {
+                let html_path = html_output_path(output_dir, page_roots, &page);
+                fs::write(&html_path, contents)
+                    .wrap_err_with(|| format!("Failed to write `{}`", html_path.display()))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn on_build_complete(&self, report: &BuildReport) -> Result<()> {
+        self.0
+            .iter()
+            .try_for_each(|plugin| plugin.on_build_complete(report))
+    }
+}
+
+/// The `plugins` in `toast.config.toml`, run by `toast-plugins`
+#[derive(Debug)]
+pub struct JsPlugins {
+    pub modules: Vec<PathBuf>,
+    pub npm_bin_dir: PathBuf,
+}
+
+impl JsPlugins {
+    /// Call `hook` in every module, in node, with each of `inputs`
+    fn run<T: DeserializeOwned>(&self, hook: &str, inputs: Vec<Value>) -> Result<Vec<T>> {
+        let bin = node_script(&self.npm_bin_dir, "toast-plugins");
+        let bin_str = bin
+            .to_str()
+            .ok_or_else(|| eyre!("failed to make npm bin into str"))?;
+        let count = inputs.len();
+        let request = json!({
+            "hook": hook,
+            "plugins": self.modules,
+            "inputs": inputs,
+        });
+        let output = run_node(
+            "toast-plugins",
+            cmd!(node_bin(), "--unhandled-rejections", "strict", bin_str)
+                .stdin_bytes(serde_json::to_vec(&request)?),
+            batch_timeout(count),
+        )
+        .wrap_err_with(|| format!("Failed to run the `{}` hook of plugins", hook))?;
+        let outputs: Vec<T> = serde_json::from_str(&output)
+            .wrap_err_with(|| format!("Failed to parse output from `toast-plugins`: {}", output))?;
+        if outputs.len() != count {
+            return Err(eyre!(
+                "`toast-plugins` returned {} results for `{}` but was given {}",
+                outputs.len(),
+                hook,
+                count
+            ));
+        }
+        Ok(outputs)
+    }
+
+    /// Replace each value in `map` with what `hook` returns for it
+    fn transform(
+        &self,
+        hook: &str,
+        map: &mut BTreeMap<String, String>,
+        input: impl Fn(&str, &str) -> Value,
+    ) -> Result<()> {
+        if map.is_empty() {
+            return Ok(());
+        }
+        let inputs = map.iter().map(|(key, value)| input(key, value)).collect();
+        let outputs: Vec<String> = self.run(hook, inputs)?;
+        for (value, output) in map.values_mut().zip(outputs) {
+            *value = output;
+        }
+        Ok(())
+    }
+}
+
+impl Plugin for JsPlugins {
+    fn on_pages_discovered(&self, pages: &mut Vec<String>) -> Result<()> {
+        if pages.is_empty() {
+            return Ok(());
+        }
+        let inputs = pages.iter().map(|page| json!({ "page": page })).collect();
+        let keep: Vec<bool> = self.run("onPageDiscovered", inputs)?;
+        let mut keep = keep.into_iter();
+        pages.retain(|_| keep.next().unwrap_or(true));
+        Ok(())
+    }
+
+    fn transform_sources(&self, sources: &mut BTreeMap<String, String>) -> Result<()> {
+        self.transform(
+            "transformSource",
+            sources,
+            |id, source| json!({ "id": id, "source": source }),
+        )
+    }
+
+    fn on_html_rendered(&self, pages: &mut BTreeMap<String, String>) -> Result<()> {
+        self.transform(
+            "onHtmlRendered",
+            pages,
+            |page, html| json!({ "page": page, "html": html }),
+        )
+    }
+
+    fn on_build_complete(&self, report: &BuildReport) -> Result<()> {
+        let _: Vec<Value> = self.run("onBuildComplete", vec![serde_json::to_value(report)?])?;
+        Ok(())
+    }
+}
+
+/// The plugins in `modules`, relative to `project_root_dir`, followed by
+/// `plugins` from Rust
+pub fn load_plugins(
+    project_root_dir: &Path,
+    npm_bin_dir: &Path,
+    modules: &[String],
+    plugins: Vec<Arc<dyn Plugin>>,
+) -> Plugins {
+    let mut all: Vec<Arc<dyn Plugin>> = vec![];
+    if !modules.is_empty() {
+        all.push(Arc::new(JsPlugins {
+            modules: modules
+                .iter()
+                .map(|module| project_root_dir.join(module))
+                .collect(),
+            npm_bin_dir: npm_bin_dir.to_path_buf(),
+        }));
+    }
+    all.extend(plugins);
+    Plugins::new(all)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct NoDrafts;
+
+    impl Plugin for NoDrafts {
+        fn on_pages_discovered(&self, pages: &mut Vec<String>) -> Result<()> {
+            pages.retain(|page| !page.contains("/drafts/"));
+            Ok(())
+        }
+
+        fn transform_sources(&self, sources: &mut BTreeMap<String, String>) -> Result<()> {
+            for source in sources.values_mut() {
+                *source = source.replace("__YEAR__", "2020");
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_plugins_run_in_order() {
+        let plugins = Plugins::new(vec![Arc::new(NoDrafts)]);
+        let mut pages = vec![
+            String::from("src/pages/index.js"),
+            String::from("src/pages/drafts/wip.js"),
+        ];
+        plugins.on_pages_discovered(&mut pages).unwrap();
+        assert_eq!(pages, vec![String::from("src/pages/index.js")]);
+
+        let mut sources = BTreeMap::new();
+        sources.insert(
+            String::from("src/pages/index.js"),
+            String::from("export default () => \"__YEAR__\";"),
+        );
+        plugins.transform_sources(&mut sources).unwrap();
+        assert_eq!(
+            sources["src/pages/index.js"],
+            "export default () => \"2020\";"
+        );
+    }
+}
@@ -0,0 +1,147 @@
+//! `.br` and `.gz` copies of html, JS, and CSS outputs, turned on with
+//! `precompress = true` in `toast.config.toml` or `--precompress`.
+//!
+//! Static hosts and nginx (`gzip_static`, `brotli_static`) serve these in
+//! place of the original when the browser accepts the encoding. Files are
+//! compressed at the highest quality across `jobs` threads, and files whose
+//! contents haven't changed since the last build are skipped.
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use crossbeam::unbounded;
+use flate2::{write::GzEncoder, Compression};
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+use tracing::instrument;
+use walkdir::WalkDir;
+
+use crate::cache::{content_hash, Cache};
+
+const COMPRESSIBLE_EXTENSIONS: [&str; 4] = ["html", "js", "mjs", "css"];
+
+struct CompressJob {
+    path: PathBuf,
+    cache_key: String,
+    hash: String,
+    contents: Vec<u8>,
+}
+
+fn is_compressible(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| COMPRESSIBLE_EXTENSIONS.contains(&ext))
+}
+
+/// `index.html` becomes `index.html.gz`, not `index.gz`
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn gzip(contents: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(vec![], Compression::best());
+    encoder.write_all(contents)?;
+    encoder.finish()
+}
+
+fn brotli(contents: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut compressed = vec![];
+    {
+        // quality 11 with a 4MB window, the same as `brotli --best`
+        let mut writer = brotli::CompressorWriter::new(&mut compressed, 4096, 11, 22);
+        writer.write_all(contents)?;
+    }
+    Ok(compressed)
+}
+
+/// Write the compressed copies of a file, returning their paths
+fn compress(job: &CompressJob) -> Result<Vec<PathBuf>> {
+    let outputs = vec![
+        (with_suffix(&job.path, ".gz"), gzip(&job.contents)),
+        (with_suffix(&job.path, ".br"), brotli(&job.contents)),
+    ];
+    outputs
+        .into_iter()
+        .map(|(path, compressed)| {
+            let compressed =
+                compressed.wrap_err_with(|| format!("Failed to compress `{}`", path.display()))?;
+            fs::write(&path, compressed)
+                .wrap_err_with(|| format!("Failed to write `{}`", path.display()))?;
+            Ok(path)
+        })
+        .collect()
+}
+
+/// Compress every html, JS, and CSS file in `output_dir`
+#[instrument(skip(cache))]
+pub fn precompress(output_dir: &Path, jobs: usize, cache: &mut Cache) -> Result<()> {
+    let (work_tx, work_rx) = unbounded();
+    for entry in WalkDir::new(output_dir)
+        .into_iter()
+        .filter_map(|result| result.ok())
+        .filter(|entry| entry.file_type().is_file() && is_compressible(entry.path()))
+    {
+        let path = entry.path();
+        let contents =
+            fs::read(path).wrap_err_with(|| format!("Failed to read `{}`", path.display()))?;
+        let hash = content_hash(&[&contents]);
+        let cache_key = format!("precompress:{}", path.display());
+        if !cache.is_fresh(&cache_key, &hash) {
+            work_tx.send(CompressJob {
+                path: path.to_path_buf(),
+                cache_key,
+                hash,
+                contents,
+            })?;
+        }
+    }
+    drop(work_tx);
+
+    let results = crossbeam::scope(|scope| {
+        let workers: Vec<_> = (0..jobs.max(1))
+            .map(|_| {
+                let work_rx = work_rx.clone();
+                scope.spawn(move |_| -> Result<Vec<(CompressJob, Vec<PathBuf>)>> {
+                    let mut compressed = vec![];
+                    for job in work_rx.iter() {
+                        let outputs = compress(&job)?;
+                        compressed.push((job, outputs));
+                    }
+                    Ok(compressed)
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| worker.join())
+            .collect::<Vec<_>>()
+    })
+    .map_err(|_| eyre!("Failed to run compression threads"))?;
+
+    for result in results {
+        let compressed = result.map_err(|_| eyre!("A compression thread panicked"))??;
+        for (job, outputs) in compressed {
+            cache.record_outputs(&job.cache_key, job.hash, outputs);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compressible_outputs() {
+        assert!(is_compressible(Path::new("public/blog/index.html")));
+        assert!(is_compressible(Path::new("public/_css/global.css")));
+        assert!(!is_compressible(Path::new("public/index.html.gz")));
+        assert!(!is_compressible(Path::new("public/_images/hero.webp")));
+        assert_eq!(
+            with_suffix(Path::new("public/index.html"), ".br"),
+            PathBuf::from("public/index.html.br")
+        );
+    }
+}
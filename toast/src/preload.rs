@@ -0,0 +1,207 @@
+//! `<link rel="modulepreload">` hints in each page's head for every
+//! module its hydration script ends up importing, found by walking the
+//! module graph from the page module and the page wrapper. Without them
+//! the browser only finds out about a module once the one importing it
+//! has loaded, one round trip per level of imports.
+//!
+//! There's nothing to split into shared chunks: toast writes one browser
+//! module per source, so a component used by many pages is one file that
+//! the browser downloads once and reuses on every page that imports it,
+//! and npm packages are already split into shared chunks in `web_modules`.
+//! With `fingerprint = true` those files get hashed names, and the hints
+//! are rewritten to them along with the rest of the page's urls.
+//!
+//! Pages can also prefetch the page modules of the pages they link to, so
+//! following a link doesn't wait on its JS:
+//!
+//! ```toml
+//! [preload]
+//! prefetch = true
+//! ```
+//!
+//! Pages with `hydrate = "none"` don't load any JS, so they don't get
+//! hints, and aren't prefetched.
+use color_eyre::eyre::{Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::Path,
+};
+use tracing::instrument;
+
+use crate::{
+    cache::Cache, frontmatter::page_slug, links::attribute_urls, pages::PageRoot,
+    renderer::html_output_path,
+};
+
+/// `[preload]` in `toast.config.toml`
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PreloadConfig {
+    /// add `<link rel="prefetch">` for the page module of every page a
+    /// page links to
+    pub prefetch: bool,
+}
+
+/// Imported by the hydration script of every page when it exists
+const PAGE_WRAPPER: &str = "src/page-wrapper.js";
+
+/// Imported by the hydration script of every page to render with
+const PREACT: &str = "web_modules/preact.js";
+
+/// The modules, relative to the output directory, that `page`'s hydration
+/// script imports. Only modules that were written to the output directory
+/// are included, which leaves out stylesheets and pages that don't
+/// hydrate.
+fn page_modules(output_dir: &Path, cache: &Cache, page: &str) -> BTreeSet<String> {
+    let mut modules = BTreeSet::new();
+    for entry in [page, PAGE_WRAPPER] {
+        if output_dir.join(entry).exists() {
+            modules.insert(entry.to_string());
+            modules.extend(cache.imports(entry));
+        }
+    }
+    modules.insert(PREACT.to_string());
+    modules
+        .into_iter()
+        .filter(|module| module.ends_with(".js") && output_dir.join(module).exists())
+        .collect()
+}
+
+/// The page modules of the pages `html` links to, from `slugs`, which maps
+/// each page's slug to its module
+fn linked_pages(html: &str, slugs: &BTreeMap<String, String>) -> BTreeSet<String> {
+    attribute_urls(html)
+        .iter()
+        .filter(|url| url.starts_with('/') && !url.starts_with("//"))
+        .filter_map(|url| {
+            let path = url.split(['?', '#']).next().unwrap_or_default();
+            let path = match path.trim_end_matches('/') {
+                "" => "/",
+                path => path,
+            };
+            slugs
+                .get(path.strip_suffix(".html").unwrap_or(path))
+                .cloned()
+        })
+        .collect()
+}
+
+fn link_tag(rel: &str, module: &str) -> String {
+    format!(
+        "<link rel=\"{}\" href=\"/{}\">",
+        rel,
+        module.trim_start_matches('/')
+    )
+}
+
+/// Add a modulepreload hint for each of `modules` and a prefetch hint for
+/// each of `prefetch` to the head of `html`, leaving out any it already
+/// has
+pub fn inject_preloads(
+    html: &str,
+    modules: &BTreeSet<String>,
+    prefetch: &BTreeSet<String>,
+) -> String {
+    let tags: String = modules
+        .iter()
+        .map(|module| link_tag("modulepreload", module))
+        .chain(
+            prefetch
+                .difference(modules)
+                .map(|module| link_tag("prefetch", module)),
+        )
+        // `integrity` can have been added after the href
+        .filter(|tag| !html.contains(tag.trim_end_matches('>')))
+        .collect();
+    let mut html = html.to_string();
+    if let Some(head_end) = html.find("</head>") {
+        html.insert_str(head_end, &tags);
+    }
+    html
+}
+
+/// Add modulepreload hints to the rendered html of `pages`, and prefetch
+/// hints for the ones in `all_pages` they link to if `config` asks for
+/// them
+#[instrument(skip(cache))]
+pub fn link_module_preloads(
+    output_dir: &Path,
+    page_roots: &[PageRoot],
+    pages: &[String],
+    all_pages: &[String],
+    config: &PreloadConfig,
+    cache: &Cache,
+) -> Result<()> {
+    let slugs: BTreeMap<String, String> = all_pages
+        .iter()
+        .filter(|page| output_dir.join(page).exists())
+        .map(|page| (page_slug(page_roots, page), page.clone()))
+        .collect();
+    for page in pages {
+        // pages that don't hydrate have no browser module
+        if !output_dir.join(page).exists() {
+            continue;
+        }
+        let html_path = html_output_path(output_dir, page_roots, page);
+        let html = match fs::read_to_string(&html_path) {
+            Ok(html) => html,
+            // pages that failed to render
+            Err(_) => continue,
+        };
+        let prefetch = if config.prefetch {
+            linked_pages(&html, &slugs)
+        } else {
+            BTreeSet::new()
+        };
+        let linked = inject_preloads(&html, &page_modules(output_dir, cache, page), &prefetch);
+        if linked != html {
+            fs::write(&html_path, linked)
+                .wrap_err_with(|| format!("Failed to write `{}`", html_path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(modules: &[&str]) -> BTreeSet<String> {
+        modules.iter().map(|module| module.to_string()).collect()
+    }
+
+    #[test]
+    fn test_inject_preloads() {
+        let modules = set(&["src/pages/index.js", "web_modules/preact.js"]);
+        let prefetch = set(&["src/pages/about.js", "src/pages/index.js"]);
+        let html = inject_preloads(
+            "<html><head><title>Hi</title></head></html>",
+            &modules,
+            &prefetch,
+        );
+        assert_eq!(
+            html,
+            "<html><head><title>Hi</title><link rel=\"modulepreload\" href=\"/src/pages/index.js\"><link rel=\"modulepreload\" href=\"/web_modules/preact.js\"><link rel=\"prefetch\" href=\"/src/pages/about.js\"></head></html>"
+        );
+        // running it again doesn't add anything
+        assert_eq!(inject_preloads(&html, &modules, &prefetch), html);
+    }
+
+    #[test]
+    fn test_linked_pages() {
+        let slugs: BTreeMap<String, String> = vec![
+            ("/", "src/pages/index.js"),
+            ("/blog/hello", "src/pages/blog/hello.js"),
+        ]
+        .into_iter()
+        .map(|(slug, page)| (slug.to_string(), page.to_string()))
+        .collect();
+        let html = r##"<a href="/">Home</a> <a href="/blog/hello/#top">Hello</a> <a href="https://toast.dev/">Toast</a> <a href="/missing">Gone</a>"##;
+        assert_eq!(
+            linked_pages(html, &slugs),
+            set(&["src/pages/blog/hello.js", "src/pages/index.js"])
+        );
+    }
+}
@@ -0,0 +1,187 @@
+//! The status line a full build shows while it runs, so a build that
+//! takes minutes isn't silent for minutes:
+//!
+//! ```text
+//! ▸▹▹ [00:01:12] 1204 discovered, 1204 compiled, 310/1180 rendered, 1530 written  blog/post.js
+//! ```
+//!
+//! with what's being built right now at the end. Sources and `sourceData`
+//! records are discovered, modules are compiled (cache hits too), pages
+//! are rendered, and compiled modules, page data, and finished html pages
+//! are written.
+//!
+//! It's drawn on stderr, and only when stdout is a terminal and logs are
+//! text, so CI logs, `--report` output on stdout, and `--log-format json`
+//! never get spinner frames mixed in. Rebuilds in watch mode are quick and
+//! show nothing.
+use indicatif::{ProgressBar, ProgressStyle};
+use std::{
+    io::IsTerminal,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use crate::logging::LogFormat;
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Show the status line for the rest of the process if stdout is a
+/// terminal and logs are text
+pub fn install(log_format: LogFormat) {
+    ENABLED.store(
+        log_format == LogFormat::Text && std::io::stdout().is_terminal(),
+        Ordering::SeqCst,
+    );
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    discovered: AtomicU64,
+    compiled: AtomicU64,
+    rendered: AtomicU64,
+    /// how many pages there are to render, once that's known
+    pages: AtomicU64,
+    written: AtomicU64,
+}
+
+/// The status line of one build. Clones update the same line, so compile
+/// threads and renderers can each have one.
+#[derive(Debug, Clone)]
+pub struct Progress {
+    bar: Arc<ProgressBar>,
+    counts: Arc<Counts>,
+}
+
+impl Progress {
+    /// A status line that's drawn if `install` allowed it
+    pub fn new() -> Progress {
+        if !ENABLED.load(Ordering::SeqCst) {
+            return Progress::hidden();
+        }
+        let bar = ProgressBar::new_spinner();
+        bar.set_style(
+            ProgressStyle::default_spinner()
+                // For more spinners check out the cli-spinners project:
+                // https://github.com/sindresorhus/cli-spinners/blob/master/spinners.json
+                .tick_strings(&["▹▹▹", "▸▹▹", "▹▸▹", "▹▹▸", "▪▪▪"])
+                .template("{spinner:.blue} [{elapsed}] {prefix}  {wide_msg}"),
+        );
+        bar.enable_steady_tick(120);
+        let progress = Progress {
+            bar: Arc::new(bar),
+            counts: Arc::default(),
+        };
+        progress.refresh();
+        progress
+    }
+
+    /// A status line that's never drawn
+    pub fn hidden() -> Progress {
+        Progress {
+            bar: Arc::new(ProgressBar::hidden()),
+            counts: Arc::default(),
+        }
+    }
+
+    fn refresh(&self) {
+        let count = |count: &AtomicU64| count.load(Ordering::SeqCst);
+        let counts = &self.counts;
+        let rendered = match count(&counts.pages) {
+            0 => count(&counts.rendered).to_string(),
+            pages => format!("{}/{}", count(&counts.rendered), pages),
+        };
+        self.bar.set_prefix(&format!(
+            "{} discovered, {} compiled, {} rendered, {} written",
+            count(&counts.discovered),
+            count(&counts.compiled),
+            rendered,
+            count(&counts.written)
+        ));
+    }
+
+    /// Say what the build is doing, like `fetching data...` or the file
+    /// it's working on
+    pub fn status(&self, message: &str) {
+        self.bar.set_message(message);
+    }
+
+    pub fn discovered(&self, count: u64) {
+        self.counts.discovered.fetch_add(count, Ordering::SeqCst);
+        self.refresh();
+    }
+
+    pub fn compiled(&self, source_id: &str) {
+        self.counts.compiled.fetch_add(1, Ordering::SeqCst);
+        self.status(source_id);
+        self.refresh();
+    }
+
+    /// How many pages are about to be rendered
+    pub fn pages(&self, count: u64) {
+        self.counts.pages.store(count, Ordering::SeqCst);
+        self.refresh();
+    }
+
+    pub fn rendered(&self, page: &str) {
+        self.counts.rendered.fetch_add(1, Ordering::SeqCst);
+        self.status(page);
+        self.refresh();
+    }
+
+    pub fn written(&self, count: u64) {
+        self.counts.written.fetch_add(count, Ordering::SeqCst);
+        self.refresh();
+    }
+
+    /// Print `line` above the status line, or just print it if there
+    /// isn't one
+    pub fn println(&self, line: String) {
+        // when the bar is hidden, so is anything printed with it
+        if self.bar.is_hidden() {
+            println!("{}", line)
+        } else {
+            self.bar.println(line);
+        }
+    }
+
+    /// Stop drawing, leaving the final counts on screen
+    pub fn finish(&self) {
+        self.refresh();
+        self.bar.abandon_with_message("done");
+    }
+}
+
+impl Default for Progress {
+    fn default() -> Progress {
+        Progress::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counts() {
+        let progress = Progress::hidden();
+        progress.discovered(3);
+        progress.compiled("src/pages/index.js");
+        progress.pages(2);
+        progress.rendered("index.js");
+        progress.written(2);
+        let count = |count: &AtomicU64| count.load(Ordering::SeqCst);
+        let counts = &progress.counts;
+        assert_eq!(
+            (
+                count(&counts.discovered),
+                count(&counts.compiled),
+                count(&counts.rendered),
+                count(&counts.pages),
+                count(&counts.written)
+            ),
+            (3, 1, 1, 2, 2)
+        );
+    }
+}
@@ -0,0 +1,155 @@
+//! Checking that the toast binary and the toast npm package can work
+//! together, before a build starts, instead of finding out from a page
+//! that fails to render in some strange way.
+//!
+//! Both sides have a protocol version, which changes whenever the
+//! arguments, stdin, or stdout of the node scripts do. The binary reads the
+//! package's from `toastProtocol` in its `package.json`, and passes its own
+//! to every node process as `TOAST_PROTOCOL`, which the scripts check
+//! when they start, see `src/protocol.mjs` in the npm package. Either way
+//! a mismatch says which side to upgrade.
+use serde::Deserialize;
+use std::{fs, path::Path};
+use thiserror::Error;
+use tracing::instrument;
+
+use crate::npm_bin::bin_script;
+
+/// Must match `PROTOCOL_VERSION` in `src/protocol.mjs` in the npm package
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The version of the npm package that speaks `PROTOCOL_VERSION`, which
+/// is what `toast new` installs. Must match `version` in its `package.json`.
+pub const NPM_PACKAGE_VERSION: &str = "0.3.3";
+
+/// Passes `PROTOCOL_VERSION` to the node scripts
+pub const PROTOCOL_ENV_VAR: &str = "TOAST_PROTOCOL";
+
+const CLI_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ProtocolError {
+    #[error("the toast npm package (version {package_version}) speaks protocol {package_protocol}, but this toast binary (version {}) speaks protocol {}: {}", CLI_VERSION, PROTOCOL_VERSION, upgrade_message(.package_protocol))]
+    Mismatch {
+        package_version: String,
+        package_protocol: u32,
+    },
+}
+
+/// What to upgrade when the package speaks `package_protocol`
+fn upgrade_message(package_protocol: &u32) -> String {
+    if *package_protocol < PROTOCOL_VERSION {
+        format!(
+            "upgrade the npm package with `npm install toast@{}`",
+            NPM_PACKAGE_VERSION
+        )
+    } else {
+        format!(
+            "upgrade the toast binary, or install the npm package it came with, `npm install toast@{}`",
+            NPM_PACKAGE_VERSION
+        )
+    }
+}
+
+/// The parts of the npm package's `package.json` the handshake reads.
+/// Packages from before the handshake don't have `toastProtocol`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PackageJson {
+    version: String,
+    #[serde(default)]
+    toast_protocol: u32,
+}
+
+/// Check the package's protocol in `package_json` against the binary's
+fn check_package(package_json: &str) -> Result<(), ProtocolError> {
+    let package: PackageJson = match serde_json::from_str(package_json) {
+        Ok(package) => package,
+        // anything that isn't a package.json is left to the build to
+        // complain about
+        Err(_) => return Ok(()),
+    };
+    if package.toast_protocol == PROTOCOL_VERSION {
+        Ok(())
+    } else {
+        Err(ProtocolError::Mismatch {
+            package_version: package.version,
+            package_protocol: package.toast_protocol,
+        })
+    }
+}
+
+/// The `package.json` of the toast npm package that installed the bins in
+/// `npm_bin_dir`
+fn read_package_json(npm_bin_dir: &Path) -> Option<String> {
+    let render = bin_script(npm_bin_dir, "toast-render");
+    render
+        .parent()
+        .map(|dir| dir.join("package.json"))
+        .into_iter()
+        .chain(
+            npm_bin_dir
+                .parent()
+                .map(|node_modules| node_modules.join("toast").join("package.json")),
+        )
+        .find_map(|path| fs::read_to_string(path).ok())
+}
+
+/// The version of the toast npm package that installed the bins in
+/// `npm_bin_dir`, if it can be found
+pub fn package_version(npm_bin_dir: &Path) -> Option<String> {
+    let package_json = read_package_json(npm_bin_dir)?;
+    serde_json::from_str::<PackageJson>(&package_json)
+        .ok()
+        .map(|package| package.version)
+}
+
+/// Check the toast npm package that installed the bins in `npm_bin_dir`,
+/// and pass the binary's protocol to every node process started after
+#[instrument]
+pub fn handshake(npm_bin_dir: &Path) -> Result<(), ProtocolError> {
+    std::env::set_var(PROTOCOL_ENV_VAR, PROTOCOL_VERSION.to_string());
+    match read_package_json(npm_bin_dir) {
+        Some(package_json) => check_package(&package_json),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_npm_package_version() {
+        let package: PackageJson =
+            serde_json::from_str(include_str!("../../toast-node-wrapper/package.json")).unwrap();
+        assert_eq!(package.version, NPM_PACKAGE_VERSION);
+        assert_eq!(package.toast_protocol, PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn test_check_package() {
+        let package = |protocol: u32| {
+            format!(
+                r#"{{ "name": "toast", "version": "0.3.3", "toastProtocol": {} }}"#,
+                protocol
+            )
+        };
+        assert_eq!(check_package(&package(PROTOCOL_VERSION)), Ok(()));
+        let old = check_package(r#"{ "name": "toast", "version": "0.3.2" }"#).unwrap_err();
+        assert_eq!(
+            old,
+            ProtocolError::Mismatch {
+                package_version: String::from("0.3.2"),
+                package_protocol: 0,
+            }
+        );
+        assert!(old
+            .to_string()
+            .ends_with(": upgrade the npm package with `npm install toast@0.3.3`"));
+        let newer = check_package(&package(PROTOCOL_VERSION + 1)).unwrap_err();
+        assert!(newer.to_string().ends_with(
+            ": upgrade the toast binary, or install the npm package it came with, `npm install toast@0.3.3`"
+        ));
+    }
+}
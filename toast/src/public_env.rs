@@ -0,0 +1,64 @@
+//! Environment variables that pages and components can read, which are
+//! the ones whose names start with `TOAST_PUBLIC_`.
+//!
+//! `process.env.TOAST_PUBLIC_API_URL` is replaced with the variable's
+//! value when a module is compiled, for both the browser and for
+//! rendering, so the value is baked into the html and the client
+//! bundles alike. Every other variable, including other `TOAST_` ones,
+//! is left alone so secrets in the build environment never end up in
+//! code the browser downloads.
+//!
+//! The variables a module mentions are part of its cache key, so
+//! changing one recompiles only the modules that use it.
+use std::collections::BTreeMap;
+
+pub const PUBLIC_ENV_PREFIX: &str = "TOAST_PUBLIC_";
+
+/// Every public variable in the build environment, by name
+pub fn public_env() -> BTreeMap<String, String> {
+    std::env::vars()
+        .filter(|(name, _)| name.starts_with(PUBLIC_ENV_PREFIX))
+        .collect()
+}
+
+/// The variables in `env` that `source` mentions, as `NAME=value` lines.
+/// A name that only shows up in a comment still counts, which at worst
+/// recompiles a module that didn't need it.
+pub fn referenced_env(source: &str, env: &BTreeMap<String, String>) -> String {
+    env.iter()
+        .filter(|(name, _)| {
+            source.match_indices(name.as_str()).any(|(index, _)| {
+                let next = source[index + name.len()..].chars().next();
+                !next.is_some_and(|c| c.is_alphanumeric() || c == '_')
+            })
+        })
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_referenced_env() {
+        let env: BTreeMap<String, String> = vec![
+            ("TOAST_PUBLIC_API", "https://api.toast.dev"),
+            ("TOAST_PUBLIC_API_KEY", "abc"),
+            ("TOAST_PUBLIC_THEME", "dark"),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+        assert_eq!(
+            referenced_env("fetch(process.env.TOAST_PUBLIC_API + \"/posts\")", &env),
+            "TOAST_PUBLIC_API=https://api.toast.dev"
+        );
+        assert_eq!(
+            referenced_env("const key = process.env.TOAST_PUBLIC_API_KEY;", &env),
+            "TOAST_PUBLIC_API_KEY=abc"
+        );
+        assert_eq!(referenced_env("export default () => null;", &env), "");
+    }
+}
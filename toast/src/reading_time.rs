@@ -0,0 +1,76 @@
+//! Word counts and reading times for `.mdx` and `.md` pages, counted from
+//! the same text the search index uses, so code blocks, imports, and JSX
+//! aren't counted. Every content page gets `wordCount` and `readingTime`
+//! (in whole minutes, rounded up) props, and they're in its entry in the
+//! content index too:
+//!
+//! ```js
+//! export default ({ title, readingTime }) => (
+//!   <header>
+//!     <h1>{title}</h1>
+//!     <p>{readingTime} min read</p>
+//!   </header>
+//! );
+//! ```
+//!
+//! Either can be set in a page's frontmatter to override what's counted.
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::search::plain_text;
+
+/// The usual estimate for reading on a screen
+pub const WORDS_PER_MINUTE: usize = 200;
+
+#[derive(Debug, Default, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ReadingStats {
+    pub word_count: usize,
+    pub reading_time: usize,
+}
+
+/// The stats for a markdown or MDX body, without its frontmatter
+pub fn reading_stats(markdown: &str) -> ReadingStats {
+    let word_count = plain_text(markdown).split_whitespace().count();
+    let reading_time = match word_count {
+        0 => 0,
+        words => (words - 1) / WORDS_PER_MINUTE + 1,
+    };
+    ReadingStats {
+        word_count,
+        reading_time,
+    }
+}
+
+/// A page's data, its frontmatter with `stats` added
+pub fn with_reading_stats(frontmatter: Option<Value>, stats: ReadingStats) -> Value {
+    let mut data = match frontmatter {
+        Some(Value::Object(frontmatter)) => frontmatter,
+        _ => Map::new(),
+    };
+    data.entry("wordCount")
+        .or_insert_with(|| Value::from(stats.word_count));
+    data.entry("readingTime")
+        .or_insert_with(|| Value::from(stats.reading_time));
+    Value::Object(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_reading_stats() {
+        let post = format!(
+            "# Hello\n\n{}\n\n```js\nnot counted\n```\n",
+            "word ".repeat(400)
+        );
+        let stats = reading_stats(&post);
+        assert_eq!(stats.word_count, 401);
+        assert_eq!(stats.reading_time, 3);
+
+        let data = with_reading_stats(Some(json!({ "readingTime": 10 })), stats);
+        assert_eq!(data, json!({ "readingTime": 10, "wordCount": 401 }));
+    }
+}
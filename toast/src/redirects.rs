@@ -0,0 +1,247 @@
+//! Redirects from old urls, written after the build for static hosts.
+//!
+//! Pages list the urls they used to live at in frontmatter:
+//!
+//! ```md
+//! ---
+//! redirect_from: [/old-post, /2019/old-post/]
+//! ---
+//! ```
+//!
+//! and anything else goes in the `redirects` table in `toast.config.toml`:
+//!
+//! ```toml
+//! [redirects]
+//! "/docs/*" = "/guide/:splat"
+//! "/twitter" = "https://twitter.com/toast"
+//! ```
+//!
+//! Every redirect is written to a Netlify `_redirects` file and the
+//! `redirects` of a `vercel.json`, both in the output directory. Hosts
+//! that support neither get a meta-refresh html page at each old url,
+//! except for patterns like `*` and `:splat` that only the hosts
+//! understand.
+use color_eyre::eyre::{Result, WrapErr};
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+use tracing::instrument;
+
+use crate::content_index::PageEntry;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Redirect {
+    pub from: String,
+    pub to: String,
+}
+
+impl Redirect {
+    /// Host-specific patterns can't be turned into an html page
+    fn is_pattern(&self) -> bool {
+        self.from.contains('*') || self.from.contains(':')
+    }
+
+    /// Where the meta-refresh page for `from` goes in the output directory
+    fn html_path(&self) -> PathBuf {
+        let from = self.from.trim_start_matches('/');
+        if from.is_empty() || from.ends_with('/') {
+            PathBuf::from(from).join("index.html")
+        } else {
+            PathBuf::from(format!("{}.html", from))
+        }
+    }
+}
+
+/// Every redirect from config and from pages' `redirect_from` frontmatter,
+/// which can be one url or a list of them. Config wins when both redirect
+/// the same url.
+pub fn collect_redirects(
+    config: &BTreeMap<String, String>,
+    entries: &[PageEntry],
+) -> Vec<Redirect> {
+    let mut redirects: BTreeMap<String, String> = BTreeMap::new();
+    for entry in entries {
+        let froms: Vec<&str> = match entry.data.get("redirect_from") {
+            Some(Value::String(from)) => vec![from.as_str()],
+            Some(Value::Array(froms)) => froms.iter().filter_map(|from| from.as_str()).collect(),
+            _ => vec![],
+        };
+        for from in froms {
+            redirects.insert(from.to_string(), entry.slug.clone());
+        }
+    }
+    redirects.extend(config.clone());
+    redirects
+        .into_iter()
+        .map(|(from, to)| Redirect { from, to })
+        .collect()
+}
+
+/// The Netlify `_redirects` format, one permanent redirect per line
+pub fn render_netlify(redirects: &[Redirect]) -> String {
+    redirects
+        .iter()
+        .map(|redirect| format!("{} {} 301\n", redirect.from, redirect.to))
+        .collect()
+}
+
+/// Redirects in the shape of `vercel.json`'s `redirects` key
+pub fn render_vercel(redirects: &[Redirect]) -> Value {
+    redirects
+        .iter()
+        .map(|redirect| {
+            json!({
+                "source": redirect.from,
+                "destination": redirect.to,
+                "permanent": true,
+            })
+        })
+        .collect()
+}
+
+pub fn render_meta_refresh(to: &str) -> String {
+    let to = to.replace('&', "&amp;").replace('"', "&quot;");
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+  <head>
+    <meta charset="utf-8">
+    <title>Redirecting…</title>
+    <link rel="canonical" href="{to}">
+    <meta http-equiv="refresh" content="0; url={to}">
+  </head>
+  <body>
+    <a href="{to}">Redirecting to {to}</a>
+  </body>
+</html>
+"#,
+        to = to
+    )
+}
+
+/// Write `_redirects`, `vercel.json`, and meta-refresh pages. Redirects
+/// are added after anything in the project's own `static/_redirects` and
+/// `static/vercel.json`, and meta-refresh pages never replace a page that
+/// was rendered at the same url.
+#[instrument(skip(redirects))]
+pub fn write_redirects(
+    project_root_dir: &Path,
+    output_dir: &Path,
+    redirects: &[Redirect],
+) -> Result<()> {
+    if redirects.is_empty() {
+        return Ok(());
+    }
+    let static_dir = project_root_dir.join("static");
+
+    let mut netlify = fs::read_to_string(static_dir.join("_redirects")).unwrap_or_default();
+    if !netlify.is_empty() && !netlify.ends_with('\n') {
+        netlify.push('\n');
+    }
+    netlify.push_str(&render_netlify(redirects));
+    let netlify_path = output_dir.join("_redirects");
+    fs::write(&netlify_path, netlify)
+        .wrap_err_with(|| format!("Failed to write `{}`", netlify_path.display()))?;
+
+    let mut vercel: Value = fs::read_to_string(static_dir.join("vercel.json"))
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .filter(Value::is_object)
+        .unwrap_or_else(|| json!({}));
+    let mut vercel_redirects = match vercel.get("redirects") {
+        Some(Value::Array(existing)) => existing.clone(),
+        _ => vec![],
+    };
+    if let Value::Array(generated) = render_vercel(redirects) {
+        vercel_redirects.extend(generated);
+    }
+    vercel["redirects"] = Value::Array(vercel_redirects);
+    let vercel_path = output_dir.join("vercel.json");
+    fs::write(&vercel_path, serde_json::to_string_pretty(&vercel)?)
+        .wrap_err_with(|| format!("Failed to write `{}`", vercel_path.display()))?;
+
+    for redirect in redirects.iter().filter(|redirect| !redirect.is_pattern()) {
+        let html_path = output_dir.join(redirect.html_path());
+        if html_path.exists() && !is_meta_refresh(&html_path) {
+            continue;
+        }
+        if let Some(dir) = html_path.parent() {
+            fs::create_dir_all(dir)
+                .wrap_err_with(|| format!("Failed to create directory `{}`", dir.display()))?;
+        }
+        fs::write(&html_path, render_meta_refresh(&redirect.to))
+            .wrap_err_with(|| format!("Failed to write redirect `{}`", html_path.display()))?;
+    }
+    Ok(())
+}
+
+/// Pages from an earlier build's redirects can be replaced
+fn is_meta_refresh(path: &Path) -> bool {
+    fs::read_to_string(path)
+        .map(|html| html.contains(r#"<meta http-equiv="refresh""#))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redirects() -> Vec<Redirect> {
+        let mut config = BTreeMap::new();
+        config.insert(String::from("/docs/*"), String::from("/guide/:splat"));
+        let entries = vec![PageEntry {
+            slug: String::from("/blog/new"),
+            data: json!({ "redirect_from": ["/old", "/2019/old/"] }),
+        }];
+        collect_redirects(&config, &entries)
+    }
+
+    #[test]
+    fn test_collect_redirects() {
+        assert_eq!(
+            redirects(),
+            vec![
+                Redirect {
+                    from: String::from("/2019/old/"),
+                    to: String::from("/blog/new")
+                },
+                Redirect {
+                    from: String::from("/docs/*"),
+                    to: String::from("/guide/:splat")
+                },
+                Redirect {
+                    from: String::from("/old"),
+                    to: String::from("/blog/new")
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_render_netlify_and_vercel() {
+        let redirects = redirects();
+        assert_eq!(
+            render_netlify(&redirects[..1]),
+            "/2019/old/ /blog/new 301\n"
+        );
+        assert_eq!(
+            render_vercel(&redirects[..1]),
+            json!([{ "source": "/2019/old/", "destination": "/blog/new", "permanent": true }])
+        );
+    }
+
+    #[test]
+    fn test_html_paths() {
+        let redirects = redirects();
+        assert_eq!(
+            redirects[0].html_path(),
+            PathBuf::from("2019/old/index.html")
+        );
+        assert!(redirects[1].is_pattern());
+        assert_eq!(redirects[2].html_path(), PathBuf::from("old.html"));
+        assert!(render_meta_refresh("/blog/new").contains("url=/blog/new"));
+    }
+}
@@ -0,0 +1,264 @@
+//! Related pages, worked out once per build instead of in every page's
+//! render. Each `[[related]]` in `toast.config.toml` relates the pages of
+//! one collection of the content index to each other:
+//!
+//! ```toml
+//! [[related]]
+//! collection = "blog"
+//! # `tags` scores pages by how many values of `key` they share, `content`
+//! # by how similar their text is
+//! by = "tags"
+//! key = "tags"
+//! limit = 5
+//! ```
+//!
+//! Every page in the collection gets a `related` prop with the slugs of
+//! its most related pages, most related first. Pages with nothing in
+//! common aren't related, so the list can be shorter than `limit`, or
+//! empty. `collection = "*"` relates every page on the site.
+//!
+//! `content` weighs each word by how rare it is across the collection
+//! (TF-IDF), so pages are related by what they're about rather than by
+//! the words every page uses.
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::content_index::{sort_entries, PageEntry};
+
+const DEFAULT_LIMIT: usize = 5;
+const DEFAULT_KEY: &str = "tags";
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RelatedBy {
+    #[default]
+    Tags,
+    Content,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RelatedConfig {
+    /// a collection in the content index, or `*` for every page
+    pub collection: String,
+    #[serde(default)]
+    pub by: RelatedBy,
+    /// the frontmatter key `tags` compares, `tags` by default
+    #[serde(default)]
+    pub key: Option<String>,
+    /// how many related pages each page gets, 5 by default
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+impl RelatedConfig {
+    fn matches(&self, entry: &PageEntry) -> bool {
+        self.collection == "*" || entry.collection() == Some(self.collection.as_str())
+    }
+}
+
+/// Whether any of `configs` needs the text of pages
+pub fn needs_text(configs: &[RelatedConfig]) -> bool {
+    configs.iter().any(|config| config.by == RelatedBy::Content)
+}
+
+fn tags<'a>(entry: &'a PageEntry, key: &str) -> HashSet<&'a str> {
+    match entry.data.get(key) {
+        Some(Value::String(tag)) => std::iter::once(tag.as_str()).collect(),
+        Some(Value::Array(tags)) => tags.iter().filter_map(|tag| tag.as_str()).collect(),
+        _ => HashSet::new(),
+    }
+}
+
+/// Lowercased words long enough to say something about a page
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() > 3)
+        .map(|word| word.to_lowercase())
+}
+
+/// A TF-IDF vector for each page's text, normalized to length 1
+fn tf_idf(texts: &[&str]) -> Vec<HashMap<String, f64>> {
+    let counts: Vec<HashMap<String, f64>> = texts
+        .iter()
+        .map(|text| {
+            let mut counts = HashMap::new();
+            for word in words(text) {
+                *counts.entry(word).or_insert(0.0) += 1.0;
+            }
+            counts
+        })
+        .collect();
+    let mut document_frequency: HashMap<&str, f64> = HashMap::new();
+    for page in &counts {
+        for word in page.keys() {
+            *document_frequency.entry(word).or_insert(0.0) += 1.0;
+        }
+    }
+    let pages = texts.len() as f64;
+    counts
+        .iter()
+        .map(|page| {
+            let mut vector: HashMap<String, f64> = page
+                .iter()
+                .map(|(word, count)| {
+                    let idf = (pages / document_frequency[word.as_str()]).ln();
+                    (word.clone(), count * idf)
+                })
+                .collect();
+            let length = vector
+                .values()
+                .map(|weight| weight * weight)
+                .sum::<f64>()
+                .sqrt();
+            if length > 0.0 {
+                for weight in vector.values_mut() {
+                    *weight /= length;
+                }
+            }
+            vector
+        })
+        .collect()
+}
+
+fn cosine(a: &HashMap<String, f64>, b: &HashMap<String, f64>) -> f64 {
+    let (small, large) = if a.len() < b.len() { (a, b) } else { (b, a) };
+    small
+        .iter()
+        .filter_map(|(word, weight)| large.get(word).map(|other| weight * other))
+        .sum()
+}
+
+/// The related slugs of every page in the collections of `configs`, from
+/// the content index and page text by slug
+pub fn related_pages(
+    configs: &[RelatedConfig],
+    entries: &[PageEntry],
+    text: &BTreeMap<String, String>,
+) -> BTreeMap<String, Vec<String>> {
+    let mut related = BTreeMap::new();
+    for config in configs {
+        let mut pages: Vec<PageEntry> = entries
+            .iter()
+            .filter(|entry| config.matches(entry))
+            .cloned()
+            .collect();
+        // ties go to the newest page
+        sort_entries(&mut pages);
+        let scores: Vec<Vec<f64>> = match config.by {
+            RelatedBy::Tags => {
+                let key = config.key.as_deref().unwrap_or(DEFAULT_KEY);
+                let tags: Vec<HashSet<&str>> = pages.iter().map(|page| tags(page, key)).collect();
+                tags.iter()
+                    .map(|a| {
+                        tags.iter()
+                            .map(|b| a.intersection(b).count() as f64)
+                            .collect()
+                    })
+                    .collect()
+            }
+            RelatedBy::Content => {
+                let texts: Vec<&str> = pages
+                    .iter()
+                    .map(|page| text.get(&page.slug).map(String::as_str).unwrap_or(""))
+                    .collect();
+                let vectors = tf_idf(&texts);
+                vectors
+                    .iter()
+                    .map(|a| vectors.iter().map(|b| cosine(a, b)).collect())
+                    .collect()
+            }
+        };
+        for (i, page) in pages.iter().enumerate() {
+            let mut candidates: Vec<(usize, f64)> = scores[i]
+                .iter()
+                .copied()
+                .enumerate()
+                .filter(|(j, score)| *j != i && *score > 0.0)
+                .collect();
+            // a stable sort, so equal scores stay newest first
+            candidates
+                .sort_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap_or(std::cmp::Ordering::Equal));
+            related.insert(
+                page.slug.clone(),
+                candidates
+                    .iter()
+                    .take(config.limit.unwrap_or(DEFAULT_LIMIT))
+                    .map(|(j, _)| pages[*j].slug.clone())
+                    .collect(),
+            );
+        }
+    }
+    related
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn entry(slug: &str, data: Value) -> PageEntry {
+        PageEntry {
+            slug: slug.to_string(),
+            data,
+        }
+    }
+
+    #[test]
+    fn test_related_by_tags() {
+        let entries = vec![
+            entry(
+                "/blog/a",
+                json!({ "tags": ["rust", "wasm"], "date": "2020-01-01" }),
+            ),
+            entry("/blog/b", json!({ "tags": ["rust"], "date": "2020-02-01" })),
+            entry(
+                "/blog/c",
+                json!({ "tags": ["rust", "wasm"], "date": "2020-03-01" }),
+            ),
+            entry("/blog/d", json!({ "tags": "css" })),
+            entry("/about", json!({ "tags": ["rust"] })),
+        ];
+        let config = RelatedConfig {
+            collection: String::from("blog"),
+            by: RelatedBy::Tags,
+            key: None,
+            limit: Some(2),
+        };
+        let related = related_pages(&[config], &entries, &BTreeMap::new());
+        assert_eq!(related["/blog/a"], vec!["/blog/c", "/blog/b"]);
+        assert!(related["/blog/d"].is_empty());
+        assert!(!related.contains_key("/about"));
+    }
+
+    #[test]
+    fn test_related_by_content() {
+        let entries = vec![
+            entry("/notes/a", json!({})),
+            entry("/notes/b", json!({})),
+            entry("/notes/c", json!({})),
+        ];
+        let mut text = BTreeMap::new();
+        text.insert(
+            String::from("/notes/a"),
+            String::from("Notes: sourdough bread baking"),
+        );
+        text.insert(
+            String::from("/notes/b"),
+            String::from("Notes: rust compiler errors"),
+        );
+        text.insert(
+            String::from("/notes/c"),
+            String::from("Notes: sourdough bread starters"),
+        );
+        let config = RelatedConfig {
+            collection: String::from("*"),
+            by: RelatedBy::Content,
+            key: None,
+            limit: None,
+        };
+        let related = related_pages(&[config], &entries, &text);
+        assert_eq!(related["/notes/a"], vec!["/notes/c"]);
+    }
+}
@@ -0,0 +1,367 @@
+//! Fetching the remote data a data file declares, before `sourceData`
+//! runs.
+//!
+//! ```js
+//! export const remoteData = {
+//!   posts: {
+//!     url: "https://cms.example.com/api/posts",
+//!     headers: { Authorization: `Bearer ${process.env.CMS_TOKEN}` },
+//!     // optional, runs in node on the parsed response
+//!     transform: (response) => response.items,
+//!   },
+//! };
+//!
+//! export const sourceData = async ({ setDataForSlug, remoteData }) => {
+//!   for (const post of remoteData.posts) {
+//!     await setDataForSlug(`/blog/${post.slug}`, { data: post });
+//!   }
+//! };
+//! ```
+//!
+//! Toast fetches every url itself, a few at a time, and keeps each
+//! response in `.toast/remote-cache`. A response is reused without a
+//! request until its `Cache-Control: max-age` runs out, and after that
+//! it's revalidated with its `ETag` or `Last-Modified`, so a CMS that
+//! hasn't changed answers with a `304` and nothing is downloaded. If a
+//! request fails and there's a cached response, the build goes ahead
+//! with it.
+//!
+//! JSON responses are passed to `transform` (and `sourceData`) parsed,
+//! and anything else as a string.
+//!
+//! With `--offline` nothing is requested. Every cached response is used
+//! however old it is, and the build fails before `sourceData` runs if any
+//! url hasn't been cached yet. External links aren't checked either, see
+//! `links`, and telemetry can't be sent.
+use async_std::future::timeout;
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use duct::cmd;
+use futures::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::instrument;
+
+use crate::{
+    cache::{content_hash, CACHE_DIR},
+    diagnostics::{Diagnostic, Diagnostics},
+    node::node_bin,
+    npm_bin::{node_loader, node_script},
+    supervisor::{batch_timeout, run_node},
+};
+
+/// Marks the line of stdout with the sources a data file declares. Must
+/// match the prefix in `toast-source-data.mjs`.
+pub const SOURCES_PREFIX: &str = "toast-remote-sources:";
+
+/// Directory in `CACHE_DIR` that responses are kept in
+pub const REMOTE_CACHE_DIR: &str = "remote-cache";
+
+/// How many urls are fetched at the same time
+const CONCURRENCY: usize = 8;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// One entry in a data file's `remoteData` export
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct RemoteSource {
+    pub name: String,
+    pub url: String,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+/// A response kept in `.toast/remote-cache`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+struct CachedResponse {
+    url: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    /// seconds since the unix epoch
+    fetched_at: u64,
+    /// from `Cache-Control`, in seconds
+    max_age: Option<u64>,
+    body: String,
+}
+
+impl CachedResponse {
+    fn is_fresh(&self, now: u64) -> bool {
+        self.max_age
+            .is_some_and(|max_age| now < self.fetched_at.saturating_add(max_age))
+    }
+}
+
+/// `max-age` from a `Cache-Control` header. `no-cache` and `no-store`
+/// mean a response has to be revalidated every time.
+fn max_age(cache_control: &str) -> Option<u64> {
+    let directives: Vec<String> = cache_control
+        .split(',')
+        .map(|directive| directive.trim().to_ascii_lowercase())
+        .collect();
+    if directives
+        .iter()
+        .any(|directive| directive == "no-cache" || directive == "no-store")
+    {
+        return None;
+    }
+    directives
+        .iter()
+        .find_map(|directive| directive.strip_prefix("max-age="))
+        .and_then(|seconds| seconds.trim_matches('"').parse().ok())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// Responses are cached by url and headers, since headers like
+/// `Authorization` can change what comes back
+fn cache_path(project_root_dir: &Path, source: &RemoteSource) -> PathBuf {
+    let headers = serde_json::to_string(&source.headers).unwrap_or_default();
+    project_root_dir
+        .join(CACHE_DIR)
+        .join(REMOTE_CACHE_DIR)
+        .join(format!(
+            "{}.json",
+            content_hash(&[source.url.as_bytes(), headers.as_bytes()])
+        ))
+}
+
+/// The `remoteData` declared by `data_file`
+#[instrument]
+pub fn remote_sources(data_file: &Path, npm_bin_dir: &Path) -> Result<Vec<RemoteSource>> {
+    let bin = node_script(npm_bin_dir, "toast-source-data");
+    let bin_str = bin
+        .to_str()
+        .ok_or_else(|| eyre!("failed to make npm bin into str"))?;
+    let stdout = run_node(
+        "toast-source-data",
+        cmd!(
+            node_bin(),
+            "--unhandled-rejections",
+            "strict",
+            "--loader",
+            node_loader(npm_bin_dir),
+            bin_str,
+            data_file,
+            "--remote-sources"
+        ),
+        batch_timeout(1),
+    )
+    .wrap_err_with(|| format!("Failed to read `remoteData` from `{}`", data_file.display()))?;
+    match stdout
+        .lines()
+        .find_map(|line| line.strip_prefix(SOURCES_PREFIX))
+    {
+        Some(sources) => serde_json::from_str(sources)
+            .wrap_err_with(|| format!("Failed to parse `remoteData` sources: {}", sources)),
+        None => Ok(vec![]),
+    }
+}
+
+/// Request `source`, revalidating `cached` if there is one
+async fn request(
+    client: &surf::Client,
+    source: &RemoteSource,
+    cached: Option<&CachedResponse>,
+) -> Result<CachedResponse> {
+    let mut request = client.get(&source.url);
+    for (name, value) in &source.headers {
+        request = request.header(name.as_str(), value.as_str());
+    }
+    if let Some(cached) = cached {
+        if let Some(etag) = &cached.etag {
+            request = request.header("If-None-Match", etag.as_str());
+        }
+        if let Some(last_modified) = &cached.last_modified {
+            request = request.header("If-Modified-Since", last_modified.as_str());
+        }
+    }
+    let mut response = timeout(REQUEST_TIMEOUT, request)
+        .await
+        .map_err(|_| eyre!("Timed out fetching `{}`", source.url))?
+        .map_err(|error| eyre!("Failed to fetch `{}`: {}", source.url, error))?;
+    let header = |name: &str| {
+        response
+            .header(name)
+            .map(|values| values.last().as_str().to_string())
+    };
+    let max_age = header("Cache-Control").and_then(|value| max_age(&value));
+    let status = u16::from(response.status());
+    if status == 304 {
+        if let Some(cached) = cached {
+            return Ok(CachedResponse {
+                fetched_at: now(),
+                max_age,
+                ..cached.clone()
+            });
+        }
+    }
+    if !(200..300).contains(&status) {
+        return Err(eyre!(
+            "Fetching `{}` failed with status {}",
+            source.url,
+            status
+        ));
+    }
+    let etag = header("ETag");
+    let last_modified = header("Last-Modified");
+    let body = response.body_string().await.map_err(|error| {
+        eyre!(
+            "Failed to read the response from `{}`: {}",
+            source.url,
+            error
+        )
+    })?;
+    Ok(CachedResponse {
+        url: source.url.clone(),
+        etag,
+        last_modified,
+        fetched_at: now(),
+        max_age,
+        body,
+    })
+}
+
+fn read_cached(path: &Path) -> Option<CachedResponse> {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+}
+
+/// The body of `source`, from the cache if it's still fresh, or if it's
+/// there at all when `offline`
+async fn fetch(
+    client: &surf::Client,
+    project_root_dir: &Path,
+    source: &RemoteSource,
+    offline: bool,
+    diagnostics: &Diagnostics,
+) -> Result<String> {
+    let path = cache_path(project_root_dir, source);
+    let cached = read_cached(&path);
+    if let Some(cached) = &cached {
+        if offline || cached.is_fresh(now()) {
+            return Ok(cached.body.clone());
+        }
+    }
+    if offline {
+        return Err(eyre!("`{}` isn't cached", source.url));
+    }
+    let response = match request(client, source, cached.as_ref()).await {
+        Ok(response) => response,
+        Err(error) => match cached {
+            Some(cached) => {
+                diagnostics.report(Diagnostic::warning(
+                    &source.url,
+                    format!("{:?}\nusing the cached response instead", error),
+                ))?;
+                return Ok(cached.body);
+            }
+            None => return Err(error),
+        },
+    };
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .wrap_err_with(|| format!("Failed to create cache directory `{}`", dir.display()))?;
+    }
+    fs::write(&path, serde_json::to_string(&response)?)
+        .wrap_err_with(|| format!("Failed to write `{}`", path.display()))?;
+    Ok(response.body)
+}
+
+/// Fetch every remote source `data_file` declares and write the responses
+/// to `remote-data.json` in `tmp_dir`, by name, for `sourceData`. Returns
+/// `None` if there aren't any.
+#[instrument(skip(diagnostics))]
+pub async fn fetch_remote_data(
+    project_root_dir: &Path,
+    data_file: &Path,
+    npm_bin_dir: &Path,
+    tmp_dir: &Path,
+    offline: bool,
+    diagnostics: &Diagnostics,
+) -> Result<Option<PathBuf>> {
+    let sources = remote_sources(data_file, npm_bin_dir)?;
+    if sources.is_empty() {
+        return Ok(None);
+    }
+    if offline {
+        let missing: Vec<String> = sources
+            .iter()
+            .filter(|source| read_cached(&cache_path(project_root_dir, source)).is_none())
+            .map(|source| format!("`remoteData.{}` from `{}`", source.name, source.url))
+            .collect();
+        if !missing.is_empty() {
+            return Err(eyre!(
+                "`--offline` only uses cached remote data, and {} {} never been fetched. Build once without `--offline` to cache {}.",
+                missing.join(", "),
+                if missing.len() == 1 { "has" } else { "have" },
+                if missing.len() == 1 { "it" } else { "them" }
+            ));
+        }
+    }
+    let client = surf::Client::new();
+    let results: Vec<(String, Result<String>)> = stream::iter(&sources)
+        .map(|source| {
+            let client = &client;
+            async move {
+                (
+                    source.name.clone(),
+                    fetch(client, project_root_dir, source, offline, diagnostics).await,
+                )
+            }
+        })
+        .buffer_unordered(CONCURRENCY)
+        .collect()
+        .await;
+    let mut responses = BTreeMap::new();
+    for (name, body) in results {
+        let body = body.wrap_err_with(|| format!("Failed to fetch `remoteData.{}`", name))?;
+        let value = serde_json::from_str(&body).unwrap_or(Value::String(body));
+        responses.insert(name, value);
+    }
+    let path = tmp_dir.join("remote-data.json");
+    fs::write(&path, serde_json::to_string(&responses)?)
+        .wrap_err_with(|| format!("Failed to write `{}`", path.display()))?;
+    Ok(Some(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_max_age() {
+        assert_eq!(max_age("public, max-age=600"), Some(600));
+        assert_eq!(max_age("max-age=600, no-cache"), None);
+        assert_eq!(max_age("private"), None);
+    }
+
+    #[test]
+    fn test_is_fresh() {
+        let response = CachedResponse {
+            url: String::from("https://cms.example.com/api/posts"),
+            etag: Some(String::from("\"abc\"")),
+            last_modified: None,
+            fetched_at: 1_000,
+            max_age: Some(60),
+            body: String::from("[]"),
+        };
+        assert!(response.is_fresh(1_059));
+        assert!(!response.is_fresh(1_060));
+        assert!(!CachedResponse {
+            max_age: None,
+            ..response
+        }
+        .is_fresh(1_000));
+    }
+}
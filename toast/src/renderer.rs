@@ -0,0 +1,175 @@
+//! Rendering page modules to html.
+//!
+//! Rendering runs each page's compiled module in a JS runtime, so it is
+//! behind the `Renderer` trait and the runtime is picked with `renderer`
+//! in `toast.config.toml`:
+//!
+//! ```toml
+//! renderer = "deno"
+//! ```
+//!
+//! Node is the default, and keeps a pool of node processes running for
+//! the whole `toast` run (see `NodePool`). Deno runs the same `toast-render` script in its
+//! node compatibility mode, so it needs the toast npm package installed
+//! but not node itself.
+//!
+//! A page that throws while it's rendered doesn't stop the others. Every
+//! renderer reports it as a `RenderFailure` with the error and its stack,
+//! and the build carries on without its html.
+use color_eyre::eyre::{eyre, Result};
+use duct::cmd;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::Debug,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::instrument;
+
+mod node_pool;
+
+use crate::{
+    node::run_cmd,
+    npm_bin::node_script,
+    pages::{page_path, PageRoot},
+    progress::Progress,
+    report::{PageTiming, RenderFailure},
+};
+pub use node_pool::NodePool;
+
+/// Which JS runtime pages are rendered with
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RendererKind {
+    #[default]
+    Node,
+    Deno,
+}
+
+/// Everything a renderer needs to turn a set of pages into html
+#[derive(Debug)]
+pub struct RenderJob<'a> {
+    /// where the node versions of compiled modules were written
+    pub dir_of_input_files: &'a Path,
+    pub output_dir: &'a Path,
+    /// page modules, relative to `dir_of_input_files`
+    pub pages: &'a [String],
+    /// see `pages::page_path`
+    pub page_roots: &'a [PageRoot],
+    pub progress: Progress,
+}
+
+/// Where a page module's html is written, `blog/post.html` for
+/// `src/pages/blog/post.js`
+pub fn html_output_path(output_dir: &Path, page_roots: &[PageRoot], page: &str) -> PathBuf {
+    output_dir.join(page_path(page_roots, page).replace(".js", ".html"))
+}
+
+/// What came of rendering a set of pages
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Rendered {
+    /// how long each page that rendered took
+    pub timings: Vec<PageTiming>,
+    /// the pages that threw
+    pub failures: Vec<RenderFailure>,
+}
+
+pub trait Renderer: Debug + Send + Sync {
+    /// Render every page in `job` to an html file in the output
+    /// directory. Pages that throw are returned as failures, errors are
+    /// for when the renderer itself fails.
+    fn render(&self, job: RenderJob) -> Result<Rendered>;
+
+    /// Modules other than pages have changed, so anything the renderer
+    /// kept from earlier renders is stale
+    fn invalidate(&self) {}
+}
+
+/// `workers` is how many pages can be rendered at the same time, for
+/// renderers that support it. A render that goes `timeout` without
+/// finishing a page is stopped, see `supervisor`.
+pub fn renderer_for(
+    kind: RendererKind,
+    npm_bin_dir: &Path,
+    workers: usize,
+    timeout: Option<Duration>,
+) -> Arc<dyn Renderer> {
+    match kind {
+        RendererKind::Node => Arc::new(NodeRenderer {
+            pool: Mutex::new(NodePool::new(npm_bin_dir.to_path_buf(), workers, timeout)),
+        }),
+        RendererKind::Deno => Arc::new(DenoRenderer {
+            npm_bin_dir: npm_bin_dir.to_path_buf(),
+            timeout,
+        }),
+    }
+}
+
+fn path_str(path: &Path) -> Result<String> {
+    path.to_str()
+        .map(String::from)
+        .ok_or_else(|| eyre!("`{}` is not a valid utf8 path", path.display()))
+}
+
+/// Renders in a pool of long-lived node processes, which is the default
+#[derive(Debug)]
+pub struct NodeRenderer {
+    pool: Mutex<NodePool>,
+}
+
+impl Renderer for NodeRenderer {
+    #[instrument]
+    fn render(&self, job: RenderJob) -> Result<Rendered> {
+        self.pool
+            .lock()
+            .map_err(|_| eyre!("node renderer lock was poisoned"))?
+            .render(
+                job.dir_of_input_files,
+                job.output_dir,
+                job.pages,
+                job.page_roots,
+                &job.progress,
+            )
+    }
+
+    fn invalidate(&self) {
+        if let Ok(mut pool) = self.pool.lock() {
+            pool.restart();
+        }
+    }
+}
+
+/// Renders with `toast-render` in deno's node compatibility mode. Node
+/// loaders aren't supported there, so `react` imports aren't aliased to
+/// `preact/compat`.
+#[derive(Debug)]
+pub struct DenoRenderer {
+    pub npm_bin_dir: PathBuf,
+    pub timeout: Option<Duration>,
+}
+
+impl Renderer for DenoRenderer {
+    #[instrument]
+    fn render(&self, job: RenderJob) -> Result<Rendered> {
+        // the npm bin is a symlink or shim without an extension, and deno
+        // decides how to load a file from its extension
+        let bin = node_script(&self.npm_bin_dir, "toast-render");
+        let mut args: Vec<String> = vec![
+            "run".to_owned(),
+            "--quiet".to_owned(),
+            "--unstable".to_owned(),
+            "--compat".to_owned(),
+            "--allow-read".to_owned(),
+            "--allow-write".to_owned(),
+            "--allow-env".to_owned(),
+            path_str(&bin)?,
+            path_str(job.dir_of_input_files)?,
+            path_str(job.output_dir)?,
+            format!("--page-roots={}", serde_json::to_string(job.page_roots)?),
+        ];
+        args.extend(job.pages.iter().cloned());
+        let command = cmd("deno", args).stderr_to_stdout();
+        run_cmd("render", command, job.progress, 0, self.timeout)
+    }
+}
@@ -0,0 +1,404 @@
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{prelude::*, BufReader},
+    path::{Path, PathBuf},
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::instrument;
+
+use crate::{
+    cancel,
+    node::node_bin,
+    npm_bin::{node_loader, node_script},
+    pages::PageRoot,
+    progress::Progress,
+    renderer::Rendered,
+    report::{PageTiming, RenderFailure},
+    supervisor::{StderrTail, Watchdog},
+};
+
+/// A pool of long-lived `toast-render-worker` node processes. Pages are
+/// sent to a worker as newline-delimited JSON on stdin and it answers each
+/// one with a line of JSON on stdout, so node only has to start once per
+/// `toast` run instead of once per render. Pages are spread across the
+/// workers so that rendering uses more than one core. A worker that goes
+/// `timeout` without answering is killed.
+#[derive(Debug)]
+pub struct NodePool {
+    npm_bin_dir: PathBuf,
+    size: usize,
+    timeout: Option<Duration>,
+    workers: Vec<Worker>,
+    /// bumped on every render so the workers import fresh copies of
+    /// page modules that were recompiled since the last render
+    version: u64,
+}
+
+#[derive(Debug)]
+struct Worker {
+    /// shared with the watchdog that kills it if it hangs
+    child: Arc<Mutex<Child>>,
+    pid: u32,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    stderr: StderrTail,
+}
+
+#[derive(Debug, Serialize)]
+struct RenderRequest<'a> {
+    id: usize,
+    #[serde(rename = "srcDir")]
+    src_dir: &'a Path,
+    #[serde(rename = "outputDir")]
+    output_dir: &'a Path,
+    page: &'a str,
+    #[serde(rename = "pageRoots")]
+    page_roots: &'a [PageRoot],
+    version: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct RenderResponse {
+    id: usize,
+    page: String,
+    render_ms: Option<f64>,
+    write_ms: Option<f64>,
+    error: Option<String>,
+}
+
+impl Worker {
+    fn spawn(npm_bin_dir: &Path) -> Result<Worker> {
+        let bin = node_script(npm_bin_dir, "toast-render-worker");
+        let child = Command::new(node_bin())
+            .arg("--loader")
+            .arg(node_loader(npm_bin_dir))
+            .arg(&bin)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .wrap_err_with(|| format!("Failed to start `{}` with node", bin.display()))?;
+        Worker::from_child(child)
+    }
+
+    fn from_child(mut child: Child) -> Result<Worker> {
+        let pid = child.id();
+        cancel::track_child(pid);
+        let stdin = child
+            .stdin
+            .take()
+            .ok_or_else(|| eyre!("Failed to open stdin of the node renderer"))?;
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| eyre!("Failed to open stdout of the node renderer"))?;
+        let stderr_tail = StderrTail::default();
+        if let Some(stderr) = child.stderr.take() {
+            stderr_tail.follow(stderr);
+        }
+        Ok(Worker {
+            child: Arc::new(Mutex::new(child)),
+            pid,
+            stdin,
+            stdout: BufReader::new(stdout),
+            stderr: stderr_tail,
+        })
+    }
+
+    fn is_running(&mut self) -> bool {
+        match self.child.lock() {
+            Ok(mut child) => matches!(child.try_wait(), Ok(None)),
+            Err(_) => false,
+        }
+    }
+
+    fn kill(&mut self) {
+        if let Ok(mut child) = self.child.lock() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        cancel::untrack_child(self.pid);
+    }
+}
+
+impl Worker {
+    /// Send every request to this worker and wait for all of the answers
+    fn render(
+        &mut self,
+        requests: &[RenderRequest],
+        progress: &Progress,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<RenderResponse>> {
+        let child = self.child.clone();
+        let stuck_child = self.child.clone();
+        let watchdog = Watchdog::start(timeout, move || {
+            if let Ok(mut child) = child.lock() {
+                let _ = child.kill();
+            }
+        });
+        let Worker {
+            stdin,
+            stdout,
+            stderr,
+            ..
+        } = self;
+        // requests are written from another thread so that a full stdout
+        // pipe can't block the worker while we're still writing to it
+        crossbeam::scope(|scope| -> Result<Vec<RenderResponse>> {
+            let writer = scope.spawn(move |_| -> std::io::Result<()> {
+                for request in requests {
+                    serde_json::to_writer(&mut *stdin, request)?;
+                    stdin.write_all(b"\n")?;
+                }
+                stdin.flush()
+            });
+
+            let read = read_responses(stdout, requests, &watchdog, progress, timeout);
+            // a worker that stopped answering might have stopped reading
+            // too, leaving the writer stuck on a full stdin pipe
+            if read.is_err() {
+                if let Ok(mut child) = stuck_child.lock() {
+                    let _ = child.kill();
+                }
+            }
+            let written = writer
+                .join()
+                .map_err(|_| eyre!("The node renderer writer thread panicked"))?;
+            let responses = read?;
+            written.wrap_err("Failed to send pages to the node renderer")?;
+            Ok(responses)
+        })
+        .map_err(|_| eyre!("Failed to run the node renderer threads"))?
+        .map_err(|err| stderr.attach(err))
+    }
+}
+
+/// Read a worker's answer to each of `requests`
+fn read_responses(
+    stdout: &mut BufReader<ChildStdout>,
+    requests: &[RenderRequest],
+    watchdog: &Watchdog,
+    progress: &Progress,
+    timeout: Option<Duration>,
+) -> Result<Vec<RenderResponse>> {
+    let mut responses = Vec::with_capacity(requests.len());
+    let mut line = String::new();
+    while responses.len() < requests.len() {
+        line.clear();
+        let read = stdout
+            .read_line(&mut line)
+            .wrap_err("Failed to read from the node renderer")?;
+        if read == 0 {
+            let pending = requests
+                .iter()
+                .find(|request| {
+                    !responses
+                        .iter()
+                        .any(|r: &RenderResponse| r.id == request.id)
+                })
+                .map(|request| request.page)
+                .unwrap_or_default();
+            if watchdog.timed_out() {
+                return Err(eyre!(
+                    "The node renderer went {}s without finishing a page while rendering `{}`, so it was stopped. Pass `--render-timeout` to wait longer",
+                    timeout.unwrap_or_default().as_secs(),
+                    pending
+                ));
+            }
+            return Err(eyre!(
+                "The node renderer exited while rendering `{}`",
+                pending
+            ));
+        }
+        let response: RenderResponse = serde_json::from_str(&line).wrap_err_with(|| {
+            format!("Failed to parse node renderer response `{}`", line.trim())
+        })?;
+        watchdog.progress();
+        progress.rendered(&response.page);
+        responses.push(response);
+    }
+    Ok(responses)
+}
+
+impl NodePool {
+    /// `size` workers are started the first time something is rendered
+    pub fn new(npm_bin_dir: PathBuf, size: usize, timeout: Option<Duration>) -> NodePool {
+        NodePool {
+            npm_bin_dir,
+            size: size.max(1),
+            timeout,
+            workers: vec![],
+            version: 0,
+        }
+    }
+
+    /// Replace any workers that have exited and start more, up to the
+    /// size of the pool, until there are `count` running
+    fn start_workers(&mut self, count: usize) -> Result<()> {
+        let count = count.min(self.size);
+        let mut running = Vec::with_capacity(count);
+        for mut worker in self.workers.drain(..) {
+            if worker.is_running() {
+                running.push(worker);
+            } else {
+                cancel::untrack_child(worker.pid);
+            }
+        }
+        while running.len() < count {
+            running.push(Worker::spawn(&self.npm_bin_dir)?);
+        }
+        self.workers = running;
+        Ok(())
+    }
+
+    /// Stop every worker so the next render starts from fresh node
+    /// processes. Node never forgets a module it has imported, so this is
+    /// how shared components and the page wrapper get reloaded.
+    pub fn restart(&mut self) {
+        for mut worker in self.workers.drain(..) {
+            worker.kill();
+        }
+    }
+
+    #[instrument(skip(self, progress))]
+    pub fn render(
+        &mut self,
+        src_dir: &Path,
+        output_dir: &Path,
+        pages: &[String],
+        page_roots: &[PageRoot],
+        progress: &Progress,
+    ) -> Result<Rendered> {
+        if pages.is_empty() {
+            return Ok(Rendered::default());
+        }
+        cancel::check()?;
+        self.version += 1;
+        let version = self.version;
+        // small renders, like a single page changing in watch mode,
+        // don't need to wait for the whole pool to start
+        self.start_workers(pages.len())?;
+
+        // deal pages out to the workers like cards, so that each of them
+        // gets a similar share of every part of the site
+        let worker_count = self.workers.len();
+        let mut batches: Vec<Vec<RenderRequest>> = (0..worker_count).map(|_| vec![]).collect();
+        for (id, page) in pages.iter().enumerate() {
+            batches[id % worker_count].push(RenderRequest {
+                id,
+                src_dir,
+                output_dir,
+                page,
+                page_roots,
+                version,
+            });
+        }
+
+        let workers = &mut self.workers;
+        let timeout = self.timeout;
+        let results = crossbeam::scope(|scope| {
+            let handles: Vec<_> = workers
+                .iter_mut()
+                .zip(batches.iter())
+                .map(|(worker, batch)| {
+                    scope.spawn(move |_| worker.render(batch, progress, timeout))
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .map_err(|_| eyre!("A node renderer thread panicked"))?
+                })
+                .collect::<Result<Vec<Vec<RenderResponse>>>>()
+        })
+        .map_err(|_| eyre!("Failed to run the node renderer threads"));
+
+        let mut responses: Vec<RenderResponse> = match results {
+            Ok(Ok(responses)) => responses.into_iter().flatten().collect(),
+            Ok(Err(err)) | Err(err) => {
+                // the workers are in an unknown state, start over next time
+                self.restart();
+                // a cancelled build kills the workers, which is why they
+                // stopped answering
+                cancel::check()?;
+                return Err(err);
+            }
+        };
+        responses.sort_by_key(|response| response.id);
+        let mut rendered = Rendered::default();
+        for response in responses {
+            match response.error {
+                Some(error) => rendered.failures.push(RenderFailure {
+                    page: response.page,
+                    error,
+                }),
+                None => rendered.timings.push(PageTiming {
+                    page: response.page,
+                    render_ms: response.render_ms.unwrap_or_default(),
+                    write_ms: response.write_ms.unwrap_or_default(),
+                }),
+            }
+        }
+        Ok(rendered)
+    }
+}
+
+impl Drop for NodePool {
+    fn drop(&mut self) {
+        self.restart();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn worker(script: &str) -> Worker {
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(script)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        Worker::from_child(child).unwrap()
+    }
+
+    /// Workers that exit or stop answering early, with more requests than
+    /// fit in a pipe still to send
+    #[test]
+    fn test_worker_stops_answering() {
+        let page = "a".repeat(64 * 1024);
+        let requests: Vec<RenderRequest> = (0..8)
+            .map(|id| RenderRequest {
+                id,
+                src_dir: Path::new("src"),
+                output_dir: Path::new("public"),
+                page: &page,
+                page_roots: &[],
+                version: 1,
+            })
+            .collect();
+
+        let mut exits = worker("exit 1");
+        let err = exits
+            .render(&requests, &Progress::hidden(), None)
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("The node renderer exited while rendering"));
+        exits.kill();
+
+        // answers with something other than JSON and then stops reading
+        let mut stops = worker("echo oops; exec sleep 60");
+        let err = stops
+            .render(&requests, &Progress::hidden(), None)
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("Failed to parse node renderer response `oops`"));
+        stops.kill();
+    }
+}
@@ -0,0 +1,284 @@
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::Path,
+    str::FromStr,
+    time::{Duration, Instant, SystemTime},
+};
+use tracing::{debug, info};
+
+/// Output formats for `--report`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    Json,
+}
+
+impl FromStr for ReportFormat {
+    type Err = color_eyre::Report;
+    fn from_str(format: &str) -> Result<Self> {
+        match format {
+            "json" => Ok(ReportFormat::Json),
+            _ => Err(eyre!(
+                "Unknown report format `{}`, the only supported format is `json`",
+                format
+            )),
+        }
+    }
+}
+
+/// A machine-readable summary of one build, for tracking build
+/// performance over time.
+#[derive(Debug, Default, Clone, Serialize, PartialEq)]
+pub struct BuildReport {
+    pub duration_ms: f64,
+    /// page modules rendered to html
+    pub pages: Vec<String>,
+    /// total size of the compiled js and the html written
+    pub bytes_written: u64,
+    pub cache_hits: usize,
+    pub cache_misses: usize,
+    pub files: Vec<FileReport>,
+    pub page_timings: Vec<PageTiming>,
+    /// pages that threw while they were rendered
+    pub render_failures: Vec<RenderFailure>,
+    pub phases: Vec<PhaseTiming>,
+    /// when the build started, for laying out `--telemetry` spans
+    #[serde(skip)]
+    pub started_at: Option<SystemTime>,
+}
+
+/// When a stage of the build, like compiling or rendering, started, in
+/// milliseconds since the build started, and how long it took
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct PhaseTiming {
+    pub name: String,
+    pub start_ms: f64,
+    pub duration_ms: f64,
+}
+
+/// How compiling a single source file went
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct FileReport {
+    pub source_id: String,
+    /// the compiled module, relative to the output directory
+    pub output: String,
+    /// whether the file was skipped because a previous build already
+    /// compiled it from the same inputs
+    pub cache_hit: bool,
+    pub duration_ms: f64,
+    pub bytes_written: u64,
+}
+
+/// How long `toast-render` took to render and write the html for a page
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PageTiming {
+    pub page: String,
+    pub render_ms: f64,
+    pub write_ms: f64,
+}
+
+/// A page that threw while it was rendered, with the error and its stack
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RenderFailure {
+    pub page: String,
+    pub error: String,
+}
+
+/// Where the time for a single page went, for `--profile`
+#[derive(Debug, Clone, PartialEq)]
+pub struct PageProfile {
+    pub page: String,
+    pub compile_ms: f64,
+    pub render_ms: f64,
+    pub write_ms: f64,
+}
+
+impl PageProfile {
+    pub fn total_ms(&self) -> f64 {
+        self.compile_ms + self.render_ms + self.write_ms
+    }
+}
+
+pub fn as_ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Log an event for each rendered page, see `logging`
+pub fn log_page_timings(timings: &[PageTiming]) {
+    for timing in timings {
+        info!(
+            page = %timing.page,
+            render_ms = timing.render_ms,
+            write_ms = timing.write_ms,
+            "rendered page"
+        );
+    }
+}
+
+impl BuildReport {
+    pub fn record_file(&mut self, file: FileReport) {
+        debug!(
+            source_id = %file.source_id,
+            cache_hit = file.cache_hit,
+            duration_ms = file.duration_ms,
+            bytes_written = file.bytes_written,
+            "compiled file"
+        );
+        if file.cache_hit {
+            self.cache_hits += 1;
+        } else {
+            self.cache_misses += 1;
+        }
+        self.bytes_written += file.bytes_written;
+        self.files.push(file);
+    }
+
+    /// Record that phase `name` ran from `phase_start` until now, in a build
+    /// that started at `build_start`
+    pub fn record_phase(&mut self, name: &str, build_start: Instant, phase_start: Instant) {
+        self.phases.push(PhaseTiming {
+            name: name.to_string(),
+            start_ms: as_ms(phase_start.duration_since(build_start)),
+            duration_ms: as_ms(phase_start.elapsed()),
+        });
+    }
+
+    pub fn render(&self, format: ReportFormat) -> Result<String> {
+        match format {
+            ReportFormat::Json => Ok(serde_json::to_string_pretty(self)?),
+        }
+    }
+
+    /// Join compile times to render and write times for every page that
+    /// was rendered, in page order
+    pub fn page_profiles(&self) -> Vec<PageProfile> {
+        let mut profiles: Vec<PageProfile> = self
+            .page_timings
+            .iter()
+            .map(|timing| PageProfile {
+                page: timing.page.clone(),
+                compile_ms: self
+                    .files
+                    .iter()
+                    .filter(|file| file.output == timing.page)
+                    .map(|file| file.duration_ms)
+                    .sum(),
+                render_ms: timing.render_ms,
+                write_ms: timing.write_ms,
+            })
+            .collect();
+        profiles.sort_by(|a, b| a.page.cmp(&b.page));
+        profiles
+    }
+
+    /// A table of per-page timings, followed by the `top` slowest pages
+    pub fn profile_table(&self, top: usize) -> String {
+        let profiles = self.page_profiles();
+        let width = profiles
+            .iter()
+            .map(|profile| profile.page.len())
+            .max()
+            .unwrap_or(0)
+            .max("page".len());
+        let header = format!(
+            "{:<width$}  {:>10}  {:>10}  {:>10}  {:>10}\n",
+            "page",
+            "compile",
+            "render",
+            "write",
+            "total",
+            width = width
+        );
+        let row = |profile: &PageProfile| {
+            format!(
+                "{:<width$}  {:>8.1}ms  {:>8.1}ms  {:>8.1}ms  {:>8.1}ms\n",
+                profile.page,
+                profile.compile_ms,
+                profile.render_ms,
+                profile.write_ms,
+                profile.total_ms(),
+                width = width
+            )
+        };
+
+        let mut table = header.clone();
+        for profile in &profiles {
+            table.push_str(&row(profile));
+        }
+        let mut slowest = profiles;
+        slowest.sort_by(|a, b| {
+            b.total_ms()
+                .partial_cmp(&a.total_ms())
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        table.push_str(&format!("\n{} slowest pages\n", top.min(slowest.len())));
+        table.push_str(&header);
+        for profile in slowest.iter().take(top) {
+            table.push_str(&row(profile));
+        }
+        table
+    }
+
+    /// Write the report to `file`, or to stdout if there isn't one
+    pub fn write(&self, format: ReportFormat, file: Option<&Path>) -> Result<()> {
+        let report = self.render(format)?;
+        match file {
+            Some(file) => fs::write(file, report)
+                .wrap_err_with(|| format!("Failed to write build report to `{}`", file.display())),
+            None => {
+                println!("{}", report);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_file_counts_cache_hits() {
+        let mut report = BuildReport::default();
+        report.record_file(FileReport {
+            source_id: String::from("src/pages/index.js"),
+            output: String::from("src/pages/index.js"),
+            cache_hit: false,
+            duration_ms: 12.0,
+            bytes_written: 100,
+        });
+        report.record_file(FileReport {
+            source_id: String::from("src/pages/about.js"),
+            output: String::from("src/pages/about.js"),
+            cache_hit: true,
+            duration_ms: 0.0,
+            bytes_written: 0,
+        });
+        assert_eq!(report.cache_hits, 1);
+        assert_eq!(report.cache_misses, 1);
+        assert_eq!(report.bytes_written, 100);
+    }
+
+    #[test]
+    fn test_slowest_pages_come_first() {
+        let mut report = BuildReport::default();
+        for (page, render_ms) in &[("src/pages/a.js", 5.0), ("src/pages/b.js", 50.0)] {
+            report.page_timings.push(PageTiming {
+                page: page.to_string(),
+                render_ms: *render_ms,
+                write_ms: 1.0,
+            });
+        }
+        let table = report.profile_table(1);
+        let slowest = table.split("slowest pages").nth(1).unwrap();
+        assert!(slowest.contains("src/pages/b.js"));
+        assert!(!slowest.contains("src/pages/a.js"));
+    }
+
+    #[test]
+    fn test_parse_report_format() {
+        assert_eq!("json".parse::<ReportFormat>().unwrap(), ReportFormat::Json);
+        assert!("yaml".parse::<ReportFormat>().is_err());
+    }
+}
@@ -0,0 +1,84 @@
+//! `--reproducible` builds, so two builds of the same commit are
+//! byte-identical.
+//!
+//! Source directories are walked in sorted order, and the time a build
+//! happened at is fixed: it's `SOURCE_DATE_EPOCH` if that's set, or the
+//! time of the last commit if the project is in a git repository. Every
+//! file in the output directory and the cache has its modification time
+//! set to it, and it's the `now` that future-dated pages are compared to.
+//! Hashed file names already only depend on file contents.
+use chrono::{DateTime, TimeZone, Utc};
+use color_eyre::eyre::{Result, WrapErr};
+use filetime::{set_file_mtime, FileTime};
+use std::path::Path;
+use tracing::instrument;
+use walkdir::WalkDir;
+
+/// <https://reproducible-builds.org/specs/source-date-epoch/>
+pub const SOURCE_DATE_EPOCH: &str = "SOURCE_DATE_EPOCH";
+
+fn parse_epoch(seconds: &str) -> Option<DateTime<Utc>> {
+    seconds
+        .trim()
+        .parse::<i64>()
+        .ok()
+        .and_then(|seconds| Utc.timestamp_opt(seconds, 0).single())
+}
+
+fn last_commit_time(project_root_dir: &Path) -> Option<DateTime<Utc>> {
+    duct::cmd!("git", "log", "-1", "--format=%ct")
+        .dir(project_root_dir)
+        .stderr_null()
+        .read()
+        .ok()
+        .and_then(|seconds| parse_epoch(&seconds))
+}
+
+/// The time a build is happening at, which is fixed for reproducible
+/// builds
+pub fn build_time(reproducible: bool, project_root_dir: &Path) -> DateTime<Utc> {
+    if !reproducible {
+        return Utc::now();
+    }
+    std::env::var(SOURCE_DATE_EPOCH)
+        .ok()
+        .and_then(|seconds| parse_epoch(&seconds))
+        .or_else(|| last_commit_time(project_root_dir))
+        .unwrap_or_else(Utc::now)
+}
+
+/// Set the modification time of every file in `dirs` to `time`
+#[instrument]
+pub fn normalize_timestamps(dirs: &[&Path], time: DateTime<Utc>) -> Result<()> {
+    let mtime = FileTime::from_unix_time(time.timestamp(), 0);
+    for dir in dirs {
+        for entry in WalkDir::new(dir)
+            .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+            .into_iter()
+            .filter_map(|result| result.ok())
+            .filter(|entry| entry.file_type().is_file())
+        {
+            set_file_mtime(entry.path(), mtime).wrap_err_with(|| {
+                format!(
+                    "Failed to set the modification time of `{}`",
+                    entry.path().display()
+                )
+            })?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_epoch() {
+        let date = chrono::NaiveDate::from_ymd_opt(2020, 9, 1)
+            .and_then(|date| date.and_hms_opt(0, 0, 0))
+            .map(|naive| Utc.from_utc_datetime(&naive));
+        assert_eq!(parse_epoch("1598918400\n"), date);
+        assert_eq!(parse_epoch("yesterday"), None);
+    }
+}
@@ -0,0 +1,205 @@
+//! `robots.txt`, written after the build from `[robots]` in
+//! `toast.config.toml`:
+//!
+//! ```toml
+//! [robots]
+//! enabled = true
+//! # rules for every crawler
+//! disallow = ["/drafts/"]
+//! allow = []
+//! # sitemaps that aren't written by toast, like one in `static/`
+//! sitemaps = ["/sitemap.xml"]
+//!
+//! # rules for one crawler
+//! [[robots.agents]]
+//! user_agent = "GPTBot"
+//! disallow = ["/"]
+//! ```
+//!
+//! The locale sitemaps, see `i18n`, are listed with any in `sitemaps`,
+//! which need `base_url` to be absolute. A `static/robots.txt` is kept,
+//! with the configured rules added after it.
+//!
+//! Preview and staging builds shouldn't be indexed, so `--disallow-all`
+//! (or `disallow_all = true`) writes a `robots.txt` that disallows every
+//! crawler from the whole site instead, whether or not `[robots]` is
+//! enabled and even if there's a `static/robots.txt`. Netlify deploy
+//! previews and branch deploys, and Vercel preview deploys, get the same
+//! `robots.txt` when `[robots]` is enabled, so only production deploys are
+//! crawled.
+use color_eyre::eyre::{Result, WrapErr};
+use serde::Deserialize;
+use std::{fs, path::Path};
+use tracing::instrument;
+
+use crate::{
+    head::absolute,
+    i18n::{sitemap_file, I18nConfig},
+};
+
+const DISALLOW_ALL: &str = "User-agent: *\nDisallow: /\n";
+
+/// `[robots]` in `toast.config.toml`
+#[derive(Debug, Default, Clone, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RobotsConfig {
+    pub enabled: bool,
+    /// paths every crawler can crawl inside disallowed ones
+    pub allow: Vec<String>,
+    /// paths no crawler should crawl
+    pub disallow: Vec<String>,
+    /// rules for specific crawlers
+    pub agents: Vec<RobotsAgent>,
+    /// urls of sitemaps toast doesn't write
+    pub sitemaps: Vec<String>,
+    /// disallow every crawler from the whole site, see `--disallow-all`
+    pub disallow_all: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RobotsAgent {
+    pub user_agent: String,
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub disallow: Vec<String>,
+}
+
+/// Whether the variables `env` looks up say this is a build of a preview
+/// deploy on Netlify or Vercel
+pub fn is_preview_deploy(env: impl Fn(&str) -> Option<String>) -> bool {
+    matches!(
+        env("CONTEXT").as_deref(),
+        Some("deploy-preview") | Some("branch-deploy")
+    ) || env("VERCEL_ENV").as_deref() == Some("preview")
+}
+
+fn render_group(output: &mut String, user_agent: &str, allow: &[String], disallow: &[String]) {
+    if !output.is_empty() {
+        output.push('\n');
+    }
+    output.push_str(&format!("User-agent: {}\n", user_agent));
+    for path in allow {
+        output.push_str(&format!("Allow: {}\n", path));
+    }
+    for path in disallow {
+        output.push_str(&format!("Disallow: {}\n", path));
+    }
+}
+
+/// The rules of `config` after `existing`, with a `Sitemap` line for each
+/// of `sitemaps`
+pub fn render_robots(config: &RobotsConfig, existing: &str, sitemaps: &[String]) -> String {
+    let mut output = existing.to_string();
+    if !output.is_empty() && !output.ends_with('\n') {
+        output.push('\n');
+    }
+    if !config.allow.is_empty() || !config.disallow.is_empty() {
+        render_group(&mut output, "*", &config.allow, &config.disallow);
+    }
+    for agent in &config.agents {
+        render_group(
+            &mut output,
+            &agent.user_agent,
+            &agent.allow,
+            &agent.disallow,
+        );
+    }
+    // an empty `Disallow` allows everything
+    if output.is_empty() {
+        output.push_str("User-agent: *\nDisallow:\n");
+    }
+    if !sitemaps.is_empty() {
+        output.push('\n');
+    }
+    for sitemap in sitemaps {
+        output.push_str(&format!("Sitemap: {}\n", sitemap));
+    }
+    output
+}
+
+/// Write `robots.txt` if `config` is enabled or crawlers are disallowed
+#[instrument(skip(config, i18n))]
+pub fn write_robots(
+    project_root_dir: &Path,
+    output_dir: &Path,
+    config: &RobotsConfig,
+    i18n: &I18nConfig,
+    base_url: Option<&str>,
+) -> Result<()> {
+    let disallow_all = config.disallow_all
+        || (config.enabled && is_preview_deploy(|name| std::env::var(name).ok()));
+    if !config.enabled && !disallow_all {
+        return Ok(());
+    }
+    let robots = if disallow_all {
+        String::from(DISALLOW_ALL)
+    } else {
+        let existing =
+            fs::read_to_string(project_root_dir.join("static/robots.txt")).unwrap_or_default();
+        let mut sitemaps: Vec<String> = match base_url {
+            Some(_) => i18n
+                .locales
+                .iter()
+                .map(|locale| absolute(&format!("/{}", sitemap_file(locale)), base_url))
+                .collect(),
+            None => vec![],
+        };
+        sitemaps.extend(
+            config
+                .sitemaps
+                .iter()
+                .map(|sitemap| absolute(sitemap, base_url)),
+        );
+        render_robots(config, &existing, &sitemaps)
+    };
+    let robots_path = output_dir.join("robots.txt");
+    fs::write(&robots_path, robots)
+        .wrap_err_with(|| format!("Failed to write `{}`", robots_path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_robots() {
+        let config = RobotsConfig {
+            enabled: true,
+            disallow: vec![String::from("/drafts/")],
+            agents: vec![RobotsAgent {
+                user_agent: String::from("GPTBot"),
+                allow: vec![],
+                disallow: vec![String::from("/")],
+            }],
+            ..RobotsConfig::default()
+        };
+        assert_eq!(
+            render_robots(
+                &config,
+                "User-agent: Bingbot\nCrawl-delay: 1",
+                &[String::from("https://toast.dev/sitemap-en.xml")]
+            ),
+            "User-agent: Bingbot\nCrawl-delay: 1\n\nUser-agent: *\nDisallow: /drafts/\n\nUser-agent: GPTBot\nDisallow: /\n\nSitemap: https://toast.dev/sitemap-en.xml\n"
+        );
+        assert_eq!(
+            render_robots(&RobotsConfig::default(), "", &[]),
+            "User-agent: *\nDisallow:\n"
+        );
+    }
+
+    #[test]
+    fn test_is_preview_deploy() {
+        assert!(is_preview_deploy(|name| match name {
+            "CONTEXT" => Some(String::from("deploy-preview")),
+            _ => None,
+        }));
+        assert!(!is_preview_deploy(|name| match name {
+            "CONTEXT" => Some(String::from("production")),
+            "VERCEL_ENV" => Some(String::from("production")),
+            _ => None,
+        }));
+    }
+}
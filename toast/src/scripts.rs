@@ -0,0 +1,162 @@
+//! The node scripts toast runs (`toast-render`, `toast-mdx`, ...), built
+//! into the binary so they're always the ones written for it, whichever
+//! version of the toast npm package is installed.
+//!
+//! Before one is run, every script is written into the toast npm package,
+//! under `.embedded/<hash>`, where node finds their dependencies (like
+//! `@mdx-js/mdx` and `sharp`) the same way it would for the package's own
+//! scripts, with npm, yarn, or pnpm. If the package can't be written to,
+//! `node_modules/.cache/toast/<hash>` is used instead, and if that fails
+//! too, the package's own scripts are run.
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+use tracing::instrument;
+
+use crate::{cache::content_hash, npm_bin::bin_script};
+
+/// Every script, by its path in the npm package, so relative imports
+/// between them still work
+const SCRIPTS: &[(&str, &str)] = &[
+    (
+        "toast-fonts.mjs",
+        include_str!("../../toast-node-wrapper/toast-fonts.mjs"),
+    ),
+    (
+        "toast-images.mjs",
+        include_str!("../../toast-node-wrapper/toast-images.mjs"),
+    ),
+    (
+        "toast-mdx.mjs",
+        include_str!("../../toast-node-wrapper/toast-mdx.mjs"),
+    ),
+    (
+        "toast-plugins.mjs",
+        include_str!("../../toast-node-wrapper/toast-plugins.mjs"),
+    ),
+    (
+        "toast-render.mjs",
+        include_str!("../../toast-node-wrapper/toast-render.mjs"),
+    ),
+    (
+        "toast-render-worker.mjs",
+        include_str!("../../toast-node-wrapper/toast-render-worker.mjs"),
+    ),
+    (
+        "toast-social-cards.mjs",
+        include_str!("../../toast-node-wrapper/toast-social-cards.mjs"),
+    ),
+    (
+        "toast-source-data.mjs",
+        include_str!("../../toast-node-wrapper/toast-source-data.mjs"),
+    ),
+    (
+        "toast-web-modules.mjs",
+        include_str!("../../toast-node-wrapper/toast-web-modules.mjs"),
+    ),
+    (
+        "src/loader.mjs",
+        include_str!("../../toast-node-wrapper/src/loader.mjs"),
+    ),
+    (
+        "src/module-aliases.mjs",
+        include_str!("../../toast-node-wrapper/src/module-aliases.mjs"),
+    ),
+    (
+        "src/page-renderer-pre.mjs",
+        include_str!("../../toast-node-wrapper/src/page-renderer-pre.mjs"),
+    ),
+    (
+        "src/protocol.mjs",
+        include_str!("../../toast-node-wrapper/src/protocol.mjs"),
+    ),
+    (
+        "src/render-page.mjs",
+        include_str!("../../toast-node-wrapper/src/render-page.mjs"),
+    ),
+];
+
+/// Written last, so a directory without it is still being written
+const COMPLETE_FILE: &str = ".complete";
+
+/// Names the directory the scripts are written to, so a different build
+/// of toast never runs another one's scripts
+fn scripts_hash() -> String {
+    let parts: Vec<&[u8]> = SCRIPTS
+        .iter()
+        .flat_map(|(path, contents)| [path.as_bytes(), contents.as_bytes()])
+        .collect();
+    content_hash(&parts)
+}
+
+fn write_scripts(dir: &Path) -> io::Result<()> {
+    if dir.join(COMPLETE_FILE).exists() {
+        return Ok(());
+    }
+    for (relative, contents) in SCRIPTS {
+        let path = dir.join(relative);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // renamed into place, so another build writing the same scripts
+        // never leaves one half-written for node
+        let tmp = path.with_extension(format!("mjs.{}", std::process::id()));
+        fs::write(&tmp, contents)?;
+        fs::rename(&tmp, &path)?;
+    }
+    fs::write(dir.join(COMPLETE_FILE), "")
+}
+
+/// Where the scripts are written for the npm package in `npm_bin_dir`,
+/// see the module docs
+#[instrument]
+fn scripts_dir(npm_bin_dir: &Path) -> Option<PathBuf> {
+    let hash = scripts_hash();
+    let render = bin_script(npm_bin_dir, "toast-render");
+    let package_dir = render
+        .extension()
+        .filter(|ext| *ext == "mjs")
+        .and_then(|_| render.parent())
+        .map(|dir| dir.join(".embedded"));
+    let cache_dir = npm_bin_dir
+        .parent()
+        .map(|node_modules| node_modules.join(".cache").join("toast"));
+    package_dir
+        .into_iter()
+        .chain(cache_dir)
+        .map(|dir| dir.join(&hash))
+        .find(|dir| write_scripts(dir).is_ok())
+}
+
+/// The embedded script at `relative`, like `toast-mdx.mjs`, once it's
+/// been written out
+pub fn embedded_script(npm_bin_dir: &Path, relative: &str) -> Option<PathBuf> {
+    if !SCRIPTS.iter().any(|(path, _)| *path == relative) {
+        return None;
+    }
+    scripts_dir(npm_bin_dir).map(|dir| dir.join(relative))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_scripts() {
+        let dir = std::env::temp_dir().join(format!("toast-scripts-{}", std::process::id()));
+        let scripts = dir.join(scripts_hash());
+        write_scripts(&scripts).unwrap();
+        assert!(scripts.join("src/render-page.mjs").is_file());
+        assert_eq!(
+            fs::read_to_string(scripts.join("toast-mdx.mjs")).unwrap(),
+            SCRIPTS
+                .iter()
+                .find(|(path, _)| *path == "toast-mdx.mjs")
+                .unwrap()
+                .1
+        );
+        assert!(scripts.join(COMPLETE_FILE).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
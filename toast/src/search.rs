@@ -0,0 +1,427 @@
+//! Client-side search indexes, configured with `[[search]]` in
+//! `toast.config.toml`
+//!
+//! ```toml
+//! [[search]]
+//! collection = "docs"
+//! fields = ["title", "description", "tags"]
+//! ```
+//!
+//! Each one is written to `toast-data/search/<collection>.json` as a list
+//! of documents with the page's `id` (its slug), `url`, the frontmatter
+//! `fields`, and the text of the page as `body`. That's what Lunr,
+//! ElasticLunr, and MiniSearch build an index from in the browser:
+//!
+//! ```js
+//! const docs = await fetch("/toast-data/search/docs.json").then((res) => res.json());
+//! const index = lunr(function () {
+//!   this.ref("id");
+//!   this.field("title");
+//!   this.field("body");
+//!   docs.forEach((doc) => this.add(doc));
+//! });
+//! ```
+//!
+//! With `format = "stork"` it's written as `<collection>.stork.toml`
+//! instead, for `stork build --input`. `collection = "*"` indexes every
+//! page on the site, as `site.json`.
+//!
+//! The body comes from the source of `.md` and `.mdx` pages, with the
+//! markdown syntax, JSX, imports and exports, and fenced code blocks
+//! taken out, so nothing has to crawl the built html. Pages made with
+//! `setDataForSlug` only have their fields indexed.
+use color_eyre::eyre::{Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    base_path::prefix_url,
+    cache::CACHE_DIR,
+    content_index::{PageEntry, DATA_DIR},
+    frontmatter,
+    url_style::UrlStyle,
+};
+
+/// File in `CACHE_DIR` with the text of every page, by slug, so watch mode
+/// can rewrite the indexes without reading every page again
+const TEXT_FILE: &str = "search-text.json";
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SearchConfig {
+    /// a collection from the content index, like `docs`, or `"*"` for
+    /// every page
+    pub collection: String,
+    /// frontmatter or `setDataForSlug` data copied into each document
+    #[serde(default = "default_fields")]
+    pub fields: Vec<String>,
+    /// index the text of each page as well
+    #[serde(default = "default_body")]
+    pub body: bool,
+    #[serde(default)]
+    pub format: SearchFormat,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchFormat {
+    /// a JSON list of documents
+    #[default]
+    Json,
+    /// a Stork config with the documents inline
+    Stork,
+}
+
+fn default_fields() -> Vec<String> {
+    vec![String::from("title"), String::from("description")]
+}
+
+fn default_body() -> bool {
+    true
+}
+
+#[derive(Debug, Serialize)]
+struct StorkConfig {
+    input: StorkInput,
+}
+
+#[derive(Debug, Serialize)]
+struct StorkInput {
+    files: Vec<StorkFile>,
+}
+
+#[derive(Debug, Serialize)]
+struct StorkFile {
+    title: String,
+    url: String,
+    contents: String,
+}
+
+impl SearchConfig {
+    fn matches(&self, slug: &str) -> bool {
+        if self.collection == "*" {
+            return true;
+        }
+        let entry = PageEntry {
+            slug: slug.to_string(),
+            data: Value::Null,
+        };
+        entry.collection() == Some(self.collection.as_str())
+    }
+
+    fn file_name(&self) -> String {
+        let stem = if self.collection == "*" {
+            "site"
+        } else {
+            self.collection.as_str()
+        };
+        match self.format {
+            SearchFormat::Json => format!("{}.json", stem),
+            SearchFormat::Stork => format!("{}.stork.toml", stem),
+        }
+    }
+}
+
+/// Drop everything that isn't a word from a run of inline markdown
+fn push_inline_text(line: &str, text: &mut String) {
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            // JSX and html tags, but not a `<` in a sentence
+            '<' if chars
+                .peek()
+                .is_some_and(|next| next.is_ascii_alphabetic() || *next == '/' || *next == '>') =>
+            {
+                chars.by_ref().find(|c| *c == '>');
+                text.push(' ');
+            }
+            // MDX expressions
+            '{' => {
+                chars.by_ref().find(|c| *c == '}');
+                text.push(' ');
+            }
+            // the url of a link or image, keeping its text
+            ']' if chars.peek() == Some(&'(') => {
+                chars.by_ref().find(|c| *c == ')');
+            }
+            '!' if chars.peek() == Some(&'[') => {}
+            '*' | '`' | '~' | '[' | ']' => {}
+            c => text.push(c),
+        }
+    }
+    text.push(' ');
+}
+
+/// The words in a markdown or MDX document, on one line
+pub fn plain_text(markdown: &str) -> String {
+    let mut text = String::new();
+    let mut in_fence = false;
+    let mut in_esm = false;
+    for line in markdown.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_fence = !in_fence;
+            continue;
+        }
+        if in_fence {
+            continue;
+        }
+        // an import or export continues until the next blank line
+        if in_esm || line.starts_with("import ") || line.starts_with("export ") {
+            in_esm = !trimmed.is_empty();
+            continue;
+        }
+        // thematic breaks and setext heading underlines
+        if !trimmed.is_empty() && trimmed.chars().all(|c| "-*_= ".contains(c)) {
+            continue;
+        }
+        let content = trimmed.trim_start_matches(['#', '>', ' ']);
+        let content = ["- ", "* ", "+ "]
+            .iter()
+            .find_map(|marker| content.strip_prefix(marker))
+            .unwrap_or(content);
+        let content = match content.split_once(". ") {
+            Some((number, rest)) if number.chars().all(|c| c.is_ascii_digit()) => rest,
+            _ => content,
+        };
+        push_inline_text(content, &mut text);
+    }
+    text.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+/// The text of a page from its `.md` or `.mdx` source
+pub fn page_text(source: &str) -> String {
+    match frontmatter::extract(source) {
+        Ok((_, body)) => plain_text(body),
+        Err(_) => plain_text(source),
+    }
+}
+
+fn text_path(project_root_dir: &Path) -> PathBuf {
+    project_root_dir.join(CACHE_DIR).join(TEXT_FILE)
+}
+
+/// The page text recorded by the last build
+pub fn read_text(project_root_dir: &Path) -> BTreeMap<String, String> {
+    fs::read_to_string(text_path(project_root_dir))
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+pub fn write_text(project_root_dir: &Path, text: &BTreeMap<String, String>) -> Result<()> {
+    let path = text_path(project_root_dir);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .wrap_err_with(|| format!("Failed to create cache directory `{}`", dir.display()))?;
+    }
+    fs::write(&path, serde_json::to_string(text)?)
+        .wrap_err_with(|| format!("Failed to write `{}`", path.display()))
+}
+
+/// Replace the text of one page, or remove it if it isn't built anymore,
+/// for rebuilds in watch mode
+pub fn update_text(project_root_dir: &Path, slug: &str, page_text: Option<String>) -> Result<()> {
+    let mut text = read_text(project_root_dir);
+    match page_text {
+        Some(page_text) => text.insert(slug.to_string(), page_text),
+        None => text.remove(slug),
+    };
+    write_text(project_root_dir, &text)
+}
+
+/// One document for every page in the collection, by slug. Pages with text
+/// but no frontmatter are included too.
+fn documents(
+    config: &SearchConfig,
+    entries: &[PageEntry],
+    text: &BTreeMap<String, String>,
+    url_style: UrlStyle,
+    base_path: Option<&str>,
+) -> Vec<Map<String, Value>> {
+    let mut pages: BTreeMap<&str, Option<&Value>> =
+        text.keys().map(|slug| (slug.as_str(), None)).collect();
+    for entry in entries {
+        pages.insert(entry.slug.as_str(), Some(&entry.data));
+    }
+    pages
+        .into_iter()
+        .filter(|(slug, _)| config.matches(slug))
+        .map(|(slug, data)| {
+            let url = url_style.url(slug);
+            let url = match base_path {
+                Some(base_path) => prefix_url(base_path, &url),
+                None => url,
+            };
+            let mut document = Map::new();
+            document.insert(String::from("id"), Value::String(slug.to_string()));
+            document.insert(String::from("url"), Value::String(url));
+            for field in &config.fields {
+                if let Some(value) = data.and_then(|data| data.get(field)) {
+                    document.insert(field.clone(), value.clone());
+                }
+            }
+            if config.body {
+                let body = text.get(slug).cloned().unwrap_or_default();
+                document.insert(String::from("body"), Value::String(body));
+            }
+            document
+        })
+        .collect()
+}
+
+fn render_stork(config: &SearchConfig, documents: &[Map<String, Value>]) -> Result<String> {
+    let as_str = |document: &Map<String, Value>, key: &str| {
+        document
+            .get(key)
+            .and_then(|value| value.as_str())
+            .map(String::from)
+    };
+    let files = documents
+        .iter()
+        .map(|document| {
+            // stork only searches `contents`, so the other fields go there
+            let contents = config
+                .fields
+                .iter()
+                .filter(|field| field.as_str() != "title")
+                .filter_map(|field| document.get(field))
+                .map(|value| match value {
+                    Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                })
+                .chain(as_str(document, "body"))
+                .collect::<Vec<String>>()
+                .join("\n");
+            StorkFile {
+                title: as_str(document, "title")
+                    .or_else(|| as_str(document, "id"))
+                    .unwrap_or_default(),
+                url: as_str(document, "url").unwrap_or_default(),
+                contents,
+            }
+        })
+        .collect();
+    Ok(toml::to_string(&StorkConfig {
+        input: StorkInput { files },
+    })?)
+}
+
+/// Write every index to `toast-data/search`, replacing indexes that
+/// aren't configured anymore
+pub fn write_search_indexes(
+    configs: &[SearchConfig],
+    entries: &[PageEntry],
+    text: &BTreeMap<String, String>,
+    url_style: UrlStyle,
+    base_path: Option<&str>,
+    output_dir: &Path,
+) -> Result<()> {
+    if configs.is_empty() {
+        return Ok(());
+    }
+    let dir = output_dir.join(DATA_DIR).join("search");
+    if dir.exists() {
+        fs::remove_dir_all(&dir).wrap_err_with(|| {
+            format!(
+                "Failed to remove old search indexes from `{}`",
+                dir.display()
+            )
+        })?;
+    }
+    fs::create_dir_all(&dir)
+        .wrap_err_with(|| format!("Failed to create directory `{}`", dir.display()))?;
+    for config in configs {
+        let documents = documents(config, entries, text, url_style, base_path);
+        let contents = match config.format {
+            SearchFormat::Json => serde_json::to_string(&documents)?,
+            SearchFormat::Stork => render_stork(config, &documents)?,
+        };
+        let file = dir.join(config.file_name());
+        fs::write(&file, contents)
+            .wrap_err_with(|| format!("Failed to write search index `{}`", file.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_plain_text() {
+        let mdx = r#"import { Chart } from "../components/chart.js";
+
+# Getting *started*
+
+Install it with [npm](https://npmjs.com), then:
+
+```js
+toast.build();
+```
+
+- one <Chart data={[1, 2]} />
+- two, since 1 < 2
+
+> ![a toaster](/toaster.png) `toast` is done
+"#;
+        assert_eq!(
+            plain_text(mdx),
+            "Getting started Install it with npm, then: one two, since 1 < 2 a toaster toast is done"
+        );
+    }
+
+    #[test]
+    fn test_documents() {
+        let config = SearchConfig {
+            collection: String::from("docs"),
+            fields: default_fields(),
+            body: true,
+            format: SearchFormat::Json,
+        };
+        let entries = vec![
+            PageEntry {
+                slug: String::from("/docs/install"),
+                data: json!({ "title": "Install", "draft": false }),
+            },
+            PageEntry {
+                slug: String::from("/blog/hello"),
+                data: json!({ "title": "Hello" }),
+            },
+        ];
+        let text: BTreeMap<String, String> = vec![
+            (
+                String::from("/docs/install"),
+                String::from("Run npm install"),
+            ),
+            (String::from("/docs/faq"), String::from("Questions")),
+        ]
+        .into_iter()
+        .collect();
+        let documents = documents(
+            &config,
+            &entries,
+            &text,
+            UrlStyle::Directory,
+            Some("/toast"),
+        );
+        assert_eq!(
+            serde_json::to_value(&documents).unwrap(),
+            json!([
+                { "id": "/docs/faq", "url": "/toast/docs/faq/", "body": "Questions" },
+                {
+                    "id": "/docs/install",
+                    "url": "/toast/docs/install/",
+                    "title": "Install",
+                    "body": "Run npm install"
+                }
+            ])
+        );
+    }
+}
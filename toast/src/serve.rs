@@ -0,0 +1,217 @@
+use async_std::task;
+use color_eyre::eyre::{Result, WrapErr};
+use std::{
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tide::{sse::Sender, Body, Redirect, Request, Response, StatusCode};
+use tracing::instrument;
+
+use crate::{base_path::strip_base_path, url_style::UrlStyle};
+
+const LIVE_RELOAD_PATH: &str = "/__toast/livereload";
+
+/// Served for any url that doesn't match a file in the output directory
+pub const NOT_FOUND_PAGE: &str = "404.html";
+
+/// Injected into html pages when live reload is enabled. Uses an
+/// EventSource because tide 0.13 doesn't support websockets and we
+/// only ever push events from the server to the browser.
+const LIVE_RELOAD_CLIENT: &str = r#"<script>
+new EventSource("/__toast/livereload").addEventListener("reload", () => window.location.reload());
+</script>"#;
+
+/// Tells browsers connected to the dev server to reload the page.
+/// Cloned into the watcher so it can signal after each rebuild.
+#[derive(Debug, Clone, Default)]
+pub struct LiveReload {
+    generation: Arc<AtomicUsize>,
+}
+
+impl LiveReload {
+    pub fn reload(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+    fn generation(&self) -> usize {
+        self.generation.load(Ordering::SeqCst)
+    }
+}
+
+#[derive(Clone)]
+struct ServeState {
+    output_dir: PathBuf,
+    url_style: UrlStyle,
+    base_path: Option<String>,
+    live_reload: Option<LiveReload>,
+}
+
+/// Serve an already-built output directory over http until the process
+/// is killed. If `live_reload` is set, html pages are served with a
+/// small client that reloads the page whenever `LiveReload::reload`
+/// is called. With the `directory` url style, directories are redirected
+/// to their url with a trailing slash like most static hosts do. With a
+/// `base_path`, the site is served under it and `/` redirects there.
+#[instrument]
+pub async fn serve(
+    output_dir: PathBuf,
+    port: u16,
+    url_style: UrlStyle,
+    base_path: Option<String>,
+    live_reload: Option<LiveReload>,
+) -> Result<()> {
+    let mut app = tide::with_state(ServeState {
+        output_dir,
+        url_style,
+        base_path,
+        live_reload,
+    });
+    app.at(LIVE_RELOAD_PATH)
+        .get(tide::sse::endpoint(live_reload_events));
+    app.at("/").get(serve_file);
+    app.at("/*path").get(serve_file);
+
+    let addr = format!("127.0.0.1:{}", port);
+    eprintln!("serving site at http://{}", addr);
+    app.listen(addr.clone())
+        .await
+        .wrap_err_with(|| format!("Failed to listen on `{}`", addr))?;
+    Ok(())
+}
+
+async fn serve_file(req: Request<ServeState>) -> tide::Result {
+    let state = req.state();
+    let full_path = req.url().path();
+    if let Some(base_path) = &state.base_path {
+        if full_path == "/" {
+            return Ok(Redirect::temporary(format!("{}/", base_path)).into());
+        }
+    }
+    let url_path = strip_base_path(state.base_path.as_deref(), full_path);
+    if state.url_style == UrlStyle::Directory
+        && !url_path.ends_with('/')
+        && state
+            .output_dir
+            .join(url_path.trim_start_matches('/'))
+            .join("index.html")
+            .is_file()
+    {
+        return Ok(Redirect::permanent(format!("{}/", full_path)).into());
+    }
+    match resolve_request_path(&state.output_dir, url_path) {
+        Some(file_path) => file_response(state, &file_path, StatusCode::Ok).await,
+        None => {
+            // `src/pages/404.js` is rendered to `404.html`, which static
+            // hosts like Netlify and Vercel also serve for unknown routes
+            let not_found = state.output_dir.join(NOT_FOUND_PAGE);
+            if not_found.is_file() {
+                file_response(state, &not_found, StatusCode::NotFound).await
+            } else {
+                Ok(Response::new(StatusCode::NotFound))
+            }
+        }
+    }
+}
+
+async fn file_response(state: &ServeState, file_path: &Path, status: StatusCode) -> tide::Result {
+    let content_type = content_type_for(file_path);
+    let body = match &state.live_reload {
+        Some(_) if content_type.starts_with("text/html") => {
+            let html = async_std::fs::read_to_string(file_path).await?;
+            Body::from_string(inject_live_reload_client(&html))
+        }
+        _ => Body::from_file(file_path).await?,
+    };
+    Ok(Response::builder(status)
+        .body(body)
+        .content_type(content_type)
+        .build())
+}
+
+async fn live_reload_events(req: Request<ServeState>, sender: Sender) -> tide::Result<()> {
+    let live_reload = match &req.state().live_reload {
+        Some(live_reload) => live_reload.clone(),
+        None => return Ok(()),
+    };
+    let mut seen = live_reload.generation();
+    let mut last_sent = Instant::now();
+    loop {
+        task::sleep(Duration::from_millis(200)).await;
+        let current = live_reload.generation();
+        if current != seen {
+            seen = current;
+            sender.send("reload", "", None).await?;
+            last_sent = Instant::now();
+        } else if last_sent.elapsed() > Duration::from_secs(10) {
+            // sending fails once the browser disconnects, which is
+            // the only way we find out to stop this loop
+            sender.send("ping", "", None).await?;
+            last_sent = Instant::now();
+        }
+    }
+}
+
+fn inject_live_reload_client(html: &str) -> String {
+    match html.rfind("</body>") {
+        Some(index) => format!("{}{}{}", &html[..index], LIVE_RELOAD_CLIENT, &html[index..]),
+        None => format!("{}{}", html, LIVE_RELOAD_CLIENT),
+    }
+}
+
+/// Map a url path onto a file in the output directory.
+///
+/// `/` and `/some/dir/` resolve to `index.html` files, and extensionless
+/// paths like `/about` fall back to `about.html` so that urls match what
+/// `toast-render` writes out.
+fn resolve_request_path(output_dir: &Path, url_path: &str) -> Option<PathBuf> {
+    let relative = Path::new(url_path.trim_start_matches('/'));
+    // never walk out of the output directory
+    if relative
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_)))
+    {
+        return None;
+    }
+    let file_path = output_dir.join(relative);
+    if file_path.is_file() {
+        return Some(file_path);
+    }
+    if file_path.is_dir() {
+        let index = file_path.join("index.html");
+        return if index.is_file() { Some(index) } else { None };
+    }
+    if file_path.extension().is_none() {
+        let html = file_path.with_extension("html");
+        if html.is_file() {
+            return Some(html);
+        }
+    }
+    None
+}
+
+/// Content-Type for a file based on its extension. Browsers refuse to
+/// execute ES modules that aren't served with a JavaScript mime type.
+pub fn content_type_for(path: &Path) -> &'static str {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("html") => "text/html; charset=utf-8",
+        Some("js") | Some("mjs") => "application/javascript; charset=utf-8",
+        Some("json") | Some("map") => "application/json",
+        Some("css") => "text/css; charset=utf-8",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("avif") => "image/avif",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("wasm") => "application/wasm",
+        _ => "application/octet-stream",
+    }
+}
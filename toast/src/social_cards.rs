@@ -0,0 +1,286 @@
+//! Social share images for pages, turned on with `[social_cards]` in
+//! `toast.config.toml`:
+//!
+//! ```toml
+//! [social_cards]
+//! enabled = true
+//! site_name = "toast.dev"
+//! background = "#1a1a2e"
+//! color = "#ffffff"
+//! accent = "#ffb600"
+//! ```
+//!
+//! Every page with a `title` in its frontmatter gets a 1200x630 card with
+//! the title, the `description` if there is one, and the site name. Cards
+//! are drawn as SVG here and turned into PNGs by `toast-social-cards`
+//! (which uses sharp), then written to `_social/` in the output directory
+//! and linked from the page with an `og:image` meta tag.
+//!
+//! Pages can set `og_image` in their frontmatter to use their own image
+//! instead, or `social_card: false` to go without. Cards are only drawn
+//! again when the page's title, description, or the card config change.
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use duct::cmd;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use tracing::instrument;
+
+use crate::{
+    cache::{content_hash, Cache},
+    feeds::escape_xml,
+    frontmatter::{self, page_slug},
+    node::node_bin,
+    npm_bin::node_script,
+    pages::PageRoot,
+    renderer::html_output_path,
+    supervisor::{batch_timeout, run_node},
+};
+
+/// Directory in the output directory that cards are written to
+pub const SOCIAL_CARDS_OUTPUT_DIR: &str = "_social";
+
+const CARD_WIDTH: u32 = 1200;
+const CARD_HEIGHT: u32 = 630;
+
+/// Titles are 64px, which fits about this many characters on a line
+const TITLE_LINE_CHARS: usize = 28;
+const TITLE_MAX_LINES: usize = 3;
+const DESCRIPTION_LINE_CHARS: usize = 56;
+const DESCRIPTION_MAX_LINES: usize = 2;
+
+/// `[social_cards]` in `toast.config.toml`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SocialCardConfig {
+    pub enabled: bool,
+    /// shown at the bottom of every card
+    pub site_name: Option<String>,
+    pub background: String,
+    /// the title and description
+    pub color: String,
+    /// the bar along the top and the site name
+    pub accent: String,
+}
+
+impl Default for SocialCardConfig {
+    fn default() -> Self {
+        SocialCardConfig {
+            enabled: false,
+            site_name: None,
+            background: String::from("#1a1a2e"),
+            color: String::from("#ffffff"),
+            accent: String::from("#ffb600"),
+        }
+    }
+}
+
+/// What `toast-social-cards` is asked to do for one card
+#[derive(Debug, Serialize)]
+struct CardJob {
+    svg: String,
+    output: PathBuf,
+}
+
+/// Break `text` into lines of at most `line_chars` characters, ending the
+/// last line with an ellipsis if it doesn't fit in `max_lines`
+fn wrap(text: &str, line_chars: usize, max_lines: usize) -> Vec<String> {
+    let mut lines: Vec<String> = vec![];
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        if !line.is_empty() && line.chars().count() + 1 + word.chars().count() > line_chars {
+            lines.push(std::mem::take(&mut line));
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(word);
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    if lines.len() > max_lines {
+        lines.truncate(max_lines);
+        if let Some(last) = lines.last_mut() {
+            last.push('…');
+        }
+    }
+    lines
+}
+
+fn text_lines(lines: &[String], x: u32, y: u32, line_height: u32) -> String {
+    lines
+        .iter()
+        .enumerate()
+        .map(|(i, line)| {
+            format!(
+                r#"<tspan x="{}" y="{}">{}</tspan>"#,
+                x,
+                y + i as u32 * line_height,
+                escape_xml(line)
+            )
+        })
+        .collect()
+}
+
+/// The card for one page, as an SVG document
+pub fn card_svg(title: &str, description: Option<&str>, config: &SocialCardConfig) -> String {
+    let title_lines = wrap(title, TITLE_LINE_CHARS, TITLE_MAX_LINES);
+    let description_lines = description
+        .map(|description| wrap(description, DESCRIPTION_LINE_CHARS, DESCRIPTION_MAX_LINES))
+        .unwrap_or_default();
+    let description_y = 160 + title_lines.len() as u32 * 80 + 30;
+    format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" viewBox="0 0 {width} {height}"><rect width="{width}" height="{height}" fill="{background}"/><rect width="{width}" height="16" fill="{accent}"/><text font-family="sans-serif" font-size="64" font-weight="700" fill="{color}">{title}</text><text font-family="sans-serif" font-size="32" fill="{color}" fill-opacity="0.8">{description}</text><text x="80" y="{site_y}" font-family="sans-serif" font-size="32" font-weight="700" fill="{accent}">{site_name}</text></svg>"#,
+        width = CARD_WIDTH,
+        height = CARD_HEIGHT,
+        background = escape_xml(&config.background),
+        accent = escape_xml(&config.accent),
+        color = escape_xml(&config.color),
+        title = text_lines(&title_lines, 80, 160, 80),
+        description = text_lines(&description_lines, 80, description_y, 44),
+        site_y = CARD_HEIGHT - 70,
+        site_name = escape_xml(config.site_name.as_deref().unwrap_or("")),
+    )
+}
+
+/// Add an `og:image` meta tag to the end of the head, unless the page
+/// already has one
+pub fn inject_og_image(html: &str, image_url: &str) -> Option<String> {
+    if html.contains(r#"property="og:image""#) {
+        return None;
+    }
+    let head_end = html.find("</head>")?;
+    let tag = format!(
+        r#"<meta property="og:image" content="{}"><meta name="twitter:card" content="summary_large_image">"#,
+        image_url.replace('&', "&amp;").replace('"', "&quot;")
+    );
+    Some(format!("{}{}{}", &html[..head_end], tag, &html[head_end..]))
+}
+
+/// `_social/blog/post.png` for `/blog/post`, and `_social/index.png` for
+/// the home page
+fn card_path(slug: &str) -> String {
+    let slug = slug.trim_matches('/');
+    let name = if slug.is_empty() { "index" } else { slug };
+    format!("{}/{}.png", SOCIAL_CARDS_OUTPUT_DIR, name)
+}
+
+/// Draw cards for `pages` that have changed since the last build and link
+/// them from the rendered html
+#[instrument(skip(cache))]
+pub fn write_social_cards(
+    output_dir: &Path,
+    npm_bin_dir: &Path,
+    page_roots: &[PageRoot],
+    pages: &[String],
+    base_url: Option<&str>,
+    config: &SocialCardConfig,
+    cache: &mut Cache,
+) -> Result<()> {
+    let base_url = base_url.unwrap_or("").trim_end_matches('/');
+    let frontmatter_index = frontmatter::read_index(output_dir);
+    let mut jobs: Vec<(String, String, CardJob)> = vec![];
+    for page in pages {
+        let slug = page_slug(page_roots, page);
+        let frontmatter = match frontmatter_index.get(&slug) {
+            Some(frontmatter) => frontmatter,
+            None => continue,
+        };
+        if frontmatter.get("social_card").and_then(Value::as_bool) == Some(false) {
+            continue;
+        }
+        let image_url = match frontmatter.get("og_image").and_then(Value::as_str) {
+            Some(url) if url.starts_with('/') => format!("{}{}", base_url, url),
+            Some(url) => url.to_string(),
+            None => {
+                let title = match frontmatter.get("title").and_then(Value::as_str) {
+                    Some(title) => title,
+                    None => continue,
+                };
+                let description = frontmatter.get("description").and_then(Value::as_str);
+                let svg = card_svg(title, description, config);
+                let path = card_path(&slug);
+                let output = output_dir.join(&path);
+                let hash = content_hash(&[svg.as_bytes()]);
+                let cache_key = format!("social_card:{}", slug);
+                if !cache.is_fresh(&cache_key, &hash) || !output.exists() {
+                    jobs.push((cache_key, hash, CardJob { svg, output }));
+                }
+                format!("{}/{}", base_url, path)
+            }
+        };
+
+        let html_path = html_output_path(output_dir, page_roots, page);
+        let html = match fs::read_to_string(&html_path) {
+            Ok(html) => html,
+            // pages that failed to render
+            Err(_) => continue,
+        };
+        if let Some(html) = inject_og_image(&html, &image_url) {
+            fs::write(&html_path, html)
+                .wrap_err_with(|| format!("Failed to write `{}`", html_path.display()))?;
+        }
+    }
+
+    if !jobs.is_empty() {
+        run_toast_social_cards(
+            &jobs.iter().map(|(_, _, job)| job).collect::<Vec<_>>(),
+            npm_bin_dir,
+        )?;
+        for (cache_key, hash, job) in jobs {
+            cache.record_outputs(&cache_key, hash, vec![job.output]);
+        }
+    }
+    Ok(())
+}
+
+/// All of the cards are sent to one node process as JSON on stdin, which
+/// writes each PNG and exits
+#[instrument(skip(jobs))]
+fn run_toast_social_cards(jobs: &[&CardJob], npm_bin_dir: &Path) -> Result<()> {
+    let bin = node_script(npm_bin_dir, "toast-social-cards");
+    let bin_str = bin
+        .to_str()
+        .ok_or_else(|| eyre!("failed to make npm bin into str"))?;
+    run_node(
+        "toast-social-cards",
+        cmd!(node_bin(), "--unhandled-rejections", "strict", bin_str)
+            .stdin_bytes(serde_json::to_vec(jobs)?),
+        batch_timeout(jobs.len()),
+    )
+    .wrap_err("Failed to draw social cards with `toast-social-cards`")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrap() {
+        assert_eq!(
+            wrap("Stacking your JAM with toast", 12, 3),
+            vec!["Stacking", "your JAM", "with toast"]
+        );
+        assert_eq!(
+            wrap("one two three four", 8, 1),
+            vec![String::from("one two…")]
+        );
+        assert_eq!(card_path("/"), "_social/index.png");
+        assert_eq!(card_path("/blog/post"), "_social/blog/post.png");
+    }
+
+    #[test]
+    fn test_inject_og_image() {
+        let html = "<html><head><title>Hi</title></head><body></body></html>";
+        let injected = inject_og_image(html, "https://toast.dev/_social/index.png").unwrap();
+        assert!(injected.contains(
+            r#"<title>Hi</title><meta property="og:image" content="https://toast.dev/_social/index.png">"#
+        ));
+        assert_eq!(inject_og_image(&injected, "/other.png"), None);
+    }
+}
@@ -0,0 +1,216 @@
+//! The data-sourcing phase of a build.
+//!
+//! Before pages are rendered, toast runs the `sourceData` export of the
+//! project's data file (`toast.js`, or `src/data.js` if there is no
+//! `toast.js`) in node. Every call to `setDataForSlug(slug, page)` in
+//! that function is written to stdout as a single line of JSON prefixed
+//! with `toast-record:`. Each record is deserialized into a
+//! `SetDataForSlug`, and `incremental_compile` creates a page for it.
+//!
+//! ```js
+//! export const sourceData = async ({ setDataForSlug }) => {
+//!   await setDataForSlug("/hello", {
+//!     component: { mode: "source", value: "export default () => <h1>hi</h1>" },
+//!     data: { some: "props" },
+//!   });
+//! };
+//! ```
+//!
+//! Any other output from the data file is passed through to the terminal.
+//! `sourceData` also gets the data file's `remoteData`, which toast has
+//! already fetched, see `remote_data`.
+use duct::{cmd, ReaderHandle};
+use std::{
+    ffi::OsString,
+    io::{prelude::*, BufReader},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use thiserror::Error;
+use tracing::instrument;
+
+use crate::{
+    cancel,
+    internal_api::SetDataForSlug,
+    node::node_bin,
+    npm_bin::{node_loader, node_script},
+    progress::Progress,
+    supervisor::{StderrTail, Watchdog},
+};
+
+/// Marks lines of stdout that are records rather than user output.
+/// Must match the prefix in `toast-source-data.mjs`.
+pub const RECORD_PREFIX: &str = "toast-record:";
+
+#[derive(Error, Debug)]
+pub enum SourceDataError {
+    #[error("npm bin path `{0}` is not valid utf8")]
+    InvalidBinPath(PathBuf),
+    #[error("failed to start node to source data from `{file}`")]
+    Spawn {
+        file: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("failed reading output while sourcing data from `{file}`")]
+    Read {
+        file: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("`setDataForSlug` in `{file}` was called with a payload toast couldn't understand: {record}")]
+    InvalidRecord {
+        file: PathBuf,
+        record: String,
+        #[source]
+        source: serde_json::Error,
+    },
+    #[error("node exited with code {code:?} while sourcing data from `{file}`{output}")]
+    Exited {
+        file: PathBuf,
+        code: Option<i32>,
+        output: String,
+    },
+    #[error("`{file}` went {seconds}s without any output while sourcing data, so it was stopped. Pass `--render-timeout` to wait longer{output}")]
+    TimedOut {
+        file: PathBuf,
+        seconds: u64,
+        output: String,
+    },
+}
+
+/// The file whose `sourceData` export creates pages, if the project has one
+pub fn find_data_file(project_root_dir: &Path) -> Option<PathBuf> {
+    [
+        project_root_dir.join("toast.js"),
+        project_root_dir.join("src").join("data.js"),
+    ]
+    .iter()
+    .find(|path| path.exists())
+    .cloned()
+}
+
+/// Run the data file's `sourceData` and collect every record it creates,
+/// in the order they were created. `remote_data` is the file
+/// `remote_data::fetch_remote_data` wrote the data file's remote sources
+/// to, if it has any. It's stopped if it goes `timeout` without output.
+#[instrument(skip(progress))]
+pub fn source_data(
+    data_file: &Path,
+    npm_bin_dir: &Path,
+    remote_data: Option<&Path>,
+    timeout: Option<Duration>,
+    progress: &Progress,
+) -> Result<Vec<SetDataForSlug>, SourceDataError> {
+    let bin = node_script(npm_bin_dir, "toast-source-data");
+    let bin_str = bin
+        .to_str()
+        .ok_or_else(|| SourceDataError::InvalidBinPath(bin.clone()))?;
+    let mut args: Vec<OsString> = vec![
+        "--unhandled-rejections".into(),
+        "strict".into(),
+        "--loader".into(),
+        node_loader(npm_bin_dir).into(),
+        bin_str.into(),
+        data_file.into(),
+    ];
+    if let Some(remote_data) = remote_data {
+        let mut flag = OsString::from("--remote-data=");
+        flag.push(remote_data);
+        args.push(flag);
+    }
+    let reader = cmd(node_bin(), args)
+        .stderr_to_stdout()
+        .unchecked()
+        .reader()
+        .map_err(|source| SourceDataError::Spawn {
+            file: data_file.to_path_buf(),
+            source,
+        })?;
+    let pids = reader.pids();
+    for pid in &pids {
+        cancel::track_child(*pid);
+    }
+    let reader = Arc::new(reader);
+    let watchdog_reader = reader.clone();
+    let watchdog = Watchdog::start(timeout, move || {
+        let _ = watchdog_reader.kill();
+    });
+    let output = StderrTail::default();
+    let records = read_records(data_file, &reader, &watchdog, &output, progress);
+    for pid in &pids {
+        cancel::untrack_child(*pid);
+    }
+    if watchdog.timed_out() {
+        return Err(SourceDataError::TimedOut {
+            file: data_file.to_path_buf(),
+            seconds: timeout.unwrap_or_default().as_secs(),
+            output: output.text(),
+        });
+    }
+    let records = records?;
+
+    match reader.try_wait() {
+        Ok(Some(status)) if status.status.success() => Ok(records),
+        Ok(Some(status)) => Err(SourceDataError::Exited {
+            file: data_file.to_path_buf(),
+            code: status.status.code(),
+            output: output.text(),
+        }),
+        Ok(None) => Err(SourceDataError::Exited {
+            file: data_file.to_path_buf(),
+            code: None,
+            output: output.text(),
+        }),
+        Err(source) => Err(SourceDataError::Read {
+            file: data_file.to_path_buf(),
+            source,
+        }),
+    }
+}
+
+/// Collect the records in `reader`, passing everything else through and
+/// keeping its tail in `output`. Any line counts as progress, so a data
+/// file is only stopped for going quiet.
+fn read_records(
+    data_file: &Path,
+    reader: &ReaderHandle,
+    watchdog: &Watchdog,
+    output: &StderrTail,
+    progress: &Progress,
+) -> Result<Vec<SetDataForSlug>, SourceDataError> {
+    let mut records = vec![];
+    let mut passthrough_lines = 0;
+    for line in BufReader::new(reader).lines() {
+        watchdog.progress();
+        let line = line.map_err(|source| SourceDataError::Read {
+            file: data_file.to_path_buf(),
+            source,
+        })?;
+        match line.strip_prefix(RECORD_PREFIX) {
+            Some(record) => {
+                let mut set: SetDataForSlug = serde_json::from_str(record).map_err(|source| {
+                    SourceDataError::InvalidRecord {
+                        file: data_file.to_path_buf(),
+                        record: record.to_string(),
+                        source,
+                    }
+                })?;
+                set.normalize();
+                progress.discovered(1);
+                progress.status(set.slug.as_str());
+                records.push(set);
+            }
+            None => {
+                // this magic number pulls off the --loader warning
+                if passthrough_lines > 1 {
+                    output.push(line.clone());
+                    progress.println(line);
+                }
+                passthrough_lines += 1;
+            }
+        }
+    }
+    Ok(records)
+}
@@ -0,0 +1,68 @@
+//! Source maps for the browser builds of modules, written next to each
+//! one as `<module>.js.map`. `toast serve` always writes them, and other
+//! builds do with `--source-maps` or `source_maps = true` in
+//! `toast.config.toml`.
+//!
+//! Maps point devtools at the source toast compiled, at its path in the
+//! project like `/src/components/nav.tsx`, and include its contents. MDX
+//! pages map to the JSX the mdx compiler generated from them.
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+
+/// Point the map swc generated at `source_id`, with `source` inlined
+pub fn finish_map(map: &str, source_id: &str, source: &str) -> String {
+    match serde_json::from_str::<Value>(map) {
+        Ok(Value::Object(mut map)) => {
+            let source_path = format!("/{}", source_id.trim_start_matches('/'));
+            map.insert(String::from("sources"), Value::from(vec![source_path]));
+            map.insert(String::from("sourcesContent"), Value::from(vec![source]));
+            Value::Object(map).to_string()
+        }
+        _ => map.to_string(),
+    }
+}
+
+/// Where the map for `browser_output_file` is written
+pub fn map_path(browser_output_file: &Path) -> PathBuf {
+    let mut path = browser_output_file.as_os_str().to_owned();
+    path.push(".map");
+    PathBuf::from(path)
+}
+
+/// `code` with a comment pointing at its map, which is next to it
+pub fn with_mapping_url(code: &str, browser_output_file: &Path) -> String {
+    let file_name = map_path(browser_output_file)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    format!("{}\n//# sourceMappingURL={}\n", code.trim_end(), file_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_finish_map() {
+        let map = finish_map(
+            r#"{"version":3,"sources":["src/pages/index.tsx"],"mappings":"AAAA"}"#,
+            "src/pages/index.tsx",
+            "export default () => <h1>hi</h1>;",
+        );
+        let map: Value = serde_json::from_str(&map).unwrap();
+        assert_eq!(map["sources"][0], "/src/pages/index.tsx");
+        assert_eq!(
+            map["sourcesContent"][0],
+            "export default () => <h1>hi</h1>;"
+        );
+        assert_eq!(map["mappings"], "AAAA");
+    }
+
+    #[test]
+    fn test_with_mapping_url() {
+        assert_eq!(
+            with_mapping_url("export default 1;\n", Path::new("/site/public/src/a.js")),
+            "export default 1;\n//# sourceMappingURL=a.js.map\n"
+        );
+    }
+}
@@ -0,0 +1,162 @@
+//! Building into a staging copy of the output directory and swapping it
+//! in once the build succeeds, so a server pointed at the output directory
+//! never serves a half-written site, and a failed or interrupted build
+//! leaves the last good one in place.
+//!
+//! The staging directory is next to the output directory, so the swap is
+//! a pair of renames on the same filesystem. It starts as a copy of the
+//! output directory, which lets the incremental cache skip whatever
+//! hasn't changed. Once it's swapped in, the build is flushed, which is
+//! when an `s3://` output directory is sent to its bucket.
+use color_eyre::eyre::{Result, WrapErr};
+use fs_extra::dir::{copy, CopyOptions};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use tracing::instrument;
+
+use crate::{cache::Cache, cancel, exit_code::Failure, incremental::IncrementalOpts};
+
+fn sibling(output_dir: &Path, suffix: &str) -> PathBuf {
+    let name = output_dir
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    output_dir.with_file_name(format!(".{}.{}", name, suffix))
+}
+
+/// Where the next build of `output_dir` is staged
+pub fn staging_dir(output_dir: &Path) -> PathBuf {
+    sibling(output_dir, "toast-staging")
+}
+
+/// Where the output directory is moved to while a staging directory
+/// takes its place
+pub fn previous_dir(output_dir: &Path) -> PathBuf {
+    sibling(output_dir, "toast-previous")
+}
+
+fn remove_dir(dir: &Path) -> Result<()> {
+    if dir.exists() {
+        fs::remove_dir_all(dir)
+            .wrap_err_with(|| format!("Failed to remove `{}`", dir.display()))?;
+    }
+    Ok(())
+}
+
+/// Start a staging directory for `output_dir` from a copy of it. Anything
+/// left over from an interrupted build is thrown away first.
+#[instrument]
+pub fn stage(output_dir: &Path) -> Result<PathBuf> {
+    let staging = staging_dir(output_dir);
+    remove_dir(&staging)?;
+    fs::create_dir_all(&staging)
+        .wrap_err_with(|| format!("Failed to create `{}`", staging.display()))?;
+    if output_dir.exists() {
+        let options = CopyOptions {
+            content_only: true,
+            overwrite: true,
+            ..CopyOptions::new()
+        };
+        copy(output_dir, &staging, &options).wrap_err_with(|| {
+            format!(
+                "Failed to copy `{}` to `{}`",
+                output_dir.display(),
+                staging.display()
+            )
+        })?;
+    }
+    Ok(staging)
+}
+
+/// Replace `output_dir` with the finished `staging` directory
+#[instrument]
+pub fn swap_in(staging: &Path, output_dir: &Path) -> Result<()> {
+    let previous = previous_dir(output_dir);
+    remove_dir(&previous)?;
+    if output_dir.exists() {
+        fs::rename(output_dir, &previous).wrap_err_with(|| {
+            format!("Failed to move `{}` out of the way", output_dir.display())
+        })?;
+    }
+    if let Err(e) = fs::rename(staging, output_dir) {
+        if previous.exists() {
+            // put the last build back rather than leave nothing there
+            let _ = fs::rename(&previous, output_dir);
+        }
+        return Err(e).wrap_err_with(|| {
+            format!(
+                "Failed to move `{}` to `{}`",
+                staging.display(),
+                output_dir.display()
+            )
+        });
+    }
+    remove_dir(&previous)
+}
+
+/// Throw away a staging directory after a failed build
+pub fn discard(staging: &Path) -> Result<()> {
+    remove_dir(staging)
+}
+
+/// Run `build` with `opts.output_dir` pointed at a staging copy of it, and
+/// swap the result in if it succeeds. If it fails, or reported errors the
+/// failure policy doesn't keep going past, the staging directory is
+/// thrown away along with the outputs `cache` recorded in it.
+#[instrument(skip(opts, cache, build))]
+pub fn build_staged<'a, T>(
+    opts: &IncrementalOpts<'a>,
+    cache: &mut Cache,
+    build: impl FnOnce(IncrementalOpts<'a>, &mut Cache) -> Result<T>,
+) -> Result<T> {
+    let _building = cancel::building();
+    let output_dir = &opts.output_dir;
+    let staging = staging_dir(output_dir);
+    // an interrupted build can leave its outputs recorded in a staging
+    // directory that's about to be replaced
+    cache.relocate_outputs(&staging, output_dir);
+    let staging = stage(output_dir)?;
+    let checkpoint = cache.checkpoint();
+    cache.relocate_outputs(output_dir, &staging);
+    let staged_opts = IncrementalOpts {
+        output_dir: staging.clone(),
+        ..opts.clone()
+    };
+    let result = build(staged_opts, cache)
+        .and_then(|built| opts.diagnostics.check().map(|_| built))
+        .and_then(|built| swap_in(&staging, output_dir).map(|_| built));
+    match result {
+        Ok(built) => {
+            cache.relocate_outputs(&staging, output_dir);
+            cache.save()?;
+            opts.fs.flush().wrap_err(Failure::Deploy)?;
+            Ok(built)
+        }
+        Err(e) => {
+            cache.rollback(checkpoint);
+            cache.save()?;
+            discard(&staging)?;
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_staging_dir() {
+        let output_dir = Path::new("/site/public");
+        assert_eq!(
+            staging_dir(output_dir),
+            PathBuf::from("/site/.public.toast-staging")
+        );
+        assert_eq!(
+            previous_dir(output_dir),
+            PathBuf::from("/site/.public.toast-previous")
+        );
+    }
+}
@@ -0,0 +1,247 @@
+//! Keeping an eye on the JS runtime processes pages are rendered in. A
+//! render that goes `--render-timeout` seconds without finishing a page
+//! is hung, so its process is killed and the build fails instead of
+//! waiting forever. What the process wrote to stderr is passed through to
+//! the terminal as usual, and its last lines are also attached to the
+//! error, so a crash or hang comes with whatever node had to say about it.
+//! The one-shot node helpers, like `toast-mdx` and `toast-images`, are
+//! run the same way by `run_node`.
+use color_eyre::{
+    eyre::{eyre, Result, WrapErr},
+    Report, Section, SectionExt,
+};
+use duct::Expression;
+use std::{
+    collections::VecDeque,
+    convert::TryFrom,
+    io::{self, prelude::*, BufReader},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::cancel;
+
+/// Used when `--render-timeout` isn't passed
+pub const DEFAULT_RENDER_TIMEOUT_SECS: u64 = 60;
+
+/// How many lines of stderr are kept for error reports
+const TAIL_LINES: usize = 20;
+
+/// How long `run_node` waits for the end of stderr once node exits
+const STDERR_GRACE: Duration = Duration::from_secs(1);
+
+/// `--render-timeout` as a duration, where 0 turns the timeout off
+pub fn render_timeout(seconds: Option<u64>) -> Option<Duration> {
+    match seconds.unwrap_or(DEFAULT_RENDER_TIMEOUT_SECS) {
+        0 => None,
+        seconds => Some(Duration::from_secs(seconds)),
+    }
+}
+
+/// How long a node helper gets for a batch of `jobs`, the default render
+/// timeout for each, since an image or a file of mdx is about as much
+/// work as a page
+pub fn batch_timeout(jobs: usize) -> Option<Duration> {
+    let jobs = u32::try_from(jobs.max(1)).unwrap_or(u32::MAX);
+    Some(Duration::from_secs(DEFAULT_RENDER_TIMEOUT_SECS).saturating_mul(jobs))
+}
+
+/// The last lines a process wrote to stderr
+#[derive(Debug, Clone, Default)]
+pub struct StderrTail {
+    lines: Arc<Mutex<VecDeque<String>>>,
+}
+
+impl StderrTail {
+    pub fn push(&self, line: String) {
+        if let Ok(mut lines) = self.lines.lock() {
+            if lines.len() == TAIL_LINES {
+                lines.pop_front();
+            }
+            lines.push_back(line);
+        }
+    }
+
+    /// Copy `stderr` to our own stderr on another thread, keeping its tail.
+    /// The thread finishes when `stderr` is closed.
+    pub fn follow(&self, stderr: impl Read + Send + 'static) -> JoinHandle<()> {
+        let tail = self.clone();
+        thread::spawn(move || {
+            for line in BufReader::new(stderr).lines() {
+                match line {
+                    Ok(line) => {
+                        eprintln!("{}", line);
+                        tail.push(line);
+                    }
+                    Err(_) => break,
+                }
+            }
+        })
+    }
+
+    /// Add the tail to `err`, if there is any
+    pub fn attach(&self, err: Report) -> Report {
+        let lines = match self.lines.lock() {
+            Ok(lines) if !lines.is_empty() => lines.iter().cloned().collect::<Vec<_>>(),
+            _ => return err,
+        };
+        err.section(lines.join("\n").header("Node stderr:"))
+    }
+
+    /// The tail on lines of its own after a colon, for error messages
+    /// that can't carry a section, or nothing if there isn't any
+    pub fn text(&self) -> String {
+        match self.lines.lock() {
+            Ok(lines) if !lines.is_empty() => {
+                format!(
+                    ":\n{}",
+                    lines.iter().cloned().collect::<Vec<_>>().join("\n")
+                )
+            }
+            _ => String::new(),
+        }
+    }
+}
+
+/// Calls `kill` if `progress` isn't called for longer than the timeout.
+/// Stops watching once every clone has been dropped.
+#[derive(Debug, Clone)]
+pub struct Watchdog {
+    progress: Option<Sender<()>>,
+    timed_out: Arc<AtomicBool>,
+}
+
+impl Watchdog {
+    /// `timeout` of `None` never calls `kill`
+    pub fn start(timeout: Option<Duration>, kill: impl FnOnce() + Send + 'static) -> Watchdog {
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let progress = timeout.map(|timeout| {
+            let (tx, rx) = channel();
+            let thread_timed_out = timed_out.clone();
+            thread::spawn(move || loop {
+                match rx.recv_timeout(timeout) {
+                    Ok(()) => continue,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                    Err(RecvTimeoutError::Timeout) => {
+                        thread_timed_out.store(true, Ordering::SeqCst);
+                        kill();
+                        return;
+                    }
+                }
+            });
+            tx
+        });
+        Watchdog {
+            progress,
+            timed_out,
+        }
+    }
+
+    /// The process did something, like finishing a page
+    pub fn progress(&self) {
+        if let Some(progress) = &self.progress {
+            let _ = progress.send(());
+        }
+    }
+
+    /// Whether the process was killed for taking too long
+    pub fn timed_out(&self) -> bool {
+        self.timed_out.load(Ordering::SeqCst)
+    }
+}
+
+/// Run the node helper `name` to completion and return what it wrote to
+/// stdout. It's tracked so a cancelled build stops it, it's killed if it
+/// runs longer than `timeout`, and its stderr is passed through, with the
+/// tail attached to the error if it fails.
+pub fn run_node(name: &str, command: Expression, timeout: Option<Duration>) -> Result<String> {
+    let (stderr, stderr_writer) =
+        io::pipe().wrap_err_with(|| format!("Failed to open stderr for `{}`", name))?;
+    // the writer is moved into the expression, which is dropped once it's
+    // started, so the child holds the only copy and the pipe closes with it
+    let handle = command
+        .stderr_file(stderr_writer)
+        .stdout_capture()
+        .unchecked()
+        .start()
+        .wrap_err_with(|| format!("Failed to start `{}` with node", name))?;
+    let pids = handle.pids();
+    for pid in &pids {
+        cancel::track_child(*pid);
+    }
+    let tail = StderrTail::default();
+    let following = tail.follow(stderr);
+    let handle = Arc::new(handle);
+    let watchdog_handle = handle.clone();
+    let watchdog = Watchdog::start(timeout, move || {
+        let _ = watchdog_handle.kill();
+    });
+    let output = handle.wait().cloned();
+    // a process node started can keep stderr open after node is gone, so
+    // the rest of it only gets a moment to arrive
+    let deadline = Instant::now() + STDERR_GRACE;
+    while !following.is_finished() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    for pid in &pids {
+        cancel::untrack_child(*pid);
+    }
+    let output = output.wrap_err_with(|| format!("`{}` node process failed", name))?;
+    if watchdog.timed_out() {
+        return Err(tail.attach(eyre!(
+            "`{}` ran for longer than {}s, so it was stopped",
+            name,
+            timeout.unwrap_or_default().as_secs()
+        )));
+    }
+    if !output.status.success() {
+        // killed by a signal, which a cancelled build does
+        cancel::check()?;
+        return Err(tail.attach(eyre!("`{}` node process failed, {}", name, output.status)));
+    }
+    String::from_utf8(output.stdout)
+        .wrap_err_with(|| format!("`{}` wrote output that isn't utf8", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watchdog() {
+        let (tx, rx) = channel();
+        let watchdog = Watchdog::start(Some(Duration::from_millis(20)), move || {
+            let _ = tx.send("killed");
+        });
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok("killed"));
+        assert!(watchdog.timed_out());
+
+        let watchdog = Watchdog::start(None, || panic!("should never time out"));
+        watchdog.progress();
+        assert!(!watchdog.timed_out());
+        assert_eq!(render_timeout(Some(0)), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_node() {
+        let script = |script| duct::cmd!("sh", "-c", script);
+        assert_eq!(
+            run_node("echo", script("echo out; echo err >&2"), None).unwrap(),
+            "out\n"
+        );
+        let failed = run_node("fail", script("echo broke >&2; exit 3"), None).unwrap_err();
+        assert!(failed.to_string().contains("exit status: 3"));
+        let hung = run_node(
+            "hang",
+            script("exec sleep 5"),
+            Some(Duration::from_millis(50)),
+        );
+        assert!(hung.unwrap_err().to_string().contains("stopped"));
+    }
+}
@@ -5,6 +5,9 @@ use swc_ecma_visit::{noop_fold_type, Fold};
 
 pub struct SWCImportMapRewrite<'a> {
     pub import_map: &'a ImportMap,
+    /// the url of the module being rewritten, which picks the import map
+    /// scope its imports are resolved with
+    pub referrer: String,
 }
 
 // fn is_source_import(val: Atom<swc_atoms::JsWordStaticSet>) -> bool {
@@ -15,21 +18,12 @@ impl Fold for SWCImportMapRewrite<'_> {
     noop_fold_type!();
 
     fn fold_import_decl(&mut self, decl: ImportDecl) -> ImportDecl {
-        if self.import_map.imports.contains_key(&decl.src.value) {
-            ImportDecl {
-                src: Str {
-                    value: self
-                        .import_map
-                        .imports
-                        .get(&decl.src.value)
-                        .unwrap()
-                        .clone(),
-                    ..decl.src
-                },
+        match self.import_map.resolve(&decl.src.value, &self.referrer) {
+            Some(value) => ImportDecl {
+                src: Str { value, ..decl.src },
                 ..decl
-            }
-        } else {
-            decl
+            },
+            None => decl,
         }
     }
 }
@@ -1,5 +1,13 @@
+//! Compiling `.js`, `.ts`, and `.tsx` sources, and the JSX the mdx
+//! compiler produces, with swc in the toast process. Each module is
+//! compiled twice, for the browser with its imports resolved through the
+//! import map, and for rendering with its imports left as they are.
+//!
+//! swc's errors are collected instead of printed, and a file that doesn't
+//! compile is a `CompileError` with a code frame of where.
+use serde::Serialize;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use tracing::instrument;
 
 use swc::{
@@ -11,15 +19,88 @@ use swc::{
 };
 use swc_common::{
     // chain,
-    errors::{ColorConfig, Handler},
+    errors::{DiagnosticBuilder, Emitter, Handler},
     FileName,
     SourceMap,
+    Span,
 };
-use swc_ecma_parser::{EsConfig, Syntax};
+use swc_ecma_parser::{EsConfig, Syntax, TsConfig};
 use swc_ecma_transforms::react;
 use swc_ecma_visit::FoldWith;
 
-use crate::{esinstall::ImportMap, swc_import_map_rewrite::SWCImportMapRewrite};
+use crate::{
+    diagnostics::CompileError, esinstall::ImportMap, module_graph::module_id,
+    public_env::public_env, source_maps::finish_map, swc_import_map_rewrite::SWCImportMapRewrite,
+};
+
+/// How browser builds are compiled. Part of every module's cache key, so
+/// changing one recompiles everything.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub struct BrowserJsOptions {
+    /// see `source_maps`
+    pub source_maps: bool,
+    /// compress and mangle, and print without whitespace
+    pub minify: bool,
+}
+
+/// A browser build, and its source map if it was asked for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompiledJs {
+    pub code: String,
+    pub map: Option<String>,
+}
+
+/// An error swc reported, and where
+type SwcError = (String, Option<Span>);
+
+/// Every error swc reports while compiling a file
+#[derive(Debug, Clone, Default)]
+struct CollectedErrors(Arc<Mutex<Vec<SwcError>>>);
+
+impl Emitter for CollectedErrors {
+    fn emit(&mut self, db: &DiagnosticBuilder<'_>) {
+        if let Ok(mut errors) = self.0.lock() {
+            errors.push((db.message(), db.span.primary_span()));
+        }
+    }
+}
+
+impl CollectedErrors {
+    fn handler(&self) -> Arc<Handler> {
+        Arc::new(Handler::with_emitter(true, false, Box::new(self.clone())))
+    }
+
+    /// The first error swc reported in `source`, or `fallback` if it
+    /// didn't report one
+    fn compile_error(
+        &self,
+        cm: &SourceMap,
+        filename: &str,
+        source: &str,
+        fallback: impl std::fmt::Display,
+    ) -> CompileError {
+        let first = self
+            .0
+            .lock()
+            .ok()
+            .and_then(|errors| errors.first().cloned());
+        match first {
+            Some((message, Some(span))) => {
+                let loc = cm.lookup_char_pos(span.lo());
+                let length = (span.hi().0 - span.lo().0) as usize;
+                CompileError::new(
+                    filename,
+                    message,
+                    source,
+                    Some((loc.line, loc.col.0 + 1)),
+                    length,
+                )
+            }
+            Some((message, None)) => CompileError::new(filename, message, source, None, 0),
+            None => CompileError::new(filename, fallback.to_string(), source, None, 0),
+        }
+    }
+}
 
 #[instrument]
 pub fn compile_js_for_browser(
@@ -27,32 +108,36 @@ pub fn compile_js_for_browser(
     filename: String,
     npm_bin_dir: PathBuf,
     import_map: ImportMap,
-) -> String {
-    let opts = &get_opts();
+    options: BrowserJsOptions,
+) -> Result<CompiledJs, CompileError> {
+    let opts = &get_opts(&filename, options.minify);
     let cm = Arc::<SourceMap>::default();
-    let handler = Arc::new(Handler::with_tty_emitter(
-        ColorConfig::Auto,
-        true,
-        false,
-        Some(cm.clone()),
-    ));
-
-    let compiler = swc::Compiler::new(cm.clone(), handler);
+    let errors = CollectedErrors::default();
+    let compiler = swc::Compiler::new(cm.clone(), errors.handler());
 
+    // where the browser loads this module from
+    let referrer = format!("/{}", module_id(&filename));
+    let original = options.source_maps.then(|| source.clone());
     let fm = cm.new_source_file(FileName::Custom(filename.clone()), source);
+    let fail = |error| errors.compile_error(&cm, &filename, &fm.src, error);
 
-    let parsed_program = compiler.parse_js(fm, JscTarget::Es2020, get_syntax(), true, true);
-    let built_config = compiler.config_for_file(opts, &FileName::Custom(filename));
-    let post_transform_program = parsed_program.map(|program| {
-        program.fold_with(&mut SWCImportMapRewrite {
-            import_map: &import_map,
-        })
+    let parsed_program = compiler
+        .parse_js(
+            fm.clone(),
+            JscTarget::Es2020,
+            get_syntax(&filename),
+            true,
+            true,
+        )
+        .map_err(fail)?;
+    let built_config = compiler
+        .config_for_file(opts, &FileName::Custom(filename.clone()))
+        .map_err(fail)?;
+    let post_transform_program = parsed_program.fold_with(&mut SWCImportMapRewrite {
+        import_map: &import_map,
+        referrer,
     });
-    let result = compiler.transform(
-        post_transform_program.unwrap(),
-        false,
-        built_config.unwrap().pass,
-    );
+    let result = compiler.transform(post_transform_program, false, built_config.pass);
     // .and_then(|program| {
     //     if let Program::Module(mut module) = program {
     //         // println!("Matched {:?}!", i);
@@ -68,31 +153,54 @@ pub fn compile_js_for_browser(
     //     }
     // });
 
-    let output = compiler.print(&result, SourceMapsConfig::default(), None, false);
+    let output = compiler
+        .print(
+            &result,
+            SourceMapsConfig::Bool(options.source_maps),
+            None,
+            options.minify,
+        )
+        .map_err(fail)?;
 
-    output.unwrap().code
+    Ok(CompiledJs {
+        map: output
+            .map
+            .zip(original)
+            .map(|(map, original)| finish_map(&map, &filename, &original)),
+        code: output.code,
+    })
 }
 
 #[instrument]
-pub fn compile_js_for_server(source: String, filename: String, npm_bin_dir: PathBuf) -> String {
-    let opts = &get_opts();
+pub fn compile_js_for_server(
+    source: String,
+    filename: String,
+    npm_bin_dir: PathBuf,
+) -> Result<String, CompileError> {
+    // rendering output is never shipped, so there's nothing to minify
+    let opts = &get_opts(&filename, false);
 
     let cm = Arc::<SourceMap>::default();
-    let handler = Arc::new(Handler::with_tty_emitter(
-        ColorConfig::Auto,
-        true,
-        false,
-        Some(cm.clone()),
-    ));
-
-    let compiler = swc::Compiler::new(cm.clone(), handler);
+    let errors = CollectedErrors::default();
+    let compiler = swc::Compiler::new(cm.clone(), errors.handler());
 
     let fm = cm.new_source_file(FileName::Custom(filename.clone()), source);
+    let fail = |error| errors.compile_error(&cm, &filename, &fm.src, error);
 
-    let parsed_program = compiler.parse_js(fm, JscTarget::Es2020, get_syntax(), true, true);
-    let built_config = compiler.config_for_file(opts, &FileName::Custom(filename));
+    let parsed_program = compiler
+        .parse_js(
+            fm.clone(),
+            JscTarget::Es2020,
+            get_syntax(&filename),
+            true,
+            true,
+        )
+        .map_err(fail)?;
+    let built_config = compiler
+        .config_for_file(opts, &FileName::Custom(filename.clone()))
+        .map_err(fail)?;
 
-    let result = compiler.transform(parsed_program.unwrap(), false, built_config.unwrap().pass);
+    let result = compiler.transform(parsed_program, false, built_config.pass);
     // .and_then(|program| {
     //     if let Program::Module(mut module) = program {
     //         // println!("Matched {:?}!", i);
@@ -108,25 +216,23 @@ pub fn compile_js_for_server(source: String, filename: String, npm_bin_dir: Path
     //     }
     // });
 
-    let output = compiler.print(&result, SourceMapsConfig::default(), None, false);
+    let output = compiler
+        .print(&result, SourceMapsConfig::default(), None, false)
+        .map_err(fail)?;
 
-    output.unwrap().code
+    Ok(output.code)
 }
 
 #[instrument]
-fn get_opts() -> Options {
+fn get_opts(filename: &str, minify: bool) -> Options {
     Options {
         is_module: true,
         config: Some(Config {
+            minify: Some(minify),
             jsc: JscConfig {
                 target: JscTarget::Es2020,
-                syntax: Some(Syntax::Es(EsConfig {
-                    jsx: true,
-                    nullish_coalescing: true,
-                    optional_chaining: true,
-                    dynamic_import: true,
-                    ..Default::default()
-                })),
+                // typescript syntax also strips the types
+                syntax: Some(get_syntax(filename)),
                 transform: Some(TransformConfig {
                     react: react::Options {
                         pragma: "h".to_string(),
@@ -136,15 +242,8 @@ fn get_opts() -> Options {
                     },
                     optimizer: Some(OptimizerConfig {
                         globals: Some(GlobalPassOption {
-                            envs: std::env::vars()
-                                .filter_map(|(k, _)| {
-                                    if k.starts_with("TOAST_") {
-                                        Some(k)
-                                    } else {
-                                        None
-                                    }
-                                })
-                                .collect(),
+                            // only public variables are inlined, see `public_env`
+                            envs: public_env().into_keys().collect(),
                             ..Default::default()
                         }),
                         ..Default::default()
@@ -159,13 +258,27 @@ fn get_opts() -> Options {
     }
 }
 
+/// TypeScript for `.ts` and `.tsx` files, JSX is allowed in `.tsx` and
+/// every other file. Types aren't checked. Both compile to `.js`, so
+/// imports of them use the `.js` extension, the way TypeScript's ES module
+/// resolution expects.
 #[instrument]
-fn get_syntax() -> Syntax {
-    Syntax::Es(EsConfig {
-        jsx: true,
-        nullish_coalescing: true,
-        optional_chaining: true,
-        dynamic_import: true,
-        ..Default::default()
-    })
+fn get_syntax(filename: &str) -> Syntax {
+    let tsx = filename.ends_with(".tsx");
+    if tsx || filename.ends_with(".ts") {
+        Syntax::Typescript(TsConfig {
+            tsx,
+            dynamic_import: true,
+            decorators: true,
+            ..Default::default()
+        })
+    } else {
+        Syntax::Es(EsConfig {
+            jsx: true,
+            nullish_coalescing: true,
+            optional_chaining: true,
+            dynamic_import: true,
+            ..Default::default()
+        })
+    }
 }
@@ -0,0 +1,210 @@
+//! Listing pages for each tag, category, or other frontmatter key,
+//! configured with `[[taxonomies]]` in `toast.config.toml`
+//!
+//! ```toml
+//! [[taxonomies]]
+//! name = "tags"
+//! ```
+//!
+//! Every page whose `tags` frontmatter is a string or a list of strings is
+//! listed on `/tags/<tag>/`, which is rendered with
+//! `src/taxonomies/tags.js` by default. That component gets `taxonomy`,
+//! `term`, `count`, and `pages` props. Every term and its count is also
+//! written to `toast-data/taxonomies/tags.json`, and is passed as the
+//! `terms` prop to the `index` component at `/tags/` if there is one.
+use color_eyre::eyre::{Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{collections::BTreeMap, fs, path::Path};
+
+use crate::content_index::{sort_entries, PageEntry, DATA_DIR};
+use crate::url_style::UrlStyle;
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TaxonomyConfig {
+    /// the frontmatter key, which is also the first segment of every
+    /// generated page's URL
+    pub name: String,
+    /// component rendered for each term, relative to the project root.
+    /// Defaults to `src/taxonomies/<name>.js`.
+    #[serde(default)]
+    pub page: Option<String>,
+    /// component rendered at `/<name>/` with every term
+    #[serde(default)]
+    pub index: Option<String>,
+}
+
+impl TaxonomyConfig {
+    pub fn page_component(&self) -> String {
+        match &self.page {
+            Some(page) => page.clone(),
+            None => format!("src/taxonomies/{}.js", self.name),
+        }
+    }
+}
+
+/// One term and how many pages have it, in `toast-data/taxonomies/`
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Term {
+    pub term: String,
+    pub url: String,
+    pub count: usize,
+}
+
+/// A generated taxonomy page and the component it re-exports
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaxonomyPage {
+    pub dest: String,
+    pub component: String,
+    pub props: Value,
+}
+
+/// Lowercase letters and numbers, with everything else collapsed into
+/// single dashes, so `Rust & WASM` is `rust-wasm`
+pub fn slugify(term: &str) -> String {
+    term.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+fn terms_of<'a>(entry: &'a PageEntry, name: &str) -> Vec<&'a str> {
+    match entry.data.get(name) {
+        Some(Value::String(term)) => vec![term.as_str()],
+        Some(Value::Array(terms)) => terms.iter().filter_map(|term| term.as_str()).collect(),
+        _ => vec![],
+    }
+}
+
+/// Group pages by the terms in their `config.name` frontmatter, keyed by
+/// the slugified term. The first spelling of a term that's seen is the
+/// one that's displayed.
+pub fn group_by_term(
+    config: &TaxonomyConfig,
+    entries: &[PageEntry],
+) -> BTreeMap<String, (String, Vec<PageEntry>)> {
+    let mut groups: BTreeMap<String, (String, Vec<PageEntry>)> = BTreeMap::new();
+    for entry in entries {
+        for term in terms_of(entry, &config.name) {
+            let slug = slugify(term);
+            if slug.is_empty() {
+                continue;
+            }
+            groups
+                .entry(slug)
+                .or_insert_with(|| (term.to_string(), vec![]))
+                .1
+                .push(entry.clone());
+        }
+    }
+    for (_, pages) in groups.values_mut() {
+        sort_entries(pages);
+    }
+    groups
+}
+
+/// Every page for one taxonomy, along with the terms for its index
+pub fn taxonomy_pages(
+    config: &TaxonomyConfig,
+    url_style: UrlStyle,
+    entries: &[PageEntry],
+) -> (Vec<TaxonomyPage>, Vec<Term>) {
+    let groups = group_by_term(config, entries);
+    let mut pages = vec![];
+    let mut terms = vec![];
+    for (slug, (term, entries)) in groups {
+        let url = url_style.url(&format!("/{}/{}/", config.name, slug));
+        pages.push(TaxonomyPage {
+            dest: format!("{}/{}/index.js", config.name, slug),
+            component: config.page_component(),
+            props: json!({
+                "taxonomy": config.name,
+                "term": term,
+                "count": entries.len(),
+                "pages": entries,
+            }),
+        });
+        terms.push(Term {
+            term,
+            url,
+            count: entries.len(),
+        });
+    }
+    if let Some(index) = &config.index {
+        pages.push(TaxonomyPage {
+            dest: format!("{}/index.js", config.name),
+            component: index.clone(),
+            props: json!({
+                "taxonomy": config.name,
+                "terms": terms,
+            }),
+        });
+    }
+    (pages, terms)
+}
+
+/// Write `toast-data/taxonomies/<name>.json`
+pub fn write_terms(config: &TaxonomyConfig, terms: &[Term], output_dir: &Path) -> Result<()> {
+    let dir = output_dir.join(DATA_DIR).join("taxonomies");
+    fs::create_dir_all(&dir)
+        .wrap_err_with(|| format!("Failed to create directory `{}`", dir.display()))?;
+    let file = dir.join(format!("{}.json", config.name));
+    fs::write(&file, serde_json::to_string(terms)?)
+        .wrap_err_with(|| format!("Failed to write taxonomy `{}`", file.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags() -> TaxonomyConfig {
+        TaxonomyConfig {
+            name: String::from("tags"),
+            page: None,
+            index: None,
+        }
+    }
+
+    fn post(slug: &str, tags: Value) -> PageEntry {
+        PageEntry {
+            slug: slug.to_string(),
+            data: json!({ "tags": tags }),
+        }
+    }
+
+    #[test]
+    fn test_slugify() {
+        assert_eq!(slugify("Rust & WASM"), "rust-wasm");
+        assert_eq!(slugify("intro"), "intro");
+    }
+
+    #[test]
+    fn test_taxonomy_pages() {
+        let entries = vec![
+            post("/blog/a", json!(["Intro", "rust"])),
+            post("/blog/b", json!("intro")),
+            post("/blog/c", json!(null)),
+        ];
+        let (pages, terms) = taxonomy_pages(&tags(), UrlStyle::Bare, &entries);
+        assert_eq!(
+            terms,
+            vec![
+                Term {
+                    term: String::from("Intro"),
+                    url: String::from("/tags/intro/"),
+                    count: 2
+                },
+                Term {
+                    term: String::from("rust"),
+                    url: String::from("/tags/rust/"),
+                    count: 1
+                },
+            ]
+        );
+        assert_eq!(pages[0].dest, "tags/intro/index.js");
+        assert_eq!(pages[0].component, "src/taxonomies/tags.js");
+        assert_eq!(pages[0].props["count"], 2);
+    }
+}
@@ -0,0 +1,362 @@
+//! Opt-in build telemetry with `--telemetry otlp` or `--telemetry
+//! honeycomb`. After a build, its timings are sent as an OpenTelemetry
+//! trace: a `build` span with a span for each phase, a `compile` span for
+//! every file that wasn't a cache hit, and `render` and `write` spans for
+//! every page. Node only reports how long each page took, not when it
+//! started, so page spans start with the render phase.
+//!
+//! `otlp` sends OTLP/HTTP JSON to the endpoint in the standard
+//! environment variables:
+//!
+//! - `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT`, or `OTEL_EXPORTER_OTLP_ENDPOINT`
+//!   with `/v1/traces` added, `http://localhost:4318` if neither is set
+//! - `OTEL_EXPORTER_OTLP_HEADERS`, like `x-api-key=secret,x-team=web`
+//! - `OTEL_SERVICE_NAME`, `toast` if it isn't set
+//!
+//! `honeycomb` sends the same trace to Honeycomb's OTLP endpoint with the
+//! key in `HONEYCOMB_API_KEY`, and `HONEYCOMB_DATASET` for classic
+//! environments. `HONEYCOMB_API_ENDPOINT` points it somewhere other than
+//! `https://api.honeycomb.io`, like the EU instance.
+use async_std::future::timeout;
+use color_eyre::eyre::{eyre, Result};
+use serde_json::{json, Value};
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::instrument;
+
+use crate::report::BuildReport;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where `--telemetry` sends build traces
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TelemetryKind {
+    Otlp,
+    Honeycomb,
+}
+
+impl FromStr for TelemetryKind {
+    type Err = color_eyre::Report;
+    fn from_str(kind: &str) -> Result<Self> {
+        match kind {
+            "otlp" => Ok(TelemetryKind::Otlp),
+            "honeycomb" => Ok(TelemetryKind::Honeycomb),
+            _ => Err(eyre!(
+                "Unknown telemetry exporter `{}`, the supported exporters are `otlp` and `honeycomb`",
+                kind
+            )),
+        }
+    }
+}
+
+/// Where traces are sent, and the headers to send with them
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exporter {
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub service_name: String,
+}
+
+/// The exporter for `kind`, configured by the variables `env` looks up
+pub fn exporter(kind: TelemetryKind, env: impl Fn(&str) -> Option<String>) -> Result<Exporter> {
+    let service_name = env("OTEL_SERVICE_NAME").unwrap_or_else(|| String::from("toast"));
+    match kind {
+        TelemetryKind::Otlp => {
+            let url = env("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT").unwrap_or_else(|| {
+                let endpoint = env("OTEL_EXPORTER_OTLP_ENDPOINT")
+                    .unwrap_or_else(|| String::from("http://localhost:4318"));
+                format!("{}/v1/traces", endpoint.trim_end_matches('/'))
+            });
+            let headers = env("OTEL_EXPORTER_OTLP_HEADERS")
+                .unwrap_or_default()
+                .split(',')
+                .filter_map(|header| {
+                    let (name, value) = header.split_once('=')?;
+                    Some((name.trim().to_string(), value.trim().to_string()))
+                })
+                .collect();
+            Ok(Exporter {
+                url,
+                headers,
+                service_name,
+            })
+        }
+        TelemetryKind::Honeycomb => {
+            let key = env("HONEYCOMB_API_KEY").ok_or_else(|| {
+                eyre!("`--telemetry honeycomb` needs an api key in `HONEYCOMB_API_KEY`")
+            })?;
+            let endpoint = env("HONEYCOMB_API_ENDPOINT")
+                .unwrap_or_else(|| String::from("https://api.honeycomb.io"));
+            let mut headers = vec![(String::from("x-honeycomb-team"), key)];
+            if let Some(dataset) = env("HONEYCOMB_DATASET") {
+                headers.push((String::from("x-honeycomb-dataset"), dataset));
+            }
+            Ok(Exporter {
+                url: format!("{}/v1/traces", endpoint.trim_end_matches('/')),
+                headers,
+                service_name,
+            })
+        }
+    }
+}
+
+/// Hands out the trace id and span ids for one trace
+struct Ids {
+    trace_id: String,
+    next_span: u64,
+}
+
+impl Ids {
+    fn new(seed: u64) -> Ids {
+        Ids {
+            trace_id: format!("{:016x}{:016x}", seed, seed.rotate_left(32) ^ 1),
+            next_span: seed,
+        }
+    }
+
+    fn span_id(&mut self) -> String {
+        // span ids can't be all zeroes
+        self.next_span = self.next_span.wrapping_add(1).max(1);
+        format!("{:016x}", self.next_span)
+    }
+}
+
+fn random_seed() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(as_nanos(SystemTime::now()) as u64);
+    hasher.finish()
+}
+
+fn as_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos())
+        .unwrap_or_default()
+}
+
+fn attribute(key: &str, value: Value) -> Value {
+    let value = match value {
+        Value::String(value) => json!({ "stringValue": value }),
+        Value::Bool(value) => json!({ "boolValue": value }),
+        value => json!({ "intValue": value.to_string() }),
+    };
+    json!({ "key": key, "value": value })
+}
+
+/// Adds spans to a trace, with times in milliseconds since the build
+/// started
+struct Trace<'a> {
+    ids: Ids,
+    start_ns: u128,
+    spans: &'a mut Vec<Value>,
+}
+
+impl Trace<'_> {
+    fn span(
+        &mut self,
+        name: &str,
+        parent: Option<&str>,
+        start_ms: f64,
+        duration_ms: f64,
+        attributes: Vec<Value>,
+    ) -> String {
+        let span_id = self.ids.span_id();
+        let start = self.start_ns + (start_ms * 1e6) as u128;
+        let end = start + (duration_ms * 1e6) as u128;
+        let mut span = json!({
+            "traceId": self.ids.trace_id,
+            "spanId": span_id,
+            "name": name,
+            // SPAN_KIND_INTERNAL
+            "kind": 1,
+            "startTimeUnixNano": start.to_string(),
+            "endTimeUnixNano": end.to_string(),
+            "attributes": attributes,
+        });
+        if let Some(parent) = parent {
+            span["parentSpanId"] = json!(parent);
+        }
+        self.spans.push(span);
+        span_id
+    }
+}
+
+/// The OTLP JSON for the trace of `report`, which has to have a start time
+pub fn otlp_traces(report: &BuildReport, service_name: &str, seed: u64) -> Value {
+    let mut spans = vec![];
+    let mut trace = Trace {
+        ids: Ids::new(seed),
+        start_ns: report.started_at.map(as_nanos).unwrap_or_default(),
+        spans: &mut spans,
+    };
+    let build = trace.span(
+        "build",
+        None,
+        0.0,
+        report.duration_ms,
+        vec![
+            attribute("toast.pages", json!(report.pages.len())),
+            attribute("toast.cache_hits", json!(report.cache_hits)),
+            attribute("toast.cache_misses", json!(report.cache_misses)),
+            attribute("toast.bytes_written", json!(report.bytes_written)),
+        ],
+    );
+    for phase in &report.phases {
+        let phase_span = trace.span(
+            &phase.name,
+            Some(&build),
+            phase.start_ms,
+            phase.duration_ms,
+            vec![],
+        );
+        match phase.name.as_str() {
+            "compile" => {
+                for file in report.files.iter().filter(|file| !file.cache_hit) {
+                    trace.span(
+                        "compile",
+                        Some(&phase_span),
+                        phase.start_ms,
+                        file.duration_ms,
+                        vec![
+                            attribute("toast.source_id", json!(file.source_id)),
+                            attribute("toast.bytes_written", json!(file.bytes_written)),
+                        ],
+                    );
+                }
+            }
+            "render" => {
+                for timing in &report.page_timings {
+                    let page = || vec![attribute("toast.page", json!(timing.page))];
+                    trace.span(
+                        "render",
+                        Some(&phase_span),
+                        phase.start_ms,
+                        timing.render_ms,
+                        page(),
+                    );
+                    trace.span(
+                        "write",
+                        Some(&phase_span),
+                        phase.start_ms + timing.render_ms,
+                        timing.write_ms,
+                        page(),
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [attribute("service.name", json!(service_name))],
+            },
+            "scopeSpans": [{
+                "scope": { "name": "toast", "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+/// Send the trace of `report` to the exporter for `kind`
+#[instrument(skip(report))]
+pub async fn export(kind: TelemetryKind, report: &BuildReport) -> Result<()> {
+    let exporter = exporter(kind, |name| std::env::var(name).ok())?;
+    let body = otlp_traces(report, &exporter.service_name, random_seed()).to_string();
+    let mut request = surf::Client::new()
+        .post(&exporter.url)
+        .header("Content-Type", "application/json");
+    for (name, value) in &exporter.headers {
+        request = request.header(name.as_str(), value.as_str());
+    }
+    let response = timeout(REQUEST_TIMEOUT, request.body(body))
+        .await
+        .map_err(|_| eyre!("Timed out sending telemetry to `{}`", exporter.url))?
+        .map_err(|error| eyre!("Failed to send telemetry to `{}`: {}", exporter.url, error))?;
+    let status = u16::from(response.status());
+    if !(200..300).contains(&status) {
+        return Err(eyre!(
+            "Sending telemetry to `{}` failed with status {}",
+            exporter.url,
+            status
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::{FileReport, PageTiming, PhaseTiming};
+
+    #[test]
+    fn test_exporter() {
+        let env = |name: &str| match name {
+            "OTEL_EXPORTER_OTLP_ENDPOINT" => Some(String::from("https://otel.example.com/")),
+            "OTEL_EXPORTER_OTLP_HEADERS" => Some(String::from("x-api-key=secret, x-team=web")),
+            _ => None,
+        };
+        let otlp = exporter(TelemetryKind::Otlp, env).unwrap();
+        assert_eq!(otlp.url, "https://otel.example.com/v1/traces");
+        assert_eq!(
+            otlp.headers[1],
+            (String::from("x-team"), String::from("web"))
+        );
+        assert_eq!(otlp.service_name, "toast");
+        assert!(exporter(TelemetryKind::Honeycomb, env).is_err());
+    }
+
+    #[test]
+    fn test_otlp_traces() {
+        let report = BuildReport {
+            duration_ms: 100.0,
+            pages: vec![String::from("src/pages/index.js")],
+            files: vec![FileReport {
+                source_id: String::from("src/pages/index.js"),
+                output: String::from("src/pages/index.js"),
+                cache_hit: false,
+                duration_ms: 5.0,
+                bytes_written: 10,
+            }],
+            page_timings: vec![PageTiming {
+                page: String::from("src/pages/index.js"),
+                render_ms: 20.0,
+                write_ms: 1.0,
+            }],
+            phases: vec![
+                PhaseTiming {
+                    name: String::from("compile"),
+                    start_ms: 0.0,
+                    duration_ms: 10.0,
+                },
+                PhaseTiming {
+                    name: String::from("render"),
+                    start_ms: 50.0,
+                    duration_ms: 30.0,
+                },
+            ],
+            started_at: Some(UNIX_EPOCH + Duration::from_secs(1)),
+            ..BuildReport::default()
+        };
+        let traces = otlp_traces(&report, "toast", 7);
+        let spans = traces["resourceSpans"][0]["scopeSpans"][0]["spans"]
+            .as_array()
+            .unwrap();
+        let names: Vec<&str> = spans
+            .iter()
+            .map(|span| span["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            names,
+            vec!["build", "compile", "compile", "render", "render", "write"]
+        );
+        let write = &spans[5];
+        assert_eq!(write["parentSpanId"], spans[3]["spanId"]);
+        assert_eq!(write["startTimeUnixNano"], "1070000000");
+        assert_eq!(write["endTimeUnixNano"], "1071000000");
+    }
+}
@@ -0,0 +1,154 @@
+//! Heading ids and a table of contents for `.mdx` and `.md` pages.
+//!
+//! Every heading gets an `id` made from its text, `rust-wasm` for
+//! `## Rust & WASM`, with `-1`, `-2`, and so on added to repeats, so
+//! `/docs/#rust-wasm` links to it. The headings are passed to the page
+//! component as a `toc` prop, and exported from its module as `toc`,
+//! nested under the heading they're in:
+//!
+//! ```json
+//! [{ "depth": 1, "text": "Intro", "id": "intro", "children": [
+//!   { "depth": 2, "text": "Rust & WASM", "id": "rust-wasm", "children": [] }
+//! ] }]
+//! ```
+//!
+//! so a docs layout can render it without parsing the page again. A `toc`
+//! key in a page's frontmatter is passed through instead.
+//!
+//! ```toml
+//! [toc]
+//! # add a `<a class="heading-anchor" href="#id">#</a>` to every heading
+//! anchors = true
+//! # leave `####` and deeper headings out of `toc`, the default is 3
+//! max_depth = 3
+//! ```
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+
+use crate::taxonomy::slugify;
+
+const DEFAULT_MAX_DEPTH: u32 = 3;
+
+/// `[toc]` in `toast.config.toml`
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TocConfig {
+    /// link every heading to itself
+    pub anchors: bool,
+    /// the deepest heading level in `toc`, headings below it still get ids
+    pub max_depth: Option<u32>,
+}
+
+/// A heading as it was found in a page, with the id it was given
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Heading {
+    pub depth: u32,
+    pub text: String,
+    pub id: String,
+}
+
+/// A heading in the `toc` prop, with the headings under it
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct TocEntry {
+    pub depth: u32,
+    pub text: String,
+    pub id: String,
+    pub children: Vec<TocEntry>,
+}
+
+/// Hands out heading ids for one page, so repeated headings don't share
+/// an id
+#[derive(Debug, Default)]
+pub struct Slugger(HashMap<String, usize>);
+
+impl Slugger {
+    pub fn slug(&mut self, text: &str) -> String {
+        let slug = match slugify(text) {
+            slug if slug.is_empty() => String::from("section"),
+            slug => slug,
+        };
+        let seen = self.0.entry(slug.clone()).or_insert(0);
+        *seen += 1;
+        match *seen {
+            1 => slug,
+            n => format!("{}-{}", slug, n - 1),
+        }
+    }
+}
+
+/// What ends the html of a heading, after its text
+pub fn closing_tag(depth: u32, id: &str, anchors: bool) -> String {
+    if anchors {
+        format!(
+            "<a class=\"heading-anchor\" href=\"#{}\" aria-hidden=\"true\">#</a></h{}>\n",
+            id, depth
+        )
+    } else {
+        format!("</h{}>\n", depth)
+    }
+}
+
+/// Nest `headings` under the heading before them with a lower depth
+pub fn table_of_contents(headings: &[Heading], config: &TocConfig) -> Vec<TocEntry> {
+    let max_depth = config.max_depth.unwrap_or(DEFAULT_MAX_DEPTH);
+    let mut toc: Vec<TocEntry> = vec![];
+    for heading in headings.iter().filter(|heading| heading.depth <= max_depth) {
+        let mut level = &mut toc;
+        while matches!(level.last(), Some(last) if last.depth < heading.depth) {
+            level = &mut level.last_mut().unwrap().children;
+        }
+        level.push(TocEntry {
+            depth: heading.depth,
+            text: heading.text.clone(),
+            id: heading.id.clone(),
+            children: vec![],
+        });
+    }
+    toc
+}
+
+/// A page's props, its frontmatter with `toc` added
+pub fn page_props(frontmatter: Option<&Value>, toc: &[TocEntry]) -> Option<Value> {
+    if toc.is_empty() {
+        return frontmatter.cloned();
+    }
+    let mut props = match frontmatter {
+        Some(Value::Object(frontmatter)) => frontmatter.clone(),
+        _ => Map::new(),
+    };
+    props.entry("toc").or_insert_with(|| json!(toc));
+    Some(Value::Object(props))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table_of_contents() {
+        let mut slugger = Slugger::default();
+        let headings: Vec<Heading> = [(1, "Intro"), (2, "Rust & WASM"), (4, "Deep"), (2, "Intro")]
+            .iter()
+            .map(|(depth, text)| Heading {
+                depth: *depth,
+                text: text.to_string(),
+                id: slugger.slug(text),
+            })
+            .collect();
+        assert_eq!(headings[3].id, "intro-1");
+        let toc = table_of_contents(&headings, &TocConfig::default());
+        assert_eq!(toc.len(), 1);
+        let ids: Vec<&str> = toc[0]
+            .children
+            .iter()
+            .map(|entry| entry.id.as_str())
+            .collect();
+        assert_eq!(ids, vec!["rust-wasm", "intro-1"]);
+        assert!(toc[0].children[0].children.is_empty());
+
+        let props = page_props(Some(&json!({ "title": "Docs" })), &toc).unwrap();
+        assert_eq!(props["title"], "Docs");
+        assert_eq!(props["toc"][0]["id"], "intro");
+    }
+}
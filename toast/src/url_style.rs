@@ -0,0 +1,173 @@
+//! What page urls look like, set with `url_style` in `toast.config.toml`.
+//! Static hosts disagree on how a url finds its html file, so this picks
+//! both where pages are written and how toast links to them:
+//!
+//! - `bare`, the default: `about.js` is written to `about.html` and linked
+//!   as `/about`, which Netlify and Vercel serve
+//! - `directory`: `about/index.html`, linked as `/about/`, which any host
+//!   that serves `index.html` for a directory (like S3 website hosting)
+//!   can serve
+//! - `file`: `about.html`, linked as `/about.html`. Directory index pages
+//!   are written next to their directory, `blog.html` for `blog/index.js`.
+//!
+//! The home page and `404.html` are always at the root of the output
+//! directory. Links in `bare` style are left as they are, so generated
+//! pages like `/blog/2/` keep their trailing slash. In the other styles,
+//! `href`s in page html that point at another page, like `/about` or
+//! `/about/`, are rewritten to that page's url.
+use color_eyre::eyre::{Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeSet, fs, path::Path};
+use tracing::instrument;
+
+use crate::{
+    frontmatter::page_slug,
+    pages::{page_path, PageRoot},
+    renderer::html_output_path,
+};
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UrlStyle {
+    #[default]
+    Bare,
+    Directory,
+    File,
+}
+
+impl UrlStyle {
+    /// Where the html the renderer wrote to `html_path`, like
+    /// `blog/post.html`, goes in this style
+    pub fn html_path(self, html_path: &str) -> String {
+        let stem = html_path.strip_suffix(".html").unwrap_or(html_path);
+        if stem == "index" || stem == "404" {
+            return html_path.to_string();
+        }
+        match (self, stem.strip_suffix("/index")) {
+            (UrlStyle::Directory, None) => format!("{}/index.html", stem),
+            (UrlStyle::File, Some(dir)) => format!("{}.html", dir),
+            _ => html_path.to_string(),
+        }
+    }
+
+    /// How to link to the page at `url`, like `/about` or `/blog/2/`
+    pub fn url(self, url: &str) -> String {
+        let path = url.trim_end_matches('/');
+        if path.is_empty() {
+            return String::from("/");
+        }
+        match self {
+            UrlStyle::Bare => url.to_string(),
+            UrlStyle::Directory => format!("{}/", path),
+            UrlStyle::File => format!("{}.html", path),
+        }
+    }
+}
+
+/// Point `href`s in `html` that link to one of `slugs` at the page's url
+/// in `url_style`
+pub fn rewrite_links(html: &str, slugs: &BTreeSet<String>, url_style: UrlStyle) -> String {
+    let mut html = html.to_string();
+    for slug in slugs.iter().filter(|slug| slug.as_str() != "/") {
+        let url = url_style.url(slug);
+        for link in &[slug.clone(), format!("{}/", slug)] {
+            if link == &url {
+                continue;
+            }
+            for quote in &['"', '\''] {
+                html = html.replace(
+                    &format!("href={}{}{}", quote, link, quote),
+                    &format!("href={}{}{}", quote, url, quote),
+                );
+            }
+        }
+    }
+    html
+}
+
+/// Move the rendered html of `pages` to where `url_style` puts it, linking
+/// to every page in `all_pages` by its styled url. This runs after
+/// everything else that reads or rewrites page html.
+#[instrument]
+pub fn apply_url_style(
+    output_dir: &Path,
+    page_roots: &[PageRoot],
+    pages: &[String],
+    all_pages: &[String],
+    url_style: UrlStyle,
+) -> Result<()> {
+    if url_style == UrlStyle::Bare {
+        return Ok(());
+    }
+    let slugs: BTreeSet<String> = all_pages
+        .iter()
+        .map(|page| page_slug(page_roots, page))
+        .collect();
+    for page in pages {
+        let from = html_output_path(output_dir, page_roots, page);
+        let html = match fs::read_to_string(&from) {
+            Ok(html) => html,
+            // pages that failed to render
+            Err(_) => continue,
+        };
+        let rendered = page_path(page_roots, page).replace(".js", ".html");
+        let to = output_dir.join(url_style.html_path(&rendered));
+        if let Some(dir) = to.parent() {
+            fs::create_dir_all(dir).wrap_err_with(|| {
+                format!("Failed to create directories for `{}`", dir.display())
+            })?;
+        }
+        fs::write(&to, rewrite_links(&html, &slugs, url_style))
+            .wrap_err_with(|| format!("Failed to write `{}`", to.display()))?;
+        if to != from {
+            fs::remove_file(&from)
+                .wrap_err_with(|| format!("Failed to remove `{}`", from.display()))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_html_path() {
+        assert_eq!(UrlStyle::Bare.html_path("about.html"), "about.html");
+        assert_eq!(
+            UrlStyle::Directory.html_path("about.html"),
+            "about/index.html"
+        );
+        assert_eq!(
+            UrlStyle::Directory.html_path("blog/index.html"),
+            "blog/index.html"
+        );
+        assert_eq!(UrlStyle::File.html_path("blog/index.html"), "blog.html");
+        assert_eq!(UrlStyle::File.html_path("index.html"), "index.html");
+        assert_eq!(UrlStyle::Directory.html_path("404.html"), "404.html");
+    }
+
+    #[test]
+    fn test_url() {
+        assert_eq!(UrlStyle::Bare.url("/blog/2/"), "/blog/2/");
+        assert_eq!(UrlStyle::Directory.url("/about"), "/about/");
+        assert_eq!(UrlStyle::File.url("/blog/2/"), "/blog/2.html");
+        assert_eq!(UrlStyle::File.url("/"), "/");
+    }
+
+    #[test]
+    fn test_rewrite_links() {
+        let slugs = vec!["/", "/about", "/blog"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(
+            rewrite_links(
+                r#"<a href="/">h</a><a href="/about">a</a><a href='/blog/'>b</a><a href="/about/team">t</a>"#,
+                &slugs,
+                UrlStyle::File
+            ),
+            r#"<a href="/">h</a><a href="/about.html">a</a><a href='/blog.html'>b</a><a href="/about/team">t</a>"#
+        );
+    }
+}
@@ -0,0 +1,101 @@
+use async_std::task;
+use color_eyre::eyre::{Result, WrapErr};
+use notify::{watcher, DebouncedEvent, RecursiveMode, Watcher};
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    sync::mpsc::channel,
+    time::Duration,
+};
+use tracing::instrument;
+
+use crate::{
+    cache::{Cache, CACHE_DIR},
+    cancel,
+    incremental::{rebuild_files, IncrementalOpts},
+    staging::{build_staged, previous_dir, staging_dir},
+};
+
+/// Watch the project directory and incrementally rebuild whatever changes,
+/// calling `on_rebuild` after every successful rebuild. `pages` is the list
+/// of rendered pages from the initial build. Runs until the process exits.
+#[instrument(skip(cache, pages, on_rebuild))]
+pub fn watch<F: FnMut()>(
+    opts: IncrementalOpts<'_>,
+    cache: &mut Cache,
+    mut pages: Vec<String>,
+    mut on_rebuild: F,
+) -> Result<()> {
+    let (tx, rx) = channel();
+    let mut watcher =
+        watcher(tx, Duration::from_millis(100)).wrap_err("Failed to create file watcher")?;
+    watcher
+        .watch(opts.project_root_dir, RecursiveMode::Recursive)
+        .wrap_err_with(|| format!("Failed to watch `{}`", opts.project_root_dir.display()))?;
+    eprintln!("watching `{}` for changes", opts.project_root_dir.display());
+
+    loop {
+        let event = rx.recv().wrap_err("File watcher disconnected")?;
+        // editors tend to write several files at once, so batch up
+        // everything that's already queued into a single rebuild
+        let mut changed = BTreeSet::new();
+        collect_paths(event, &mut changed);
+        while let Ok(event) = rx.try_recv() {
+            collect_paths(event, &mut changed);
+        }
+        let changed: Vec<PathBuf> = changed
+            .into_iter()
+            .filter(|path| is_source_path(&opts, path))
+            .collect();
+        if changed.is_empty() {
+            continue;
+        }
+
+        let rebuilt = build_staged(&opts, cache, |opts, cache| {
+            task::block_on(rebuild_files(opts, cache, &mut pages, &changed))
+        });
+        match rebuilt {
+            Ok(()) => {
+                // watching keeps going past errors, they're only listed
+                let _ = opts.diagnostics.finish();
+                eprintln!("rebuilt {} changed file(s)", changed.len());
+                on_rebuild();
+            }
+            Err(e) if cancel::is_cancelled() => return Err(e),
+            // a broken file shouldn't stop the watcher, the user is
+            // probably about to fix it
+            Err(e) => eprintln!("{:?}", e),
+        }
+    }
+}
+
+fn collect_paths(event: DebouncedEvent, paths: &mut BTreeSet<PathBuf>) {
+    match event {
+        DebouncedEvent::Create(path)
+        | DebouncedEvent::Write(path)
+        | DebouncedEvent::Remove(path) => {
+            paths.insert(path);
+        }
+        DebouncedEvent::Rename(from, to) => {
+            paths.insert(from);
+            paths.insert(to);
+        }
+        _ => {}
+    }
+}
+
+/// Toast writes into the project directory, so make sure we don't
+/// rebuild in response to our own output.
+fn is_source_path(opts: &IncrementalOpts<'_>, path: &Path) -> bool {
+    let root = opts.project_root_dir;
+    let ignored = [
+        opts.output_dir.clone(),
+        staging_dir(&opts.output_dir),
+        previous_dir(&opts.output_dir),
+        root.join(".tmp"),
+        root.join(CACHE_DIR),
+        root.join("node_modules"),
+        root.join(".git"),
+    ];
+    !ignored.iter().any(|dir| path.starts_with(dir))
+}
@@ -0,0 +1,120 @@
+//! `toast import-map`, which bundles the dependencies in `package.json`
+//! into `web_modules` and writes the import map that points at them.
+//!
+//! Each dependency's ESM entry point, and every subpath it lists in
+//! `exports` (like `preact/hooks`), is bundled by `toast-web-modules`
+//! (which uses esbuild). Code shared between them is split into
+//! `web_modules/common/` so it's only loaded once.
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use duct::cmd;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+use tracing::instrument;
+
+use crate::{
+    esinstall::{parse_import_map, ImportMap},
+    node::node_bin,
+    npm_bin::node_script,
+    supervisor::{batch_timeout, run_node},
+};
+
+/// What `toast-web-modules` is asked to bundle
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct WebModulesJob {
+    project_root: PathBuf,
+    out_dir: PathBuf,
+    packages: Vec<String>,
+}
+
+/// The dependencies in `package.json` that are loaded in the browser.
+/// `toast` itself is only used at build time.
+pub fn browser_dependencies(package_json: &str) -> Result<Vec<String>> {
+    let package: serde_json::Value =
+        serde_json::from_str(package_json).wrap_err("Failed to parse `package.json`")?;
+    Ok(package
+        .get("dependencies")
+        .and_then(|deps| deps.as_object())
+        .map(|deps| {
+            deps.keys()
+                .filter(|name| name.as_str() != "toast")
+                .cloned()
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+/// Bundle every dependency into `web_modules` in `output_dir` and write
+/// the import map for them to `import_map_path`
+#[instrument]
+pub fn install_web_modules(
+    project_root_dir: &Path,
+    output_dir: &Path,
+    import_map_path: &Path,
+    npm_bin_dir: &Path,
+) -> Result<ImportMap> {
+    let package_json_path = project_root_dir.join("package.json");
+    let package_json = fs::read_to_string(&package_json_path)
+        .wrap_err_with(|| format!("Failed to read `{}`", package_json_path.display()))?;
+    let packages = browser_dependencies(&package_json)?;
+
+    let imports: BTreeMap<String, String> = if packages.is_empty() {
+        BTreeMap::new()
+    } else {
+        let bin = node_script(npm_bin_dir, "toast-web-modules");
+        let bin_str = bin
+            .to_str()
+            .ok_or_else(|| eyre!("failed to make npm bin into str"))?;
+        let job = WebModulesJob {
+            project_root: project_root_dir.to_path_buf(),
+            out_dir: output_dir.join("web_modules"),
+            packages,
+        };
+        let output = run_node(
+            "toast-web-modules",
+            cmd!(node_bin(), "--unhandled-rejections", "strict", bin_str)
+                .stdin_bytes(serde_json::to_vec(&job)?),
+            batch_timeout(job.packages.len()),
+        )
+        .wrap_err("Failed to bundle dependencies with `toast-web-modules`")?;
+        serde_json::from_str(&output).wrap_err_with(|| {
+            format!(
+                "Failed to parse output from `toast-web-modules`: {}",
+                output
+            )
+        })?
+    };
+
+    let contents = serde_json::to_string_pretty(&serde_json::json!({ "imports": imports }))?;
+    if let Some(dir) = import_map_path.parent() {
+        fs::create_dir_all(dir)
+            .wrap_err_with(|| format!("Failed to create directories for `{}`", dir.display()))?;
+    }
+    fs::write(import_map_path, &contents).wrap_err_with(|| {
+        format!(
+            "Failed to write import map to `{}`",
+            import_map_path.display()
+        )
+    })?;
+    Ok(parse_import_map(&contents)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_browser_dependencies() -> Result<()> {
+        let package_json = r#"{
+          "dependencies": { "preact": "^10.4.8", "toast": "^0.3.3" },
+          "devDependencies": { "prettier": "^2.1.1" }
+        }"#;
+        assert_eq!(browser_dependencies(package_json)?, vec!["preact"]);
+        assert!(browser_dependencies("{}")?.is_empty());
+        Ok(())
+    }
+}